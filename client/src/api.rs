@@ -19,6 +19,13 @@ pub struct InstancePathParams {
     pub instance_id: Uuid,
 }
 
+#[derive(Clone, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceDiskPathParams {
+    pub instance_id: Uuid,
+    /// Name of the disk, as given in the server configuration.
+    pub name: String,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
 pub struct InstanceEnsureRequest {
     pub properties: InstanceProperties,
@@ -238,6 +245,183 @@ pub struct DiskRequest {
     // Crucible related opts
     pub gen: u64,
     pub volume_construction_request: crucible::VolumeConstructionRequest,
+
+    #[serde(default)]
+    pub throttle: Option<DiskThrottle>,
+
+    /// Identifying details presented for the disk when attached as an NVMe
+    /// device, in place of the defaults.
+    #[serde(default)]
+    pub nvme_identity: Option<NvmeDiskIdentity>,
+
+    /// Revision of the NVMe specification presented for the disk when
+    /// attached as an NVMe device, defaulting to 1.0.
+    #[serde(default)]
+    pub nvme_version: Option<NvmeVersion>,
+}
+
+/// Revision of the NVMe specification presented by an NVMe device.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema)]
+pub enum NvmeVersion {
+    #[serde(rename = "1.0")]
+    V1_0,
+    #[serde(rename = "1.3")]
+    V1_3,
+    #[serde(rename = "1.4")]
+    V1_4,
+}
+
+/// Identifying details of a disk attached as an NVMe device.
+///
+/// Text fields must be printable ASCII of no more than 20 characters for the
+/// serial number, 40 for the model number and 8 for the firmware revision.
+/// Identifiers are given as hex digits.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct NvmeDiskIdentity {
+    /// Serial number, which otherwise defaults to the disk name.
+    pub serial_number: Option<String>,
+    /// Model number.
+    pub model_number: Option<String>,
+    /// Firmware revision.
+    pub firmware_rev: Option<String>,
+    /// IEEE OUI of the vendor (6 hex digits).
+    pub ieee_oui: Option<String>,
+    /// IEEE Extended Unique Identifier of the namespace (16 hex digits).
+    pub eui64: Option<String>,
+    /// Globally unique identifier of the namespace (32 hex digits).
+    pub nguid: Option<String>,
+}
+
+/// I/O limits applied to a disk.
+///
+/// Limits which are absent are not enforced, while limits of zero are
+/// rejected.  Burst allowances default to one second's worth of the
+/// corresponding sustained rate.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct DiskThrottle {
+    /// Sustained operations per second.
+    pub iops: Option<u64>,
+    /// Operations which may be issued at once, having been accumulated at the
+    /// sustained rate while idle.
+    pub iops_burst: Option<u64>,
+    /// Sustained bytes per second.
+    pub bps: Option<u64>,
+    /// Bytes which may be transferred at once, having been accumulated at the
+    /// sustained rate while idle.
+    pub bps_burst: Option<u64>,
+}
+
+/// I/O statistics for one type of disk operation.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct DiskOpStats {
+    /// Completed operations.
+    pub ops: u64,
+    /// Bytes transferred by completed operations.
+    pub bytes: u64,
+    /// Operations which completed with an error.
+    pub errors: u64,
+    /// Histogram of latencies from request arrival to completion.
+    ///
+    /// Entry 0 counts latencies below 1us, while entry `n` counts those in
+    /// `[2^(n-1), 2^n)` microseconds.  The final entry also counts any
+    /// latencies beyond its range.
+    pub latency_us: Vec<u64>,
+}
+
+/// I/O statistics for a disk.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskStats {
+    pub name: String,
+    pub read: DiskOpStats,
+    pub write: DiskOpStats,
+    pub flush: DiskOpStats,
+    pub discard: DiskOpStats,
+    pub write_zeroes: DiskOpStats,
+    /// Requests received from the guest but not yet completed.
+    pub in_flight: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceDiskStatsResponse {
+    pub disks: Vec<DiskStats>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskThrottleRequest {
+    pub throttle: DiskThrottle,
+}
+
+/// Range of logical blocks, from `start` up to (but excluding) `end`.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskLbaRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Faults injected into the operations issued to a disk.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct DiskFaults {
+    /// Fail every Nth operation.
+    pub fail_every: Option<u64>,
+    /// Fail any operation touching these ranges of logical blocks.
+    #[serde(default)]
+    pub fail_lba_ranges: Vec<DiskLbaRange>,
+    /// Delay the completion of operations which are not failed.
+    pub latency_ms: Option<u64>,
+    /// Complete all operations as unsupported.
+    #[serde(default)]
+    pub unsupported: bool,
+    /// Fail all operations which would modify the disk.
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+/// Faults to inject into a disk, which must be configured for fault injection.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskFaultsRequest {
+    pub faults: DiskFaults,
+}
+
+/// Media to insert into a read-only disk.
+///
+/// Any faults injected into the disk are injected into the new media too.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskMediaRequest {
+    /// Path to the (read-only) media to insert, or `None` to eject any media
+    /// currently present.  The media must reside within the media directory
+    /// configured for the server, against which a relative path is resolved.
+    pub path: Option<String>,
+    /// Logical block size of the inserted media, defaulting to that of the
+    /// media it replaces.
+    #[serde(default)]
+    pub block_size: Option<u32>,
+}
+
+/// New size for a disk, whose backend must support resizing.  Others are
+/// refused with the error code `Unsupported`.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskResizeRequest {
+    /// New size of the disk in bytes, which must be a multiple of its block
+    /// size.
+    pub size: u64,
+}
+
+/// Action to take on the copy-on-write overlay atop a disk.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema)]
+pub enum DiskOverlayAction {
+    /// Throw away the writes held in the overlay, reverting the disk to the
+    /// contents of its backend.
+    Discard,
+    /// Write the contents of the overlay into the image underlying the disk
+    /// (which must be a raw `file` block device), and then discard them.
+    Commit,
+}
+
+/// Action for the overlay of a disk, which must be configured with one.  Its
+/// instance must not be running.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskOverlayRequest {
+    pub action: DiskOverlayAction,
 }
 
 #[derive(Clone, Deserialize, Serialize, JsonSchema)]
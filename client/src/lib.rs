@@ -158,6 +158,91 @@ impl Client {
         self.put_no_response(path, Some(body)).await
     }
 
+    /// Returns I/O statistics for the disks attached to an instance.
+    pub async fn instance_disk_stats(
+        &self,
+        id: Uuid,
+    ) -> Result<api::InstanceDiskStatsResponse, Error> {
+        let path =
+            format!("http://{}/instances/{}/disk-stats", self.address, id);
+        self.get(path, None).await
+    }
+
+    /// Updates the I/O limits applied to a disk.
+    pub async fn disk_throttle_put(
+        &self,
+        id: Uuid,
+        name: &str,
+        request: &api::DiskThrottleRequest,
+    ) -> Result<(), Error> {
+        let path = format!(
+            "http://{}/instances/{}/disks/{}/throttle",
+            self.address, id, name
+        );
+        let body = Body::from(serde_json::to_string(request).unwrap());
+        self.put_no_response(path, Some(body)).await
+    }
+
+    /// Updates the faults injected into a disk's operations.
+    pub async fn disk_faults_put(
+        &self,
+        id: Uuid,
+        name: &str,
+        request: &api::DiskFaultsRequest,
+    ) -> Result<(), Error> {
+        let path = format!(
+            "http://{}/instances/{}/disks/{}/faults",
+            self.address, id, name
+        );
+        let body = Body::from(serde_json::to_string(request).unwrap());
+        self.put_no_response(path, Some(body)).await
+    }
+
+    /// Inserts media into (or ejects media from) a read-only disk.
+    pub async fn disk_media_put(
+        &self,
+        id: Uuid,
+        name: &str,
+        request: &api::DiskMediaRequest,
+    ) -> Result<(), Error> {
+        let path = format!(
+            "http://{}/instances/{}/disks/{}/media",
+            self.address, id, name
+        );
+        let body = Body::from(serde_json::to_string(request).unwrap());
+        self.put_no_response(path, Some(body)).await
+    }
+
+    /// Resizes a disk, notifying the guest of its new capacity.
+    pub async fn disk_resize_put(
+        &self,
+        id: Uuid,
+        name: &str,
+        request: &api::DiskResizeRequest,
+    ) -> Result<(), Error> {
+        let path = format!(
+            "http://{}/instances/{}/disks/{}/resize",
+            self.address, id, name
+        );
+        let body = Body::from(serde_json::to_string(request).unwrap());
+        self.put_no_response(path, Some(body)).await
+    }
+
+    /// Discards or commits the copy-on-write overlay atop a disk.
+    pub async fn disk_overlay_put(
+        &self,
+        id: Uuid,
+        name: &str,
+        request: &api::DiskOverlayRequest,
+    ) -> Result<(), Error> {
+        let path = format!(
+            "http://{}/instances/{}/disks/{}/overlay",
+            self.address, id, name
+        );
+        let body = Body::from(serde_json::to_string(request).unwrap());
+        self.put_no_response(path, Some(body)).await
+    }
+
     /// Get the status of an ongoing migration
     pub async fn instance_migrate_status(
         &self,
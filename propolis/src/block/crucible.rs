@@ -82,6 +82,18 @@ impl block::Backend for CrucibleBackend {
         }
     }
 
+    fn driver(&self) -> Option<Arc<block::Driver>> {
+        self.driver.lock().unwrap().clone()
+    }
+
+    fn resize(&self, _total_size: u64) -> Result<()> {
+        // The extents of a volume are fixed by its regions
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "resizing crucible volumes not supported",
+        ))
+    }
+
     fn attach(
         &self,
         dev: Arc<dyn block::Device>,
@@ -98,7 +110,7 @@ impl block::Backend for CrucibleBackend {
             });
 
         // Spawn driver to service block dev requests
-        let driver = Arc::new(block::Driver::new(dev, req_handler));
+        let driver = Arc::new(block::Driver::new(dev, req_handler, None));
         driver.spawn("crucible", NonZeroUsize::new(8).unwrap(), disp)?;
         *driverg = Some(driver);
 
@@ -166,6 +178,30 @@ fn process_write_request(
     Ok(())
 }
 
+/// Write zeroes to crucible, a bounded chunk at a time
+fn process_write_zeroes_request(
+    block_io: &(dyn BlockIO + Send + Sync),
+    offset: u64,
+    len: usize,
+) -> std::result::Result<(), CrucibleError> {
+    // A whole number of blocks, for any supported block size
+    const CHUNK: usize = 1024 * 1024;
+
+    let mut done = 0;
+    while done < len {
+        let n = CHUNK.min(len - done);
+        let vec: Vec<u8> = vec![0; n];
+
+        let offset = block_io.byte_offset_to_block(offset + done as u64)?;
+
+        let mut waiter = block_io.write(offset, crucible::Bytes::from(vec))?;
+        waiter.block_wait()?;
+        done += n;
+    }
+
+    Ok(())
+}
+
 /// Send flush to crucible
 fn process_flush_request(
     block_io: &(dyn BlockIO + Send + Sync),
@@ -211,6 +247,21 @@ fn process_request(
             process_flush_request(block_io)
                 .map_err(map_crucible_error_to_io)?;
         }
+        block::Operation::Discard(_off, _len) => {
+            // Discard is advisory, and crucible has no means to deallocate
+            // storage, so there is nothing to do.
+        }
+        block::Operation::WriteZeroes(off, len) => {
+            if read_only {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    "backend is read-only",
+                ));
+            }
+
+            process_write_zeroes_request(block_io, off as u64, len)
+                .map_err(map_crucible_error_to_io)?;
+        }
     }
 
     Ok(())
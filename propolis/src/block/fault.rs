@@ -0,0 +1,313 @@
+//! Fault-injecting wrapper around another block backend.
+//!
+//! Requests from the block device are checked against a [`FaultPolicy`] before
+//! being passed on to the driver of the inner backend, which applies any fault
+//! selected for them: those to be failed are completed without the inner
+//! backend ever seeing them, but are otherwise accounted for (in statistics
+//! and the like) just as any other failure.
+
+use std::io::Result;
+use std::num::NonZeroU64;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::DeviceInfo;
+use crate::block;
+use crate::dispatch::{DispCtx, Dispatcher};
+use crate::inventory::Entity;
+
+/// Faults to inject into the requests issued to a [`FaultBackend`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FaultPolicy {
+    /// Fail every Nth operation
+    pub fail_every: Option<NonZeroU64>,
+    /// Fail any operation touching these ranges of logical blocks
+    pub fail_lba_ranges: Vec<Range<u64>>,
+    /// Delay the completion of operations passed to the inner backend
+    pub latency: Option<Duration>,
+    /// Complete all operations as unsupported
+    pub unsupported: bool,
+    /// Fail all operations which would modify the disk
+    pub read_only: bool,
+}
+
+/// Fault to be injected into a request by the [`block::Driver`].
+#[derive(Copy, Clone, Debug)]
+pub(super) enum Fault {
+    /// Complete the request with this result, without servicing it
+    Fail(block::Result),
+    /// Delay the completion of the request once it has been serviced
+    Delay(Duration),
+}
+
+struct FaultState {
+    policy: Mutex<FaultPolicy>,
+    /// Count of operations checked against the policy
+    count: AtomicU64,
+    block_size: u64,
+}
+
+impl FaultState {
+    fn check(&self, op: block::Operation, len: usize) -> Option<Fault> {
+        let nth = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        let policy = self.policy.lock().unwrap();
+
+        if policy.unsupported {
+            return Some(Fault::Fail(block::Result::Unsupported));
+        }
+        let range = match op {
+            block::Operation::Read(off) => Some(off),
+            block::Operation::Write(off)
+            | block::Operation::Discard(off, _)
+            | block::Operation::WriteZeroes(off, _) => {
+                if policy.read_only {
+                    return Some(Fault::Fail(block::Result::Failure));
+                }
+                Some(off)
+            }
+            block::Operation::Flush(_, _) => None,
+        }
+        .map(|off| {
+            let first = off as u64 / self.block_size;
+            let last = (off + len.max(1) - 1) as u64 / self.block_size;
+            first..(last + 1)
+        });
+        if let Some(range) = range {
+            if policy
+                .fail_lba_ranges
+                .iter()
+                .any(|r| r.start < range.end && range.start < r.end)
+            {
+                return Some(Fault::Fail(block::Result::Failure));
+            }
+        }
+        if let Some(every) = policy.fail_every {
+            if nth % every.get() == 0 {
+                return Some(Fault::Fail(block::Result::Failure));
+            }
+        }
+        policy.latency.map(Fault::Delay)
+    }
+}
+
+/// Block device interposed between the real device and the inner backend.
+struct FaultDevice {
+    dev: Arc<dyn block::Device>,
+    state: Arc<FaultState>,
+}
+
+impl block::Device for FaultDevice {
+    fn next(&self, ctx: &DispCtx) -> Option<block::Request> {
+        let mut req = self.dev.next(ctx)?;
+        req.fault = self.state.check(req.oper(), req.len());
+        Some(req)
+    }
+
+    fn set_notifier(&self, f: Option<Box<block::NotifierFn>>) {
+        self.dev.set_notifier(f)
+    }
+}
+
+/// Block backend which injects faults into requests bound for another.
+pub struct FaultBackend {
+    inner: Arc<dyn block::Backend>,
+    state: Arc<FaultState>,
+}
+
+impl FaultBackend {
+    pub fn create(
+        inner: Arc<dyn block::Backend>,
+        policy: FaultPolicy,
+    ) -> Result<Arc<Self>> {
+        let block_size = inner.info().block_size as u64;
+        let state = Arc::new(FaultState {
+            policy: Mutex::new(policy),
+            count: AtomicU64::new(0),
+            block_size,
+        });
+
+        Ok(Arc::new(Self { inner, state }))
+    }
+
+    /// Currently configured fault policy.
+    pub fn policy(&self) -> FaultPolicy {
+        self.state.policy.lock().unwrap().clone()
+    }
+
+    /// Replace the fault policy, taking effect for subsequent requests.
+    pub fn set_policy(&self, policy: FaultPolicy) {
+        *self.state.policy.lock().unwrap() = policy;
+    }
+}
+
+impl block::Backend for FaultBackend {
+    fn info(&self) -> DeviceInfo {
+        self.inner.info()
+    }
+
+    fn driver(&self) -> Option<Arc<block::Driver>> {
+        self.inner.driver()
+    }
+
+    fn resize(&self, total_size: u64) -> Result<()> {
+        self.inner.resize(total_size)
+    }
+
+    fn attach(
+        &self,
+        dev: Arc<dyn block::Device>,
+        disp: &Dispatcher,
+    ) -> Result<()> {
+        let fdev =
+            Arc::new(FaultDevice { dev, state: Arc::clone(&self.state) });
+        self.inner.attach(fdev, disp)
+    }
+}
+
+impl Entity for FaultBackend {
+    fn type_name(&self) -> &'static str {
+        "block-fault"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::test_util::{TestDevice, TestInstance, READ_BASE};
+    use crate::block::Backend;
+    use crate::common::GuestRegion;
+
+    fn state(policy: FaultPolicy) -> FaultState {
+        FaultState {
+            policy: Mutex::new(policy),
+            count: AtomicU64::new(0),
+            block_size: 512,
+        }
+    }
+
+    fn fails(f: Option<Fault>) -> bool {
+        matches!(f, Some(Fault::Fail(block::Result::Failure)))
+    }
+
+    #[test]
+    fn fail_every_nth() {
+        let s = state(FaultPolicy {
+            fail_every: NonZeroU64::new(3),
+            ..Default::default()
+        });
+        let results: Vec<bool> = (0..6)
+            .map(|_| fails(s.check(block::Operation::Read(0), 512)))
+            .collect();
+        assert_eq!(results, [false, false, true, false, false, true]);
+    }
+
+    #[test]
+    fn fail_lba_range() {
+        let s = state(FaultPolicy {
+            fail_lba_ranges: vec![10..20],
+            ..Default::default()
+        });
+        // Blocks 8 and 9: just short of the range
+        assert!(!fails(s.check(block::Operation::Read(8 * 512), 1024)));
+        // Blocks 9 and 10: overlapping the start
+        assert!(fails(s.check(block::Operation::Write(9 * 512), 1024)));
+        assert!(fails(s.check(block::Operation::Read(19 * 512), 512)));
+        assert!(!fails(s.check(block::Operation::Read(20 * 512), 512)));
+        // Flushes do not target specific blocks
+        assert!(!fails(s.check(block::Operation::Flush(0, 0), 0)));
+    }
+
+    #[test]
+    fn read_only_and_unsupported() {
+        let s = state(FaultPolicy { read_only: true, ..Default::default() });
+        assert!(!fails(s.check(block::Operation::Read(0), 512)));
+        assert!(fails(s.check(block::Operation::Write(0), 512)));
+        assert!(fails(s.check(block::Operation::Discard(0, 512), 512)));
+
+        let s = state(FaultPolicy { unsupported: true, ..Default::default() });
+        assert!(matches!(
+            s.check(block::Operation::Read(0), 512),
+            Some(Fault::Fail(block::Result::Unsupported))
+        ));
+    }
+
+    #[test]
+    fn injected_failures_recorded() {
+        let test = TestInstance::new();
+        let inner =
+            block::InMemoryBackend::create(vec![0; 4096], false, 512).unwrap();
+        let be = FaultBackend::create(
+            inner,
+            FaultPolicy {
+                fail_every: NonZeroU64::new(2),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let dev = TestDevice::new();
+        test.attach(be.as_ref(), &dev).unwrap();
+
+        let results: Vec<bool> = (0..4)
+            .map(|_| {
+                matches!(test.read(&dev, 0, 512).0, block::Result::Success)
+            })
+            .collect();
+        assert_eq!(results, [true, false, true, false]);
+
+        // The failures are counted as any others would be
+        let stats = be.driver().unwrap().stats().snapshot();
+        assert_eq!(stats.read.ops, 4);
+        assert_eq!(stats.read.errors, 2);
+        assert_eq!(stats.read.bytes, 2 * 512);
+    }
+
+    #[test]
+    fn latency_does_not_hold_workers() {
+        const LATENCY: Duration = Duration::from_secs(1);
+
+        let test = TestInstance::new();
+        let inner =
+            block::InMemoryBackend::create(vec![0; 8192], false, 512).unwrap();
+        let be = FaultBackend::create(
+            inner,
+            FaultPolicy {
+                latency: Some(LATENCY),
+                fail_lba_ranges: vec![8..9],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let dev = TestDevice::new();
+        test.attach(be.as_ref(), &dev).unwrap();
+
+        // The in-memory backend has but a single worker, which would be held
+        // by each delayed write in turn were it left to serve the delay...
+        let delayed: Vec<_> = (0..4)
+            .map(|i| {
+                test.submit(&dev, |donef| {
+                    let region = GuestRegion(READ_BASE, 512);
+                    block::Request::new_write(i * 512, vec![region], donef)
+                })
+            })
+            .collect();
+
+        // ... so a request failed outright, issued after them, would complete
+        // only after they do.
+        let (res, _) = test.read(&dev, 8 * 512, 512);
+        assert!(matches!(res, block::Result::Failure));
+        for p in delayed.iter() {
+            assert!(p.try_wait().is_none());
+        }
+        for p in delayed {
+            assert!(matches!(p.wait(), block::Result::Success));
+        }
+
+        // The delay is reflected in the recorded latency
+        let stats = be.driver().unwrap().stats().snapshot();
+        assert_eq!(stats.write.ops, 4);
+        // (The first 20 buckets hold latencies below 2^19us, some 524ms)
+        assert_eq!(stats.write.latency_us[..20].iter().sum::<u64>(), 0);
+    }
+}
@@ -1,5 +1,7 @@
+use std::convert::TryFrom;
 use std::io::{Error, ErrorKind, Result};
 use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
 use super::DeviceInfo;
@@ -15,7 +17,7 @@ pub struct InMemoryBackend {
 
     read_only: bool,
     block_size: usize,
-    sectors: usize,
+    sectors: AtomicU64,
 }
 
 impl InMemoryBackend {
@@ -55,7 +57,7 @@ impl InMemoryBackend {
 
             read_only,
             block_size,
-            sectors: len / block_size,
+            sectors: AtomicU64::new((len / block_size) as u64),
         };
 
         Ok(Arc::new(this))
@@ -66,11 +68,34 @@ impl block::Backend for InMemoryBackend {
     fn info(&self) -> DeviceInfo {
         DeviceInfo {
             block_size: self.block_size as u32,
-            total_size: self.sectors as u64,
+            total_size: self.sectors.load(Ordering::Acquire),
             writable: !self.read_only,
         }
     }
 
+    fn driver(&self) -> Option<Arc<block::Driver>> {
+        self.driver.lock().unwrap().clone()
+    }
+
+    fn resize(&self, total_size: u64) -> Result<()> {
+        if self.read_only {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "backend is read-only",
+            ));
+        }
+        let mut bytes = self.bytes.lock().unwrap();
+        if total_size < self.sectors.load(Ordering::Acquire) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "shrinking disk not supported",
+            ));
+        }
+        bytes.resize(total_size as usize * self.block_size, 0);
+        self.sectors.store(total_size, Ordering::Release);
+        Ok(())
+    }
+
     fn attach(
         &self,
         dev: Arc<dyn block::Device>,
@@ -87,7 +112,7 @@ impl block::Backend for InMemoryBackend {
             });
 
         // Spawn driver to service block dev requests
-        let driver = Arc::new(block::Driver::new(dev, req_handler));
+        let driver = Arc::new(block::Driver::new(dev, req_handler, None));
         driver.spawn("mem", NonZeroUsize::new(1).unwrap(), disp)?;
         *driverg = Some(driver);
 
@@ -101,6 +126,23 @@ impl Entity for InMemoryBackend {
     }
 }
 
+/// Check that `len` bytes at `offset` lie within `size` bytes of storage,
+/// returning the bounds of that range.
+fn check_range(offset: u64, len: usize, size: usize) -> Result<(usize, usize)> {
+    let end =
+        usize::try_from(offset).ok().and_then(|start| start.checked_add(len));
+    match end {
+        Some(end) if end <= size => Ok((offset as usize, end)),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "invalid offset {} and len {} when bytes len is {}",
+                offset, len, size,
+            ),
+        )),
+    }
+}
+
 /// Read from bytes into guest memory
 fn process_read_request(
     bytes: &Mutex<Vec<u8>>,
@@ -110,20 +152,7 @@ fn process_read_request(
 ) -> Result<()> {
     let bytes = bytes.lock().unwrap();
 
-    let start = offset as usize;
-    let end = offset as usize + len;
-
-    if start >= bytes.len() || end > bytes.len() {
-        return Err(std::io::Error::new(
-            ErrorKind::InvalidInput,
-            format!(
-                "invalid offset {} and len {} when bytes len is {}",
-                offset,
-                len,
-                bytes.len(),
-            ),
-        ));
-    }
+    let (start, end) = check_range(offset, len, bytes.len())?;
 
     let data = &bytes[start..end];
 
@@ -145,20 +174,7 @@ fn process_write_request(
 ) -> Result<()> {
     let mut bytes = bytes.lock().unwrap();
 
-    let start = offset as usize;
-    let end = offset as usize + len;
-
-    if start >= bytes.len() || end > bytes.len() {
-        return Err(std::io::Error::new(
-            ErrorKind::InvalidInput,
-            format!(
-                "invalid offset {} and len {} when bytes len is {}",
-                offset,
-                len,
-                bytes.len(),
-            ),
-        ));
-    }
+    let (start, end) = check_range(offset, len, bytes.len())?;
 
     let data = &mut bytes[start..end];
 
@@ -171,6 +187,21 @@ fn process_write_request(
     Ok(())
 }
 
+/// Zero a range of bytes
+fn process_zero_request(
+    bytes: &Mutex<Vec<u8>>,
+    offset: u64,
+    len: usize,
+) -> Result<()> {
+    let mut bytes = bytes.lock().unwrap();
+
+    let (start, end) = check_range(offset, len, bytes.len())?;
+
+    bytes[start..end].fill(0);
+
+    Ok(())
+}
+
 fn process_request(
     bytes: &Mutex<Vec<u8>>,
     req: &block::Request,
@@ -203,7 +234,37 @@ fn process_request(
         block::Operation::Flush(_off, _len) => {
             // nothing to do
         }
+        block::Operation::Discard(off, len)
+        | block::Operation::WriteZeroes(off, len) => {
+            if read_only {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    "backend is read-only",
+                ));
+            }
+
+            process_zero_request(bytes, off as u64, len)?;
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn range_checks() {
+        assert_eq!(check_range(0, 4096, 4096).unwrap(), (0, 4096));
+        assert_eq!(check_range(512, 512, 4096).unwrap(), (512, 1024));
+
+        // An empty range is acceptable anywhere up to the end of the device
+        assert_eq!(check_range(4096, 0, 4096).unwrap(), (4096, 4096));
+        assert!(check_range(4097, 0, 4096).is_err());
+
+        assert!(check_range(3584, 1024, 4096).is_err());
+        assert!(check_range(512, usize::MAX, 4096).is_err());
+        assert!(check_range(u64::MAX, 512, 4096).is_err());
+    }
+}
@@ -1,16 +1,23 @@
 //! Implements an interface to virtualized block devices.
 
 use std::collections::VecDeque;
+use std::future::Future;
 use std::num::NonZeroUsize;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
 
 use crate::common::*;
 use crate::dispatch::{AsyncCtx, DispCtx, Dispatcher, SyncCtx, WakeFn};
 use crate::vmm::{MemCtx, SubMapping};
 
 use futures::future::BoxFuture;
-use tokio::sync::{Notify, Semaphore};
+use tokio::runtime::Handle;
+use tokio::sync::{oneshot, Notify, Semaphore};
+
+mod fault;
+use fault::Fault;
+pub use fault::{FaultBackend, FaultPolicy};
 
 mod file;
 pub use file::FileBackend;
@@ -23,6 +30,21 @@ pub use self::crucible::CrucibleBackend;
 mod in_memory;
 pub use in_memory::InMemoryBackend;
 
+mod overlay;
+pub use overlay::{OverlayBackend, OverlayScratch};
+
+mod qcow2;
+pub use qcow2::Qcow2Backend;
+
+mod stats;
+pub use stats::{OpStatsSnapshot, Stats, StatsSnapshot, LATENCY_BUCKETS};
+
+mod throttle;
+pub use throttle::{Throttle, ThrottleLimits};
+
+#[cfg(test)]
+pub(crate) mod test_util;
+
 pub type ByteOffset = usize;
 pub type ByteLen = usize;
 
@@ -35,6 +57,10 @@ pub enum Operation {
     Write(ByteOffset),
     /// Flush buffer(s) for [offset, offset + len)
     Flush(ByteOffset, ByteLen),
+    /// Discard (deallocate) [offset, offset + len)
+    Discard(ByteOffset, ByteLen),
+    /// Write zeroes to [offset, offset + len)
+    WriteZeroes(ByteOffset, ByteLen),
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -65,6 +91,14 @@ pub struct Request {
     /// submit it to the backend, we update this to point to the correct shared reference.
     /// See [`Request::track_outstanding`].
     outstanding: Option<Arc<OutstandingReqs>>,
+
+    /// Fault to be injected by the [`Driver`] when servicing the request, as
+    /// selected by a [`FaultBackend`]
+    fault: Option<Fault>,
+
+    /// Result of servicing the request, should the backend have deferred its
+    /// completion with [`Request::defer`]
+    deferred: Mutex<Option<oneshot::Receiver<Result>>>,
 }
 impl Request {
     pub fn new_read(
@@ -73,7 +107,14 @@ impl Request {
         donef: Box<CompleteFn>,
     ) -> Self {
         let op = Operation::Read(off);
-        Self { op, regions, donef: Some(donef), outstanding: None }
+        Self {
+            op,
+            regions,
+            donef: Some(donef),
+            outstanding: None,
+            fault: None,
+            deferred: Mutex::new(None),
+        }
     }
 
     pub fn new_write(
@@ -82,12 +123,54 @@ impl Request {
         donef: Box<CompleteFn>,
     ) -> Self {
         let op = Operation::Write(off);
-        Self { op, regions, donef: Some(donef), outstanding: None }
+        Self {
+            op,
+            regions,
+            donef: Some(donef),
+            outstanding: None,
+            fault: None,
+            deferred: Mutex::new(None),
+        }
     }
 
     pub fn new_flush(off: usize, len: usize, donef: Box<CompleteFn>) -> Self {
         let op = Operation::Flush(off, len);
-        Self { op, regions: Vec::new(), donef: Some(donef), outstanding: None }
+        Self {
+            op,
+            regions: Vec::new(),
+            donef: Some(donef),
+            outstanding: None,
+            fault: None,
+            deferred: Mutex::new(None),
+        }
+    }
+
+    pub fn new_discard(off: usize, len: usize, donef: Box<CompleteFn>) -> Self {
+        let op = Operation::Discard(off, len);
+        Self {
+            op,
+            regions: Vec::new(),
+            donef: Some(donef),
+            outstanding: None,
+            fault: None,
+            deferred: Mutex::new(None),
+        }
+    }
+
+    pub fn new_write_zeroes(
+        off: usize,
+        len: usize,
+        donef: Box<CompleteFn>,
+    ) -> Self {
+        let op = Operation::WriteZeroes(off, len);
+        Self {
+            op,
+            regions: Vec::new(),
+            donef: Some(donef),
+            outstanding: None,
+            fault: None,
+            deferred: Mutex::new(None),
+        }
     }
 
     /// Type of operation being issued.
@@ -108,7 +191,17 @@ impl Request {
             Operation::Write(_) => {
                 self.regions.iter().map(|r| mem.readable_region(r)).collect()
             }
-            Operation::Flush(_, _) => None,
+            Operation::Flush(_, _)
+            | Operation::Discard(_, _)
+            | Operation::WriteZeroes(_, _) => None,
+        }
+    }
+
+    /// Number of bytes transferred to or from guest memory by the operation
+    pub fn xfer_len(&self) -> usize {
+        match &self.op {
+            Operation::Read(_) | Operation::Write(_) => self.len(),
+            _ => 0,
         }
     }
 
@@ -118,7 +211,9 @@ impl Request {
             Operation::Read(_) | Operation::Write(_) => {
                 self.regions.iter().map(|r| r.1).sum()
             }
-            Operation::Flush(_, len) => *len,
+            Operation::Flush(_, len)
+            | Operation::Discard(_, len)
+            | Operation::WriteZeroes(_, len) => *len,
         }
     }
 
@@ -134,6 +229,18 @@ impl Request {
             .decrement();
     }
 
+    /// Defer the completion of the request past the return of the backend's
+    /// request handler, until its result is sent through the returned channel.
+    ///
+    /// The [`Driver`] waits for that result without tying up a worker thread,
+    /// so the backend may finish servicing the request by way of another.
+    fn defer(&self) -> oneshot::Sender<Result> {
+        let (tx, rx) = oneshot::channel();
+        let old = self.deferred.lock().unwrap().replace(rx);
+        assert!(old.is_none(), "request already deferred");
+        tx
+    }
+
     /// Update this request to plug into the outstanding I/O requests for a block device & backend.
     fn track_outstanding(&mut self, outstanding: Arc<OutstandingReqs>) {
         let old = std::mem::replace(&mut self.outstanding, Some(outstanding));
@@ -165,6 +272,23 @@ pub trait Device: Send + Sync + 'static {
     fn next(&self, ctx: &DispCtx) -> Option<Request>;
 
     fn set_notifier(&self, f: Option<Box<NotifierFn>>);
+
+    /// Present the media described by `info` (or no media at all) in place of
+    /// any current media, notifying the guest of the change.  This is also
+    /// used to report a change in the size of the current media.
+    ///
+    /// While no media is present, the device is expected to fail any requests
+    /// itself, as there is no backend to service them.
+    fn set_media(
+        &self,
+        _info: Option<DeviceInfo>,
+        _ctx: &DispCtx,
+    ) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "media change not supported by device",
+        ))
+    }
 }
 
 pub trait Backend: Send + Sync + 'static {
@@ -174,6 +298,76 @@ pub trait Backend: Send + Sync + 'static {
         disp: &Dispatcher,
     ) -> std::io::Result<()>;
     fn info(&self) -> DeviceInfo;
+
+    /// Driver servicing requests for this backend, once attached.
+    fn driver(&self) -> Option<Arc<Driver>> {
+        None
+    }
+
+    /// Change the size of the backing storage to `total_size` logical blocks.
+    ///
+    /// Backends which cannot be resized fail with
+    /// [`std::io::ErrorKind::Unsupported`].  The device is not notified: see
+    /// [`resize`] for that.
+    fn resize(&self, total_size: u64) -> std::io::Result<()>;
+
+    /// Stop servicing requests from the attached device.
+    ///
+    /// Requests already issued to the backend are completed, but no more are
+    /// taken from the device.  A detached backend cannot be attached again.
+    fn detach(&self) {
+        if let Some(driver) = self.driver() {
+            driver.stop();
+        }
+    }
+}
+
+/// Resize the storage attached to a running block device, notifying the guest
+/// of the new capacity.
+pub fn resize(
+    dev: &Arc<dyn Device>,
+    backend: &Arc<dyn Backend>,
+    total_size: u64,
+    disp: &Dispatcher,
+) -> std::io::Result<()> {
+    backend.resize(total_size)?;
+    let info = backend.info();
+    let mut res = Ok(());
+    disp.with_ctx(|ctx| res = dev.set_media(Some(info), ctx));
+    res
+}
+
+/// Change the media presented by a running block device.
+///
+/// Any `old` backend is first detached from `dev`, and the requests it has
+/// already taken from the device are allowed to complete.  Only then is the
+/// guest shown the `new` backend (or no media at all), which is attached to
+/// service subsequent requests.
+pub async fn change_media(
+    dev: &Arc<dyn Device>,
+    old: Option<&Arc<dyn Backend>>,
+    new: Option<&Arc<dyn Backend>>,
+    disp: &Dispatcher,
+) -> std::io::Result<()> {
+    if let Some(old) = old {
+        old.detach();
+        if let Some(driver) = old.driver() {
+            driver.drained().await;
+        }
+    }
+
+    let info = new.map(|be| be.info());
+    let mut res = Ok(());
+    disp.with_ctx(|ctx| res = dev.set_media(info, ctx));
+    res?;
+
+    if let Some(new) = new {
+        // The backend driver spawns tasks onto the instance runtime
+        let hdl = disp.handle();
+        let _guard = hdl.as_ref().map(|hdl| hdl.enter());
+        new.attach(Arc::clone(dev), disp)?;
+    }
+    Ok(())
 }
 
 pub type NotifierFn = dyn Fn(&dyn Device, &DispCtx) + Send + Sync + 'static;
@@ -351,6 +545,99 @@ impl Notifier {
 pub type BackendProcessFn =
     dyn Fn(&Request, &DispCtx) -> std::io::Result<()> + Send + Sync + 'static;
 
+pub type BackendBatchFn =
+    dyn Fn(&Batch, &DispCtx) -> std::io::Result<()> + Send + Sync + 'static;
+
+/// Limits on the requests which a [`Driver`] may merge into a [`Batch`].
+#[derive(Copy, Clone, Debug)]
+pub struct BatchLimits {
+    /// Maximum number of requests merged into one batch
+    pub max_reqs: usize,
+    /// Maximum total length (in bytes) of the merged requests
+    pub max_bytes: usize,
+    /// Maximum number of guest memory regions across the merged requests
+    pub max_regions: usize,
+}
+
+/// Adjacent reads (or writes) merged by a [`Driver`] to be issued to the
+/// backend as a single operation.
+pub struct Batch {
+    /// Operation covering the entire batch
+    op: Operation,
+    /// Requests merged into the batch, in order of ascending offset
+    reqs: Vec<Request>,
+}
+impl Batch {
+    /// Type of operation being issued, starting at the offset of the first
+    /// merged request.
+    pub fn oper(&self) -> Operation {
+        self.op
+    }
+
+    /// Requests merged into the batch
+    pub fn requests(&self) -> &[Request] {
+        &self.reqs[..]
+    }
+
+    /// Guest memory underlying the batch, in the order it is to be transferred
+    pub fn mappings<'a>(&self, mem: &'a MemCtx) -> Option<Vec<SubMapping<'a>>> {
+        let mut maps = Vec::new();
+        for req in self.reqs.iter() {
+            maps.extend(req.mappings(mem)?);
+        }
+        Some(maps)
+    }
+
+    /// Total length of the merged requests
+    pub fn len(&self) -> usize {
+        self.reqs.iter().map(Request::len).sum()
+    }
+}
+
+/// Move requests from the front of `queue` into `batch` for as long as they
+/// continue, in the same direction, where the last left off.
+fn gather_batch(
+    queue: &mut VecDeque<(Request, Instant)>,
+    batch: &mut Vec<(Request, Instant)>,
+    limits: &BatchLimits,
+) {
+    let first = &batch[0].0;
+    if first.fault.is_some() {
+        return;
+    }
+    let (write, mut end) = match first.op {
+        Operation::Read(off) => (false, off + first.len()),
+        Operation::Write(off) => (true, off + first.len()),
+        _ => return,
+    };
+    let mut bytes = first.len();
+    let mut regions = first.regions.len();
+
+    while let Some((next, _)) = queue.front() {
+        if next.fault.is_some() {
+            break;
+        }
+        let off = match (next.op, write) {
+            (Operation::Read(off), false) | (Operation::Write(off), true) => {
+                off
+            }
+            _ => break,
+        };
+        let len = next.len();
+        if off != end
+            || batch.len() >= limits.max_reqs
+            || bytes + len > limits.max_bytes
+            || regions + next.regions.len() > limits.max_regions
+        {
+            break;
+        }
+        end += len;
+        bytes += len;
+        regions += next.regions.len();
+        batch.push(queue.pop_front().unwrap());
+    }
+}
+
 /// Driver used to service requests from a block device with a specific backend.
 pub struct Driver {
     /// The block device generating the requests to service
@@ -359,8 +646,13 @@ pub struct Driver {
     /// Backend provided handler for requests from block device
     req_handler: Box<BackendProcessFn>,
 
-    /// Queue of I/O requests from the device ready to be serviced by the backend
-    queue: Mutex<VecDeque<Request>>,
+    /// Backend provided handler for batches of merged requests, for those
+    /// backends which opt in to having requests merged
+    batch: Option<(Box<BackendBatchFn>, BatchLimits)>,
+
+    /// Queue of I/O requests from the device ready to be serviced by the backend,
+    /// along with the time at which each was received from the device
+    queue: Mutex<VecDeque<(Request, Instant)>>,
 
     /// Synchronization primitive used to block backend worker threads on requests in the queue
     cv: Condvar,
@@ -370,13 +662,38 @@ pub struct Driver {
 
     /// Notify handle used by block device to proactively inform us of any new requests
     wake: Arc<Notify>,
+
+    /// Limits on the rate at which requests are handed to the backend
+    throttle: Throttle,
+
+    /// Statistics for requests serviced by the backend
+    stats: Stats,
+
+    /// Set once the driver should stop taking requests from the block device
+    stopped: AtomicBool,
+
+    /// Set once the scheduling task has exited, after which no more requests
+    /// will be added to the queue
+    sched_done: AtomicBool,
+
+    /// Notified as a stopped driver makes progress towards being drained
+    drained: Notify,
+
+    /// Runtime on which the completion of requests delayed by an injected
+    /// fault is scheduled
+    rt: Mutex<Option<Handle>>,
 }
 
 impl Driver {
     /// Create new `BackendDriver` to service requests for the given block device.
+    ///
+    /// Backends opting in to having adjacent reads (or writes) merged provide
+    /// a `batch_handler`, to which batches are passed within the accompanying
+    /// limits.  Each of the merged requests is still completed individually.
     pub fn new(
         bdev: Arc<dyn Device>,
         req_handler: Box<BackendProcessFn>,
+        batch_handler: Option<(Box<BackendBatchFn>, BatchLimits)>,
     ) -> Self {
         let wake = Arc::new(Notify::new());
         // Wire up notifier to the block device
@@ -387,10 +704,57 @@ impl Driver {
         Self {
             bdev,
             req_handler,
+            batch: batch_handler,
             queue: Mutex::new(VecDeque::new()),
             cv: Condvar::new(),
             idle_threads: Semaphore::new(0),
             wake,
+            throttle: Throttle::unlimited(),
+            stats: Stats::new(),
+            stopped: AtomicBool::new(false),
+            sched_done: AtomicBool::new(false),
+            drained: Notify::new(),
+            rt: Mutex::new(None),
+        }
+    }
+
+    /// I/O statistics for requests from the block device.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Rate limits applied to requests from the block device.
+    pub fn throttle(&self) -> &Throttle {
+        &self.throttle
+    }
+
+    /// Stop taking requests from the block device.
+    ///
+    /// Requests already taken from the device are serviced, after which the
+    /// worker threads and scheduling task exit.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Release);
+        self.bdev.set_notifier(None);
+
+        // Rouse the scheduling task wherever it may be waiting
+        self.idle_threads.close();
+        self.wake.notify_one();
+
+        let _guard = self.queue.lock().unwrap();
+        self.cv.notify_all();
+    }
+
+    /// Wait until a stopped driver has completed every request it took from
+    /// the block device.
+    pub async fn drained(&self) {
+        loop {
+            let notified = self.drained.notified();
+            if self.sched_done.load(Ordering::Acquire)
+                && self.stats.in_flight() == 0
+            {
+                return;
+            }
+            notified.await;
         }
     }
 
@@ -402,6 +766,7 @@ impl Driver {
         worker_count: NonZeroUsize,
         disp: &Dispatcher,
     ) -> std::io::Result<()> {
+        *self.rt.lock().unwrap() = disp.handle();
         for i in 0..worker_count.get() {
             let worker_self = Arc::clone(self);
 
@@ -441,8 +806,10 @@ impl Driver {
     }
 
     /// Worker thread's main-loop: looks for requests to service in the queue.
-    fn blocking_loop(&self, sctx: &mut SyncCtx) {
-        let mut idled = false;
+    fn blocking_loop(self: &Arc<Self>, sctx: &mut SyncCtx) {
+        // Each request queued is accounted for by an idle thread, until the
+        // thread which took it from the queue is done with it.
+        self.idle_threads.add_permits(1);
         loop {
             if sctx.check_yield() {
                 break;
@@ -450,40 +817,217 @@ impl Driver {
 
             // Check if we've received any requests to process
             let mut guard = self.queue.lock().unwrap();
-            if let Some(req) = guard.pop_front() {
+            if let Some(first) = guard.pop_front() {
+                let mut entries = vec![first];
+                if let Some((_, limits)) = &self.batch {
+                    gather_batch(&mut guard, &mut entries, limits);
+                }
                 drop(guard);
-                idled = false;
+                // Each request was taken from the device on account of an
+                // idle thread, but only this one is to service them.
+                if entries.len() > 1 {
+                    self.idle_threads.add_permits(entries.len() - 1);
+                }
                 let logger = sctx.log().clone();
                 let ctx = sctx.dispctx();
-                match (self.req_handler)(&req, &ctx) {
-                    Ok(()) => req.complete(Result::Success, &ctx),
-                    Err(e) => {
-                        slog::error!(logger, "{e:?} error on req {:?}", req.op);
-                        req.complete(Result::Failure, &ctx)
-                    }
+                if entries.len() == 1 {
+                    let (req, arrival) = entries.pop().unwrap();
+                    self.process_one(req, arrival, &ctx, &logger);
+                } else {
+                    self.process_batch(entries, &ctx, &logger);
                 }
+                self.idle_threads.add_permits(1);
             } else {
-                // Wait until more requests are available
-                if !idled {
-                    self.idle_threads.add_permits(1);
-                    idled = true;
+                if self.sched_done.load(Ordering::Acquire) {
+                    // Stopped, with nothing more to be queued
+                    break;
                 }
+
+                // Wait until more requests are available
                 let _guard = self
                     .cv
                     .wait_while(guard, |g| {
                         // While `sctx.check_yield()` is tempting here, it will
                         // block if this thread goes into a quiesce state,
                         // excluding all others from the queue lock.
-                        g.is_empty() && !sctx.pending_reqs()
+                        g.is_empty()
+                            && !sctx.pending_reqs()
+                            && !self.sched_done.load(Ordering::Acquire)
                     })
                     .unwrap();
             }
         }
     }
 
+    /// Service a single request, recording its completion.
+    fn process_one(
+        self: &Arc<Self>,
+        req: Request,
+        arrival: Instant,
+        ctx: &DispCtx,
+        logger: &slog::Logger,
+    ) {
+        if let Some(Fault::Fail(res)) = req.fault {
+            slog::error!(logger, "injected {res:?} on req {:?}", req.op);
+            self.complete(req, arrival, res, ctx);
+            return;
+        }
+        let res = match (self.req_handler)(&req, ctx) {
+            Ok(()) => Result::Success,
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+                Result::Unsupported
+            }
+            Err(e) => {
+                slog::error!(logger, "{e:?} error on req {:?}", req.op);
+                Result::Failure
+            }
+        };
+        self.complete(req, arrival, res, ctx);
+    }
+
+    /// Service a batch of merged requests.
+    ///
+    /// Should the batch fail as a whole, its requests are retried one at a
+    /// time, so any error is attributed only to the requests responsible.
+    fn process_batch(
+        self: &Arc<Self>,
+        entries: Vec<(Request, Instant)>,
+        ctx: &DispCtx,
+        logger: &slog::Logger,
+    ) {
+        let (batch_handler, _) = self.batch.as_ref().unwrap();
+        let (reqs, arrivals): (Vec<_>, Vec<_>) = entries.into_iter().unzip();
+        let batch = Batch { op: reqs[0].op, reqs };
+
+        let res = batch_handler(&batch, ctx);
+        let entries = batch.reqs.into_iter().zip(arrivals);
+        match res {
+            Ok(()) => {
+                for (req, arrival) in entries {
+                    self.complete(req, arrival, Result::Success, ctx);
+                }
+            }
+            Err(e) => {
+                slog::warn!(
+                    logger,
+                    "{e:?} error on batch {:?}, retrying individually",
+                    batch.op
+                );
+                for (req, arrival) in entries {
+                    self.process_one(req, arrival, ctx, logger);
+                }
+            }
+        }
+    }
+
+    /// Complete a request, and record the result in the statistics.
+    fn complete(
+        self: &Arc<Self>,
+        mut req: Request,
+        arrival: Instant,
+        res: Result,
+        ctx: &DispCtx,
+    ) {
+        let deferred = req.deferred.get_mut().unwrap().take();
+        if let Some(rx) = deferred {
+            // Whatever else befell the request, it is not done with until the
+            // backend has finished with it.
+            self.complete_later(req, arrival, ctx, async move {
+                match (res, rx.await) {
+                    (Result::Success, Ok(res)) => res,
+                    (Result::Success, Err(_)) => Result::Failure,
+                    (res, _) => res,
+                }
+            });
+            return;
+        }
+        if let Some(Fault::Delay(latency)) = req.fault.take() {
+            if self.rt.lock().unwrap().is_some() {
+                // Hold off the completion on a timer, rather than tying up the
+                // worker thread for the duration.
+                self.complete_later(req, arrival, ctx, async move {
+                    tokio::time::sleep(latency).await;
+                    res
+                });
+                return;
+            }
+            // Without a runtime to schedule it on, the delay is served by the
+            // worker thread itself.
+            std::thread::sleep(latency);
+        }
+        self.record(&req, res, arrival);
+        req.complete(res, ctx);
+        self.retire();
+    }
+
+    /// Complete a request with the result of `res`, once it is ready.
+    ///
+    /// The wait is scheduled on the runtime if there is one, rather than tying
+    /// up the calling worker thread for the duration.
+    fn complete_later(
+        self: &Arc<Self>,
+        mut req: Request,
+        arrival: Instant,
+        ctx: &DispCtx,
+        res: impl Future<Output = Result> + Send + 'static,
+    ) {
+        let rt = match self.rt.lock().unwrap().clone() {
+            Some(rt) => rt,
+            None => {
+                let res = futures::executor::block_on(res);
+                self.complete(req, arrival, res, ctx);
+                return;
+            }
+        };
+        let this = Arc::clone(self);
+        let actx = ctx.async_ctx();
+        rt.spawn(async move {
+            let res = res.await;
+            match actx.dispctx().await {
+                Some(ctx) => this.complete(req, arrival, res, &ctx),
+                None => {
+                    // The instance is being torn down, leaving nothing to
+                    // complete the request to, but it must still be accounted
+                    // for.
+                    this.record(&req, Result::Failure, arrival);
+                    req.donef.take();
+                    if let Some(outstanding) = req.outstanding.take() {
+                        outstanding.decrement();
+                    }
+                    this.retire();
+                }
+            }
+        });
+    }
+
+    /// Record the result of a request in the statistics.
+    ///
+    /// This is done before the completion is delivered to the device, so that
+    /// it is reflected in the statistics by the time the guest can see it.
+    fn record(&self, req: &Request, res: Result, arrival: Instant) {
+        self.stats.completed(
+            req.op,
+            req.xfer_len(),
+            matches!(res, Result::Success),
+            arrival.elapsed(),
+        );
+    }
+
+    /// Account for a completed request as no longer in flight, waking anyone
+    /// waiting for a stopped driver to be drained.
+    fn retire(&self) {
+        self.stats.retired();
+        if self.stopped.load(Ordering::Acquire) {
+            self.drained.notify_waiters();
+        }
+    }
+
     /// Attempt to grab a request from the block device (if one's available)
     async fn next_req(&self, actx: &AsyncCtx) -> Option<Request> {
         loop {
+            if self.stopped.load(Ordering::Acquire) {
+                return None;
+            }
             {
                 let ctx = actx.dispctx().await?;
                 if let Some(req) = self.bdev.next(&ctx) {
@@ -499,20 +1043,215 @@ impl Driver {
 
     /// Scheduling task body: feed worker threads with requests from block device.
     async fn do_scheduling(&self, actx: &AsyncCtx) {
+        self.schedule(actx).await;
+
+        // Let the worker threads exit once the queue is drained
+        self.sched_done.store(true, Ordering::Release);
+        self.drained.notify_waiters();
+        let _guard = self.queue.lock().unwrap();
+        self.cv.notify_all();
+    }
+
+    async fn schedule(&self, actx: &AsyncCtx) {
         loop {
             // Are they any idle worker threads?
-            let avail = self.idle_threads.acquire().await.unwrap();
+            let avail = match self.idle_threads.acquire().await {
+                Ok(avail) => avail,
+                // The semaphore is closed when the driver is stopped
+                Err(_) => return,
+            };
             // We found an idle thread!
             // It will increase the permit count once it's done with any work
             avail.forget();
 
             // Get the next request to process
+            if self.stopped.load(Ordering::Acquire) {
+                return;
+            }
             if let Some(req) = self.next_req(actx).await {
-                let mut queue = self.queue.lock().unwrap();
-                queue.push_back(req);
-                drop(queue);
-                self.cv.notify_one();
+                self.enqueue(req).await;
+
+                // When merging requests, pick up any others which are already
+                // available from the block device, so they may be considered
+                // alongside the first.
+                if let Some((_, limits)) = &self.batch {
+                    for _ in 1..limits.max_reqs {
+                        // As with the first, each request is accounted for by
+                        // an idle thread, which is returned should the device
+                        // have nothing more to offer.
+                        let avail = match self.idle_threads.try_acquire() {
+                            Ok(avail) => avail,
+                            Err(_) => break,
+                        };
+                        let req = match actx.dispctx().await {
+                            Some(ctx) => self.bdev.next(&ctx),
+                            None => None,
+                        };
+                        match req {
+                            Some(req) => {
+                                avail.forget();
+                                self.enqueue(req).await;
+                            }
+                            None => break,
+                        }
+                    }
+                }
             }
         }
     }
+
+    /// Queue a request from the block device for the worker threads, once
+    /// admitted by the throttle.
+    async fn enqueue(&self, req: Request) {
+        let arrival = Instant::now();
+        self.stats.arrived();
+        self.throttle.admit(req.xfer_len()).await;
+
+        let mut queue = self.queue.lock().unwrap();
+        queue.push_back((req, arrival));
+        drop(queue);
+        self.cv.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn req(op: Operation, lens: &[usize]) -> (Request, Instant) {
+        let regions =
+            lens.iter().map(|l| GuestRegion(GuestAddr(0), *l)).collect();
+        let donef: Box<CompleteFn> = Box::new(|_op, _res, _ctx| {});
+        let req = match op {
+            Operation::Read(off) => Request::new_read(off, regions, donef),
+            Operation::Write(off) => Request::new_write(off, regions, donef),
+            _ => panic!("unexpected op"),
+        };
+        (req, Instant::now())
+    }
+
+    fn offsets(entries: Vec<(Request, Instant)>) -> Vec<Operation> {
+        entries
+            .into_iter()
+            .map(|(mut req, _)| {
+                // Defuse the completion check, as these are never issued
+                req.donef.take();
+                req.op
+            })
+            .collect()
+    }
+
+    const LIMITS: BatchLimits =
+        BatchLimits { max_reqs: 4, max_bytes: 16384, max_regions: 8 };
+
+    #[test]
+    fn gather_adjacent() {
+        let mut queue: VecDeque<_> = vec![
+            req(Operation::Read(4096), &[4096]),
+            req(Operation::Read(8192), &[2048, 2048]),
+            // Not adjacent to the previous request
+            req(Operation::Read(16384), &[4096]),
+        ]
+        .into();
+        let mut batch = vec![req(Operation::Read(0), &[4096])];
+        gather_batch(&mut queue, &mut batch, &LIMITS);
+
+        assert_eq!(
+            offsets(batch),
+            [Operation::Read(0), Operation::Read(4096), Operation::Read(8192)]
+        );
+        assert_eq!(offsets(queue.into()), [Operation::Read(16384)]);
+    }
+
+    #[test]
+    fn gather_same_direction() {
+        let mut queue: VecDeque<_> =
+            vec![req(Operation::Read(4096), &[4096])].into();
+        let mut batch = vec![req(Operation::Write(0), &[4096])];
+        gather_batch(&mut queue, &mut batch, &LIMITS);
+
+        assert_eq!(offsets(batch), [Operation::Write(0)]);
+        assert_eq!(offsets(queue.into()), [Operation::Read(4096)]);
+    }
+
+    #[test]
+    fn gather_within_limits() {
+        let mut queue: VecDeque<_> =
+            (1..8).map(|i| req(Operation::Write(i * 1024), &[1024])).collect();
+        let mut batch = vec![req(Operation::Write(0), &[1024])];
+        gather_batch(&mut queue, &mut batch, &LIMITS);
+        assert_eq!(batch.len(), LIMITS.max_reqs);
+        offsets(batch);
+        offsets(queue.into());
+
+        // Larger requests are instead limited by the total length
+        let mut queue: VecDeque<_> =
+            vec![req(Operation::Write(8192), &[8192, 4096])].into();
+        let mut batch = vec![req(Operation::Write(0), &[8192])];
+        gather_batch(&mut queue, &mut batch, &LIMITS);
+        assert_eq!(batch.len(), 1);
+        offsets(batch);
+        offsets(queue.into());
+    }
+
+    #[test]
+    fn media_swap_drains_in_flight() {
+        use test_util::{TestDevice, TestInstance, WRITE_BASE};
+
+        const LATENCY: std::time::Duration =
+            std::time::Duration::from_millis(200);
+
+        let test = TestInstance::new();
+        let old_media = InMemoryBackend::create(vec![0x11; 4096], true, 512)
+            .unwrap() as Arc<dyn Backend>;
+        let old = FaultBackend::create(
+            old_media,
+            FaultPolicy { latency: Some(LATENCY), ..Default::default() },
+        )
+        .unwrap() as Arc<dyn Backend>;
+        let new = InMemoryBackend::create(vec![0x22; 8192], true, 512).unwrap()
+            as Arc<dyn Backend>;
+        let dev = TestDevice::new();
+        test.attach(old.as_ref(), &dev).unwrap();
+
+        // Reads from the old media are still held up by the injected latency
+        // when the media is changed...
+        let pending: Vec<_> = (0..4)
+            .map(|i| {
+                test.submit(&dev, |donef| {
+                    let region = GuestRegion(WRITE_BASE + i * 512, 512);
+                    Request::new_read(i * 512, vec![region], donef)
+                })
+            })
+            .collect();
+        let driver = old.driver().unwrap();
+        while driver.stats().snapshot().in_flight < 4 {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let bdev = Arc::clone(&dev) as Arc<dyn Device>;
+        futures::executor::block_on(change_media(
+            &bdev,
+            Some(&old),
+            Some(&new),
+            &test.inst.disp,
+        ))
+        .unwrap();
+
+        // ... but have completed, against the old media, before the new media
+        // is presented to the guest.
+        let stats = driver.stats().snapshot();
+        assert_eq!(stats.read.ops, 4);
+        assert_eq!(stats.in_flight, 0);
+        for p in pending {
+            assert!(matches!(p.wait(), Result::Success));
+        }
+        assert_eq!(test.contents(WRITE_BASE, 2048), vec![0x11; 2048]);
+        assert_eq!(dev.media.lock().unwrap().unwrap().total_size, 16);
+
+        // Subsequent requests are serviced by the new media
+        let (res, data) = test.read(&dev, 4096, 512);
+        assert!(matches!(res, Result::Success));
+        assert_eq!(data, vec![0x22; 512]);
+        assert_eq!(driver.stats().snapshot().read.ops, 4);
+    }
 }
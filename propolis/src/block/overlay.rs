@@ -0,0 +1,759 @@
+//! Copy-on-write overlay atop an arbitrary (read-only) base backend.
+//!
+//! Reads of blocks which have not been written through the overlay are
+//! forwarded to the base backend, while writes land in a scratch area (either
+//! a sparse file or memory).  An allocation bitmap, at the granularity of the
+//! base device's block size, tracks which blocks reside in the scratch area.
+//!
+//! The base backend can only be read into guest memory, so a write covering
+//! only part of a block cannot fill in the remainder from the base.  Such
+//! blocks are instead tracked as partially allocated, with the byte ranges
+//! written to the scratch area overlaid upon the base contents when read.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex};
+
+use super::DeviceInfo;
+use crate::block;
+use crate::dispatch::{DispCtx, Dispatcher};
+use crate::inventory::Entity;
+use crate::vmm::SubMapping;
+
+/// Backing store for blocks written through the overlay.
+pub enum OverlayScratch {
+    /// Sparse file, with blocks stored at their native offset
+    File(File),
+    /// Host memory, allocated as blocks are written
+    Memory,
+}
+
+struct ScratchState {
+    kind: OverlayScratch,
+    block_size: usize,
+    blocks: usize,
+    /// Block contents, when using [`OverlayScratch::Memory`]
+    mem: BTreeMap<usize, Box<[u8]>>,
+    /// Allocation bitmap: a set bit indicates the block resides in scratch
+    bitmap: Vec<u64>,
+    /// Byte ranges (in ascending order) written to blocks which otherwise
+    /// still reside in the base
+    partial: BTreeMap<usize, Vec<Range<usize>>>,
+}
+
+impl ScratchState {
+    fn new(kind: OverlayScratch, block_size: usize, blocks: usize) -> Self {
+        Self {
+            kind,
+            block_size,
+            blocks,
+            mem: BTreeMap::new(),
+            bitmap: vec![0; (blocks + 63) / 64],
+            partial: BTreeMap::new(),
+        }
+    }
+
+    fn check_block(&self, blk: usize) -> Result<()> {
+        if blk >= self.blocks {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("block {blk} beyond end of overlay"),
+            ));
+        }
+        Ok(())
+    }
+
+    fn is_allocated(&self, blk: usize) -> Result<bool> {
+        self.check_block(blk)?;
+        Ok(self.bitmap[blk / 64] & (1 << (blk % 64)) != 0)
+    }
+
+    fn set_allocated(&mut self, blk: usize) -> Result<()> {
+        self.check_block(blk)?;
+        self.bitmap[blk / 64] |= 1 << (blk % 64);
+        self.partial.remove(&blk);
+        Ok(())
+    }
+
+    /// Number of blocks at least partially residing in scratch
+    fn allocated_count(&self) -> usize {
+        let full: usize =
+            self.bitmap.iter().map(|w| w.count_ones() as usize).sum();
+        full + self.partial.len()
+    }
+
+    /// Byte ranges of a block which reside in scratch
+    fn allocated_ranges(&self, blk: usize) -> Result<Vec<Range<usize>>> {
+        if self.is_allocated(blk)? {
+            Ok(vec![0..self.block_size])
+        } else {
+            Ok(self.partial.get(&blk).cloned().unwrap_or_default())
+        }
+    }
+
+    /// Read from scratch, starting `start` bytes into a block
+    fn read_range(
+        &self,
+        blk: usize,
+        start: usize,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        self.check_block(blk)?;
+        match &self.kind {
+            OverlayScratch::File(fp) => {
+                fp.read_exact_at(buf, (blk * self.block_size + start) as u64)
+            }
+            OverlayScratch::Memory => {
+                let data = self.mem.get(&blk).ok_or_else(|| {
+                    Error::new(ErrorKind::Other, "block missing from scratch")
+                })?;
+                buf.copy_from_slice(&data[start..(start + buf.len())]);
+                Ok(())
+            }
+        }
+    }
+
+    /// Write to scratch, starting `start` bytes into a block, which becomes
+    /// (or remains) partially allocated unless the write covers all of it.
+    fn write_range(
+        &mut self,
+        blk: usize,
+        start: usize,
+        buf: &[u8],
+    ) -> Result<()> {
+        self.check_block(blk)?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let end = start + buf.len();
+        assert!(end <= self.block_size);
+        let block_size = self.block_size;
+        match &self.kind {
+            OverlayScratch::File(fp) => {
+                fp.write_all_at(buf, (blk * block_size + start) as u64)?;
+            }
+            OverlayScratch::Memory => {
+                let data = self
+                    .mem
+                    .entry(blk)
+                    .or_insert_with(|| vec![0u8; block_size].into());
+                data[start..end].copy_from_slice(buf);
+            }
+        }
+
+        if self.is_allocated(blk)? {
+            return Ok(());
+        }
+        let ranges = self.partial.entry(blk).or_default();
+        add_range(ranges, start..end);
+        if ranges[..] == [0..block_size] {
+            self.set_allocated(blk)?;
+        }
+        Ok(())
+    }
+
+    fn discard(&mut self) -> Result<()> {
+        if let OverlayScratch::File(fp) = &self.kind {
+            // Truncating the file releases any space it consumed, while
+            // preserving its size for subsequent sparse writes.
+            let len = fp.metadata()?.len();
+            fp.set_len(0)?;
+            fp.set_len(len)?;
+        }
+        self.mem.clear();
+        self.bitmap.iter_mut().for_each(|w| *w = 0);
+        self.partial.clear();
+        Ok(())
+    }
+}
+
+/// Insert `new` into a list of ascending, disjoint ranges, merging it with any
+/// it overlaps or abuts.
+fn add_range(ranges: &mut Vec<Range<usize>>, new: Range<usize>) {
+    ranges.push(new);
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+    for r in ranges.drain(..) {
+        match merged.last_mut() {
+            Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+            _ => merged.push(r),
+        }
+    }
+    *ranges = merged;
+}
+
+/// Block device through which the overlay issues reads to its base backend.
+struct BaseQueue {
+    reqs: Mutex<VecDeque<block::Request>>,
+    notifier: block::Notifier,
+}
+
+impl BaseQueue {
+    /// Issue a request to the base backend, which completes it in its own
+    /// time.
+    fn submit(&self, req: block::Request, ctx: &DispCtx) {
+        self.reqs.lock().unwrap().push_back(req);
+        self.notifier.notify(self, ctx);
+    }
+}
+
+impl block::Device for BaseQueue {
+    fn next(&self, _ctx: &DispCtx) -> Option<block::Request> {
+        self.notifier.next_arming(|| self.reqs.lock().unwrap().pop_front())
+    }
+
+    fn set_notifier(&self, f: Option<Box<block::NotifierFn>>) {
+        self.notifier.set(f)
+    }
+}
+
+/// Copy-on-write overlay backend.
+pub struct OverlayBackend {
+    base: Arc<dyn block::Backend>,
+    base_queue: Arc<BaseQueue>,
+    scratch: Arc<Mutex<ScratchState>>,
+
+    driver: Mutex<Option<Arc<block::Driver>>>,
+    worker_count: NonZeroUsize,
+
+    block_size: usize,
+    sectors: usize,
+}
+
+impl OverlayBackend {
+    /// Creates an overlay atop `base`, storing written blocks in `scratch`.
+    ///
+    /// The base backend is never written to, regardless of whether it is
+    /// itself writable.
+    pub fn create(
+        base: Arc<dyn block::Backend>,
+        scratch: OverlayScratch,
+        worker_count: NonZeroUsize,
+    ) -> Result<Arc<Self>> {
+        let info = base.info();
+        let block_size = info.block_size as usize;
+        let sectors = info.total_size as usize;
+
+        if let OverlayScratch::File(fp) = &scratch {
+            fp.set_len((block_size * sectors) as u64)?;
+        }
+
+        let this = Self {
+            base,
+            base_queue: Arc::new(BaseQueue {
+                reqs: Mutex::new(VecDeque::new()),
+                notifier: block::Notifier::new(),
+            }),
+            scratch: Arc::new(Mutex::new(ScratchState::new(
+                scratch, block_size, sectors,
+            ))),
+
+            driver: Mutex::new(None),
+            worker_count,
+
+            block_size,
+            sectors,
+        };
+
+        Ok(Arc::new(this))
+    }
+
+    /// Number of blocks which have been (at least partially) written through
+    /// the overlay.
+    pub fn dirty_blocks(&self) -> usize {
+        self.scratch.lock().unwrap().allocated_count()
+    }
+
+    /// Discard all writes made through the overlay, reverting to the contents
+    /// of the base backend.
+    ///
+    /// The guest should not have I/O in flight when this is called.
+    pub fn discard(&self) -> Result<()> {
+        self.scratch.lock().unwrap().discard()
+    }
+
+    /// Commit all writes made through the overlay into `target` at their
+    /// respective offsets, and then discard them from the overlay.
+    ///
+    /// `target` is typically the file underlying the base backend.  Neither
+    /// the guest nor the base backend should have I/O in flight when this is
+    /// called.
+    pub fn commit(&self, target: &File) -> Result<()> {
+        let mut scratch = self.scratch.lock().unwrap();
+        let mut buf = vec![0u8; self.block_size];
+        for blk in 0..self.sectors {
+            for r in scratch.allocated_ranges(blk)? {
+                let buf = &mut buf[..r.len()];
+                scratch.read_range(blk, r.start, buf)?;
+                target.write_all_at(
+                    buf,
+                    (blk * self.block_size + r.start) as u64,
+                )?;
+            }
+        }
+        target.sync_data()?;
+        scratch.discard()
+    }
+}
+
+impl block::Backend for OverlayBackend {
+    fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            block_size: self.block_size as u32,
+            total_size: self.sectors as u64,
+            writable: true,
+        }
+    }
+
+    fn driver(&self) -> Option<Arc<block::Driver>> {
+        self.driver.lock().unwrap().clone()
+    }
+
+    fn resize(&self, _total_size: u64) -> Result<()> {
+        // The overlay is sized to match the base it sits on
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "resizing overlays not supported",
+        ))
+    }
+
+    fn attach(
+        &self,
+        dev: Arc<dyn block::Device>,
+        disp: &Dispatcher,
+    ) -> Result<()> {
+        let mut driverg = self.driver.lock().unwrap();
+        assert!(driverg.is_none());
+
+        self.base.attach(
+            Arc::clone(&self.base_queue) as Arc<dyn block::Device>,
+            disp,
+        )?;
+
+        let base_queue = Arc::clone(&self.base_queue);
+        let scratch = Arc::clone(&self.scratch);
+        let req_handler =
+            Box::new(move |req: &block::Request, ctx: &DispCtx| {
+                process_request(&base_queue, &scratch, req, ctx)
+            });
+
+        // Spawn driver to service block dev requests
+        let driver = Arc::new(block::Driver::new(dev, req_handler, None));
+        driver.spawn("overlay", self.worker_count, disp)?;
+        *driverg = Some(driver);
+
+        Ok(())
+    }
+}
+
+impl Entity for OverlayBackend {
+    fn type_name(&self) -> &'static str {
+        "block-overlay"
+    }
+}
+
+/// Copy `data` into the guest `mappings`, starting `offset` bytes into them.
+fn write_mappings_at(
+    mappings: &[SubMapping],
+    mut offset: usize,
+    mut data: &[u8],
+) -> Result<()> {
+    for mapping in mappings {
+        if data.is_empty() {
+            break;
+        }
+        if offset >= mapping.len() {
+            offset -= mapping.len();
+            continue;
+        }
+        let len = data.len().min(mapping.len() - offset);
+        let sub = mapping.subregion(offset, len).unwrap();
+        sub.write_bytes(&data[..len])?;
+        data = &data[len..];
+        offset = 0;
+    }
+    Ok(())
+}
+
+/// Blocks spanned by `len` bytes at `off`, failing if they extend beyond the
+/// end of the overlay.
+fn block_span(
+    scratch: &ScratchState,
+    off: usize,
+    len: usize,
+) -> Result<Range<usize>> {
+    let end = off
+        .checked_add(len)
+        .filter(|end| *end <= scratch.blocks * scratch.block_size);
+    match end {
+        Some(end) => {
+            let bs = scratch.block_size;
+            Ok((off / bs)..((end + bs - 1) / bs))
+        }
+        None => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("request ({off}, {len}) beyond end of overlay"),
+        )),
+    }
+}
+
+/// Portion of a block covered by `len` bytes at `off`, as a range of bytes
+/// within the block.
+fn block_portion(
+    blk: usize,
+    block_size: usize,
+    off: usize,
+    len: usize,
+) -> Range<usize> {
+    let blk_start = blk * block_size;
+    let start = off.max(blk_start) - blk_start;
+    let end = (off + len).min(blk_start + block_size) - blk_start;
+    start..end
+}
+
+/// Copy any data written through the overlay over the guest `mappings` of a
+/// read from `off`.
+fn patch_from_scratch(
+    scratch: &ScratchState,
+    mappings: &[SubMapping],
+    off: usize,
+) -> Result<()> {
+    let len = mappings.iter().map(|m| m.len()).sum();
+    let block_size = scratch.block_size;
+    let mut buf = vec![0u8; block_size];
+    for blk in block_span(scratch, off, len)? {
+        let portion = block_portion(blk, block_size, off, len);
+        for r in scratch.allocated_ranges(blk)? {
+            let start = r.start.max(portion.start);
+            let end = r.end.min(portion.end);
+            if start >= end {
+                continue;
+            }
+            let buf = &mut buf[..(end - start)];
+            scratch.read_range(blk, start, buf)?;
+            write_mappings_at(mappings, blk * block_size + start - off, buf)?;
+        }
+    }
+    Ok(())
+}
+
+fn process_request(
+    base_queue: &BaseQueue,
+    scratch: &Arc<Mutex<ScratchState>>,
+    req: &block::Request,
+    ctx: &DispCtx,
+) -> Result<()> {
+    let mem = ctx.mctx.memctx();
+    match req.oper() {
+        block::Operation::Read(off) => {
+            let maps = req.mappings(&mem).ok_or_else(|| {
+                Error::new(ErrorKind::Other, "bad guest region")
+            })?;
+            let len = req.len();
+
+            let any_base = {
+                let scratch = scratch.lock().unwrap();
+                let mut any_base = false;
+                for blk in block_span(&scratch, off, len)? {
+                    any_base |= !scratch.is_allocated(blk)?;
+                }
+                any_base
+            };
+            if !any_base {
+                return patch_from_scratch(
+                    &scratch.lock().unwrap(),
+                    &maps,
+                    off,
+                );
+            }
+
+            // Read the entire range from the base, and then patch in any data
+            // which has since been written to the overlay.  This is left to
+            // finish as the base completes the read, rather than waiting on it
+            // here, where it could be held up indefinitely should the base
+            // driver be paused ahead of this one.
+            let regions = req.regions().to_vec();
+            let done = req.defer();
+            let scratch = Arc::clone(scratch);
+            let patch_regions = regions.clone();
+            let donef = Box::new(move |_op, res, ctx: &DispCtx| {
+                let res = match res {
+                    block::Result::Success => {
+                        let mem = ctx.mctx.memctx();
+                        let patched = patch_regions
+                            .iter()
+                            .map(|r| mem.writable_region(r))
+                            .collect::<Option<Vec<_>>>()
+                            .ok_or_else(|| {
+                                Error::new(ErrorKind::Other, "bad guest region")
+                            })
+                            .and_then(|maps| {
+                                let scratch = scratch.lock().unwrap();
+                                patch_from_scratch(&scratch, &maps, off)
+                            });
+                        match patched {
+                            Ok(()) => block::Result::Success,
+                            Err(e) => {
+                                slog::error!(
+                                    ctx.log,
+                                    "{e:?} error patching overlay read"
+                                );
+                                block::Result::Failure
+                            }
+                        }
+                    }
+                    res => res,
+                };
+                let _ = done.send(res);
+            });
+            base_queue
+                .submit(block::Request::new_read(off, regions, donef), ctx);
+        }
+        block::Operation::Write(off) => {
+            let maps = req.mappings(&mem).ok_or_else(|| {
+                Error::new(ErrorKind::Other, "bad guest region")
+            })?;
+
+            let mut data = vec![0u8; req.len()];
+            let mut nread = 0;
+            for mapping in maps.iter() {
+                nread += mapping
+                    .read_bytes(&mut data[nread..(nread + mapping.len())])?;
+            }
+            if nread != req.len() {
+                return Err(Error::new(ErrorKind::Other, "bad write length"));
+            }
+
+            let mut scratch = scratch.lock().unwrap();
+            let block_size = scratch.block_size;
+            for blk in block_span(&scratch, off, data.len())? {
+                let portion = block_portion(blk, block_size, off, data.len());
+                let pos = blk * block_size + portion.start - off;
+                scratch.write_range(
+                    blk,
+                    portion.start,
+                    &data[pos..(pos + portion.len())],
+                )?;
+            }
+        }
+        block::Operation::Discard(off, len) => {
+            // Discard is advisory, and writes already made to the overlay
+            // cannot fall back to the base contents, so there is nothing to do
+            // beyond checking the request.
+            block_span(&scratch.lock().unwrap(), off, len)?;
+        }
+        block::Operation::WriteZeroes(off, len) => {
+            let mut scratch = scratch.lock().unwrap();
+            let block_size = scratch.block_size;
+            let zeroes = vec![0u8; block_size];
+            for blk in block_span(&scratch, off, len)? {
+                let portion = block_portion(blk, block_size, off, len);
+                scratch.write_range(
+                    blk,
+                    portion.start,
+                    &zeroes[..portion.len()],
+                )?;
+            }
+        }
+        block::Operation::Flush(_off, _len) => {
+            if let OverlayScratch::File(fp) = &scratch.lock().unwrap().kind {
+                fp.sync_data()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::test_util::{TestDevice, TestInstance, WRITE_BASE};
+    use crate::common::GuestRegion;
+
+    fn exercise(mut state: ScratchState) {
+        state.write_range(3, 0, &[0xaa; 512]).unwrap();
+        state.write_range(70, 0, &[0x55; 512]).unwrap();
+        assert!(state.is_allocated(3).unwrap());
+        assert!(state.is_allocated(70).unwrap());
+        assert!(!state.is_allocated(4).unwrap());
+        assert_eq!(state.allocated_count(), 2);
+
+        let mut buf = [0u8; 512];
+        state.read_range(70, 0, &mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == 0x55));
+
+        // Partial writes are tracked until they cover the whole block
+        state.write_range(5, 100, &[0x11; 200]).unwrap();
+        state.write_range(5, 400, &[0x22; 112]).unwrap();
+        assert!(!state.is_allocated(5).unwrap());
+        assert_eq!(state.allocated_ranges(5).unwrap(), [100..300, 400..512]);
+        assert_eq!(state.allocated_count(), 3);
+        let mut buf = [0u8; 200];
+        state.read_range(5, 100, &mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == 0x11));
+        state.write_range(5, 0, &[0x33; 400]).unwrap();
+        assert!(state.is_allocated(5).unwrap());
+        assert_eq!(state.allocated_count(), 3);
+
+        // Blocks beyond the end are refused, rather than panicking
+        assert!(state.is_allocated(128).is_err());
+        assert!(state.write_range(128, 0, &[0; 512]).is_err());
+
+        state.discard().unwrap();
+        assert_eq!(state.allocated_count(), 0);
+        assert!(state.allocated_ranges(5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn memory_scratch() {
+        exercise(ScratchState::new(OverlayScratch::Memory, 512, 128));
+    }
+
+    #[test]
+    fn file_scratch() {
+        let fp = tempfile::tempfile().unwrap();
+        fp.set_len(128 * 512).unwrap();
+        exercise(ScratchState::new(OverlayScratch::File(fp), 512, 128));
+    }
+
+    #[test]
+    fn add_ranges() {
+        let mut ranges = Vec::new();
+        add_range(&mut ranges, 10..20);
+        add_range(&mut ranges, 30..40);
+        assert_eq!(ranges, [10..20, 30..40]);
+        add_range(&mut ranges, 0..5);
+        add_range(&mut ranges, 20..25);
+        assert_eq!(ranges, [0..5, 10..25, 30..40]);
+        add_range(&mut ranges, 4..35);
+        assert_eq!(ranges, [0..40]);
+    }
+
+    #[test]
+    fn overlay_reads_and_writes() {
+        const BS: usize = 512;
+        const BLOCKS: usize = 16;
+        let base: Vec<u8> = (0..(BS * BLOCKS)).map(|i| (i / 7) as u8).collect();
+
+        let test = TestInstance::new();
+        let base_be =
+            block::InMemoryBackend::create(base.clone(), true, BS).unwrap();
+        let overlay = OverlayBackend::create(
+            base_be,
+            OverlayScratch::Memory,
+            NonZeroUsize::new(2).unwrap(),
+        )
+        .unwrap();
+        let dev = TestDevice::new();
+        test.attach(overlay.as_ref(), &dev).unwrap();
+
+        // Untouched blocks come from the base
+        let (res, data) = test.read(&dev, 0, BS * BLOCKS);
+        assert!(matches!(res, block::Result::Success));
+        assert_eq!(data, base);
+
+        let mut expect = base.clone();
+        let mut write = |off: usize, data: &[u8]| {
+            let res = test.write(&dev, off, data);
+            assert!(matches!(res, block::Result::Success));
+            expect[off..(off + data.len())].copy_from_slice(data);
+        };
+        // Aligned, whole blocks
+        write(2 * BS, &[0xaa; 2 * BS]);
+        // Partial head and tail blocks, around a whole block
+        write(5 * BS + 100, &[0xbb; 2 * BS]);
+        // Within a single block, touching neither end
+        write(9 * BS + 10, &[0xcc; 20]);
+        // Over part of an already-allocated block
+        write(3 * BS + 256, &[0xdd; 32]);
+
+        let (res, data) = test.read(&dev, 0, BS * BLOCKS);
+        assert!(matches!(res, block::Result::Success));
+        assert_eq!(data, expect);
+
+        // Unaligned reads see the same
+        let (res, data) = test.read(&dev, 5 * BS + 50, BS);
+        assert!(matches!(res, block::Result::Success));
+        assert_eq!(data, &expect[(5 * BS + 50)..(6 * BS + 50)]);
+
+        let zero = test.submit(&dev, |donef| {
+            block::Request::new_write_zeroes(9 * BS + 15, BS, donef)
+        });
+        assert!(matches!(zero.wait(), block::Result::Success));
+        expect[(9 * BS + 15)..(10 * BS + 15)].fill(0);
+        let (_res, data) = test.read(&dev, 0, BS * BLOCKS);
+        assert_eq!(data, expect);
+
+        // Requests beyond the end fail, rather than panicking
+        let res = test.write(&dev, BS * BLOCKS - 100, &[0xee; BS]);
+        assert!(matches!(res, block::Result::Failure));
+        let (res, _data) = test.read(&dev, BS * BLOCKS, BS);
+        assert!(matches!(res, block::Result::Failure));
+        let zero = test.submit(&dev, |donef| {
+            block::Request::new_write_zeroes(BS * BLOCKS, BS, donef)
+        });
+        assert!(matches!(zero.wait(), block::Result::Failure));
+
+        // Committing writes out partial blocks as well as whole ones
+        let target = tempfile::tempfile().unwrap();
+        target.write_all_at(&base, 0).unwrap();
+        overlay.commit(&target).unwrap();
+        let mut committed = vec![0u8; BS * BLOCKS];
+        target.read_exact_at(&mut committed, 0).unwrap();
+        assert_eq!(committed, expect);
+        assert_eq!(overlay.dirty_blocks(), 0);
+
+        // Resizing is refused outright, rather than quietly ignored
+        let err = block::Backend::resize(&*overlay, 2 * BLOCKS as u64);
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::Unsupported);
+        assert_eq!(block::Backend::info(&*overlay).total_size, BLOCKS as u64);
+    }
+
+    #[test]
+    fn base_reads_do_not_hold_quiesce() {
+        const BS: usize = 512;
+
+        let test = TestInstance::new();
+        let base_mem =
+            block::InMemoryBackend::create(vec![0x5a; 8 * BS], true, BS)
+                .unwrap();
+        let base = block::FaultBackend::create(
+            base_mem,
+            block::FaultPolicy {
+                latency: Some(std::time::Duration::from_secs(1)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let overlay = OverlayBackend::create(
+            base,
+            OverlayScratch::Memory,
+            NonZeroUsize::new(1).unwrap(),
+        )
+        .unwrap();
+        let dev = TestDevice::new();
+        test.attach(overlay.as_ref(), &dev).unwrap();
+
+        // With a read from the base still outstanding, the overlay's worker is
+        // free to reach its yield point, so quiescing is not held up by it...
+        let pending = test.submit(&dev, |donef| {
+            let region = GuestRegion(WRITE_BASE, BS);
+            block::Request::new_read(0, vec![region], donef)
+        });
+        let base_driver = overlay.base.driver().unwrap();
+        while base_driver.stats().snapshot().in_flight == 0 {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        test.inst.disp.quiesce();
+        assert!(pending.try_wait().is_none());
+
+        // ... and the read completes once the instance carries on.
+        test.inst.disp.release();
+        assert!(matches!(pending.wait(), block::Result::Success));
+        assert_eq!(test.contents(WRITE_BASE, BS), vec![0x5a; BS]);
+    }
+}
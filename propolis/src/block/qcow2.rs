@@ -0,0 +1,1135 @@
+//! Block backend for images in the QEMU copy-on-write (qcow2) format.
+//!
+//! Both version 2 and version 3 images are supported, subject to the following
+//! limitations:
+//! - Encrypted and compressed clusters are not supported.
+//! - Internal snapshots may be present, but such images are opened read-only.
+//! - Only the default 16-bit refcount width is supported for writable images.
+//!
+//! Clusters are allocated on first write by appending them to the end of the
+//! image file.  Reads of unallocated clusters are satisfied from the backing
+//! file (which may itself be a qcow2 image) or return zeroes.  Clusters which
+//! may be shared (lacking the COPIED flag) are copied before being written.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Result};
+use std::num::NonZeroUsize;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::DeviceInfo;
+use crate::block;
+use crate::dispatch::{DispCtx, Dispatcher};
+use crate::inventory::Entity;
+use crate::vmm::SubMapping;
+
+// XXX: completely arb for now
+const MAX_WORKERS: usize = 32;
+
+/// Limit on the length of a chain of backing files, guarding against loops.
+const MAX_BACKING_DEPTH: usize = 16;
+
+const QCOW_MAGIC: u32 = 0x5146_49fb;
+const V2_HEADER_LEN: usize = 72;
+const V3_HEADER_LEN: usize = 104;
+
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+
+/// Largest L1 table accepted, in entries (matching QEMU's 32MiB limit).
+const MAX_L1_ENTRIES: u64 = (32 << 20) / 8;
+/// Largest refcount table accepted, in bytes (matching QEMU's 8MiB limit).
+const MAX_REFCOUNT_TABLE_SIZE: u64 = 8 << 20;
+/// Longest backing file name accepted (matching QEMU).
+const MAX_BACKING_FILE_SIZE: u32 = 1023;
+
+/// Host offset bits within L1 and L2 table entries.
+const ENTRY_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// Cluster is referenced exactly once, and may be written in place.
+const ENTRY_COPIED: u64 = 1 << 63;
+/// L2 entry describes a compressed cluster.
+const L2_COMPRESSED: u64 = 1 << 62;
+/// L2 entry reads as all zeroes (v3 only).
+const L2_ZERO: u64 = 1 << 0;
+
+/// Refcount width (as log2 of bits) implied for v2 images.
+const DEFAULT_REFCOUNT_ORDER: u32 = 4;
+
+/// Parsed qcow2 image header.
+#[derive(Clone, Debug, PartialEq)]
+struct Header {
+    version: u32,
+    backing_file_offset: u64,
+    backing_file_size: u32,
+    cluster_bits: u32,
+    size: u64,
+    crypt_method: u32,
+    l1_size: u32,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+    nb_snapshots: u32,
+    incompatible_features: u64,
+    refcount_order: u32,
+}
+
+impl Header {
+    fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < V2_HEADER_LEN {
+            return Err(invalid("qcow2 header truncated"));
+        }
+        if BigEndian::read_u32(&buf[0..4]) != QCOW_MAGIC {
+            return Err(invalid("bad qcow2 magic"));
+        }
+        let version = BigEndian::read_u32(&buf[4..8]);
+        let (incompatible_features, refcount_order) = match version {
+            2 => (0, DEFAULT_REFCOUNT_ORDER),
+            3 => {
+                if buf.len() < V3_HEADER_LEN {
+                    return Err(invalid("qcow2 v3 header truncated"));
+                }
+                (
+                    BigEndian::read_u64(&buf[72..80]),
+                    BigEndian::read_u32(&buf[96..100]),
+                )
+            }
+            v => {
+                return Err(invalid(&format!("unsupported qcow2 version {v}")))
+            }
+        };
+
+        let hdr = Header {
+            version,
+            backing_file_offset: BigEndian::read_u64(&buf[8..16]),
+            backing_file_size: BigEndian::read_u32(&buf[16..20]),
+            cluster_bits: BigEndian::read_u32(&buf[20..24]),
+            size: BigEndian::read_u64(&buf[24..32]),
+            crypt_method: BigEndian::read_u32(&buf[32..36]),
+            l1_size: BigEndian::read_u32(&buf[36..40]),
+            l1_table_offset: BigEndian::read_u64(&buf[40..48]),
+            refcount_table_offset: BigEndian::read_u64(&buf[48..56]),
+            refcount_table_clusters: BigEndian::read_u32(&buf[56..60]),
+            nb_snapshots: BigEndian::read_u32(&buf[60..64]),
+            incompatible_features,
+            refcount_order,
+        };
+
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&hdr.cluster_bits) {
+            return Err(invalid(&format!(
+                "unsupported qcow2 cluster_bits {}",
+                hdr.cluster_bits
+            )));
+        }
+        if hdr.crypt_method != 0 {
+            return Err(unsupported("encrypted qcow2 images"));
+        }
+        if hdr.incompatible_features != 0 {
+            return Err(unsupported(&format!(
+                "qcow2 incompatible features {:#x}",
+                hdr.incompatible_features
+            )));
+        }
+        if hdr.refcount_order > 6 {
+            return Err(invalid("qcow2 refcount_order out of range"));
+        }
+        if hdr.size % 512 != 0 {
+            return Err(unsupported(
+                "qcow2 image sizes not a multiple of 512 bytes",
+            ));
+        }
+        if hdr.backing_file_offset != 0
+            && hdr.backing_file_size > MAX_BACKING_FILE_SIZE
+        {
+            return Err(invalid("qcow2 backing file name too long"));
+        }
+
+        // The L1 table must be large enough to map the entire virtual disk,
+        // which in turn bounds the virtual size.
+        let l2_span =
+            (1u64 << hdr.cluster_bits) * ((1u64 << hdr.cluster_bits) / 8);
+        let l1_needed = hdr.size / l2_span + u64::from(hdr.size % l2_span != 0);
+        if l1_needed > MAX_L1_ENTRIES || hdr.l1_size as u64 > MAX_L1_ENTRIES {
+            return Err(invalid("qcow2 L1 table too large"));
+        }
+        if (hdr.l1_size as u64) < l1_needed {
+            return Err(invalid("qcow2 L1 table too small for image size"));
+        }
+        let refcount_table_size =
+            (hdr.refcount_table_clusters as u64) << hdr.cluster_bits;
+        if refcount_table_size > MAX_REFCOUNT_TABLE_SIZE {
+            return Err(invalid("qcow2 refcount table too large"));
+        }
+
+        Ok(hdr)
+    }
+}
+
+/// Where the data for a given guest offset resides.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum ClusterMapping {
+    /// Cluster allocated at the given host offset, and writable in place.
+    Data(u64),
+    /// Cluster allocated at the given host offset, but possibly referenced
+    /// elsewhere, so it must be copied before being written.
+    Shared(u64),
+    /// Cluster reads as zeroes, with an optional preallocated host offset
+    /// which may be written in place.
+    Zero(Option<u64>),
+    /// Cluster not allocated in this image.
+    Unallocated,
+    /// Cluster is compressed.
+    Compressed,
+}
+
+/// Source of data for clusters not allocated in an image.
+enum Backing {
+    Raw { fp: File, len: u64 },
+    Qcow2(Box<Qcow2Image>),
+}
+
+impl Backing {
+    fn open(path: &Path, depth: usize) -> Result<Self> {
+        if depth >= MAX_BACKING_DEPTH {
+            return Err(invalid("qcow2 backing chain too deep"));
+        }
+
+        let fp = OpenOptions::new().read(true).open(path)?;
+        let len = fp.metadata()?.len();
+        let mut magic = [0u8; 4];
+        if len >= 4 {
+            fp.read_exact_at(&mut magic, 0)?;
+        }
+        if BigEndian::read_u32(&magic) == QCOW_MAGIC {
+            let img = Qcow2Image::open_file(fp, path, true, depth + 1)?;
+            Ok(Backing::Qcow2(Box::new(img)))
+        } else {
+            Ok(Backing::Raw { fp, len })
+        }
+    }
+
+    fn len(&self) -> u64 {
+        match self {
+            Backing::Raw { len, .. } => *len,
+            Backing::Qcow2(img) => img.hdr.size,
+        }
+    }
+
+    /// Read from the backing data, with any portion beyond its end reading as
+    /// zeroes.
+    fn read_at(&self, buf: &mut [u8], off: u64) -> Result<()> {
+        let avail = self.len().saturating_sub(off).min(buf.len() as u64);
+        let (data, tail) = buf.split_at_mut(avail as usize);
+        if !data.is_empty() {
+            match self {
+                Backing::Raw { fp, .. } => fp.read_exact_at(data, off)?,
+                Backing::Qcow2(img) => img.read_at(data, off)?,
+            }
+        }
+        tail.fill(0);
+        Ok(())
+    }
+}
+
+/// State of an open qcow2 image.
+struct Qcow2Image {
+    fp: File,
+    hdr: Header,
+    read_only: bool,
+
+    cluster_size: u64,
+    /// Cached copy of the L1 table
+    l1: Vec<u64>,
+    /// Cached copy of the refcount table
+    refcount_table: Vec<u64>,
+    backing: Option<Backing>,
+
+    /// Host offset at which the next cluster will be allocated
+    next_free: u64,
+}
+
+impl Qcow2Image {
+    fn open_file(
+        fp: File,
+        path: &Path,
+        read_only: bool,
+        depth: usize,
+    ) -> Result<Self> {
+        let mut hbuf = [0u8; V3_HEADER_LEN];
+        let flen = fp.metadata()?.len();
+        let hlen = (V3_HEADER_LEN as u64).min(flen) as usize;
+        fp.read_exact_at(&mut hbuf[..hlen], 0)?;
+        let hdr = Header::parse(&hbuf[..hlen])?;
+
+        let cluster_size = 1u64 << hdr.cluster_bits;
+        if !read_only {
+            if hdr.nb_snapshots != 0 {
+                return Err(unsupported(
+                    "writes to qcow2 images with snapshots",
+                ));
+            }
+            if hdr.refcount_order != DEFAULT_REFCOUNT_ORDER {
+                return Err(unsupported(&format!(
+                    "writes with qcow2 refcount_order {}",
+                    hdr.refcount_order
+                )));
+            }
+        }
+
+        // Everything read from the image must lie within the file
+        let l1_len = hdr.l1_size as u64 * 8;
+        check_extent(flen, hdr.l1_table_offset, l1_len, "L1 table")?;
+        let refcount_len = hdr.refcount_table_clusters as u64 * cluster_size;
+        check_extent(
+            flen,
+            hdr.refcount_table_offset,
+            refcount_len,
+            "refcount table",
+        )?;
+        if hdr.backing_file_offset != 0 {
+            check_extent(
+                flen,
+                hdr.backing_file_offset,
+                hdr.backing_file_size as u64,
+                "backing file name",
+            )?;
+        }
+
+        let l1 = read_table(&fp, hdr.l1_table_offset, hdr.l1_size as usize)?;
+        let refcount_table = read_table(
+            &fp,
+            hdr.refcount_table_offset,
+            (refcount_len / 8) as usize,
+        )?;
+
+        let backing = if hdr.backing_file_offset != 0 {
+            let mut name = vec![0u8; hdr.backing_file_size as usize];
+            fp.read_exact_at(&mut name, hdr.backing_file_offset)?;
+            let name = String::from_utf8(name)
+                .map_err(|_| invalid("qcow2 backing file name not UTF-8"))?;
+            let mut bpath = PathBuf::from(name);
+            if bpath.is_relative() {
+                if let Some(dir) = path.parent() {
+                    bpath = dir.join(bpath);
+                }
+            }
+            Some(Backing::open(&bpath, depth)?)
+        } else {
+            None
+        };
+
+        let next_free = (flen + cluster_size - 1) & !(cluster_size - 1);
+
+        Ok(Self {
+            fp,
+            hdr,
+            read_only,
+            cluster_size,
+            l1,
+            refcount_table,
+            backing,
+            next_free,
+        })
+    }
+
+    fn l2_entries(&self) -> u64 {
+        self.cluster_size / 8
+    }
+
+    /// Split a guest offset into its L1 index, L2 index, and in-cluster offset
+    fn split_offset(&self, off: u64) -> (usize, u64, u64) {
+        let in_cluster = off & (self.cluster_size - 1);
+        let cluster = off >> self.hdr.cluster_bits;
+        let l2_idx = cluster % self.l2_entries();
+        let l1_idx = (cluster / self.l2_entries()) as usize;
+        (l1_idx, l2_idx, in_cluster)
+    }
+
+    /// Host offset of the L2 entry for a guest offset, if its table exists
+    fn l2_entry_addr(&self, off: u64) -> Option<u64> {
+        let (l1_idx, l2_idx, _) = self.split_offset(off);
+        let l2_table = *self.l1.get(l1_idx)? & ENTRY_OFFSET_MASK;
+        if l2_table == 0 {
+            None
+        } else {
+            Some(l2_table + l2_idx * 8)
+        }
+    }
+
+    fn lookup(&self, off: u64) -> Result<ClusterMapping> {
+        let addr = match self.l2_entry_addr(off) {
+            Some(a) => a,
+            None => return Ok(ClusterMapping::Unallocated),
+        };
+        let entry = read_u64(&self.fp, addr)?;
+        if entry & L2_COMPRESSED != 0 {
+            return Ok(ClusterMapping::Compressed);
+        }
+        let host = entry & ENTRY_OFFSET_MASK;
+        let copied = entry & ENTRY_COPIED != 0;
+        if self.hdr.version >= 3 && entry & L2_ZERO != 0 {
+            return Ok(ClusterMapping::Zero(if host != 0 && copied {
+                Some(host)
+            } else {
+                None
+            }));
+        }
+        if host == 0 {
+            Ok(ClusterMapping::Unallocated)
+        } else if copied {
+            Ok(ClusterMapping::Data(host))
+        } else {
+            Ok(ClusterMapping::Shared(host))
+        }
+    }
+
+    /// Read guest data at `off` into `buf`
+    fn read_at(&self, buf: &mut [u8], off: u64) -> Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = off + done as u64;
+            let in_cluster = pos & (self.cluster_size - 1);
+            let chunk = (buf.len() - done)
+                .min((self.cluster_size - in_cluster) as usize);
+            let dst = &mut buf[done..(done + chunk)];
+
+            match self.lookup(pos)? {
+                ClusterMapping::Data(host) | ClusterMapping::Shared(host) => {
+                    self.fp.read_exact_at(dst, host + in_cluster)?
+                }
+                ClusterMapping::Zero(_) => dst.fill(0),
+                ClusterMapping::Unallocated => match &self.backing {
+                    Some(b) => b.read_at(dst, pos)?,
+                    None => dst.fill(0),
+                },
+                ClusterMapping::Compressed => {
+                    return Err(unsupported("compressed qcow2 clusters"))
+                }
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Write guest data from `buf` at `off`, allocating clusters as required
+    fn write_at(&mut self, buf: &[u8], off: u64) -> Result<()> {
+        self.check_writable()?;
+
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = off + done as u64;
+            let in_cluster = pos & (self.cluster_size - 1);
+            let chunk = (buf.len() - done)
+                .min((self.cluster_size - in_cluster) as usize);
+            let src = &buf[done..(done + chunk)];
+
+            match self.lookup(pos)? {
+                ClusterMapping::Data(host) => {
+                    self.fp.write_all_at(src, host + in_cluster)?;
+                }
+                ClusterMapping::Compressed => {
+                    return Err(unsupported("compressed qcow2 clusters"))
+                }
+                mapping => {
+                    // Populate a full cluster with the existing contents (from
+                    // a shared cluster, the backing file, or zeroes) overlaid
+                    // with the new data.
+                    let cluster_start = pos - in_cluster;
+                    let mut data = vec![0u8; self.cluster_size as usize];
+                    if chunk as u64 != self.cluster_size {
+                        match (mapping, &self.backing) {
+                            (ClusterMapping::Shared(host), _) => {
+                                self.fp.read_exact_at(&mut data, host)?
+                            }
+                            (ClusterMapping::Unallocated, Some(b)) => {
+                                b.read_at(&mut data, cluster_start)?
+                            }
+                            _ => {}
+                        }
+                    }
+                    data[(in_cluster as usize)..(in_cluster as usize + chunk)]
+                        .copy_from_slice(src);
+
+                    let host = match mapping {
+                        ClusterMapping::Zero(Some(host)) => host,
+                        _ => self.alloc_cluster()?,
+                    };
+                    self.fp.write_all_at(&data, host)?;
+                    self.set_l2_entry(pos, host | ENTRY_COPIED)?;
+                    if let ClusterMapping::Shared(old) = mapping {
+                        self.drop_ref(old)?;
+                    }
+                }
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Zero `len` bytes of guest data at `off`.
+    ///
+    /// Whole clusters are marked with the zero flag (on v3 images) rather than
+    /// being allocated and filled.  Clusters which already read as zeroes are
+    /// left untouched.
+    fn write_zeroes(&mut self, off: u64, len: u64) -> Result<()> {
+        self.check_writable()?;
+
+        let zeroes = vec![0u8; self.cluster_size as usize];
+        let mut done = 0;
+        while done < len {
+            let pos = off + done;
+            let in_cluster = pos & (self.cluster_size - 1);
+            let chunk = (len - done).min(self.cluster_size - in_cluster);
+
+            let mapping = self.lookup(pos)?;
+            let reads_zero = match mapping {
+                ClusterMapping::Zero(_) => true,
+                ClusterMapping::Unallocated => self.backing.is_none(),
+                _ => false,
+            };
+            if reads_zero {
+                // Nothing to do
+            } else if self.hdr.version >= 3 && chunk == self.cluster_size {
+                let entry = match mapping {
+                    // Keep an exclusively owned cluster as preallocated
+                    ClusterMapping::Data(host) => host | ENTRY_COPIED | L2_ZERO,
+                    _ => L2_ZERO,
+                };
+                self.set_l2_entry(pos, entry)?;
+                if let ClusterMapping::Shared(old) = mapping {
+                    self.drop_ref(old)?;
+                }
+            } else {
+                self.write_at(&zeroes[..(chunk as usize)], pos)?;
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "backend is read-only",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Update the L2 entry for a guest offset, allocating the L2 table if it
+    /// does not yet exist, or copying it if it may be referenced elsewhere.
+    fn set_l2_entry(&mut self, off: u64, entry: u64) -> Result<()> {
+        let (l1_idx, _, _) = self.split_offset(off);
+        let l1_entry = match self.l1.get(l1_idx) {
+            Some(e) => *e,
+            None => return Err(invalid("offset beyond qcow2 L1 table")),
+        };
+        let old_table = l1_entry & ENTRY_OFFSET_MASK;
+        if old_table == 0 || l1_entry & ENTRY_COPIED == 0 {
+            let mut data = vec![0u8; self.cluster_size as usize];
+            if old_table != 0 {
+                self.fp.read_exact_at(&mut data, old_table)?;
+            }
+            let table = self.alloc_cluster()?;
+            self.fp.write_all_at(&data, table)?;
+            let l1_entry = table | ENTRY_COPIED;
+            write_u64(
+                &self.fp,
+                self.hdr.l1_table_offset + l1_idx as u64 * 8,
+                l1_entry,
+            )?;
+            self.l1[l1_idx] = l1_entry;
+            if old_table != 0 {
+                self.drop_ref(old_table)?;
+            }
+        }
+        write_u64(&self.fp, self.l2_entry_addr(off).unwrap(), entry)
+    }
+
+    /// Allocate a cluster at the end of the image, recording its refcount.
+    fn alloc_cluster(&mut self) -> Result<u64> {
+        let host = self.next_free;
+        self.next_free += self.cluster_size;
+        self.fp.set_len(self.next_free)?;
+        self.set_refcount(host, 1)?;
+        Ok(host)
+    }
+
+    /// Drop a reference to a cluster which has been copied away from.
+    fn drop_ref(&mut self, host: u64) -> Result<()> {
+        let count = self.refcount(host)?;
+        if count > 0 {
+            self.set_refcount(host, count - 1)?;
+        }
+        Ok(())
+    }
+
+    fn refcount(&self, host: u64) -> Result<u16> {
+        let per_block = self.cluster_size / 2;
+        let cluster = host >> self.hdr.cluster_bits;
+        let rt_idx = (cluster / per_block) as usize;
+        let blk_idx = cluster % per_block;
+
+        let block = match self.refcount_table.get(rt_idx) {
+            Some(e) => *e & ENTRY_OFFSET_MASK,
+            None => 0,
+        };
+        if block == 0 {
+            return Ok(0);
+        }
+        let mut buf = [0u8; 2];
+        self.fp.read_exact_at(&mut buf, block + blk_idx * 2)?;
+        Ok(BigEndian::read_u16(&buf))
+    }
+
+    fn set_refcount(&mut self, host: u64, count: u16) -> Result<()> {
+        // 16-bit refcounts (refcount_order = 4), as checked at open time
+        let per_block = self.cluster_size / 2;
+        let cluster = host >> self.hdr.cluster_bits;
+        let rt_idx = (cluster / per_block) as usize;
+        let blk_idx = cluster % per_block;
+
+        if rt_idx >= self.refcount_table.len() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "qcow2 refcount table exhausted",
+            ));
+        }
+        let mut block = self.refcount_table[rt_idx] & ENTRY_OFFSET_MASK;
+        if block == 0 {
+            // Allocate a new refcount block, which must account for itself
+            block = self.next_free;
+            self.next_free += self.cluster_size;
+            self.fp.set_len(self.next_free)?;
+            self.fp
+                .write_all_at(&vec![0u8; self.cluster_size as usize], block)?;
+            write_u64(
+                &self.fp,
+                self.hdr.refcount_table_offset + rt_idx as u64 * 8,
+                block,
+            )?;
+            self.refcount_table[rt_idx] = block;
+            self.set_refcount(block, 1)?;
+        }
+
+        let mut buf = [0u8; 2];
+        BigEndian::write_u16(&mut buf, count);
+        self.fp.write_all_at(&buf, block + blk_idx * 2)
+    }
+}
+
+fn read_table(fp: &File, off: u64, entries: usize) -> Result<Vec<u64>> {
+    let mut buf = vec![0u8; entries * 8];
+    fp.read_exact_at(&mut buf, off)?;
+    Ok(buf.chunks_exact(8).map(BigEndian::read_u64).collect())
+}
+
+fn read_u64(fp: &File, off: u64) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    fp.read_exact_at(&mut buf, off)?;
+    Ok(BigEndian::read_u64(&buf))
+}
+
+fn write_u64(fp: &File, off: u64, val: u64) -> Result<()> {
+    let mut buf = [0u8; 8];
+    BigEndian::write_u64(&mut buf, val);
+    fp.write_all_at(&buf, off)
+}
+
+/// Check that `len` bytes at `off` lie within a file of `flen` bytes.
+fn check_extent(flen: u64, off: u64, len: u64, what: &str) -> Result<()> {
+    match off.checked_add(len) {
+        Some(end) if end <= flen => Ok(()),
+        _ => Err(invalid(&format!("qcow2 {what} beyond end of file"))),
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn unsupported(what: &str) -> Error {
+    Error::new(ErrorKind::Unsupported, format!("{what} not supported"))
+}
+
+/// Block backend for qcow2 images.
+pub struct Qcow2Backend {
+    image: Arc<RwLock<Qcow2Image>>,
+
+    driver: Mutex<Option<Arc<block::Driver>>>,
+    worker_count: NonZeroUsize,
+
+    read_only: bool,
+    block_size: usize,
+    sectors: usize,
+}
+
+impl Qcow2Backend {
+    /// Creates a new block device from a qcow2 image at `path`.
+    pub fn create(
+        path: impl AsRef<Path>,
+        readonly: bool,
+        worker_count: NonZeroUsize,
+    ) -> Result<Arc<Self>> {
+        if worker_count.get() > MAX_WORKERS {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "too many workers",
+            ));
+        }
+        let p: &Path = path.as_ref();
+
+        let meta = std::fs::metadata(p)?;
+        let read_only = readonly || meta.permissions().readonly();
+
+        let fp = OpenOptions::new().read(true).write(!read_only).open(p)?;
+        let image = Qcow2Image::open_file(fp, p, read_only, 0)?;
+        let sectors = (image.hdr.size / 512) as usize;
+
+        let this = Self {
+            image: Arc::new(RwLock::new(image)),
+
+            driver: Mutex::new(None),
+            worker_count,
+
+            read_only,
+            block_size: 512,
+            sectors,
+        };
+
+        Ok(Arc::new(this))
+    }
+}
+
+impl block::Backend for Qcow2Backend {
+    fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            block_size: self.block_size as u32,
+            total_size: self.sectors as u64,
+            writable: !self.read_only,
+        }
+    }
+
+    fn driver(&self) -> Option<Arc<block::Driver>> {
+        self.driver.lock().unwrap().clone()
+    }
+
+    fn resize(&self, _total_size: u64) -> Result<()> {
+        // The L1 table is sized for the virtual disk when the image is created
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "resizing qcow2 images not supported",
+        ))
+    }
+
+    fn attach(
+        &self,
+        dev: Arc<dyn block::Device>,
+        disp: &Dispatcher,
+    ) -> Result<()> {
+        let mut driverg = self.driver.lock().unwrap();
+        assert!(driverg.is_none());
+
+        let image = Arc::clone(&self.image);
+        let req_handler =
+            Box::new(move |req: &block::Request, ctx: &DispCtx| {
+                process_request(&image, req, ctx)
+            });
+
+        // Spawn driver to service block dev requests
+        let driver = Arc::new(block::Driver::new(dev, req_handler, None));
+        driver.spawn("qcow2", self.worker_count, disp)?;
+        *driverg = Some(driver);
+
+        Ok(())
+    }
+}
+
+impl Entity for Qcow2Backend {
+    fn type_name(&self) -> &'static str {
+        "block-qcow2"
+    }
+}
+
+/// Read from the image into guest memory
+fn process_read_request(
+    image: &RwLock<Qcow2Image>,
+    offset: u64,
+    len: usize,
+    mappings: &Vec<SubMapping>,
+) -> Result<()> {
+    let mut data = vec![0u8; len];
+    image.read().unwrap().read_at(&mut data, offset)?;
+
+    let mut nwritten = 0;
+    for mapping in mappings {
+        nwritten +=
+            mapping.write_bytes(&data[nwritten..(nwritten + mapping.len())])?;
+    }
+    if nwritten != len {
+        return Err(Error::new(ErrorKind::Other, "bad read length"));
+    }
+
+    Ok(())
+}
+
+/// Write from guest memory into the image
+fn process_write_request(
+    image: &RwLock<Qcow2Image>,
+    offset: u64,
+    len: usize,
+    mappings: &Vec<SubMapping>,
+) -> Result<()> {
+    let mut data = vec![0u8; len];
+
+    let mut nread = 0;
+    for mapping in mappings {
+        nread +=
+            mapping.read_bytes(&mut data[nread..(nread + mapping.len())])?;
+    }
+    if nread != len {
+        return Err(Error::new(ErrorKind::Other, "bad write length"));
+    }
+
+    image.write().unwrap().write_at(&data, offset)
+}
+
+/// Check that [off, off + len) lies within a disk of `size` bytes.
+fn check_range(off: usize, len: usize, size: u64) -> Result<()> {
+    match (off as u64).checked_add(len as u64) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(Error::new(ErrorKind::InvalidInput, "range beyond disk")),
+    }
+}
+
+fn process_request(
+    image: &RwLock<Qcow2Image>,
+    req: &block::Request,
+    ctx: &DispCtx,
+) -> Result<()> {
+    let size = image.read().unwrap().hdr.size;
+    let mem = ctx.mctx.memctx();
+    match req.oper() {
+        block::Operation::Read(off) => {
+            let maps = req.mappings(&mem).ok_or_else(|| {
+                Error::new(ErrorKind::Other, "bad guest region")
+            })?;
+
+            check_range(off, req.len(), size)?;
+            process_read_request(image, off as u64, req.len(), &maps)?;
+        }
+        block::Operation::Write(off) => {
+            let maps = req.mappings(&mem).ok_or_else(|| {
+                Error::new(ErrorKind::Other, "bad guest region")
+            })?;
+
+            check_range(off, req.len(), size)?;
+            process_write_request(image, off as u64, req.len(), &maps)?;
+        }
+        block::Operation::Flush(_off, _len) => {
+            image.read().unwrap().fp.sync_data()?;
+        }
+        block::Operation::Discard(off, len) => {
+            // Discard is advisory, and clusters are not reclaimed from the
+            // image, so there is nothing to do beyond validating the range.
+            check_range(off, len, size)?;
+        }
+        block::Operation::WriteZeroes(off, len) => {
+            check_range(off, len, size)?;
+            image.write().unwrap().write_zeroes(off as u64, len as u64)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::test_util::{TestDevice, TestInstance};
+
+    const CLUSTER_BITS: u32 = 16;
+    const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+
+    /// Build a minimal, empty v3 image: header cluster, L1 table cluster,
+    /// refcount table cluster, and one refcount block.
+    fn create_image(path: &Path, size: u64, backing: Option<&str>) {
+        let l2_span = CLUSTER_SIZE * (CLUSTER_SIZE / 8);
+        let l1_size = ((size + l2_span - 1) / l2_span) as u32;
+
+        let mut img = vec![0u8; 4 * CLUSTER_SIZE as usize];
+        let hdr = &mut img[..V3_HEADER_LEN];
+        BigEndian::write_u32(&mut hdr[0..4], QCOW_MAGIC);
+        BigEndian::write_u32(&mut hdr[4..8], 3);
+        BigEndian::write_u32(&mut hdr[20..24], CLUSTER_BITS);
+        BigEndian::write_u64(&mut hdr[24..32], size);
+        BigEndian::write_u32(&mut hdr[36..40], l1_size);
+        BigEndian::write_u64(&mut hdr[40..48], CLUSTER_SIZE);
+        BigEndian::write_u64(&mut hdr[48..56], 2 * CLUSTER_SIZE);
+        BigEndian::write_u32(&mut hdr[56..60], 1);
+        BigEndian::write_u32(&mut hdr[96..100], DEFAULT_REFCOUNT_ORDER);
+        BigEndian::write_u32(&mut hdr[100..104], V3_HEADER_LEN as u32);
+        if let Some(name) = backing {
+            let off = V3_HEADER_LEN + 8;
+            BigEndian::write_u64(&mut img[8..16], off as u64);
+            BigEndian::write_u32(&mut img[16..20], name.len() as u32);
+            img[off..(off + name.len())].copy_from_slice(name.as_bytes());
+        }
+
+        // refcount table -> refcount block at cluster 3
+        let rt = 2 * CLUSTER_SIZE as usize;
+        BigEndian::write_u64(&mut img[rt..(rt + 8)], 3 * CLUSTER_SIZE);
+        let rb = 3 * CLUSTER_SIZE as usize;
+        for i in 0..4 {
+            BigEndian::write_u16(&mut img[(rb + i * 2)..(rb + i * 2 + 2)], 1);
+        }
+
+        std::fs::write(path, img).unwrap();
+    }
+
+    fn open(path: &Path, read_only: bool) -> Qcow2Image {
+        let fp =
+            OpenOptions::new().read(true).write(!read_only).open(path).unwrap();
+        Qcow2Image::open_file(fp, path, read_only, 0).unwrap()
+    }
+
+    #[test]
+    fn header_rejects_bad_magic() {
+        let buf = [0u8; V3_HEADER_LEN];
+        assert!(Header::parse(&buf).is_err());
+    }
+
+    #[test]
+    fn header_fields_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        let open_err = |patch: &dyn Fn(&mut [u8])| {
+            create_image(&path, 1 << 24, Some("base.raw"));
+            let mut img = std::fs::read(&path).unwrap();
+            patch(&mut img);
+            std::fs::write(&path, img).unwrap();
+            let fp = OpenOptions::new().read(true).open(&path).unwrap();
+            match Qcow2Image::open_file(fp, &path, true, 0) {
+                Ok(_) => panic!("corrupt image opened"),
+                Err(e) => e.kind(),
+            }
+        };
+
+        // Tables and names, whether absurdly large or merely extending past
+        // the end of the file, are rejected before being read.
+        let kind = open_err(&|h| BigEndian::write_u32(&mut h[36..40], !0));
+        assert_eq!(kind, ErrorKind::InvalidData);
+        let kind = open_err(&|h| BigEndian::write_u32(&mut h[36..40], 1 << 20));
+        assert_eq!(kind, ErrorKind::InvalidData);
+        let kind = open_err(&|h| BigEndian::write_u32(&mut h[56..60], !0));
+        assert_eq!(kind, ErrorKind::InvalidData);
+        let kind = open_err(&|h| BigEndian::write_u32(&mut h[56..60], 64));
+        assert_eq!(kind, ErrorKind::InvalidData);
+        let kind = open_err(&|h| BigEndian::write_u32(&mut h[16..20], !0));
+        assert_eq!(kind, ErrorKind::InvalidData);
+        let kind = open_err(&|h| BigEndian::write_u64(&mut h[8..16], !0 - 4));
+        assert_eq!(kind, ErrorKind::InvalidData);
+
+        // Virtual sizes too large to map, or not a whole number of sectors
+        let kind = open_err(&|h| BigEndian::write_u64(&mut h[24..32], !511));
+        assert_eq!(kind, ErrorKind::InvalidData);
+        let kind = open_err(&|h| BigEndian::write_u64(&mut h[24..32], 1000));
+        assert_eq!(kind, ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn requests_beyond_disk_rejected() {
+        const BS: usize = 512;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        create_image(&path, 16 * BS as u64, None);
+
+        let test = TestInstance::new();
+        let workers = NonZeroUsize::new(1).unwrap();
+        let be = Qcow2Backend::create(&path, false, workers).unwrap();
+        let dev = TestDevice::new();
+        test.attach(be.as_ref(), &dev).unwrap();
+
+        let res = test.write(&dev, 15 * BS, &[0xaa; BS]);
+        assert!(matches!(res, block::Result::Success));
+        let flen = std::fs::metadata(&path).unwrap().len();
+
+        let res = test.write(&dev, 15 * BS, &[0xbb; 2 * BS]);
+        assert!(matches!(res, block::Result::Failure));
+        let (res, _) = test.read(&dev, 16 * BS, BS);
+        assert!(matches!(res, block::Result::Failure));
+        let res = test
+            .submit(&dev, |donef| {
+                block::Request::new_write_zeroes(usize::MAX - BS + 1, BS, donef)
+            })
+            .wait();
+        assert!(matches!(res, block::Result::Failure));
+
+        // Nothing was written or allocated for the rejected requests
+        let (res, data) = test.read(&dev, 15 * BS, BS);
+        assert!(matches!(res, block::Result::Success));
+        assert!(data.iter().all(|b| *b == 0xaa));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), flen);
+    }
+
+    #[test]
+    fn empty_image_reads_zero() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        create_image(&path, 1 << 24, None);
+
+        let img = open(&path, true);
+        assert_eq!(img.hdr.size, 1 << 24);
+        let mut buf = vec![0xffu8; 4096];
+        img.read_at(&mut buf, 8192).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn write_allocates_and_reads_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        create_image(&path, 1 << 24, None);
+
+        let mut img = open(&path, false);
+        // Straddle a cluster boundary to exercise split writes
+        let off = CLUSTER_SIZE - 512;
+        let data: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+        img.write_at(&data, off).unwrap();
+        assert!(matches!(img.lookup(off).unwrap(), ClusterMapping::Data(_)));
+        drop(img);
+
+        // Metadata must survive reopening the image
+        let img = open(&path, true);
+        let mut buf = vec![0u8; 1024];
+        img.read_at(&mut buf, off).unwrap();
+        assert_eq!(buf, data);
+
+        let mut buf = vec![0xffu8; 512];
+        img.read_at(&mut buf, off - 512).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn backing_file_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base.raw");
+        std::fs::write(&base, vec![0xaau8; 8192]).unwrap();
+        let path = dir.path().join("overlay.qcow2");
+        create_image(&path, 1 << 24, Some("base.raw"));
+
+        let mut img = open(&path, false);
+        img.write_at(&[0x55u8; 512], 512).unwrap();
+
+        let mut buf = vec![0u8; 2048];
+        img.read_at(&mut buf, 0).unwrap();
+        assert!(buf[..512].iter().all(|b| *b == 0xaa));
+        assert!(buf[512..1024].iter().all(|b| *b == 0x55));
+        assert!(buf[1024..].iter().all(|b| *b == 0xaa));
+
+        // Beyond the end of the backing file reads as zero
+        let mut buf = vec![0xffu8; 512];
+        img.read_at(&mut buf, 8192).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+
+        // The base image itself is left untouched
+        assert!(std::fs::read(&base).unwrap().iter().all(|b| *b == 0xaa));
+    }
+
+    #[test]
+    fn write_zeroes_sets_zero_flag() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base.raw");
+        std::fs::write(&base, vec![0xaau8; 4 * CLUSTER_SIZE as usize]).unwrap();
+        let path = dir.path().join("overlay.qcow2");
+        create_image(&path, 1 << 24, Some("base.raw"));
+
+        let mut img = open(&path, false);
+        img.write_at(&[0x55u8; 512], 0).unwrap();
+        let host = match img.lookup(0).unwrap() {
+            ClusterMapping::Data(host) => host,
+            m => panic!("unexpected mapping {:?}", m),
+        };
+        let len = img.fp.metadata().unwrap().len();
+
+        // Whole clusters are flagged rather than allocated, with an allocated
+        // cluster kept around for later writes.
+        img.write_zeroes(0, 3 * CLUSTER_SIZE).unwrap();
+        assert_eq!(img.lookup(0).unwrap(), ClusterMapping::Zero(Some(host)));
+        assert_eq!(
+            img.lookup(CLUSTER_SIZE).unwrap(),
+            ClusterMapping::Zero(None)
+        );
+        assert_eq!(img.fp.metadata().unwrap().len(), len);
+
+        // Partial clusters are written out
+        img.write_zeroes(3 * CLUSTER_SIZE + 512, 512).unwrap();
+        assert!(matches!(
+            img.lookup(3 * CLUSTER_SIZE).unwrap(),
+            ClusterMapping::Data(_)
+        ));
+
+        let mut buf = vec![0xffu8; 4 * CLUSTER_SIZE as usize];
+        img.read_at(&mut buf, 0).unwrap();
+        let split = 3 * CLUSTER_SIZE as usize;
+        assert!(buf[..split].iter().all(|b| *b == 0));
+        assert!(buf[split..(split + 512)].iter().all(|b| *b == 0xaa));
+        assert!(buf[(split + 512)..(split + 1024)].iter().all(|b| *b == 0));
+        assert!(buf[(split + 1024)..].iter().all(|b| *b == 0xaa));
+
+        // Writes land in the preallocated cluster
+        img.write_at(&[0x11u8; 512], 512).unwrap();
+        assert_eq!(img.lookup(0).unwrap(), ClusterMapping::Data(host));
+        let mut buf = vec![0xffu8; 1024];
+        img.read_at(&mut buf, 0).unwrap();
+        assert!(buf[..512].iter().all(|b| *b == 0));
+        assert!(buf[512..].iter().all(|b| *b == 0x11));
+    }
+
+    #[test]
+    fn shared_cluster_copied_on_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        create_image(&path, 1 << 24, None);
+
+        let mut img = open(&path, false);
+        img.write_at(&vec![0x55u8; CLUSTER_SIZE as usize], 0).unwrap();
+        let old = match img.lookup(0).unwrap() {
+            ClusterMapping::Data(host) => host,
+            m => panic!("unexpected mapping {:?}", m),
+        };
+
+        // Share the cluster (and the L2 table referencing it), as an internal
+        // snapshot would.
+        let addr = img.l2_entry_addr(0).unwrap();
+        write_u64(&img.fp, addr, old).unwrap();
+        img.set_refcount(old, 2).unwrap();
+        let old_table = img.l1[0] & ENTRY_OFFSET_MASK;
+        img.l1[0] = old_table;
+        img.set_refcount(old_table, 2).unwrap();
+        assert_eq!(img.lookup(0).unwrap(), ClusterMapping::Shared(old));
+
+        img.write_at(&[0x11u8; 512], 512).unwrap();
+        let new = match img.lookup(0).unwrap() {
+            ClusterMapping::Data(host) => host,
+            m => panic!("unexpected mapping {:?}", m),
+        };
+        assert_ne!(new, old);
+        assert_ne!(img.l1[0] & ENTRY_OFFSET_MASK, old_table);
+        assert_eq!(img.refcount(old).unwrap(), 1);
+        assert_eq!(img.refcount(old_table).unwrap(), 1);
+
+        // The shared copies are left intact
+        let mut buf = vec![0u8; CLUSTER_SIZE as usize];
+        img.fp.read_exact_at(&mut buf, old).unwrap();
+        assert!(buf.iter().all(|b| *b == 0x55));
+        assert_eq!(read_u64(&img.fp, addr).unwrap(), old);
+
+        img.read_at(&mut buf, 0).unwrap();
+        assert!(buf[..512].iter().all(|b| *b == 0x55));
+        assert!(buf[512..1024].iter().all(|b| *b == 0x11));
+        assert!(buf[1024..].iter().all(|b| *b == 0x55));
+    }
+}
@@ -0,0 +1,219 @@
+//! Per-backend I/O statistics gathered by [`Driver`](super::Driver).
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use super::Operation;
+
+/// Number of buckets in a [`LatencyHistogram`]
+pub const LATENCY_BUCKETS: usize = 32;
+
+/// Histogram of request latencies, with power-of-two microsecond buckets.
+///
+/// Bucket `0` counts latencies below 1us, while bucket `n` counts those in
+/// `[2^(n-1), 2^n)` microseconds.  The final bucket also absorbs any latencies
+/// beyond its range.
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl LatencyHistogram {
+    fn new() -> Self {
+        // AtomicU64 is not Copy, precluding the [expr; N] array form
+        let buckets = Default::default();
+        Self { buckets }
+    }
+
+    fn bucket_for(latency: Duration) -> usize {
+        let us = latency.as_micros().min(u64::MAX as u128) as u64;
+        let idx = (u64::BITS - us.leading_zeros()) as usize;
+        idx.min(LATENCY_BUCKETS - 1)
+    }
+
+    fn record(&self, latency: Duration) {
+        self.buckets[Self::bucket_for(latency)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> [u64; LATENCY_BUCKETS] {
+        let mut out = [0; LATENCY_BUCKETS];
+        for (o, b) in out.iter_mut().zip(self.buckets.iter()) {
+            *o = b.load(Ordering::Relaxed);
+        }
+        out
+    }
+}
+
+struct OpStats {
+    ops: AtomicU64,
+    bytes: AtomicU64,
+    errors: AtomicU64,
+    latency: LatencyHistogram,
+}
+
+impl OpStats {
+    fn new() -> Self {
+        Self {
+            ops: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            latency: LatencyHistogram::new(),
+        }
+    }
+
+    fn snapshot(&self) -> OpStatsSnapshot {
+        OpStatsSnapshot {
+            ops: self.ops.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            latency_us: self.latency.snapshot(),
+        }
+    }
+}
+
+/// Point-in-time copy of the statistics for one type of [`Operation`].
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct OpStatsSnapshot {
+    /// Completed operations
+    pub ops: u64,
+    /// Bytes transferred by completed operations
+    pub bytes: u64,
+    /// Operations which completed with an error
+    pub errors: u64,
+    /// Latency from request arrival to completion (see [`LatencyHistogram`])
+    pub latency_us: [u64; LATENCY_BUCKETS],
+}
+
+/// Point-in-time copy of the statistics for a block backend.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct StatsSnapshot {
+    pub read: OpStatsSnapshot,
+    pub write: OpStatsSnapshot,
+    pub flush: OpStatsSnapshot,
+    pub discard: OpStatsSnapshot,
+    pub write_zeroes: OpStatsSnapshot,
+    /// Requests received from the device but not yet completed
+    pub in_flight: u64,
+}
+
+/// I/O statistics for a block backend.
+pub struct Stats {
+    read: OpStats,
+    write: OpStats,
+    flush: OpStats,
+    discard: OpStats,
+    write_zeroes: OpStats,
+    in_flight: AtomicU64,
+}
+
+impl Stats {
+    pub(super) fn new() -> Self {
+        Self {
+            read: OpStats::new(),
+            write: OpStats::new(),
+            flush: OpStats::new(),
+            discard: OpStats::new(),
+            write_zeroes: OpStats::new(),
+            in_flight: AtomicU64::new(0),
+        }
+    }
+
+    fn for_op(&self, op: Operation) -> &OpStats {
+        match op {
+            Operation::Read(_) => &self.read,
+            Operation::Write(_) => &self.write,
+            Operation::Flush(_, _) => &self.flush,
+            Operation::Discard(_, _) => &self.discard,
+            Operation::WriteZeroes(_, _) => &self.write_zeroes,
+        }
+    }
+
+    /// Record the arrival of a request from the device.
+    pub(super) fn arrived(&self) {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the completion of a request.
+    pub(super) fn completed(
+        &self,
+        op: Operation,
+        bytes: usize,
+        success: bool,
+        latency: Duration,
+    ) {
+        let stats = self.for_op(op);
+        stats.ops.fetch_add(1, Ordering::Relaxed);
+        if success {
+            stats.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        } else {
+            stats.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        stats.latency.record(latency);
+    }
+
+    /// Record that a completed request is no longer in flight.
+    ///
+    /// This is kept apart from [`Stats::completed`] so that the completion is
+    /// recorded before it is delivered to the device, while the request still
+    /// counts as in flight until it has been.
+    pub(super) fn retired(&self) {
+        self.in_flight.fetch_sub(1, Ordering::Release);
+    }
+
+    /// Number of requests which have arrived but are yet to complete.
+    pub(super) fn in_flight(&self) -> u64 {
+        self.in_flight.load(Ordering::Acquire)
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            read: self.read.snapshot(),
+            write: self.write.snapshot(),
+            flush: self.flush.snapshot(),
+            discard: self.discard.snapshot(),
+            write_zeroes: self.write_zeroes.snapshot(),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets() {
+        let us = Duration::from_micros;
+        assert_eq!(LatencyHistogram::bucket_for(Duration::from_nanos(500)), 0);
+        assert_eq!(LatencyHistogram::bucket_for(us(1)), 1);
+        assert_eq!(LatencyHistogram::bucket_for(us(2)), 2);
+        assert_eq!(LatencyHistogram::bucket_for(us(3)), 2);
+        assert_eq!(LatencyHistogram::bucket_for(us(1024)), 11);
+        assert_eq!(
+            LatencyHistogram::bucket_for(Duration::from_secs(1 << 40)),
+            LATENCY_BUCKETS - 1
+        );
+    }
+
+    #[test]
+    fn completion_accounting() {
+        let stats = Stats::new();
+        stats.arrived();
+        stats.arrived();
+        assert_eq!(stats.snapshot().in_flight, 2);
+
+        stats.completed(Operation::Read(0), 4096, true, Duration::ZERO);
+        stats.completed(Operation::Read(0), 4096, false, Duration::ZERO);
+        // Requests remain in flight until retired
+        assert_eq!(stats.snapshot().in_flight, 2);
+        stats.retired();
+        stats.retired();
+
+        let snap = stats.snapshot();
+        assert_eq!(snap.in_flight, 0);
+        assert_eq!(snap.read.ops, 2);
+        assert_eq!(snap.read.bytes, 4096);
+        assert_eq!(snap.read.errors, 1);
+        assert_eq!(snap.read.latency_us[0], 2);
+        assert_eq!(snap.write, OpStatsSnapshot::default());
+    }
+}
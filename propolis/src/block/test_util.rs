@@ -0,0 +1,190 @@
+//! Helpers for exercising block backends in tests.
+//!
+//! A [`TestDevice`] issues requests to a backend attached to it, within a
+//! test instance (see [`Instance::new_test`]), whose guest memory comprises
+//! [`READ_BASE`] (from which writes are sourced) and [`WRITE_BASE`] (into
+//! which reads land), along with [`RW_BASE`] for structures shared in both
+//! directions.
+
+use std::collections::VecDeque;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use crate::block;
+use crate::common::{GuestAddr, GuestRegion};
+use crate::dispatch::DispCtx;
+use crate::instance::{Instance, ReqState};
+
+use tokio::runtime::Runtime;
+
+/// Guest memory which the guest may read (and thus be the source of writes)
+pub(crate) const READ_BASE: GuestAddr = GuestAddr(0);
+/// Guest memory which the guest may write (and thus be the target of reads)
+pub(crate) const WRITE_BASE: GuestAddr = GuestAddr(1024 * 1024);
+/// Guest memory which the guest may both read and write
+pub(crate) const RW_BASE: GuestAddr = GuestAddr(2 * 1024 * 1024);
+
+/// How long to wait for a request to complete before declaring it lost
+pub(crate) const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A running test instance, and the runtime backing it.
+pub(crate) struct TestInstance {
+    pub inst: Arc<Instance>,
+    // Held for the lifetime of the instance
+    rt: Runtime,
+}
+impl TestInstance {
+    pub fn new() -> Self {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let inst = Instance::new_test(Some(rt.handle().clone())).unwrap();
+        inst.set_target_state(ReqState::Run).unwrap();
+        Self { inst, rt }
+    }
+
+    /// Attach `backend` to `dev`, spawning its driver within the instance.
+    pub fn attach<D: block::Device>(
+        &self,
+        backend: &dyn block::Backend,
+        dev: &Arc<D>,
+    ) -> std::io::Result<()> {
+        let _guard = self.rt.enter();
+        backend
+            .attach(Arc::clone(dev) as Arc<dyn block::Device>, &self.inst.disp)
+    }
+
+    /// Run `func` with a dispatcher context, within the runtime so that it
+    /// may spawn tasks.
+    pub fn with_ctx(&self, func: impl FnOnce(&DispCtx)) {
+        let _guard = self.rt.enter();
+        self.inst.disp.with_ctx(func)
+    }
+
+    /// Fill guest memory at `addr` with `data`.
+    pub fn fill(&self, addr: GuestAddr, data: &[u8]) {
+        self.with_ctx(|ctx| {
+            let mem = ctx.mctx.memctx();
+            let region = GuestRegion(addr, data.len());
+            let map = mem.direct_writable_region(&region).unwrap();
+            assert_eq!(map.write_bytes(data).unwrap(), data.len());
+        })
+    }
+
+    /// Read `len` bytes of guest memory at `addr`.
+    pub fn contents(&self, addr: GuestAddr, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        self.with_ctx(|ctx| {
+            let mem = ctx.mctx.memctx();
+            assert_eq!(mem.direct_read_into(addr, &mut buf, len), Some(len));
+        });
+        buf
+    }
+
+    /// Write `data` to the device at `off`, by way of guest memory.
+    pub fn write(
+        &self,
+        dev: &TestDevice,
+        off: usize,
+        data: &[u8],
+    ) -> block::Result {
+        self.fill(READ_BASE, data);
+        let region = GuestRegion(READ_BASE, data.len());
+        self.submit(dev, |donef| {
+            block::Request::new_write(off, vec![region], donef)
+        })
+        .wait()
+    }
+
+    /// Read `len` bytes from the device at `off`, by way of guest memory.
+    pub fn read(
+        &self,
+        dev: &TestDevice,
+        off: usize,
+        len: usize,
+    ) -> (block::Result, Vec<u8>) {
+        self.fill(WRITE_BASE, &vec![0xa5; len]);
+        let region = GuestRegion(WRITE_BASE, len);
+        let res = self
+            .submit(dev, |donef| {
+                block::Request::new_read(off, vec![region], donef)
+            })
+            .wait();
+        (res, self.contents(WRITE_BASE, len))
+    }
+
+    /// Issue a request to the device, without waiting for it to complete.
+    pub fn submit(
+        &self,
+        dev: &TestDevice,
+        make_req: impl FnOnce(Box<block::CompleteFn>) -> block::Request,
+    ) -> Pending {
+        let mut pending = None;
+        self.with_ctx(|ctx| pending = Some(dev.submit(make_req, ctx)));
+        pending.unwrap()
+    }
+}
+
+/// Completion of a request issued through a [`TestDevice`].
+pub(crate) struct Pending(mpsc::Receiver<block::Result>);
+impl Pending {
+    pub fn wait(self) -> block::Result {
+        self.0.recv_timeout(TIMEOUT).expect("request did not complete")
+    }
+
+    /// The result of the request, if it has already completed.
+    pub fn try_wait(&self) -> Option<block::Result> {
+        self.0.try_recv().ok()
+    }
+}
+
+/// Block device issuing requests on behalf of a test.
+pub(crate) struct TestDevice {
+    reqs: Mutex<VecDeque<block::Request>>,
+    notifier: block::Notifier,
+    /// Media most recently presented via [`block::Device::set_media`]
+    pub media: Mutex<Option<block::DeviceInfo>>,
+}
+impl TestDevice {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            reqs: Mutex::new(VecDeque::new()),
+            notifier: block::Notifier::new(),
+            media: Mutex::new(None),
+        })
+    }
+
+    fn submit(
+        &self,
+        make_req: impl FnOnce(Box<block::CompleteFn>) -> block::Request,
+        ctx: &DispCtx,
+    ) -> Pending {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let tx = Mutex::new(tx);
+        let req = make_req(Box::new(move |_op, res, _ctx| {
+            let _ = tx.lock().unwrap().send(res);
+        }));
+        self.reqs.lock().unwrap().push_back(req);
+        self.notifier.notify(self, ctx);
+        Pending(rx)
+    }
+}
+impl block::Device for TestDevice {
+    fn next(&self, _ctx: &DispCtx) -> Option<block::Request> {
+        self.notifier.next_arming(|| self.reqs.lock().unwrap().pop_front())
+    }
+
+    fn set_notifier(&self, f: Option<Box<block::NotifierFn>>) {
+        self.notifier.set(f)
+    }
+
+    fn set_media(
+        &self,
+        info: Option<block::DeviceInfo>,
+        _ctx: &DispCtx,
+    ) -> std::io::Result<()> {
+        *self.media.lock().unwrap() = info;
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+//! Token-bucket limits on the rate at which requests are issued to a backend.
+
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::util::token_bucket::TokenBucket;
+
+/// Limits on the I/O issued to a block backend.
+///
+/// Limits which are `None` are not enforced, while those of zero are invalid
+/// (rather than taken to mean "unlimited").  The burst allowances default to
+/// one second's worth of the corresponding sustained rate.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ThrottleLimits {
+    /// Sustained operations per second
+    pub iops: Option<u64>,
+    /// Operations which may be issued at once, after having been idle long
+    /// enough to accumulate them at the sustained rate
+    pub iops_burst: Option<u64>,
+    /// Sustained bytes (read or written) per second
+    pub bps: Option<u64>,
+    /// Bytes which may be transferred at once, after having been idle long
+    /// enough to accumulate them at the sustained rate
+    pub bps_burst: Option<u64>,
+}
+
+impl ThrottleLimits {
+    /// Are any limits to be enforced?
+    pub fn is_limited(&self) -> bool {
+        self.iops.is_some() || self.bps.is_some()
+    }
+
+    /// Check that none of the limits are zero, which would admit no I/O.
+    pub fn validate(&self) -> Result<()> {
+        let limits = [
+            ("iops", self.iops),
+            ("iops_burst", self.iops_burst),
+            ("bps", self.bps),
+            ("bps_burst", self.bps_burst),
+        ];
+        for (name, limit) in limits {
+            if limit == Some(0) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} limit must be non-zero", name),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+struct ThrottleState {
+    limits: ThrottleLimits,
+    iops: Option<TokenBucket>,
+    bps: Option<TokenBucket>,
+}
+
+impl ThrottleState {
+    fn new(limits: ThrottleLimits, now: Instant) -> Result<Self> {
+        limits.validate()?;
+        Ok(Self {
+            limits,
+            iops: limits
+                .iops
+                .map(|r| TokenBucket::new(r, limits.iops_burst, now))
+                .transpose()?,
+            bps: limits
+                .bps
+                .map(|r| TokenBucket::new(r, limits.bps_burst, now))
+                .transpose()?,
+        })
+    }
+}
+
+/// Rate limiter applied to requests before they are handed to a backend.
+pub struct Throttle {
+    state: Mutex<ThrottleState>,
+}
+
+impl Throttle {
+    /// Create a throttle enforcing `limits`.
+    ///
+    /// Invalid limits (see [`ThrottleLimits::validate`]) are rejected.
+    pub fn new(limits: ThrottleLimits) -> Result<Self> {
+        let state = ThrottleState::new(limits, Instant::now())?;
+        Ok(Self { state: Mutex::new(state) })
+    }
+
+    /// Create a throttle which enforces no limits.
+    pub fn unlimited() -> Self {
+        let state = ThrottleState {
+            limits: ThrottleLimits::default(),
+            iops: None,
+            bps: None,
+        };
+        Self { state: Mutex::new(state) }
+    }
+
+    /// Currently configured limits.
+    pub fn limits(&self) -> ThrottleLimits {
+        self.state.lock().unwrap().limits
+    }
+
+    /// Replace the configured limits, starting with full buckets.
+    ///
+    /// Invalid limits are rejected, leaving those in place unchanged.
+    pub fn set_limits(&self, limits: ThrottleLimits) -> Result<()> {
+        let state = ThrottleState::new(limits, Instant::now())?;
+        *self.state.lock().unwrap() = state;
+        Ok(())
+    }
+
+    /// Attempt to admit a request transferring `bytes`.
+    ///
+    /// Returns `None` if the request was admitted (consuming tokens), or the
+    /// time to wait before trying again.
+    fn try_admit(&self, bytes: usize, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let mut delay = Duration::ZERO;
+        if let Some(b) = state.iops.as_mut() {
+            b.refill(now);
+            delay = delay.max(b.delay(1.0));
+        }
+        if let Some(b) = state.bps.as_mut() {
+            b.refill(now);
+            delay = delay.max(b.delay(bytes as f64));
+        }
+        if delay > Duration::ZERO {
+            return Some(delay);
+        }
+
+        if let Some(b) = state.iops.as_mut() {
+            b.take(1.0);
+        }
+        if let Some(b) = state.bps.as_mut() {
+            b.take(bytes as f64);
+        }
+        None
+    }
+
+    /// Wait until a request transferring `bytes` may be issued.
+    pub async fn admit(&self, bytes: usize) {
+        while let Some(delay) = self.try_admit(bytes, Instant::now()) {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits(iops: Option<u64>, bps: Option<u64>) -> ThrottleLimits {
+        ThrottleLimits { iops, bps, ..Default::default() }
+    }
+
+    #[test]
+    fn unlimited_always_admits() {
+        let t = Throttle::unlimited();
+        let now = Instant::now();
+        for _ in 0..1000 {
+            assert_eq!(t.try_admit(1 << 20, now), None);
+        }
+    }
+
+    #[test]
+    fn iops_burst_then_throttle() {
+        let t = Throttle::new(ThrottleLimits {
+            iops: Some(10),
+            iops_burst: Some(20),
+            ..Default::default()
+        })
+        .unwrap();
+        let now = Instant::now();
+        for _ in 0..20 {
+            assert_eq!(t.try_admit(0, now), None);
+        }
+        let delay = t.try_admit(0, now).expect("should be throttled");
+        assert!(delay <= Duration::from_millis(101));
+
+        // One token accrues after 100ms at 10 IOPS
+        let later = now + Duration::from_millis(100);
+        assert_eq!(t.try_admit(0, later), None);
+        assert!(t.try_admit(0, later).is_some());
+    }
+
+    #[test]
+    fn bps_large_request_waits_for_full_bucket() {
+        let t = Throttle::new(limits(None, Some(1000))).unwrap();
+        let now = Instant::now();
+        // Larger than the (default) burst, but admitted from a full bucket
+        assert_eq!(t.try_admit(4000, now), None);
+
+        // The debt must be repaid before the next request is admitted
+        let delay = t.try_admit(1, now).expect("should be throttled");
+        assert!(delay >= Duration::from_secs(3));
+        assert_eq!(t.try_admit(1, now + Duration::from_secs(4)), None);
+    }
+
+    #[test]
+    fn set_limits_replaces_buckets() {
+        let t = Throttle::new(limits(Some(1), None)).unwrap();
+        let now = Instant::now();
+        assert_eq!(t.try_admit(0, now), None);
+        assert!(t.try_admit(0, now).is_some());
+
+        t.set_limits(ThrottleLimits::default()).unwrap();
+        assert_eq!(t.limits(), ThrottleLimits::default());
+        assert_eq!(t.try_admit(0, now), None);
+    }
+
+    #[test]
+    fn burst_is_bucket_capacity() {
+        // The burst is all that may be issued at once, not an allowance on
+        // top of the sustained rate
+        let t = Throttle::new(ThrottleLimits {
+            bps: Some(1000),
+            bps_burst: Some(500),
+            ..Default::default()
+        })
+        .unwrap();
+        let now = Instant::now();
+        assert_eq!(t.try_admit(500, now), None);
+        assert!(t.try_admit(1, now).is_some());
+
+        // Nor do tokens accumulate beyond it while idle
+        let later = now + Duration::from_secs(10);
+        assert_eq!(t.try_admit(500, later), None);
+        assert!(t.try_admit(1, later).is_some());
+    }
+
+    #[test]
+    fn zero_limits_rejected() {
+        let t = Throttle::new(limits(Some(10), None)).unwrap();
+        for zeroed in [
+            limits(Some(0), None),
+            limits(None, Some(0)),
+            ThrottleLimits {
+                iops: Some(1),
+                iops_burst: Some(0),
+                ..limits(None, None)
+            },
+            ThrottleLimits {
+                bps: Some(1),
+                bps_burst: Some(0),
+                ..limits(None, None)
+            },
+        ] {
+            assert!(zeroed.validate().is_err());
+            let err = Throttle::new(zeroed).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            let err = t.set_limits(zeroed).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            assert_eq!(t.limits(), limits(Some(10), None));
+        }
+    }
+}
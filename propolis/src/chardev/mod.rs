@@ -18,9 +18,15 @@ pub type BlockingSourceConsumer =
     Box<dyn Fn(&[u8], &DispCtx) + Send + Sync + 'static>;
 
 pub trait Sink: Send + Sync + 'static {
-    // XXX: make this slice based
     fn write(&self, data: u8, ctx: &DispCtx) -> bool;
 
+    /// Write as much of `data` as the sink will accept, returning the number
+    /// of bytes written.  Sinks which can take data in bulk (rather than a
+    /// byte at a time) should override this.
+    fn write_bytes(&self, data: &[u8], ctx: &DispCtx) -> usize {
+        data.iter().take_while(|c| self.write(**c, ctx)).count()
+    }
+
     /// Set notifier callback for when sink becomes writable.  If that callback acquires any
     /// exclusion resources (locks, etc), they must not be held setting the notifier.
     fn set_notifier(&self, f: Option<SinkNotifier>);
@@ -247,14 +247,8 @@ impl SinkBuffer {
                 // If the buffer started empty, try to kick the sink into
                 // accepting data.
                 if inner.buf.is_empty() {
-                    while !data.is_empty() {
-                        if sink.write(data[0], &ctx) {
-                            data = &data[1..];
-                            nwritten += 1;
-                        } else {
-                            break;
-                        }
-                    }
+                    nwritten = sink.write_bytes(data, &ctx);
+                    data = &data[nwritten..];
                 }
 
                 // Push whatever is left into the buffer
@@ -281,11 +275,12 @@ impl SinkBuffer {
 
     fn notify(&self, sink: &dyn Sink, ctx: &DispCtx) {
         let mut inner = self.inner.lock().unwrap();
-        while let Some(c) = inner.buf.pop_front() {
-            if !sink.write(c, ctx) {
-                inner.buf.push_front(c);
+        while !inner.buf.is_empty() {
+            let nwritten = sink.write_bytes(inner.buf.as_slices().0, ctx);
+            if nwritten == 0 {
                 break;
             }
+            inner.buf.drain(..nwritten);
         }
         if inner.buf.is_empty() || !inner.wait_empty {
             self.notify.notify_one();
@@ -2,11 +2,11 @@ use std::cmp::min;
 use std::mem::size_of;
 
 use super::bits::{self, *};
-use super::queue::{QueueId, ADMIN_QUEUE_ID};
-use crate::common::GuestAddr;
+use super::queue::{DoorbellBuffers, QueueId, ADMIN_QUEUE_ID};
+use crate::common::{GuestAddr, GuestRegion, PAGE_OFFSET};
 use crate::{common::PAGE_SIZE, dispatch::DispCtx};
 
-use super::{cmds, NvmeCtrl, NvmeError, MAX_NUM_IO_QUEUES};
+use super::{cmds, NvmeCtrl, NvmeError, NvmeVersion, MAX_NUM_IO_QUEUES};
 
 impl NvmeCtrl {
     /// Service Create I/O Completion Queue command.
@@ -87,7 +87,10 @@ impl NvmeCtrl {
             cmd.qsize,
             ctx,
         ) {
-            Ok(_) => cmds::Completion::success(),
+            Ok(_) => {
+                self.arbiter.add_sq(cmd.qid, cmd.queue_prio);
+                cmds::Completion::success()
+            }
             Err(NvmeError::InvalidCompQueue(_)) => {
                 cmds::Completion::specific_err(
                     StatusCodeType::CmdSpecific,
@@ -183,18 +186,45 @@ impl NvmeCtrl {
     ///
     /// See NVMe 1.0e Section 5.10 Get Log Page command
     pub(super) fn acmd_get_log_page(
-        &self,
+        &mut self,
         cmd: &cmds::GetLogPageCmd,
         ctx: &DispCtx,
     ) -> cmds::Completion {
-        assert!((cmd.len as usize) < PAGE_SIZE);
-        let buf = cmd
-            .data(ctx.mctx.memctx())
-            .next()
-            .expect("missing prp entry for log page response");
-        // TODO: actually keep a log that we can write back instead of all zeros
-        assert!(ctx.mctx.memctx().write_byte(buf.0, 0, cmd.len as usize));
-        cmds::Completion::success()
+        // The SMART / Health log is only kept for the controller as a whole
+        // (LPA bit 0 is clear) so there is nothing namespace-specific in
+        // either page.
+        let (lid, comp) = match cmd.log_page_ident {
+            cmds::LogPageIdent::Error => {
+                (LOG_ID_ERROR, write_log_page(cmd, &self.logs.error_log(), ctx))
+            }
+            cmds::LogPageIdent::Smart => {
+                (LOG_ID_SMART, write_log_page(cmd, &self.logs.smart_log(), ctx))
+            }
+            cmds::LogPageIdent::ChangedNsList => {
+                // NSIDs in increasing order and zero-padded to fill the page
+                let mut list = [0u32; PAGE_SIZE / size_of::<u32>()];
+                for (entry, nsid) in
+                    list.iter_mut().zip(self.events.changed_ns())
+                {
+                    *entry = nsid;
+                }
+                (LOG_ID_CHANGED_NS_LIST, write_log_page(cmd, &list, ctx))
+            }
+            cmds::LogPageIdent::Firmware
+            | cmds::LogPageIdent::Reserved
+            | cmds::LogPageIdent::IOSpecifc(_)
+            | cmds::LogPageIdent::Vendor(_) => {
+                // Other log pages have nothing to report
+                return write_log_page(cmd, &(), ctx);
+            }
+        };
+
+        // Reading the log page acknowledges any associated events, unless
+        // the host asked for them to be retained.
+        if comp.is_success() && !cmd.rae {
+            self.events.log_read(lid);
+        }
+        comp
     }
 
     /// Service Identify command.
@@ -206,23 +236,43 @@ impl NvmeCtrl {
         ctx: &DispCtx,
     ) -> cmds::Completion {
         match cmd.cns {
-            IDENT_CNS_NAMESPACE => match cmd.nsid {
-                1 => {
+            // 0 is not a valid NSID (See NVMe 1.0e, Section 6.1 Namespaces)
+            // We also don't currently support namespace management
+            // and so treat the 'broadcast' NSID (0xffffffff) as invalid
+            // along with any other unattached namespace
+            IDENT_CNS_NAMESPACE => match self.ns(cmd.nsid) {
+                Some(ns) => {
                     assert!(size_of::<bits::IdentifyNamespace>() <= PAGE_SIZE);
                     let buf = cmd
                         .data(ctx.mctx.memctx())
                         .next()
                         .expect("missing prp entry for ident response");
-                    assert!(ctx.mctx.memctx().write(buf.0, &self.ns_ident));
+                    assert!(ctx.mctx.memctx().write(buf.0, &ns.ident));
                     cmds::Completion::success()
                 }
-                // 0 is not a valid NSID (See NVMe 1.0e, Section 6.1 Namespaces)
-                // We also don't currently support namespace management
-                // and so treat the 'broadcast' NSID (0xffffffff) as invalid
-                // along with any other namespace
-                0 | 0xffffffff => cmds::Completion::generic_err(STS_INVALID_NS),
-                _ => cmds::Completion::generic_err(STS_INVALID_NS),
+                None => cmds::Completion::generic_err(STS_INVALID_NS),
             },
+            IDENT_CNS_ACTIVE_NS_LIST => {
+                // The 0xfffffffe and 0xffffffff NSIDs are invalid here
+                if cmd.nsid >= 0xfffffffe {
+                    return cmds::Completion::generic_err(STS_INVALID_NS);
+                }
+
+                // Active NSIDs greater than that specified, in increasing
+                // order and zero-padded to fill the page
+                let mut list = [0u32; PAGE_SIZE / size_of::<u32>()];
+                let nsids = (1..=self.namespaces.len() as u32)
+                    .filter(|nsid| *nsid > cmd.nsid);
+                for (entry, nsid) in list.iter_mut().zip(nsids) {
+                    *entry = nsid;
+                }
+                let buf = cmd
+                    .data(ctx.mctx.memctx())
+                    .next()
+                    .expect("missing prp entry for ident response");
+                assert!(ctx.mctx.memctx().write(buf.0, &list));
+                cmds::Completion::success()
+            }
             IDENT_CNS_CONTROLLER => {
                 assert!(size_of::<bits::IdentifyController>() <= PAGE_SIZE);
                 let buf = cmd
@@ -232,22 +282,53 @@ impl NvmeCtrl {
                 assert!(ctx.mctx.memctx().write(buf.0, &self.ctrl_ident));
                 cmds::Completion::success()
             }
-            // We currently present NVMe version 1.0 in which CNS is a 1-bit field
-            // and hence only need to support the NAMESPACE and CONTROLLER cases
+            IDENT_CNS_NS_DESC_LIST if self.version >= NvmeVersion::V1_3 => {
+                let ns = match self.ns(cmd.nsid) {
+                    Some(ns) => ns,
+                    None => {
+                        return cmds::Completion::generic_err(STS_INVALID_NS)
+                    }
+                };
+
+                // The descriptors, zero-padded to fill the page
+                let descs = ns.id_descs();
+                let mut list = [0u8; PAGE_SIZE];
+                list[..descs.len()].copy_from_slice(&descs);
+                let buf = cmd
+                    .data(ctx.mctx.memctx())
+                    .next()
+                    .expect("missing prp entry for ident response");
+                assert!(ctx.mctx.memctx().write(buf.0, &list));
+                cmds::Completion::success()
+            }
+            // In NVMe 1.0, CNS is a 1-bit field, but we also support the
+            // Active Namespace ID list from 1.1 for the benefit of hosts which
+            // look for it.  The remaining values introduced since are for
+            // namespace management, which we don't support.
             _ => cmds::Completion::generic_err(bits::STS_INVAL_FIELD),
         }
     }
 
     /// Service Set Features command.
     ///
-    /// See NVMe 1.0e Section 5.12 Set Features command
+    /// See NVMe 1.3 Section 5.21 Set Features command
     pub(super) fn acmd_set_features(
-        &self,
+        &mut self,
         cmd: &cmds::SetFeaturesCmd,
         _ctx: &DispCtx,
     ) -> cmds::Completion {
+        // The Save field is reserved unless advertised through ONCS
+        let save = cmd.save && self.version >= NvmeVersion::V1_3;
         match cmd.fid {
             cmds::FeatureIdent::NumberOfQueues { ncqr, nsqr } => {
+                // The number of queues is only ever allocated for the life of
+                // the controller
+                if save {
+                    return cmds::Completion::specific_err(
+                        StatusCodeType::CmdSpecific,
+                        STS_FEAT_NOT_SAVEABLE,
+                    );
+                }
                 if ncqr == 0 || nsqr == 0 {
                     return cmds::Completion::generic_err(STS_INVAL_FIELD);
                 }
@@ -258,23 +339,133 @@ impl NvmeCtrl {
                 let nsqa = min(nsqr as u32, MAX_NUM_IO_QUEUES as u32);
 
                 // `ncqa`/`nsqa` are 0-based values so subtract 1
-                cmds::Completion::success_val((ncqa - 1) << 16 | (nsqa - 1))
+                let num_queues = (ncqa - 1) << 16 | (nsqa - 1);
+                self.num_queues = Some(num_queues);
+                cmds::Completion::success_val(num_queues)
             }
-            cmds::FeatureIdent::Reserved
-            | cmds::FeatureIdent::Arbitration
-            | cmds::FeatureIdent::PowerManagement
-            | cmds::FeatureIdent::LbaRangeType
-            | cmds::FeatureIdent::TemperatureThreshold
-            | cmds::FeatureIdent::ErrorRecovery
-            | cmds::FeatureIdent::VolatileWriteCache
-            | cmds::FeatureIdent::InterruptCoalescing
-            | cmds::FeatureIdent::InterruptVectorConfiguration
-            | cmds::FeatureIdent::WriteAtomicity
-            | cmds::FeatureIdent::AsynchronousEventConfiguration
-            | cmds::FeatureIdent::SoftwareProgressMarker
-            | cmds::FeatureIdent::Vendor(_) => {
-                cmds::Completion::generic_err(STS_INVAL_FIELD)
+            _ => self.features.set(&cmd.fid, cmd.value, save),
+        }
+    }
+
+    /// Service Get Features command.
+    ///
+    /// See NVMe 1.3 Section 5.9 Get Features command
+    pub(super) fn acmd_get_features(
+        &self,
+        cmd: &cmds::GetFeaturesCmd,
+        _ctx: &DispCtx,
+    ) -> cmds::Completion {
+        // Controllers predating NVMe 1.1, which introduced the Select field,
+        // report no capabilities for any feature.
+        if self.version == NvmeVersion::V1_0 && cmd.sel == FEAT_SEL_SUPPORTED {
+            return cmds::Completion::success_val(0);
+        }
+        // The Select field is reserved unless advertised through ONCS, in
+        // which case the current value is the one returned
+        let sel = if self.version >= NvmeVersion::V1_3 {
+            cmd.sel
+        } else {
+            FEAT_SEL_CURRENT
+        };
+        match cmd.fid {
+            cmds::FeatureIdent::NumberOfQueues { .. } => {
+                // Until the host requests some number of queues, report the
+                // max we could allocate (0-based)
+                let max = MAX_NUM_IO_QUEUES as u32 - 1;
+                match sel {
+                    FEAT_SEL_CURRENT => cmds::Completion::success_val(
+                        self.num_queues.unwrap_or(max << 16 | max),
+                    ),
+                    FEAT_SEL_DEFAULT | FEAT_SEL_SAVED => {
+                        cmds::Completion::success_val(max << 16 | max)
+                    }
+                    // Changeable, but not saveable
+                    FEAT_SEL_SUPPORTED => {
+                        cmds::Completion::success_val(FEAT_CAP_CHANGEABLE)
+                    }
+                    _ => cmds::Completion::generic_err(STS_INVAL_FIELD),
+                }
+            }
+            _ => self.features.get(&cmd.fid, cmd.params, sel),
+        }
+    }
+
+    /// Service Doorbell Buffer Config command.
+    ///
+    /// See NVMe 1.3 Section 5.7 Doorbell Buffer Config command
+    pub(super) fn acmd_doorbell_buf_cfg(
+        &mut self,
+        cmd: &cmds::DoorbellBufCfgCmd,
+        ctx: &DispCtx,
+    ) -> cmds::Completion {
+        // Only advertised (through OACS) from NVMe 1.3 onwards
+        if self.version < NvmeVersion::V1_3 {
+            return cmds::Completion::generic_err(STS_INVAL_OPC);
+        }
+
+        // Each buffer must be a page-aligned page of guest memory we can
+        // both read and write
+        let memctx = ctx.mctx.memctx();
+        for base in [cmd.shadow_doorbells, cmd.event_idxs] {
+            let region = GuestRegion(GuestAddr(base), PAGE_SIZE);
+            if (base & PAGE_OFFSET as u64) != 0
+                || memctx.readable_region(&region).is_none()
+                || memctx.writable_region(&region).is_none()
+            {
+                return cmds::Completion::generic_err(STS_INVAL_FIELD);
+            }
+        }
+
+        let bufs = DoorbellBuffers {
+            shadow: GuestAddr(cmd.shadow_doorbells),
+            event_idx: GuestAddr(cmd.event_idxs),
+        };
+        // Any I/O queues which already exist switch over right away, while
+        // those created later will pick up the buffers then
+        for (qid, cq) in self.cqs.iter().enumerate() {
+            if let (Some(cq), Some(shadow)) = (cq, bufs.cq(qid as QueueId)) {
+                cq.set_shadow(shadow, ctx);
+            }
+        }
+        for (qid, sq) in self.sqs.iter().enumerate() {
+            if let (Some(sq), Some(shadow)) = (sq, bufs.sq(qid as QueueId)) {
+                sq.set_shadow(shadow, ctx);
             }
         }
+        self.doorbell_bufs = Some(bufs);
+
+        cmds::Completion::success()
+    }
+}
+
+/// Write out (up to the requested length of) a log page to the data buffer
+/// of a Get Log Page command, zero-filling any remainder.
+fn write_log_page<T: Copy>(
+    cmd: &cmds::GetLogPageCmd,
+    page: &T,
+    ctx: &DispCtx,
+) -> cmds::Completion {
+    let mem = ctx.mctx.memctx();
+    // Safety: The log page structures are plain-old-data, so can be viewed as
+    // the bytes to be written out.
+    let raw = unsafe {
+        std::slice::from_raw_parts(
+            page as *const T as *const u8,
+            size_of::<T>(),
+        )
+    };
+
+    let mut done = 0;
+    for region in cmd.data(ctx.mctx.memctx()) {
+        let len = min(raw.len().saturating_sub(done), region.1);
+        if len > 0 && mem.write_from(region.0, &raw[done..], len) != Some(len) {
+            return cmds::Completion::generic_err(STS_DATA_XFER_ERR);
+        }
+        if region.1 > len && !mem.write_byte(region.0 + len, 0, region.1 - len)
+        {
+            return cmds::Completion::generic_err(STS_DATA_XFER_ERR);
+        }
+        done += region.1;
     }
+    cmds::Completion::success()
 }
@@ -0,0 +1,228 @@
+//! Arbitration between the I/O Submission Queues of a controller, deciding
+//! which of them to take the next command from.
+//!
+//! See NVMe 1.0e Section 4.7 Command Arbitration
+
+use std::collections::BTreeMap;
+
+use super::bits::{ArbitrationMechanism, ArbitrationParams};
+use super::cmds::QueuePriority;
+use super::queue::QueueId;
+
+/// The number of classes Submission Queues are grouped into.
+///
+/// With Round Robin arbitration, every queue belongs to the same class.  With
+/// Weighted Round Robin with Urgent Priority Class, there is a class for each
+/// [`QueuePriority`].
+const NUM_CLASSES: usize = 4;
+
+/// Decides the order in which the I/O Submission Queues are serviced.
+///
+/// Within a class, queues take turns in order of their IDs, each servicing up
+/// to the Arbitration Burst of commands at a time.  Under Weighted Round
+/// Robin, the Urgent class has strict priority over the rest, which are
+/// serviced in proportion to their weights.
+#[derive(Default)]
+pub(super) struct Arbiter {
+    /// The priority with which each I/O Submission Queue was created
+    prios: BTreeMap<QueueId, QueuePriority>,
+
+    /// The queue most recently serviced in each class
+    last: [Option<QueueId>; NUM_CLASSES],
+
+    /// Commands which may yet be taken from the most recently serviced queue
+    /// in each class before moving on to the next
+    burst_left: [u32; NUM_CLASSES],
+
+    /// Commands which may yet be taken from each of the weighted classes
+    /// before all of their weights are replenished
+    credits: [u32; NUM_CLASSES],
+
+    /// Storage for the order returned by [`Arbiter::order`], kept across
+    /// calls so that arbitrating for each command doesn't allocate
+    order_buf: Vec<QueueId>,
+}
+
+impl Arbiter {
+    /// Add an I/O Submission Queue with the given priority.
+    pub fn add_sq(&mut self, sqid: QueueId, prio: QueuePriority) {
+        self.prios.insert(sqid, prio);
+    }
+
+    /// Remove a deleted I/O Submission Queue.
+    pub fn remove_sq(&mut self, sqid: QueueId) {
+        self.prios.remove(&sqid);
+    }
+
+    /// The priorities of all the I/O Submission Queues, by ID.
+    pub fn priorities(
+        &self,
+    ) -> impl Iterator<Item = (QueueId, QueuePriority)> + '_ {
+        self.prios.iter().map(|(sqid, prio)| (*sqid, *prio))
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    fn class(mech: ArbitrationMechanism, prio: QueuePriority) -> usize {
+        match mech {
+            ArbitrationMechanism::WeightedRoundRobinWithUrgent => prio as usize,
+            _ => 0,
+        }
+    }
+
+    /// The I/O Submission Queues, in the order they should be checked for a
+    /// command to service next.
+    pub fn order(&mut self, mech: ArbitrationMechanism) -> &[QueueId] {
+        let mut classes = [0, 1, 2, 3];
+        if mech == ArbitrationMechanism::WeightedRoundRobinWithUrgent {
+            // Urgent first, then the weighted classes with credits left ahead
+            // of those without.  Falling back on the latter keeps us from
+            // idling while there are commands to service.
+            classes[1..].sort_by_key(|class| self.credits[*class] == 0);
+        }
+
+        self.order_buf.clear();
+        for class in classes {
+            let base = self.order_buf.len();
+            self.order_buf.extend(
+                self.prios
+                    .iter()
+                    .filter(|(_, prio)| Self::class(mech, **prio) == class)
+                    .map(|(sqid, _)| *sqid),
+            );
+            let members = &mut self.order_buf[base..];
+
+            // Start from the queue in the midst of its burst, or else the one
+            // following it
+            let start = match self.last[class] {
+                Some(last) if self.burst_left[class] > 0 => {
+                    members.iter().position(|sqid| *sqid >= last)
+                }
+                Some(last) => members.iter().position(|sqid| *sqid > last),
+                None => None,
+            }
+            .unwrap_or(0);
+            members.rotate_left(start);
+        }
+        &self.order_buf
+    }
+
+    /// Account for a command taken from the I/O Submission Queue `sqid`.
+    pub fn serviced(
+        &mut self,
+        sqid: QueueId,
+        mech: ArbitrationMechanism,
+        params: ArbitrationParams,
+    ) {
+        let prio = match self.prios.get(&sqid) {
+            Some(prio) => *prio,
+            None => return,
+        };
+        let class = Self::class(mech, prio);
+
+        if self.last[class] != Some(sqid) || self.burst_left[class] == 0 {
+            // Start a new burst
+            self.last[class] = Some(sqid);
+            self.burst_left[class] = match params.ab() {
+                0b111 => u32::MAX,
+                ab => 1 << ab,
+            };
+        }
+        self.burst_left[class] -= 1;
+
+        if mech == ArbitrationMechanism::WeightedRoundRobinWithUrgent
+            && prio != QueuePriority::Urgent
+        {
+            if self.credits[class] == 0 {
+                // Every class with commands to service has used up its
+                // weight, so start a new round
+                self.credits[QueuePriority::High as usize] =
+                    params.hpw() as u32 + 1;
+                self.credits[QueuePriority::Medium as usize] =
+                    params.mpw() as u32 + 1;
+                self.credits[QueuePriority::Low as usize] =
+                    params.lpw() as u32 + 1;
+            }
+            self.credits[class] -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const WRR: ArbitrationMechanism =
+        ArbitrationMechanism::WeightedRoundRobinWithUrgent;
+    const RR: ArbitrationMechanism = ArbitrationMechanism::RoundRobin;
+
+    /// Pick the next queue to service out of those with commands available,
+    /// as the controller would.
+    fn pick(
+        arb: &mut Arbiter,
+        mech: ArbitrationMechanism,
+        params: ArbitrationParams,
+        busy: &[QueueId],
+    ) -> Option<QueueId> {
+        let sqid =
+            arb.order(mech).iter().copied().find(|id| busy.contains(id))?;
+        arb.serviced(sqid, mech, params);
+        Some(sqid)
+    }
+
+    #[test]
+    fn round_robin() {
+        let mut arb = Arbiter::default();
+        arb.add_sq(1, QueuePriority::Urgent);
+        arb.add_sq(2, QueuePriority::Low);
+        arb.add_sq(3, QueuePriority::High);
+
+        // Priorities are ignored, and each queue gets a single command (AB=0)
+        let params = ArbitrationParams(0);
+        let picks: Vec<_> = (0..6)
+            .map(|_| pick(&mut arb, RR, params, &[1, 2, 3]).unwrap())
+            .collect();
+        assert_eq!(picks, [1, 2, 3, 1, 2, 3]);
+
+        // Up to 4 commands at a time with AB=2
+        let params = ArbitrationParams(0).with_ab(2);
+        let picks: Vec<_> = (0..6)
+            .map(|_| pick(&mut arb, RR, params, &[2, 3]).unwrap())
+            .collect();
+        assert_eq!(picks, [2, 2, 2, 2, 3, 3]);
+
+        // Removed queues are skipped over
+        arb.remove_sq(3);
+        assert_eq!(arb.order(RR), [1, 2]);
+    }
+
+    #[test]
+    fn weighted_round_robin() {
+        let mut arb = Arbiter::default();
+        arb.add_sq(1, QueuePriority::Urgent);
+        arb.add_sq(2, QueuePriority::High);
+        arb.add_sq(3, QueuePriority::Medium);
+        arb.add_sq(4, QueuePriority::Low);
+
+        // High, Medium and Low weights of 3, 2 and 1 respectively
+        let params =
+            ArbitrationParams(0).with_hpw(2).with_mpw(1).with_lpw(0).with_ab(7);
+
+        // Urgent queues always go first
+        assert_eq!(pick(&mut arb, WRR, params, &[1, 2, 3, 4]), Some(1));
+
+        let mut counts = [0; 5];
+        for _ in 0..60 {
+            let sqid = pick(&mut arb, WRR, params, &[2, 3, 4]).unwrap();
+            counts[sqid as usize] += 1;
+        }
+        assert_eq!(counts, [0, 0, 30, 20, 10]);
+
+        // Classes without commands don't hold up the others
+        for _ in 0..10 {
+            assert_eq!(pick(&mut arb, WRR, params, &[4]), Some(4));
+        }
+    }
+}
@@ -177,7 +177,7 @@ bitstruct! {
         /// Arbitration Mechanism Supported (AMS)
         ///
         /// Whether or not the controller supports Weighted Round Robin with Urgent.
-        pub ams_roundrobin: bool = 17;
+        pub ams_wrr: bool = 17;
 
         /// Arbitration Mechanism Supported (AMS)
         ///
@@ -475,6 +475,20 @@ bitstruct! {
 /// See NVMe 1.0e Section 3.1.2 Offset 08h: VS - Version
 pub const NVME_VER_1_0: u32 = 0x00010000;
 
+/// Controller Version NVM Express 1.3
+///
+/// Bits 31:16  Major Version Number (MJR) = "1"
+/// Bits 15:08  Minor Version Number (MNR) = "3"
+/// Bits 07:00  Tertiary Version Number (TER) = "0"
+///
+/// See NVMe 1.3 Section 3.1.2 Offset 08h: VS - Version
+pub const NVME_VER_1_3: u32 = 0x00010300;
+
+/// Controller Version NVM Express 1.4
+///
+/// See NVMe 1.4 Section 3.1.2 Offset 08h: VS - Version
+pub const NVME_VER_1_4: u32 = 0x00010400;
+
 // Admin Command Opcodes
 // See NVMe 1.0e Section 5, Figure 25 Opcodes for Admin Commands
 
@@ -498,6 +512,10 @@ pub const ADMIN_OPC_SET_FEATURES: u8 = 0x09;
 pub const ADMIN_OPC_GET_FEATURES: u8 = 0x0A;
 /// Asynchronous Event Request Command Opcode
 pub const ADMIN_OPC_ASYNC_EVENT_REQ: u8 = 0x0c;
+/// Doorbell Buffer Config Command Opcode
+///
+/// See NVMe 1.3 Section 5.7 Doorbell Buffer Config command
+pub const ADMIN_OPC_DOORBELL_BUF_CFG: u8 = 0x7C;
 
 // NVM Command Opcodes
 // See NVMe 1.0e Section 6, Figure 99 Opcodes for NVM Commands
@@ -508,6 +526,10 @@ pub const NVM_OPC_FLUSH: u8 = 0x00;
 pub const NVM_OPC_WRITE: u8 = 0x01;
 /// Read Command Opcode
 pub const NVM_OPC_READ: u8 = 0x02;
+/// Write Zeroes Command Opcode
+pub const NVM_OPC_WRITE_ZEROES: u8 = 0x08;
+/// Dataset Management Command Opcode
+pub const NVM_OPC_DATASET_MGMT: u8 = 0x09;
 
 // Generic Command Status values
 // See NVMe 1.0e Section 4.5.1.2.1, Figure 17 Status Code - Generic Command Status Values
@@ -577,6 +599,16 @@ pub const STS_INVALID_NS: u8 = 0xB;
 /// The command was aborted due to a protocol violation in a multi-command sequence.
 pub const STS_COMMAND_SEQ_ERR: u8 = 0xC;
 
+/// LBA Out of Range
+///
+/// The command references an LBA that exceeds the size of the namespace.
+pub const STS_LBA_RANGE: u8 = 0x80;
+
+/// Namespace Not Ready
+///
+/// The namespace is not ready to be accessed.
+pub const STS_NS_NOT_READY: u8 = 0x82;
+
 // Command Specific Status values
 // See NVMe 1.0e Section 4.5.1.2.2, Figure 19 Status Code - Command Specific Status Values
 
@@ -598,6 +630,15 @@ pub const STS_DELETE_IO_Q_INVAL_QID: u8 = 0x1;
 /// Invalid Queue Deletion
 pub const STS_DELETE_IO_Q_INVAL_Q_DELETION: u8 = 0xC;
 
+/// Asynchronous Event Request Limit Exceeded
+pub const STS_ASYNC_EVENT_LIMIT_EXCEEDED: u8 = 0x5;
+
+/// Invalid Log Page
+pub const STS_INVALID_LOG_PAGE: u8 = 0x9;
+
+/// Feature Identifier Not Saveable
+pub const STS_FEAT_NOT_SAVEABLE: u8 = 0xD;
+
 // NVM Command Specific Status values
 // See NVMe 1.0e Section 4.5.1.2.2, Figure 20 Status Code - Command Specific Status Values, NVM Command Set
 
@@ -658,6 +699,183 @@ pub const FEAT_ID_WRITE_ATOMIC: u8 = 0x0A;
 /// See NVMe 1.0e Section 5.12.1.11 Asynchronous Event Configuration (Feature Identifier 0Bh)
 pub const FEAT_ID_ASYNC_EVENT_CFG: u8 = 0x0B;
 
+bitstruct! {
+    /// Representation of the Arbitration feature.
+    ///
+    /// See NVMe 1.0e Section 5.12.1.1 Arbitration (Feature Identifier 01h)
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct ArbitrationParams(pub u32) {
+        /// Arbitration Burst (AB)
+        ///
+        /// The maximum number of commands which may be taken from a
+        /// Submission Queue at a time, as a power of two (2^n).  A value of
+        /// 111b indicates no limit.
+        pub ab: u8 = 0..3;
+
+        /// Reserved
+        reserved: u8 = 3..8;
+
+        /// Low Priority Weight (LPW)
+        ///
+        /// The number of commands which may be taken from the Low priority
+        /// class per round. This is a 0's based value.
+        pub lpw: u8 = 8..16;
+
+        /// Medium Priority Weight (MPW)
+        ///
+        /// The number of commands which may be taken from the Medium priority
+        /// class per round. This is a 0's based value.
+        pub mpw: u8 = 16..24;
+
+        /// High Priority Weight (HPW)
+        ///
+        /// The number of commands which may be taken from the High priority
+        /// class per round. This is a 0's based value.
+        pub hpw: u8 = 24..32;
+    }
+}
+
+bitstruct! {
+    /// Representation of the Asynchronous Event Configuration feature.
+    ///
+    /// See NVMe 1.3 Section 5.21.1.11, Figure 128 Asynchronous Event Configuration - Command Dword 11
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct AsyncEventConfig(pub u32) {
+        /// SMART / Health Critical Warnings
+        ///
+        /// Each bit enables events for the corresponding bit of the Critical
+        /// Warning field in the SMART / Health Information log.
+        pub crit_warn: u8 = 0..8;
+
+        /// Namespace Attribute Notices
+        ///
+        /// Whether the Namespace Attribute Changed event is sent to the host.
+        pub ns_attr_notices: bool = 8;
+
+        /// Firmware Activation Notices
+        pub fw_activation_notices: bool = 9;
+
+        /// Reserved
+        reserved: u32 = 10..32;
+    }
+}
+
+// Get Features Select values
+// See NVMe 1.3 Section 5.15, Figure 108 Get Features - Command Dword 10
+
+/// Select - Current value of the feature
+pub const FEAT_SEL_CURRENT: u8 = 0b00;
+
+/// Select - Default value of the feature
+pub const FEAT_SEL_DEFAULT: u8 = 0b01;
+
+/// Select - Saved value of the feature
+pub const FEAT_SEL_SAVED: u8 = 0b10;
+
+/// Select - Supported capabilities of the feature
+pub const FEAT_SEL_SUPPORTED: u8 = 0b11;
+
+// Get Features - Supported Capabilities
+// See NVMe 1.3 Section 5.15.2, Figure 110 Get Features - Supported Capabilities
+
+/// The feature is saveable
+pub const FEAT_CAP_SAVEABLE: u32 = 1 << 0;
+
+/// The feature is namespace specific
+pub const FEAT_CAP_NS_SPECIFIC: u32 = 1 << 1;
+
+/// The feature is changeable
+pub const FEAT_CAP_CHANGEABLE: u32 = 1 << 2;
+
+/// OACS - Doorbell Buffer Config command supported
+pub const OACS_DOORBELL_BUF_CFG: u16 = 1 << 8;
+
+/// ONCS - Dataset Management command supported
+pub const ONCS_DATASET_MGMT: u16 = 1 << 2;
+
+/// ONCS - Write Zeroes command supported
+pub const ONCS_WRITE_ZEROES: u16 = 1 << 3;
+
+/// ONCS - Save field of Set Features and Select field of Get Features supported
+pub const ONCS_FEAT_SAVE_SELECT: u16 = 1 << 4;
+
+/// Dataset Management - Attribute - Deallocate (AD)
+///
+/// See NVMe 1.3 Section 6.7, Figure 270 Dataset Management - Command Dword 11
+pub const DSM_ATTR_DEALLOCATE: u32 = 1 << 2;
+
+/// The max number of ranges in a single Dataset Management command
+pub const DSM_MAX_RANGES: usize = 256;
+
+// Asynchronous Event Information
+// See NVMe 1.3 Section 5.2, Figure 45 Asynchronous Event Request - Completion Queue Entry Dword 0
+
+/// Asynchronous Event Type - Error Status
+pub const ASYNC_EVENT_TYPE_ERROR: u8 = 0x0;
+
+/// Asynchronous Event Type - SMART / Health Status
+pub const ASYNC_EVENT_TYPE_SMART: u8 = 0x1;
+
+/// Asynchronous Event Type - Notice
+pub const ASYNC_EVENT_TYPE_NOTICE: u8 = 0x2;
+
+/// Asynchronous Event Information - Write to Invalid Doorbell Register (Error)
+///
+/// See NVMe 1.3 Section 5.2.1, Figure 46 Asynchronous Event Information - Error Status
+pub const ASYNC_EVENT_INFO_INVALID_DOORBELL: u8 = 0x0;
+
+/// Asynchronous Event Information - Invalid Doorbell Write Value (Error)
+pub const ASYNC_EVENT_INFO_INVALID_DOORBELL_VALUE: u8 = 0x1;
+
+/// Asynchronous Event Information - Diagnostic Failure (Error)
+pub const ASYNC_EVENT_INFO_DIAGNOSTIC_FAILURE: u8 = 0x2;
+
+/// Asynchronous Event Information - Persistent Internal Error (Error)
+pub const ASYNC_EVENT_INFO_PERSISTENT_INTERNAL_ERR: u8 = 0x3;
+
+/// Asynchronous Event Information - Transient Internal Error (Error)
+pub const ASYNC_EVENT_INFO_TRANSIENT_INTERNAL_ERR: u8 = 0x4;
+
+/// Asynchronous Event Information - NVM Subsystem Reliability (SMART / Health)
+///
+/// See NVMe 1.3 Section 5.2.1, Figure 47 Asynchronous Event Information - SMART / Health Status
+pub const ASYNC_EVENT_INFO_RELIABILITY: u8 = 0x0;
+
+/// Asynchronous Event Information - Temperature Threshold (SMART / Health)
+pub const ASYNC_EVENT_INFO_TEMP_THRESHOLD: u8 = 0x1;
+
+/// Asynchronous Event Information - Spare Below Threshold (SMART / Health)
+pub const ASYNC_EVENT_INFO_SPARE_BELOW_THRESHOLD: u8 = 0x2;
+
+/// Asynchronous Event Information - Namespace Attribute Changed (Notice)
+///
+/// See NVMe 1.3 Section 5.2.1, Figure 49 Asynchronous Event Information - Notice
+pub const ASYNC_EVENT_INFO_NS_ATTR_CHANGED: u8 = 0x0;
+
+// SMART / Health Critical Warning bits
+// See NVMe 1.0e Section 5.10.1.2, Figure 60 Get Log Page - SMART / Health Information Log
+
+/// Critical Warning - Available spare space has fallen below the threshold
+pub const CRIT_WARN_SPARE: u8 = 1 << 0;
+
+/// Critical Warning - Temperature has exceeded a critical threshold
+pub const CRIT_WARN_TEMPERATURE: u8 = 1 << 1;
+
+/// Critical Warning - Device reliability has been degraded
+pub const CRIT_WARN_RELIABILITY: u8 = 1 << 2;
+
+// Log Page Identifiers
+// See NVMe 1.0e Section 5.10.1, Figure 58 Get Log Page - Log Page Identifiers
+
+/// Log Page Identifier - Error Information
+pub const LOG_ID_ERROR: u8 = 0x01;
+
+/// Log Page Identifier - SMART / Health Information
+pub const LOG_ID_SMART: u8 = 0x02;
+
+/// Log Page Identifier - Changed Namespace List
+pub const LOG_ID_CHANGED_NS_LIST: u8 = 0x04;
+
 // Identify CNS values
 
 /// Identify - Namespace Structure
@@ -672,6 +890,38 @@ pub const IDENT_CNS_NAMESPACE: u8 = 0x0;
 /// See NVMe 1.0e Section 5.11
 pub const IDENT_CNS_CONTROLLER: u8 = 0x1;
 
+/// Identify - Active Namespace ID list
+///
+/// Return the list of (up to 1024) active NSIDs greater than that specified in
+/// response to Identify command.
+/// See NVMe 1.1 Section 5.11
+pub const IDENT_CNS_ACTIVE_NS_LIST: u8 = 0x2;
+
+/// Identify - Namespace Identification Descriptor list
+///
+/// Return the list of identifiers for the specified namespace in response to
+/// Identify command.
+/// See NVMe 1.3 Section 5.15.2, Figure 116 Identify - Namespace Identification
+/// Descriptor
+pub const IDENT_CNS_NS_DESC_LIST: u8 = 0x3;
+
+// Namespace Identifier Types (NIDT)
+// See NVMe 1.3 Section 5.15.2, Figure 116 Identify - Namespace Identification
+// Descriptor
+
+/// Namespace Identifier Type - IEEE Extended Unique Identifier (EUI-64)
+pub const NIDT_EUI64: u8 = 0x1;
+/// Namespace Identifier Type - Namespace Globally Unique Identifier (NGUID)
+pub const NIDT_NGUID: u8 = 0x2;
+/// Namespace Identifier Type - Namespace UUID
+pub const NIDT_UUID: u8 = 0x3;
+
+/// Optional Asynchronous Events Supported (OAES) - Namespace Attribute Notices
+///
+/// See NVMe 1.3 Section 5.15.2, Figure 109 Identify - Identify Controller
+/// Data Structure
+pub const OAES_NS_ATTR: u32 = 1 << 8;
+
 /// The type of value specified in the Status Field (SF) of a command completion.
 ///
 /// See NVMe 1.0e Section 4.5.1.1 Status Code Type (SCT)
@@ -813,13 +1063,40 @@ pub struct IdentifyController {
     /// reported as a power of two (2^n). A value of 0h indicates no restrictions on
     /// transfer size. The restrictions includes interleaved metadata.
     pub mdts: u8,
-    /// Reserved - Bytes 255:78
-    pub _resv1: [u8; 178],
+    /// Controller ID (CNTLID) (NVMe 1.1)
+    ///
+    /// The NVM subsystem unique controller identifier.
+    pub cntlid: u16,
+    /// Version (VER) (NVMe 1.2)
+    ///
+    /// Same value reported in the VS register, or 0 for earlier versions.
+    pub ver: u32,
+    /// RTD3 Resume Latency (RTD3R) (NVMe 1.2)
+    ///
+    /// Expected latency in microseconds to resume from Runtime D3.
+    pub rtd3r: u32,
+    /// RTD3 Entry Latency (RTD3E) (NVMe 1.2)
+    ///
+    /// Typical latency in microseconds to enter Runtime D3.
+    pub rtd3e: u32,
+    /// Optional Asynchronous Events Supported (OAES) (NVMe 1.2)
+    ///
+    /// Bit 9 indicates support for Firmware Activation Notices.
+    /// Bit 8 indicates support for Namespace Attribute Notices (and the
+    /// Changed Namespace List log page).
+    pub oaes: u32,
+    /// Controller Attributes (CTRATT) (NVMe 1.3)
+    ///
+    /// Bit 0 indicates support for a 128-bit Host Identifier.
+    pub ctratt: u32,
+    /// Reserved - Bytes 255:100
+    pub _resv1: [u8; 156],
 
     // bytes 256-511 - Admin Command Set Attributes & Optional Controller Capabilities
     /// Optional Admin Command Support (OACS)
     ///
-    /// Bits 15:3 are reserved.
+    /// Bits 15:9 and 7:3 are reserved.
+    /// Bit 8 indicates Doorbell Buffer Config command support.
     /// Bit 2 indicates Firmware Activate & Download command support.
     /// Bit 1 indicates Format NVM command support.
     /// Bit 0 indicates Security Send/Receive command support.
@@ -899,7 +1176,10 @@ pub struct IdentifyController {
     pub nn: u32,
     /// Option NVM Command Support (ONCS)
     ///
-    /// Bits 15:3 are reserved.
+    /// Bits 15:5 are reserved.
+    /// Bit 4 indicates support for the Save field of Set Features and the
+    /// Select field of Get Features (NVMe 1.1).
+    /// Bit 3 indicates Write Zeroes command support (NVMe 1.1).
     /// Bit 2 indicates Dataset Management command support.
     /// Bit 1 indicates Write Uncorrectable command support.
     /// Bit 0 indicates Compare command support.
@@ -938,10 +1218,15 @@ pub struct IdentifyController {
     /// See NVMe 1.0e Section 4.2, Figure 8 Command Format - Admin and NVM Vendor Specific Commands (Optional)
     /// See NVMe 1.0e Section 8.7 Standard Vendor Specific Command Format
     pub nvscc: u8,
-    /// Reserved - Bytes 703:531
-    pub _resv4: [u8; 173],
-    /// Reserved (I/O Command Set Attributes) - Bytes 2047:704
-    pub _resv5: [u8; 1344],
+    /// Reserved - Bytes 767:531
+    pub _resv4: [u8; 237],
+    /// NVM Subsystem NVMe Qualified Name (SUBNQN) (NVMe 1.2.1)
+    ///
+    /// UTF-8 null-terminated string uniquely identifying the NVM subsystem.
+    /// See NVMe 1.3 Section 7.9 NVMe Qualified Names
+    pub subnqn: [u8; 256],
+    /// Reserved (I/O Command Set Attributes) - Bytes 2047:1024
+    pub _resv5: [u8; 1024],
 
     // bytes 2048-3071 - Power State Descriptors
     /// Power State Descriptors (PSD0-PSD31)
@@ -966,6 +1251,12 @@ impl Default for IdentifyController {
             ieee: [0; 3],
             cmic: 0,
             mdts: 0,
+            cntlid: 0,
+            ver: 0,
+            rtd3r: 0,
+            rtd3e: 0,
+            oaes: 0,
+            ctratt: 0,
             oacs: 0,
             acl: 0,
             aerl: 0,
@@ -984,14 +1275,15 @@ impl Default for IdentifyController {
             awun: 0,
             awupf: 0,
             nvscc: 0,
+            subnqn: [0; 256],
             psd: [PowerStateDescriptor::default(); 32],
             vs: [0; 1024],
 
-            _resv1: [0; 178],
+            _resv1: [0; 156],
             _resv2: [0; 246],
             _resv3: [0; 2],
-            _resv4: [0; 173],
-            _resv5: [0; 1344],
+            _resv4: [0; 237],
+            _resv5: [0; 1024],
         }
     }
 }
@@ -1097,8 +1389,22 @@ pub struct IdentifyNamespace {
     ///     100b-111b = Reserved
     /// See NVMe 1.0e Section 8.3 End-to-end Data Protection (Optional)
     pub dps: u8,
-    /// Reserved - Bytes 127:30
-    pub _resv1: [u8; 98],
+    /// Reserved - Bytes 103:30
+    pub _resv1: [u8; 74],
+    /// Namespace Globally Unique Identifier (NGUID) (NVMe 1.2)
+    ///
+    /// A 128-bit value, globally unique and assigned to the namespace when
+    /// created, which remains fixed throughout its life.  A value of 0 means
+    /// no NGUID is reported.
+    /// See NVMe 1.3 Section 7.10 Unique Identifier
+    pub nguid: [u8; 16],
+    /// IEEE Extended Unique Identifier (EUI64) (NVMe 1.1)
+    ///
+    /// A 64-bit IEEE Extended Unique Identifier, globally unique and assigned
+    /// to the namespace when created.  A value of 0 means no EUI-64 is
+    /// reported.
+    /// See NVMe 1.3 Section 7.10 Unique Identifier
+    pub eui64: [u8; 8],
     /// LBA Formats (LBAF0-LBAF15)
     ///
     /// The list of supported LBA formats.
@@ -1123,15 +1429,188 @@ impl Default for IdentifyNamespace {
             mc: 0,
             dpc: 0,
             dps: 0,
+            nguid: [0; 16],
+            eui64: [0; 8],
             lbaf: [LbaFormat::default(); 16],
             vs: [0; 3712],
 
-            _resv1: [0; 98],
+            _resv1: [0; 74],
             _resv2: [0; 192],
         }
     }
 }
 
+/// An entry in the Error Information log page.
+///
+/// See NVMe 1.0e Section 5.10.1.1, Figure 59 Get Log Page - Error Information Log Entry
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct ErrorLogEntry {
+    /// Error Count
+    ///
+    /// Unique identifier for the error, incremented for each new error.  A
+    /// value of 0 indicates an invalid entry.
+    pub error_count: u64,
+    /// Submission Queue ID
+    ///
+    /// The Submission Queue of the command that the error is associated with.
+    pub sqid: u16,
+    /// Command ID
+    ///
+    /// The Command Identifier of the command that the error is associated with.
+    pub cid: u16,
+    /// Status Field
+    ///
+    /// The Status Field of the completion for the command, with the Phase Tag
+    /// in bit 0 reported as 0.
+    pub status: u16,
+    /// Parameter Error Location
+    ///
+    /// The byte (bits 7:0) and bit (bits 10:8) in the command that contained
+    /// the error, or 0xFFFF if not applicable.
+    pub param_err_loc: u16,
+    /// LBA
+    ///
+    /// The first LBA that experienced the error, if applicable.
+    pub lba: u64,
+    /// Namespace
+    ///
+    /// The namespace that the error is associated with, if applicable.
+    pub nsid: u32,
+    /// Vendor Specific Information Available
+    pub vs: u8,
+    /// Reserved - Bytes 63:29
+    pub _resv: [u8; 35],
+}
+
+// We can't derive Default since Default isn't impl'd
+// for [T; N] where N > 32 yet (rust #61415)
+impl Default for ErrorLogEntry {
+    fn default() -> Self {
+        Self {
+            error_count: 0,
+            sqid: 0,
+            cid: 0,
+            status: 0,
+            param_err_loc: 0,
+            lba: 0,
+            nsid: 0,
+            vs: 0,
+
+            _resv: [0; 35],
+        }
+    }
+}
+
+/// The SMART / Health Information log page.
+///
+/// See NVMe 1.0e Section 5.10.1.2, Figure 60 Get Log Page - SMART / Health Information Log
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+pub struct SmartLog {
+    /// Critical Warning
+    ///
+    /// Bits 7:5 are reserved.
+    /// Bit 4 indicates the volatile memory backup device has failed.
+    /// Bit 3 indicates the media has been placed in read only mode.
+    /// Bit 2 indicates the device reliability has been degraded.
+    /// Bit 1 indicates the temperature has exceeded a critical threshold.
+    /// Bit 0 indicates the available spare space has fallen below the threshold.
+    pub crit_warn: u8,
+    /// Temperature of the overall device (controller and NVM) in Kelvin.
+    pub temp: u16,
+    /// Available Spare
+    ///
+    /// Normalized percentage (0 to 100%) of the remaining spare capacity.
+    pub avail_spare: u8,
+    /// Available Spare Threshold
+    ///
+    /// When the Available Spare falls below this (percentage) threshold, an
+    /// asynchronous event may occur.
+    pub avail_spare_thresh: u8,
+    /// Percentage Used
+    ///
+    /// Vendor specific estimate of the percentage of the NVM life used.
+    pub pct_used: u8,
+    /// Reserved - Bytes 31:06
+    pub _resv1: [u8; 26],
+    /// Data Units Read
+    ///
+    /// Number of 512 byte data units read by the host, reported in thousands
+    /// and rounded up.
+    pub data_units_read: u128,
+    /// Data Units Written
+    ///
+    /// Number of 512 byte data units written by the host, reported in
+    /// thousands and rounded up.
+    pub data_units_written: u128,
+    /// Number of read commands completed by the controller.
+    pub host_read_cmds: u128,
+    /// Number of write commands completed by the controller.
+    pub host_write_cmds: u128,
+    /// Controller Busy Time
+    ///
+    /// Amount of time (in minutes) the controller is busy with I/O commands.
+    pub ctrl_busy_time: u128,
+    /// Number of power cycles.
+    pub power_cycles: u128,
+    /// Number of power-on hours.
+    pub power_on_hours: u128,
+    /// Number of unsafe shutdowns, i.e. those without a prior shutdown
+    /// notification from the host.
+    pub unsafe_shutdowns: u128,
+    /// Media Errors
+    ///
+    /// Number of occurrences where the controller detected an unrecovered
+    /// data integrity error.
+    pub media_errors: u128,
+    /// Number of Error Information log entries over the life of the controller.
+    pub num_err_log_entries: u128,
+    /// Reserved - Bytes 511:192
+    pub _resv2: [u8; 320],
+}
+
+// We can't derive Default since Default isn't impl'd
+// for [T; N] where N > 32 yet (rust #61415)
+impl Default for SmartLog {
+    fn default() -> Self {
+        Self {
+            crit_warn: 0,
+            temp: 0,
+            avail_spare: 0,
+            avail_spare_thresh: 0,
+            pct_used: 0,
+            data_units_read: 0,
+            data_units_written: 0,
+            host_read_cmds: 0,
+            host_write_cmds: 0,
+            ctrl_busy_time: 0,
+            power_cycles: 0,
+            power_on_hours: 0,
+            unsafe_shutdowns: 0,
+            media_errors: 0,
+            num_err_log_entries: 0,
+
+            _resv1: [0; 26],
+            _resv2: [0; 320],
+        }
+    }
+}
+
+/// A range of logical blocks specified by a Dataset Management command.
+///
+/// See NVMe 1.3 Section 6.7, Figure 271 Dataset Management - Range Definition
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct DsmRange {
+    /// Context Attributes
+    pub cattr: u32,
+    /// Length in logical blocks
+    pub nlb: u32,
+    /// Starting LBA
+    pub slba: u64,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -1145,5 +1624,8 @@ mod test {
         assert_eq!(size_of::<IdentifyController>(), 4096);
         assert_eq!(size_of::<LbaFormat>(), 4);
         assert_eq!(size_of::<IdentifyNamespace>(), 4096);
+        assert_eq!(size_of::<ErrorLogEntry>(), 64);
+        assert_eq!(size_of::<SmartLog>(), 512);
+        assert_eq!(size_of::<DsmRange>(), 16);
     }
 }
@@ -37,9 +37,11 @@ pub enum AdminCmd {
     /// Set Features Command
     SetFeatures(SetFeaturesCmd),
     /// Get Features Command
-    GetFeatures,
+    GetFeatures(GetFeaturesCmd),
     /// Asynchronous Event Request Command
     AsyncEventReq,
+    /// Doorbell Buffer Config Command
+    DoorbellBufCfg(DoorbellBufCfgCmd),
     /// An unknown admin command
     Unknown(RawSubmission),
 }
@@ -52,13 +54,8 @@ impl AdminCmd {
                 AdminCmd::DeleteIOSubQ(raw.cdw10 as u16)
             }
             bits::ADMIN_OPC_CREATE_IO_SQ => {
-                let queue_prio = match (raw.cdw11 & 0b110) >> 1 {
-                    0b00 => QueuePriority::Urgent,
-                    0b01 => QueuePriority::High,
-                    0b10 => QueuePriority::Medium,
-                    0b11 => QueuePriority::Low,
-                    _ => unreachable!(),
-                };
+                let queue_prio =
+                    QueuePriority::from(((raw.cdw11 & 0b110) >> 1) as u8);
                 AdminCmd::CreateIOSubQ(CreateIOSQCmd {
                     prp: raw.prp1,
                     qsize: (raw.cdw10 >> 16) + 1, // Convert from 0's based
@@ -72,8 +69,9 @@ impl AdminCmd {
                 AdminCmd::GetLogPage(GetLogPageCmd {
                     nsid: raw.nsid,
                     // Convert from 0's based dword
-                    len: (((raw.cdw10 & 0xFFF) >> 16) + 1) * 4,
+                    len: (((raw.cdw10 >> 16) & 0xFFF) + 1) * 4,
                     log_page_ident: LogPageIdent::from(raw.cdw10 as u8),
+                    rae: (raw.cdw10 & (1 << 15)) != 0,
                     prp1: raw.prp1,
                     prp2: raw.prp2,
                 })
@@ -92,8 +90,11 @@ impl AdminCmd {
                 })
             }
             bits::ADMIN_OPC_IDENTIFY => AdminCmd::Identify(IdentifyCmd {
-                // Only the last bit is used for NVMe 1.0e
-                cns: raw.cdw10 as u8 & 0b1,
+                // Only the last bit is used for NVMe 1.0e, while NVMe 1.1
+                // extends this to the last two and NVMe 1.3 to the whole
+                // byte. Which values are accepted depends on the version we
+                // present.
+                cns: raw.cdw10 as u8,
                 nsid: raw.nsid,
                 prp1: raw.prp1,
                 prp2: raw.prp2,
@@ -102,10 +103,24 @@ impl AdminCmd {
             bits::ADMIN_OPC_SET_FEATURES => {
                 AdminCmd::SetFeatures(SetFeaturesCmd {
                     fid: FeatureIdent::from((raw.cdw10 as u8, raw.cdw11)),
+                    value: raw.cdw11,
+                    save: (raw.cdw10 & (1 << 31)) != 0,
+                })
+            }
+            bits::ADMIN_OPC_GET_FEATURES => {
+                AdminCmd::GetFeatures(GetFeaturesCmd {
+                    fid: FeatureIdent::from((raw.cdw10 as u8, raw.cdw11)),
+                    params: raw.cdw11,
+                    sel: (raw.cdw10 >> 8) as u8 & 0b111,
                 })
             }
-            bits::ADMIN_OPC_GET_FEATURES => AdminCmd::GetFeatures,
             bits::ADMIN_OPC_ASYNC_EVENT_REQ => AdminCmd::AsyncEventReq,
+            bits::ADMIN_OPC_DOORBELL_BUF_CFG => {
+                AdminCmd::DoorbellBufCfg(DoorbellBufCfgCmd {
+                    shadow_doorbells: raw.prp1,
+                    event_idxs: raw.prp2,
+                })
+            }
             _ => AdminCmd::Unknown(raw),
         };
         let _fuse = match (raw.cdw0 >> 8) & 0b11 {
@@ -201,16 +216,29 @@ pub struct CreateIOSQCmd {
 }
 
 /// Priority Levels
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum QueuePriority {
     /// Highest strict priority class (excluding Commands submitted to Admin Submission Queue)
-    Urgent,
+    Urgent = 0b00,
     /// Lowest strict priority class: Level - High
-    High,
+    High = 0b01,
     /// Lowest strict priority class: Level - Medium
-    Medium,
+    Medium = 0b10,
     /// Lowest strict priority class: Level - Low
-    Low,
+    Low = 0b11,
+}
+
+impl From<u8> for QueuePriority {
+    /// Convert from a Queue Priority (QPRIO) field, ignoring any higher bits.
+    fn from(qprio: u8) -> Self {
+        match qprio & 0b11 {
+            0b00 => QueuePriority::Urgent,
+            0b01 => QueuePriority::High,
+            0b10 => QueuePriority::Medium,
+            0b11 => QueuePriority::Low,
+            _ => unreachable!(),
+        }
+    }
 }
 
 /// Get Log Page Command Parameters
@@ -229,6 +257,13 @@ pub struct GetLogPageCmd {
     /// The ID of the log page to retrieve.
     pub log_page_ident: LogPageIdent,
 
+    /// Retain Asynchronous Event (RAE)
+    ///
+    /// Whether any asynchronous event associated with the log page should
+    /// be left unacknowledged by the read.
+    /// See NVMe 1.3 Section 5.14.1, Figure 97 Get Log Page - Command Dword 10
+    pub rae: bool,
+
     /// PRP Entry 1 (PRP1)
     ///
     /// The first PRP entry specifying the start of the data buffer.
@@ -245,7 +280,7 @@ pub struct GetLogPageCmd {
 impl GetLogPageCmd {
     /// Returns an Iterator that yields [`GuestRegion`]'s to write the log page data to.
     pub fn data<'a>(&'a self, mem: MemCtx<'a>) -> PrpIter<'a> {
-        PrpIter::new(PAGE_SIZE as u64, self.prp1, self.prp2, mem)
+        PrpIter::new(self.len as u64, self.prp1, self.prp2, mem)
     }
 }
 
@@ -262,6 +297,8 @@ pub enum LogPageIdent {
     Smart,
     /// Firmware Slot Information Log PAge
     Firmware,
+    /// Changed Namespace List Log Page
+    ChangedNsList,
     /// I/O Command Set Specific Log Page
     IOSpecifc(u8),
     /// Vendor Specific Log Page
@@ -275,7 +312,8 @@ impl From<u8> for LogPageIdent {
             1 => LogPageIdent::Error,
             2 => LogPageIdent::Smart,
             3 => LogPageIdent::Firmware,
-            0x04..=0x7F => LogPageIdent::Reserved,
+            4 => LogPageIdent::ChangedNsList,
+            0x05..=0x7F => LogPageIdent::Reserved,
             0x80..=0xBF => LogPageIdent::IOSpecifc(ident),
             0xC0..=0xFF => LogPageIdent::Vendor(ident),
         }
@@ -320,6 +358,54 @@ pub struct SetFeaturesCmd {
     ///
     /// The feature that attributes are being specified for.
     pub fid: FeatureIdent,
+
+    /// The attributes being specified, as Command Dword 11.
+    pub value: u32,
+
+    /// Save (SV)
+    ///
+    /// Whether the attributes should also be saved, persisting across
+    /// resets.
+    pub save: bool,
+}
+
+/// Get Features Command Parameters
+#[derive(Debug)]
+pub struct GetFeaturesCmd {
+    /// Feature Identifier (FID)
+    ///
+    /// The feature that attributes are being requested for.
+    pub fid: FeatureIdent,
+
+    /// Feature specific parameters (e.g. the interrupt vector for the
+    /// Interrupt Vector Configuration feature), as Command Dword 11.
+    pub params: u32,
+
+    /// Select (SEL)
+    ///
+    /// Which value of the attributes to return: current, default, saved or
+    /// the supported capabilities of the feature.
+    /// See NVMe 1.3 Section 5.15, Figure 108 Get Features - Command Dword 10
+    pub sel: u8,
+}
+
+/// Doorbell Buffer Config Command Parameters
+///
+/// See NVMe 1.3 Section 5.7 Doorbell Buffer Config command
+#[derive(Debug)]
+pub struct DoorbellBufCfgCmd {
+    /// PRP Entry 1 (PRP1)
+    ///
+    /// The page-aligned 64-bit base address of the Shadow Doorbell buffer, to
+    /// which the host writes updated doorbell values.
+    pub shadow_doorbells: u64,
+
+    /// PRP Entry 2 (PRP2)
+    ///
+    /// The page-aligned 64-bit base address of the EventIdx buffer, in which
+    /// the controller indicates when the host must also write the doorbell
+    /// registers.
+    pub event_idxs: u64,
 }
 
 /// Feature Identifiers
@@ -431,6 +517,10 @@ pub enum NvmCmd {
     Write(WriteCmd),
     /// Read data and metadata
     Read(ReadCmd),
+    /// Set a range of logical blocks to zero
+    WriteZeroes(WriteZeroesCmd),
+    /// Indicate attributes for ranges of logical blocks
+    DatasetMgmt(DatasetMgmtCmd),
     /// An unknown NVM command
     Unknown(RawSubmission),
 }
@@ -460,6 +550,18 @@ impl NvmCmd {
                 prp1: raw.prp1,
                 prp2: raw.prp2,
             }),
+            bits::NVM_OPC_WRITE_ZEROES => NvmCmd::WriteZeroes(WriteZeroesCmd {
+                slba: (raw.cdw11 as u64) << 32 | raw.cdw10 as u64,
+                // Convert from 0's based value
+                nlb: raw.cdw12 as u16 as u32 + 1,
+            }),
+            bits::NVM_OPC_DATASET_MGMT => NvmCmd::DatasetMgmt(DatasetMgmtCmd {
+                // Convert from 0's based value
+                nr: (raw.cdw10 & 0xFF) as usize + 1,
+                attr: raw.cdw11,
+                prp1: raw.prp1,
+                prp2: raw.prp2,
+            }),
             _ => NvmCmd::Unknown(raw),
         };
         Ok(cmd)
@@ -530,6 +632,56 @@ impl ReadCmd {
     }
 }
 
+/// Write Zeroes Command Parameters
+#[derive(Debug)]
+pub struct WriteZeroesCmd {
+    /// Starting LBA (SLBA)
+    ///
+    /// 64-bit base address of the first logical block to be zeroed.
+    pub slba: u64,
+
+    /// Number of Logical Blocks (NLB)
+    ///
+    /// The number of logical blocks to be zeroed.
+    pub nlb: u32,
+}
+
+/// Dataset Management Command Parameters
+#[derive(Debug)]
+pub struct DatasetMgmtCmd {
+    /// Number of Ranges (NR)
+    ///
+    /// The number of 16 byte range sets specified in the data buffer.
+    pub nr: usize,
+
+    /// Attributes (Integral Dataset for Read/Write, Deallocate)
+    pub attr: u32,
+
+    /// PRP Entry 1 (PRP1)
+    ///
+    /// The first PRP entry specifying the start of the range list.
+    prp1: u64,
+
+    /// PRP Entry 2 (PRP2)
+    ///
+    /// If PRP1 specifies enough space, then PRP2 is reserved. Otherwise
+    /// PRP2 may either be another PRP entry or a PRP list as necessary.
+    prp2: u64,
+}
+
+impl DatasetMgmtCmd {
+    /// Whether the host asks for the ranges to be deallocated
+    pub fn deallocate(&self) -> bool {
+        self.attr & bits::DSM_ATTR_DEALLOCATE != 0
+    }
+
+    /// Returns an Iterator that yields [`GuestRegion`]'s holding the ranges.
+    pub fn data<'a>(&'a self, mem: MemCtx<'a>) -> PrpIter<'a> {
+        let sz = self.nr * std::mem::size_of::<bits::DsmRange>();
+        PrpIter::new(sz as u64, self.prp1, self.prp2, mem)
+    }
+}
+
 /// Indicates the possible states of a [`PrpIter`].
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 enum PrpNext {
@@ -766,6 +918,12 @@ impl Completion {
         }
     }
 
+    /// Whether the Completion indicates the command was successful
+    pub fn is_success(&self) -> bool {
+        self.status
+            == Self::status_field(StatusCodeType::Generic, bits::STS_SUCCESS)
+    }
+
     /// Helper method to combine StatusCodeType and status code
     fn status_field(sct: StatusCodeType, sc: u8) -> u16 {
         (sc as u16) << 1 | ((sct as u8) as u16) << 9
@@ -0,0 +1,278 @@
+//! Asynchronous events reported to the host through outstanding Asynchronous
+//! Event Request commands.
+//!
+//! See NVMe 1.3 Section 5.2 Asynchronous Event Request command
+
+use std::collections::{BTreeSet, VecDeque};
+
+use crate::dispatch::DispCtx;
+
+use super::bits::{self, AsyncEventConfig};
+use super::cmds::Completion;
+use super::migrate;
+use super::queue::CompQueueEntryPermit;
+
+use serde::{Deserialize, Serialize};
+
+/// Error conditions not tied to any particular command.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ErrorEvent {
+    /// The host wrote the doorbell of a queue which was not created
+    InvalidDoorbell,
+    /// The host wrote an invalid value to a doorbell
+    InvalidDoorbellValue,
+    /// A failure was detected while performing diagnostics
+    DiagnosticFailure,
+    /// A failure occurred which is persistent, or which could not be isolated
+    PersistentInternal,
+    /// A transient failure occurred
+    TransientInternal,
+}
+
+/// SMART / Health conditions, each of which corresponds to a Critical Warning
+/// in the SMART / Health Information log.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum SmartEvent {
+    /// The reliability of the device has been degraded
+    Reliability,
+    /// The temperature has exceeded a critical threshold
+    Temperature,
+    /// The available spare space has fallen below its threshold
+    SpareBelowThreshold,
+}
+
+impl SmartEvent {
+    /// The Critical Warning bit raised by the condition
+    pub(super) fn crit_warn(&self) -> u8 {
+        match self {
+            SmartEvent::Reliability => bits::CRIT_WARN_RELIABILITY,
+            SmartEvent::Temperature => bits::CRIT_WARN_TEMPERATURE,
+            SmartEvent::SpareBelowThreshold => bits::CRIT_WARN_SPARE,
+        }
+    }
+}
+
+/// An event which may be reported to the host of an NVMe controller.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum AsyncEvent {
+    /// An error condition (see the Error Information log)
+    Error(ErrorEvent),
+    /// A SMART / Health condition (see the SMART / Health Information log)
+    SmartHealth(SmartEvent),
+    /// The attributes of the namespace with the given NSID have changed (see
+    /// the Changed Namespace List log)
+    NsAttrChanged(u32),
+}
+
+impl AsyncEvent {
+    fn event_type(&self) -> u8 {
+        match self {
+            AsyncEvent::Error(_) => bits::ASYNC_EVENT_TYPE_ERROR,
+            AsyncEvent::SmartHealth(_) => bits::ASYNC_EVENT_TYPE_SMART,
+            AsyncEvent::NsAttrChanged(_) => bits::ASYNC_EVENT_TYPE_NOTICE,
+        }
+    }
+
+    fn info(&self) -> u8 {
+        match self {
+            AsyncEvent::Error(e) => match e {
+                ErrorEvent::InvalidDoorbell => {
+                    bits::ASYNC_EVENT_INFO_INVALID_DOORBELL
+                }
+                ErrorEvent::InvalidDoorbellValue => {
+                    bits::ASYNC_EVENT_INFO_INVALID_DOORBELL_VALUE
+                }
+                ErrorEvent::DiagnosticFailure => {
+                    bits::ASYNC_EVENT_INFO_DIAGNOSTIC_FAILURE
+                }
+                ErrorEvent::PersistentInternal => {
+                    bits::ASYNC_EVENT_INFO_PERSISTENT_INTERNAL_ERR
+                }
+                ErrorEvent::TransientInternal => {
+                    bits::ASYNC_EVENT_INFO_TRANSIENT_INTERNAL_ERR
+                }
+            },
+            AsyncEvent::SmartHealth(e) => match e {
+                SmartEvent::Reliability => bits::ASYNC_EVENT_INFO_RELIABILITY,
+                SmartEvent::Temperature => {
+                    bits::ASYNC_EVENT_INFO_TEMP_THRESHOLD
+                }
+                SmartEvent::SpareBelowThreshold => {
+                    bits::ASYNC_EVENT_INFO_SPARE_BELOW_THRESHOLD
+                }
+            },
+            AsyncEvent::NsAttrChanged(_) => {
+                bits::ASYNC_EVENT_INFO_NS_ATTR_CHANGED
+            }
+        }
+    }
+
+    /// The log page the host reads to learn more about (and so acknowledge)
+    /// the event.
+    fn log_page(&self) -> u8 {
+        match self {
+            AsyncEvent::Error(_) => bits::LOG_ID_ERROR,
+            AsyncEvent::SmartHealth(_) => bits::LOG_ID_SMART,
+            AsyncEvent::NsAttrChanged(_) => bits::LOG_ID_CHANGED_NS_LIST,
+        }
+    }
+
+    /// Dword 0 of the completion for the Asynchronous Event Request reporting
+    /// the event.
+    fn completion_dw0(&self) -> u32 {
+        (self.event_type() & 0b111) as u32
+            | (self.info() as u32) << 8
+            | (self.log_page() as u32) << 16
+    }
+}
+
+/// Outstanding Asynchronous Event Requests and the events awaiting them.
+pub(super) struct AsyncEvents {
+    /// Outstanding Asynchronous Event Requests awaiting an event to report
+    aers: VecDeque<(u16, CompQueueEntryPermit)>,
+
+    /// Events awaiting an outstanding request to report them
+    pending: VecDeque<AsyncEvent>,
+
+    /// Log pages associated with events already reported, which are masked
+    /// until the host reads the log page
+    masked: BTreeSet<u8>,
+
+    /// Namespaces whose attributes have changed since the Changed Namespace
+    /// List log was last read
+    changed_ns: BTreeSet<u32>,
+}
+
+impl AsyncEvents {
+    pub fn new() -> Self {
+        Self {
+            aers: VecDeque::new(),
+            pending: VecDeque::new(),
+            masked: BTreeSet::new(),
+            changed_ns: BTreeSet::new(),
+        }
+    }
+
+    /// Drop all outstanding requests and pending events, as part of a
+    /// Controller Reset.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// The number of outstanding Asynchronous Event Requests
+    pub fn outstanding(&self) -> usize {
+        self.aers.len()
+    }
+
+    /// Hold onto an Asynchronous Event Request until there is an event to
+    /// report with it.
+    pub fn request(
+        &mut self,
+        cid: u16,
+        cqe_permit: CompQueueEntryPermit,
+        ctx: &DispCtx,
+    ) {
+        self.aers.push_back((cid, cqe_permit));
+        self.report(ctx);
+    }
+
+    /// Queue an event to be reported to the host, unless it is disabled by
+    /// `config` or the host has yet to acknowledge a prior event of the same
+    /// kind.
+    pub fn raise(
+        &mut self,
+        event: AsyncEvent,
+        config: AsyncEventConfig,
+        ctx: &DispCtx,
+    ) {
+        let enabled = match event {
+            // Error events cannot be disabled
+            AsyncEvent::Error(_) => true,
+            AsyncEvent::SmartHealth(e) => {
+                config.crit_warn() & e.crit_warn() != 0
+            }
+            AsyncEvent::NsAttrChanged(nsid) => {
+                self.changed_ns.insert(nsid);
+                config.ns_attr_notices()
+            }
+        };
+        if !enabled
+            || self.masked.contains(&event.log_page())
+            || self.pending.contains(&event)
+        {
+            return;
+        }
+        self.pending.push_back(event);
+        self.report(ctx);
+    }
+
+    /// Complete outstanding Asynchronous Event Requests with any pending
+    /// events.
+    fn report(&mut self, ctx: &DispCtx) {
+        while !self.aers.is_empty() {
+            let event = match self.pending.pop_front() {
+                Some(event) => event,
+                None => break,
+            };
+            let (cid, cqe_permit) = self.aers.pop_front().unwrap();
+
+            // Further events of this kind are masked until the host reads the
+            // associated log page
+            self.masked.insert(event.log_page());
+
+            let comp = Completion::success_val(event.completion_dw0());
+            cqe_permit.push_completion(cid, comp, ctx);
+        }
+    }
+
+    /// Note that the host has read the given log page, acknowledging any
+    /// events associated with it.
+    pub fn log_read(&mut self, lid: u8) {
+        self.masked.remove(&lid);
+        if lid == bits::LOG_ID_CHANGED_NS_LIST {
+            self.changed_ns.clear();
+        }
+    }
+
+    /// Namespaces whose attributes have changed since the Changed Namespace
+    /// List log was last read
+    pub fn changed_ns(&self) -> impl Iterator<Item = u32> + '_ {
+        self.changed_ns.iter().copied()
+    }
+
+    pub fn export(&self) -> migrate::NvmeAsyncEventsV1 {
+        migrate::NvmeAsyncEventsV1 {
+            aer_cids: self.aers.iter().map(|(cid, _)| *cid).collect(),
+            pending: self.pending.iter().copied().collect(),
+            masked: self.masked.iter().copied().collect(),
+            changed_ns: self.changed_ns.iter().copied().collect(),
+        }
+    }
+
+    /// Restore the events pending or masked on the source of a migration.
+    ///
+    /// Outstanding requests are restored separately through
+    /// [`AsyncEvents::request`], once entries have been reserved for them.
+    pub fn import(&mut self, saved: &migrate::NvmeAsyncEventsV1) {
+        self.pending = saved.pending.iter().copied().collect();
+        self.masked = saved.masked.iter().copied().collect();
+        self.changed_ns = saved.changed_ns.iter().copied().collect();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn completion_dw0() {
+        let event = AsyncEvent::NsAttrChanged(1);
+        assert_eq!(event.completion_dw0(), 0x04_00_02);
+
+        let event = AsyncEvent::SmartHealth(SmartEvent::Temperature);
+        assert_eq!(event.completion_dw0(), 0x02_01_01);
+
+        let event = AsyncEvent::Error(ErrorEvent::InvalidDoorbellValue);
+        assert_eq!(event.completion_dw0(), 0x01_01_00);
+    }
+}
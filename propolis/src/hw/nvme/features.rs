@@ -0,0 +1,355 @@
+//! Controller features managed through the Set Features and Get Features
+//! commands.
+//!
+//! See NVMe 1.3 Section 5.21 Set Features command
+
+use std::collections::BTreeSet;
+
+use super::bits::{self, ArbitrationParams, AsyncEventConfig};
+use super::cmds::{Completion, FeatureIdent};
+use super::{migrate, NVME_MSIX_COUNT};
+
+/// Composite temperature over which the host is warned by default (70C in
+/// Kelvin)
+const DEFAULT_OVER_TEMP_THRESH: u16 = 343;
+
+/// Values of the features kept by the controller, each in its Command Dword
+/// 11 representation.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct FeatureValues {
+    pub arbitration: u32,
+    pub power_mgmt: u32,
+    /// Over and under temperature thresholds for the composite temperature
+    pub temp_thresh: [u16; 2],
+    pub error_recovery: u32,
+    pub vwc: u32,
+    pub intr_coalescing: u32,
+    /// Interrupt vectors for which interrupt coalescing is disabled
+    pub intr_coalescing_disabled: BTreeSet<u16>,
+    pub write_atomicity: u32,
+    pub async_event_cfg: u32,
+}
+
+impl Default for FeatureValues {
+    fn default() -> Self {
+        Self {
+            // Arbitration Burst of 1 and (unused) weights of 1
+            arbitration: 0,
+            // Power State 0, the only one we support
+            power_mgmt: 0,
+            temp_thresh: [DEFAULT_OVER_TEMP_THRESH, 0],
+            // No time limit for error recovery
+            error_recovery: 0,
+            // The volatile write cache starts out enabled
+            vwc: 1,
+            intr_coalescing: 0,
+            intr_coalescing_disabled: BTreeSet::new(),
+            write_atomicity: 0,
+            // Hosts which predate the feature (we may report NVMe 1.0) will
+            // never configure it, so every event we are able to report is
+            // enabled.
+            async_event_cfg: AsyncEventConfig(0)
+                .with_crit_warn(
+                    bits::CRIT_WARN_SPARE
+                        | bits::CRIT_WARN_TEMPERATURE
+                        | bits::CRIT_WARN_RELIABILITY,
+                )
+                .with_ns_attr_notices(true)
+                .0,
+        }
+    }
+}
+
+impl FeatureValues {
+    /// Get the value of a feature, given the feature specific parameters of
+    /// a Get Features command.
+    fn get(&self, fid: &FeatureIdent, params: u32) -> Result<u32, u8> {
+        let value = match fid {
+            FeatureIdent::Arbitration => self.arbitration,
+            FeatureIdent::PowerManagement => self.power_mgmt,
+            FeatureIdent::TemperatureThreshold => {
+                let thsel = temp_thresh_sel(params)?;
+                params & !0xFFFF | self.temp_thresh[thsel] as u32
+            }
+            FeatureIdent::ErrorRecovery => self.error_recovery,
+            FeatureIdent::VolatileWriteCache => self.vwc,
+            FeatureIdent::InterruptCoalescing => self.intr_coalescing,
+            FeatureIdent::InterruptVectorConfiguration => {
+                let iv = intr_vector(params)?;
+                let cd = self.intr_coalescing_disabled.contains(&iv);
+                (cd as u32) << 16 | iv as u32
+            }
+            FeatureIdent::WriteAtomicity => self.write_atomicity,
+            FeatureIdent::AsynchronousEventConfiguration => {
+                self.async_event_cfg
+            }
+            FeatureIdent::Reserved
+            | FeatureIdent::LbaRangeType
+            | FeatureIdent::NumberOfQueues { .. }
+            | FeatureIdent::SoftwareProgressMarker
+            | FeatureIdent::Vendor(_) => return Err(bits::STS_INVAL_FIELD),
+        };
+        Ok(value)
+    }
+
+    /// Set the value of a feature from the Command Dword 11 of a Set Features
+    /// command, ignoring any reserved bits.
+    fn set(&mut self, fid: &FeatureIdent, value: u32) -> Result<(), u8> {
+        match fid {
+            // Arbitration Burst, and Low/Medium/High Priority Weights
+            FeatureIdent::Arbitration => self.arbitration = value & !0xF8,
+            FeatureIdent::PowerManagement => {
+                // We only support the one power state (NPSS = 0)
+                if value & 0x1F != 0 {
+                    return Err(bits::STS_INVAL_FIELD);
+                }
+                // Workload Hint
+                self.power_mgmt = value & 0xE0;
+            }
+            FeatureIdent::TemperatureThreshold => {
+                let thsel = temp_thresh_sel(value)?;
+                self.temp_thresh[thsel] = value as u16;
+            }
+            // Time Limited Error Recovery.  Deallocated or Unwritten Logical
+            // Block Error (DULBE) is unsupported.
+            FeatureIdent::ErrorRecovery => self.error_recovery = value & 0xFFFF,
+            // Volatile Write Cache Enable
+            FeatureIdent::VolatileWriteCache => self.vwc = value & 0b1,
+            // Aggregation Time and Threshold
+            FeatureIdent::InterruptCoalescing => {
+                self.intr_coalescing = value & 0xFFFF
+            }
+            FeatureIdent::InterruptVectorConfiguration => {
+                let iv = intr_vector(value)?;
+                if value & (1 << 16) != 0 {
+                    self.intr_coalescing_disabled.insert(iv);
+                } else {
+                    self.intr_coalescing_disabled.remove(&iv);
+                }
+            }
+            // Disable Normal
+            FeatureIdent::WriteAtomicity => self.write_atomicity = value & 0b1,
+            FeatureIdent::AsynchronousEventConfiguration => {
+                self.async_event_cfg = value & 0x3FF
+            }
+            FeatureIdent::Reserved
+            | FeatureIdent::LbaRangeType
+            | FeatureIdent::NumberOfQueues { .. }
+            | FeatureIdent::SoftwareProgressMarker
+            | FeatureIdent::Vendor(_) => return Err(bits::STS_INVAL_FIELD),
+        }
+        Ok(())
+    }
+
+    fn export(&self) -> migrate::NvmeFeatureValuesV1 {
+        migrate::NvmeFeatureValuesV1 {
+            arbitration: self.arbitration,
+            power_mgmt: self.power_mgmt,
+            temp_thresh: self.temp_thresh,
+            error_recovery: self.error_recovery,
+            vwc: self.vwc,
+            intr_coalescing: self.intr_coalescing,
+            intr_coalescing_disabled: self
+                .intr_coalescing_disabled
+                .iter()
+                .copied()
+                .collect(),
+            write_atomicity: self.write_atomicity,
+            async_event_cfg: self.async_event_cfg,
+        }
+    }
+
+    fn import(saved: &migrate::NvmeFeatureValuesV1) -> Self {
+        Self {
+            arbitration: saved.arbitration,
+            power_mgmt: saved.power_mgmt,
+            temp_thresh: saved.temp_thresh,
+            error_recovery: saved.error_recovery,
+            vwc: saved.vwc,
+            intr_coalescing: saved.intr_coalescing,
+            intr_coalescing_disabled: saved
+                .intr_coalescing_disabled
+                .iter()
+                .copied()
+                .collect(),
+            write_atomicity: saved.write_atomicity,
+            async_event_cfg: saved.async_event_cfg,
+        }
+    }
+}
+
+/// Pick out which threshold (over or under temperature) is selected for the
+/// Temperature Threshold feature.
+fn temp_thresh_sel(cdw11: u32) -> Result<usize, u8> {
+    // Threshold Temperature Select (TMPSEL): only the composite temperature
+    // (0) is reported
+    let tmpsel = (cdw11 >> 16) & 0xF;
+    // Threshold Type Select (THSEL): over (0) or under (1) temperature
+    let thsel = (cdw11 >> 20) & 0b11;
+    match (tmpsel, thsel) {
+        (0, 0) | (0, 1) => Ok(thsel as usize),
+        _ => Err(bits::STS_INVAL_FIELD),
+    }
+}
+
+/// Pick out the interrupt vector specified for the Interrupt Vector
+/// Configuration feature.
+fn intr_vector(cdw11: u32) -> Result<u16, u8> {
+    let iv = cdw11 as u16;
+    if iv >= NVME_MSIX_COUNT {
+        return Err(bits::STS_INVAL_FIELD);
+    }
+    Ok(iv)
+}
+
+/// The feature table of a controller.
+#[derive(Debug, Default)]
+pub(super) struct Features {
+    /// Values currently in effect
+    pub current: FeatureValues,
+
+    /// Values saved by the host, which are restored on Controller Reset
+    saved: FeatureValues,
+}
+
+impl Features {
+    /// Revert the current values to those saved, as part of a Controller
+    /// Reset.
+    pub fn reset(&mut self) {
+        self.current = self.saved.clone();
+    }
+
+    pub fn export(&self) -> migrate::NvmeFeaturesV1 {
+        migrate::NvmeFeaturesV1 {
+            current: self.current.export(),
+            saved: self.saved.export(),
+        }
+    }
+
+    pub fn import(&mut self, saved: &migrate::NvmeFeaturesV1) {
+        self.current = FeatureValues::import(&saved.current);
+        self.saved = FeatureValues::import(&saved.saved);
+    }
+
+    /// The Arbitration parameters currently in effect
+    pub fn arbitration(&self) -> ArbitrationParams {
+        ArbitrationParams(self.current.arbitration)
+    }
+
+    /// The Asynchronous Event Configuration currently in effect
+    pub fn async_event_cfg(&self) -> AsyncEventConfig {
+        AsyncEventConfig(self.current.async_event_cfg)
+    }
+
+    /// Service a Set Features command for the given feature.
+    pub fn set(
+        &mut self,
+        fid: &FeatureIdent,
+        value: u32,
+        save: bool,
+    ) -> Completion {
+        let mut res = self.current.set(fid, value);
+        if res.is_ok() && save {
+            res = self.saved.set(fid, value);
+        }
+        match res {
+            Ok(()) => Completion::success(),
+            Err(sts) => Completion::generic_err(sts),
+        }
+    }
+
+    /// Service a Get Features command for the given feature.
+    pub fn get(&self, fid: &FeatureIdent, params: u32, sel: u8) -> Completion {
+        let res = match sel {
+            bits::FEAT_SEL_CURRENT => self.current.get(fid, params),
+            bits::FEAT_SEL_DEFAULT => FeatureValues::default().get(fid, params),
+            bits::FEAT_SEL_SAVED => self.saved.get(fid, params),
+            bits::FEAT_SEL_SUPPORTED => {
+                // Every feature we keep is both changeable and saveable,
+                // with none being namespace specific.
+                self.current.get(fid, params).map(|_| {
+                    bits::FEAT_CAP_SAVEABLE | bits::FEAT_CAP_CHANGEABLE
+                })
+            }
+            _ => Err(bits::STS_INVAL_FIELD),
+        };
+        match res {
+            Ok(value) => Completion::success_val(value),
+            Err(sts) => Completion::generic_err(sts),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn value(comp: Completion) -> u32 {
+        assert!(comp.is_success());
+        comp.dw0
+    }
+
+    #[test]
+    fn saved_values_restored_on_reset() {
+        let mut features = Features::default();
+        let vwc = FeatureIdent::VolatileWriteCache;
+        let arb = FeatureIdent::Arbitration;
+
+        assert!(features.set(&vwc, 0, true).is_success());
+        assert!(features.set(&arb, 0x0302_0103, false).is_success());
+        assert_eq!(
+            value(features.get(&arb, 0, bits::FEAT_SEL_CURRENT)),
+            0x0302_0103
+        );
+        assert_eq!(value(features.get(&arb, 0, bits::FEAT_SEL_SAVED)), 0);
+
+        features.reset();
+        assert_eq!(value(features.get(&vwc, 0, bits::FEAT_SEL_CURRENT)), 0);
+        assert_eq!(value(features.get(&vwc, 0, bits::FEAT_SEL_DEFAULT)), 1);
+        assert_eq!(value(features.get(&arb, 0, bits::FEAT_SEL_CURRENT)), 0);
+    }
+
+    #[test]
+    fn per_selector_values() {
+        let mut features = Features::default();
+        let temp = FeatureIdent::TemperatureThreshold;
+        let ivc = FeatureIdent::InterruptVectorConfiguration;
+
+        // Under temperature threshold (THSEL = 1)
+        assert!(features.set(&temp, 1 << 20 | 273, false).is_success());
+        assert_eq!(
+            value(features.get(&temp, 1 << 20, bits::FEAT_SEL_CURRENT)),
+            1 << 20 | 273
+        );
+        assert_eq!(
+            value(features.get(&temp, 0, bits::FEAT_SEL_CURRENT)),
+            DEFAULT_OVER_TEMP_THRESH as u32
+        );
+        // Only the composite temperature sensor is supported
+        assert!(!features.set(&temp, 1 << 16 | 300, false).is_success());
+
+        assert!(features.set(&ivc, 1 << 16 | 3, false).is_success());
+        assert_eq!(
+            value(features.get(&ivc, 3, bits::FEAT_SEL_CURRENT)),
+            1 << 16 | 3
+        );
+        assert_eq!(value(features.get(&ivc, 2, bits::FEAT_SEL_CURRENT)), 2);
+        assert!(!features
+            .get(&ivc, NVME_MSIX_COUNT as u32, bits::FEAT_SEL_CURRENT)
+            .is_success());
+    }
+
+    #[test]
+    fn unsupported_features() {
+        let mut features = Features::default();
+        let pm = FeatureIdent::PowerManagement;
+        assert!(!features.set(&pm, 1, false).is_success());
+        assert!(!features
+            .get(&FeatureIdent::LbaRangeType, 0, bits::FEAT_SEL_CURRENT)
+            .is_success());
+        assert_eq!(
+            value(features.get(&pm, 0, bits::FEAT_SEL_SUPPORTED)),
+            bits::FEAT_CAP_SAVEABLE | bits::FEAT_CAP_CHANGEABLE
+        );
+    }
+}
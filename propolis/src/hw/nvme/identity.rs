@@ -0,0 +1,196 @@
+//! Identifying details of a controller and its namespaces which may be
+//! configured for a device, rather than made up by us.
+
+use super::bits::IdentifyController;
+use super::ns::NsIds;
+use super::NvmeError;
+
+/// Identifying details presented by a controller in place of our defaults.
+///
+/// See NVMe 1.0e Section 5.11, Figure 66 Identify - Identify Controller Data
+/// Structure
+#[derive(Clone, Debug, Default)]
+pub struct CtrlIdentity {
+    /// Serial Number (SN), of up to 20 ASCII characters
+    pub serial_number: Option<String>,
+
+    /// Model Number (MN), of up to 40 ASCII characters
+    pub model_number: Option<String>,
+
+    /// Firmware Revision (FR), of up to 8 ASCII characters
+    pub firmware_rev: Option<String>,
+
+    /// IEEE OUI Identifier (IEEE), as 6 hex digits
+    ///
+    /// Also used for the EUI-64 and NGUID we derive for each namespace.
+    pub ieee_oui: Option<String>,
+}
+
+impl CtrlIdentity {
+    /// Fill in the identifying fields of `ident`, with the serial number
+    /// defaulting to (as much as fits of) `name`.
+    pub(super) fn apply(
+        &self,
+        name: &str,
+        ident: &mut IdentifyController,
+    ) -> Result<(), NvmeError> {
+        ident.sn = match &self.serial_number {
+            Some(sn) => ascii_field("serial number", sn)?,
+            None => {
+                let sz = std::cmp::min(ident.sn.len(), name.len());
+                padded(&name.as_bytes()[..sz])
+            }
+        };
+        ident.mn = match &self.model_number {
+            Some(mn) => ascii_field("model number", mn)?,
+            None => padded(b""),
+        };
+        ident.fr = match &self.firmware_rev {
+            Some(fr) => ascii_field("firmware revision", fr)?,
+            None => padded(b""),
+        };
+        if let Some(oui) = &self.ieee_oui {
+            ident.ieee = hex_field("IEEE OUI", oui)?;
+        }
+        Ok(())
+    }
+}
+
+/// Globally unique identifiers reported for a namespace in place of those we
+/// derive.
+///
+/// See NVMe 1.3 Section 7.10 Unique Identifier
+#[derive(Clone, Debug, Default)]
+pub struct NsIdentity {
+    /// IEEE Extended Unique Identifier (EUI-64), as 16 hex digits
+    pub eui64: Option<String>,
+
+    /// Namespace Globally Unique Identifier (NGUID), as 32 hex digits
+    pub nguid: Option<String>,
+}
+
+impl NsIdentity {
+    /// Override the identifiers in `ids` with those configured.
+    pub(super) fn apply(&self, ids: &mut NsIds) -> Result<(), NvmeError> {
+        if let Some(eui64) = &self.eui64 {
+            ids.eui64 = hex_field("EUI-64", eui64)?;
+        }
+        if let Some(nguid) = &self.nguid {
+            ids.nguid = hex_field("NGUID", nguid)?;
+        }
+        Ok(())
+    }
+}
+
+/// Convert `val` into an ASCII string field of `N` bytes, zero-filling the
+/// remainder.
+fn ascii_field<const N: usize>(
+    what: &'static str,
+    val: &str,
+) -> Result<[u8; N], NvmeError> {
+    if !val.bytes().all(|b| (b' '..=b'~').contains(&b)) {
+        return Err(NvmeError::InvalidIdentity(
+            what,
+            "must be printable ASCII".to_string(),
+        ));
+    }
+    if val.len() > N {
+        return Err(NvmeError::InvalidIdentity(
+            what,
+            format!("must be at most {} characters", N),
+        ));
+    }
+    Ok(padded(val.as_bytes()))
+}
+
+/// Copy `val` into an ASCII string field of `N` bytes, padded with spaces as
+/// the Identify data structures require.
+fn padded<const N: usize>(val: &[u8]) -> [u8; N] {
+    let mut field = [b' '; N];
+    field[..val.len()].copy_from_slice(val);
+    field
+}
+
+/// Convert `val`, given as hex digits, into an identifier of `N` bytes.
+fn hex_field<const N: usize>(
+    what: &'static str,
+    val: &str,
+) -> Result<[u8; N], NvmeError> {
+    if val.len() != 2 * N || !val.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(NvmeError::InvalidIdentity(
+            what,
+            format!("must be {} hex digits", 2 * N),
+        ));
+    }
+    let mut field = [0u8; N];
+    for (i, b) in field.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&val[2 * i..2 * i + 2], 16).unwrap();
+    }
+    Ok(field)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ctrl_identity() {
+        let mut ident = IdentifyController::default();
+        CtrlIdentity::default()
+            .apply("a-rather-long-block-device-name", &mut ident)
+            .unwrap();
+        assert_eq!(&ident.sn, b"a-rather-long-block-");
+        // Fields are padded with spaces, even when left blank
+        CtrlIdentity::default().apply("name", &mut ident).unwrap();
+        assert_eq!(&ident.sn, b"name                ");
+        assert!(ident.mn.iter().all(|b| *b == b' '));
+        assert_eq!(&ident.fr, b"        ");
+
+        let ctrl = CtrlIdentity {
+            serial_number: Some("SN1234".to_string()),
+            model_number: Some("Propolis NVMe Disk".to_string()),
+            firmware_rev: Some("1.0".to_string()),
+            ieee_oui: Some("A84025".to_string()),
+        };
+        let mut ident = IdentifyController::default();
+        ctrl.apply("name", &mut ident).unwrap();
+        assert_eq!(&ident.sn, b"SN1234              ");
+        assert_eq!(&ident.mn[..18], b"Propolis NVMe Disk");
+        assert!(ident.mn[18..].iter().all(|b| *b == b' '));
+        assert_eq!(&ident.fr, b"1.0     ");
+        assert_eq!(ident.ieee, [0xA8, 0x40, 0x25]);
+
+        // Lengths are checked against those of the Identify fields
+        let long_fr = CtrlIdentity {
+            firmware_rev: Some("123456789".to_string()),
+            ..Default::default()
+        };
+        assert!(long_fr.apply("name", &mut ident).is_err());
+        let bad_sn = CtrlIdentity {
+            serial_number: Some("SN\u{e9}".to_string()),
+            ..Default::default()
+        };
+        assert!(bad_sn.apply("name", &mut ident).is_err());
+        let short_oui = CtrlIdentity {
+            ieee_oui: Some("A840".to_string()),
+            ..Default::default()
+        };
+        assert!(short_oui.apply("name", &mut ident).is_err());
+    }
+
+    #[test]
+    fn ns_identity() {
+        let mut ids = NsIds::default();
+        let ns = NsIdentity {
+            eui64: Some("a8402500000000ff".to_string()),
+            nguid: None,
+        };
+        ns.apply(&mut ids).unwrap();
+        assert_eq!(ids.eui64, [0xA8, 0x40, 0x25, 0, 0, 0, 0, 0xFF]);
+        assert_eq!(ids.nguid, [0; 16]);
+
+        let bad_nguid =
+            NsIdentity { nguid: Some("zz".repeat(16)), ..Default::default() };
+        assert!(bad_nguid.apply(&mut ids).is_err());
+    }
+}
@@ -0,0 +1,297 @@
+//! Controller state reported through the Error Information and
+//! SMART / Health Information log pages.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::block::{self, Operation};
+use crate::dispatch::DispCtx;
+
+use super::bits::{ErrorLogEntry, RawSubmission, SmartLog};
+use super::cmds::Completion;
+use super::migrate;
+use super::queue::{CompQueueEntryPermit, QueueId};
+
+/// The number of entries kept in the Error Information log
+pub(super) const NUM_ERROR_LOG_ENTRIES: usize = 64;
+
+/// Size of the data units tallied in the SMART / Health log
+const DATA_UNIT_SIZE: u64 = 512;
+
+/// Composite temperature reported in the SMART / Health log (25C in Kelvin)
+const TEMPERATURE: u16 = 298;
+
+/// Error Information log entries, most recent first.
+#[derive(Default)]
+struct ErrorLog {
+    /// Number of errors recorded over the life of the controller
+    count: u64,
+    entries: VecDeque<ErrorLogEntry>,
+}
+
+/// Per-controller counters and error history backing the log pages.
+///
+/// I/O commands are completed outside of the controller lock, so everything
+/// here is updated through shared references.
+pub(super) struct LogPages {
+    /// Critical Warnings raised for the controller
+    crit_warn: AtomicU8,
+
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    host_read_cmds: AtomicU64,
+    host_write_cmds: AtomicU64,
+    media_errors: AtomicU64,
+    power_cycles: AtomicU64,
+    unsafe_shutdowns: AtomicU64,
+
+    /// When the controller was (first) powered on, as rebased on import
+    powered_on: Mutex<Instant>,
+
+    errors: Mutex<ErrorLog>,
+}
+
+impl LogPages {
+    pub fn new() -> Self {
+        Self {
+            crit_warn: AtomicU8::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            host_read_cmds: AtomicU64::new(0),
+            host_write_cmds: AtomicU64::new(0),
+            media_errors: AtomicU64::new(0),
+            // Count the initial power on
+            power_cycles: AtomicU64::new(1),
+            unsafe_shutdowns: AtomicU64::new(0),
+            powered_on: Mutex::new(Instant::now()),
+            errors: Mutex::new(ErrorLog::default()),
+        }
+    }
+
+    /// Tally a completed I/O operation of `size` bytes.
+    pub fn record_io(&self, op: Operation, size: usize, res: block::Result) {
+        let (cmds, bytes) = match op {
+            Operation::Read(_) => (&self.host_read_cmds, &self.bytes_read),
+            Operation::Write(_) => (&self.host_write_cmds, &self.bytes_written),
+            _ => return,
+        };
+        cmds.fetch_add(1, Ordering::Relaxed);
+        match res {
+            block::Result::Success => {
+                bytes.fetch_add(size as u64, Ordering::Relaxed);
+            }
+            block::Result::Failure => {
+                self.media_errors.fetch_add(1, Ordering::Relaxed);
+            }
+            block::Result::Unsupported => {}
+        }
+    }
+
+    /// Raise the given Critical Warning bit(s) in the SMART / Health log.
+    pub fn raise_crit_warn(&self, bits: u8) {
+        self.crit_warn.fetch_or(bits, Ordering::Relaxed);
+    }
+
+    /// Note a power cycle of the controller, and whether the host failed to
+    /// shut it down beforehand.
+    pub fn power_cycle(&self, unsafe_shutdown: bool) {
+        self.power_cycles.fetch_add(1, Ordering::Relaxed);
+        if unsafe_shutdown {
+            self.unsafe_shutdowns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Add an entry to the Error Information log for a failed command.
+    pub fn record_error(
+        &self,
+        sqid: QueueId,
+        sub: &RawSubmission,
+        lba: Option<u64>,
+        comp: &Completion,
+    ) {
+        let mut log = self.errors.lock().unwrap();
+        log.count += 1;
+        let entry = ErrorLogEntry {
+            error_count: log.count,
+            sqid,
+            cid: sub.cid(),
+            status: comp.status,
+            // Not tracked
+            param_err_loc: 0xFFFF,
+            lba: lba.unwrap_or(0),
+            nsid: sub.nsid,
+            ..Default::default()
+        };
+        log.entries.push_front(entry);
+        log.entries.truncate(NUM_ERROR_LOG_ENTRIES);
+    }
+
+    /// Place the completion of a command onto its Completion Queue, adding an
+    /// entry to the Error Information log should it indicate failure.
+    pub fn push_completion(
+        &self,
+        sub: &RawSubmission,
+        lba: Option<u64>,
+        comp: Completion,
+        cqe_permit: CompQueueEntryPermit,
+        ctx: &DispCtx,
+    ) {
+        if !comp.is_success() {
+            // No need to log anything if the command was implicitly aborted
+            // along with its Submission Queue
+            if let Some(sqid) = cqe_permit.sqid() {
+                self.record_error(sqid, sub, lba, &comp);
+            }
+        }
+        cqe_permit.push_completion(sub.cid(), comp, ctx);
+    }
+
+    pub fn export(&self) -> migrate::NvmeLogPagesV1 {
+        let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+        migrate::NvmeLogPagesV1 {
+            crit_warn: self.crit_warn.load(Ordering::Relaxed),
+            bytes_read: load(&self.bytes_read),
+            bytes_written: load(&self.bytes_written),
+            host_read_cmds: load(&self.host_read_cmds),
+            host_write_cmds: load(&self.host_write_cmds),
+            media_errors: load(&self.media_errors),
+            power_cycles: load(&self.power_cycles),
+            unsafe_shutdowns: load(&self.unsafe_shutdowns),
+            error_count: self.errors.lock().unwrap().count,
+            power_on_secs: self.power_on_time().as_secs(),
+        }
+    }
+
+    /// Restore the counters of the controller on the source of a migration.
+    ///
+    /// The entries of the Error Information log are not carried over.
+    pub fn import(&self, saved: &migrate::NvmeLogPagesV1) {
+        let store = |v: &AtomicU64, val| v.store(val, Ordering::Relaxed);
+        self.crit_warn.store(saved.crit_warn, Ordering::Relaxed);
+        store(&self.bytes_read, saved.bytes_read);
+        store(&self.bytes_written, saved.bytes_written);
+        store(&self.host_read_cmds, saved.host_read_cmds);
+        store(&self.host_write_cmds, saved.host_write_cmds);
+        store(&self.media_errors, saved.media_errors);
+        store(&self.power_cycles, saved.power_cycles);
+        store(&self.unsafe_shutdowns, saved.unsafe_shutdowns);
+        self.errors.lock().unwrap().count = saved.error_count;
+
+        // Carry on counting from the time accrued on the source
+        let now = Instant::now();
+        let on_time = Duration::from_secs(saved.power_on_secs);
+        *self.powered_on.lock().unwrap() =
+            now.checked_sub(on_time).unwrap_or(now);
+    }
+
+    /// Time for which the controller has been powered on.
+    fn power_on_time(&self) -> Duration {
+        self.powered_on.lock().unwrap().elapsed()
+    }
+
+    /// The Error Information log page, with the most recent error first.
+    pub fn error_log(&self) -> [ErrorLogEntry; NUM_ERROR_LOG_ENTRIES] {
+        let log = self.errors.lock().unwrap();
+        let mut page = [ErrorLogEntry::default(); NUM_ERROR_LOG_ENTRIES];
+        for (slot, entry) in page.iter_mut().zip(log.entries.iter()) {
+            *slot = *entry;
+        }
+        page
+    }
+
+    /// The SMART / Health Information log page.
+    pub fn smart_log(&self) -> SmartLog {
+        let load = |v: &AtomicU64| v.load(Ordering::Relaxed) as u128;
+        // Reported in thousands of data units, rounded up
+        let data_units = |v: &AtomicU64| {
+            let units = load(v) / DATA_UNIT_SIZE as u128;
+            (units + 999) / 1000
+        };
+        let power_on_hours = self.power_on_time().as_secs() / 3600;
+
+        SmartLog {
+            crit_warn: self.crit_warn.load(Ordering::Relaxed),
+            temp: TEMPERATURE,
+            avail_spare: 100,
+            avail_spare_thresh: 10,
+            data_units_read: data_units(&self.bytes_read),
+            data_units_written: data_units(&self.bytes_written),
+            host_read_cmds: load(&self.host_read_cmds),
+            host_write_cmds: load(&self.host_write_cmds),
+            power_cycles: load(&self.power_cycles),
+            power_on_hours: power_on_hours as u128,
+            unsafe_shutdowns: load(&self.unsafe_shutdowns),
+            media_errors: load(&self.media_errors),
+            num_err_log_entries: self.errors.lock().unwrap().count as u128,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hw::nvme::bits;
+
+    fn sub(cid: u16, nsid: u32) -> RawSubmission {
+        RawSubmission { cdw0: (cid as u32) << 16, nsid, ..Default::default() }
+    }
+
+    #[test]
+    fn error_log_order() {
+        let logs = LogPages::new();
+        let comp = Completion::generic_err(bits::STS_DATA_XFER_ERR);
+        for cid in 0..(NUM_ERROR_LOG_ENTRIES as u16 + 2) {
+            logs.record_error(1, &sub(cid, 1), Some(cid as u64), &comp);
+        }
+
+        let page = logs.error_log();
+        // Most recent first, with the oldest entries having been dropped
+        assert_eq!(page[0].error_count, NUM_ERROR_LOG_ENTRIES as u64 + 2);
+        assert_eq!(page[0].cid, NUM_ERROR_LOG_ENTRIES as u16 + 1);
+        assert_eq!(page[0].lba, NUM_ERROR_LOG_ENTRIES as u64 + 1);
+        assert_eq!(page[NUM_ERROR_LOG_ENTRIES - 1].error_count, 3);
+        assert_eq!(page[0].status, comp.status);
+
+        let smart = logs.smart_log();
+        assert_eq!({ smart.num_err_log_entries }, 66);
+    }
+
+    #[test]
+    fn smart_counters() {
+        let logs = LogPages::new();
+        // 1000 data units, plus a bit more to be rounded up
+        logs.record_io(Operation::Read(0), 512 * 1000, block::Result::Success);
+        logs.record_io(Operation::Read(0), 512, block::Result::Success);
+        logs.record_io(Operation::Read(0), 512, block::Result::Failure);
+        logs.record_io(Operation::Write(0), 4096, block::Result::Success);
+        logs.record_io(Operation::Flush(0, 0), 0, block::Result::Success);
+        logs.power_cycle(true);
+        logs.power_cycle(false);
+
+        let smart = logs.smart_log();
+        assert_eq!({ smart.data_units_read }, 2);
+        assert_eq!({ smart.data_units_written }, 1);
+        assert_eq!({ smart.host_read_cmds }, 3);
+        assert_eq!({ smart.host_write_cmds }, 1);
+        assert_eq!({ smart.media_errors }, 1);
+        assert_eq!({ smart.power_cycles }, 3);
+        assert_eq!({ smart.unsafe_shutdowns }, 1);
+    }
+
+    #[test]
+    fn power_on_hours_migrated() {
+        let src = LogPages::new();
+        let mut saved = src.export();
+        assert_eq!(saved.power_on_secs, 0);
+
+        // Time accrued on the source counts towards that on the destination
+        saved.power_on_secs = 3 * 3600 + 1800;
+        let dst = LogPages::new();
+        dst.import(&saved);
+        assert_eq!({ dst.smart_log().power_on_hours }, 3);
+        assert!(dst.export().power_on_secs >= saved.power_on_secs);
+    }
+}
@@ -1,30 +1,88 @@
 use std::convert::TryInto;
 use std::mem::size_of;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex, MutexGuard};
 
 use crate::dispatch::DispCtx;
 use crate::hw::pci;
-use crate::migrate::{Migrate, Migrator};
+use crate::migrate::{Migrate, MigrateStateError, Migrator};
 use crate::util::regmap::RegMap;
 use crate::{block, common::*};
 
-use erased_serde::Serialize;
+use erased_serde::{Deserializer, Serialize};
 use futures::future::BoxFuture;
 use lazy_static::lazy_static;
 use thiserror::Error;
 
 mod admin;
+mod arbitration;
 mod bits;
 mod cmds;
+mod events;
+mod features;
+mod identity;
+mod log_page;
+mod ns;
 mod queue;
 mod requests;
+#[cfg(test)]
+mod test_util;
 
+use arbitration::Arbiter;
 use bits::*;
-use queue::{CompQueue, QueueId, SubQueue};
+use events::AsyncEvents;
+use features::Features;
+use log_page::{LogPages, NUM_ERROR_LOG_ENTRIES};
+use ns::{NsIds, NsState, MAX_NUM_NAMESPACES, OXIDE_OUI};
+use queue::{
+    CompQueue, CompQueueEntryPermit, DoorbellBuffers, QueueId, SubQueue,
+};
+
+pub use events::{AsyncEvent, ErrorEvent, SmartEvent};
+pub use identity::{CtrlIdentity, NsIdentity};
+pub use ns::NvmeNs;
 
 /// The max number of MSI-X interrupts we support
 const NVME_MSIX_COUNT: u16 = 1024;
 
+/// The revision of the NVMe specification presented to the host
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NvmeVersion {
+    V1_0,
+    V1_3,
+    V1_4,
+}
+
+impl NvmeVersion {
+    /// The value of the Version (VS) register for this revision
+    fn vs(self) -> u32 {
+        match self {
+            NvmeVersion::V1_0 => NVME_VER_1_0,
+            NvmeVersion::V1_3 => NVME_VER_1_3,
+            NvmeVersion::V1_4 => NVME_VER_1_4,
+        }
+    }
+}
+
+impl Default for NvmeVersion {
+    fn default() -> Self {
+        NvmeVersion::V1_0
+    }
+}
+
+impl FromStr for NvmeVersion {
+    type Err = NvmeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1.0" => Ok(NvmeVersion::V1_0),
+            "1.3" => Ok(NvmeVersion::V1_3),
+            "1.4" => Ok(NvmeVersion::V1_4),
+            _ => Err(NvmeError::UnsupportedVersion(s.to_string())),
+        }
+    }
+}
+
 /// NVMe errors
 #[derive(Debug, Error)]
 pub enum NvmeError {
@@ -90,6 +148,19 @@ pub enum NvmeError {
     /// The specified Namespace ID did not correspond to a valid Namespace
     #[error("the namespace specified ({0}) is invalid")]
     InvalidNamespace(u32),
+
+    /// Unsupported revision of the NVMe specification requested
+    #[error("the requested NVMe version ({0}) is unsupported")]
+    UnsupportedVersion(String),
+
+    /// A configured identifying detail is unfit for its Identify field
+    #[error("invalid {0}: {1}")]
+    InvalidIdentity(&'static str, String),
+
+    /// No Completion Queue entry could be reserved for a command carried over
+    /// from the source of a migration
+    #[error("no completion queue entry available for submission queue ({0})")]
+    CompQueueEntryUnavailable(QueueId),
 }
 
 /// Internal NVMe Controller State
@@ -142,14 +213,34 @@ struct NvmeCtrl {
     /// The list of Submission Queues handled by the controller
     sqs: [Option<Arc<SubQueue>>; MAX_NUM_QUEUES],
 
+    /// Arbitration between the I/O Submission Queues
+    arbiter: Arbiter,
+
+    /// The Shadow Doorbell and EventIdx buffers for the I/O queues, if the
+    /// host has provided them with a Doorbell Buffer Config command
+    doorbell_bufs: Option<DoorbellBuffers>,
+
+    /// The revision of the NVMe specification presented to the host
+    version: NvmeVersion,
+
     /// The Identify structure returned for Identify controller commands
     ctrl_ident: IdentifyController,
 
-    /// The Identify structure returned for Identify namespace commands
-    ns_ident: IdentifyNamespace,
+    /// The attached namespaces, indexed by NSID - 1
+    namespaces: Vec<NsState>,
+
+    /// Asynchronous Event Requests and the events to be reported with them
+    events: AsyncEvents,
 
-    /// Underlying Block Device info
-    binfo: block::DeviceInfo,
+    /// Counters and error history reported through the log pages
+    logs: Arc<LogPages>,
+
+    /// Current and saved values of the features managed by Set/Get Features
+    features: Features,
+
+    /// The Number of Queues allocated in response to the host's request
+    /// (as Set Features Completion Dword 0), if it has made one
+    num_queues: Option<u32>,
 
     /// Whether or not we should service guest commands
     paused: bool,
@@ -203,6 +294,9 @@ impl NvmeCtrl {
             .ok_or(NvmeError::MsixHdlUnavailable)?
             .clone();
         let cq = CompQueue::new(cqid, iv, size, base, ctx, msix_hdl)?;
+        if let Some(shadow) = self.doorbell_bufs.and_then(|b| b.cq(cqid)) {
+            cq.set_shadow(shadow, ctx);
+        }
         self.cqs[cqid as usize] = Some(Arc::new(cq));
         Ok(())
     }
@@ -227,6 +321,9 @@ impl NvmeCtrl {
         }
         let cq = self.get_cq(cqid)?;
         let sq = SubQueue::new(sqid, cq, size, base, ctx)?;
+        if let Some(shadow) = self.doorbell_bufs.and_then(|b| b.sq(sqid)) {
+            sq.set_shadow(shadow, ctx);
+        }
         self.sqs[sqid as usize] = Some(sq);
         Ok(())
     }
@@ -264,6 +361,7 @@ impl NvmeCtrl {
 
         // Remove it from the authoritative list of SQs
         self.sqs[sqid as usize] = None;
+        self.arbiter.remove_sq(sqid);
         Ok(())
     }
 
@@ -328,9 +426,15 @@ impl NvmeCtrl {
 
             // These may only be configured while we're disabled
             if !self.ctrl.cc.enabled() {
-                // We only support round robin arbitration
-                if cc.ams() != ArbitrationMechanism::RoundRobin {
-                    return Err(NvmeError::UnsupportedArbitrationMechanism);
+                // We support round robin arbitration, along with weighted
+                // round robin as advertised in CAP.AMS
+                match cc.ams() {
+                    ArbitrationMechanism::RoundRobin => {}
+                    ArbitrationMechanism::WeightedRoundRobinWithUrgent
+                        if self.ctrl.cap.ams_wrr() => {}
+                    _ => {
+                        return Err(NvmeError::UnsupportedArbitrationMechanism)
+                    }
                 }
 
                 // We only supported an MPS of 0 (4K pages)
@@ -391,19 +495,237 @@ impl NvmeCtrl {
         for cq in &mut self.cqs {
             *cq = None;
         }
+        self.arbiter.reset();
+        self.doorbell_bufs = None;
 
         // Clear the CC & CSTS registers
         // Sets CC.EN=0 and CSTS.RDY=0
         self.ctrl.cc = Configuration(0);
         self.ctrl.csts = Status(0);
 
+        // Any outstanding AERs went away along with the admin queues
+        self.events.reset();
+
+        // Features revert to their saved values, and the host must again
+        // request the number of queues it wants
+        self.features.reset();
+        self.num_queues = None;
+
+        // As did any I/O commands not yet picked up by their namespace
+        for ns in &self.namespaces {
+            ns.dev.routed.lock().unwrap().clear();
+            ns.dev.deallocs.lock().unwrap().clear();
+        }
+
         // The other registers (e.g. CAP/VS) we never modify
         // and thus don't need to do anything on reset
     }
 
-    /// Convert some number of logical blocks to bytes with the currently active LBA data size
-    fn nlb_to_size(&self, b: usize) -> usize {
-        b << (self.ns_ident.lbaf[(self.ns_ident.flbas & 0xF) as usize]).lbads
+    /// Returns the namespace with the given `nsid`, if one is attached.
+    fn ns(&self, nsid: u32) -> Option<&NsState> {
+        let idx = nsid.checked_sub(1)? as usize;
+        self.namespaces.get(idx)
+    }
+
+    /// Update a namespace to reflect the given media (or lack thereof) and
+    /// notify the host of the change.
+    fn set_media(
+        &mut self,
+        nsid: u32,
+        binfo: Option<block::DeviceInfo>,
+        ctx: &DispCtx,
+    ) {
+        let idx = nsid as usize - 1;
+        self.namespaces[idx].set_media(binfo);
+
+        if binfo.is_none() {
+            // Fail anything already routed to the namespace
+            let ns = &self.namespaces[idx];
+            let routed: Vec<_> =
+                ns.dev.routed.lock().unwrap().drain(..).collect();
+            for (sub, cqe_permit) in routed {
+                let comp = cmds::Completion::generic_err(STS_NS_NOT_READY);
+                self.logs.push_completion(&sub, None, comp, cqe_permit, ctx);
+            }
+
+            // With no namespace left to pick up I/O commands, dispose of any
+            // which are pending here instead.
+            if !self.paused && !self.any_media() {
+                self.route_pending_io(ctx);
+            }
+        }
+        self.raise_event(AsyncEvent::NsAttrChanged(nsid), ctx);
+    }
+
+    /// Is media present in any of the attached namespaces?
+    fn any_media(&self) -> bool {
+        self.namespaces.iter().any(|ns| ns.binfo.is_some())
+    }
+
+    /// Pass an I/O command on to the namespace it targets, or complete it with
+    /// an error if there is no such namespace able to service it.
+    fn route_io(
+        &self,
+        sub: RawSubmission,
+        cqe_permit: CompQueueEntryPermit,
+        ctx: &DispCtx,
+    ) {
+        let status = match self.ns(sub.nsid) {
+            Some(ns) if ns.binfo.is_some() => {
+                ns.dev.routed.lock().unwrap().push_back((sub, cqe_permit));
+                ns.dev.notifier.notify(&*ns.dev, ctx);
+                return;
+            }
+            Some(_) => STS_NS_NOT_READY,
+            None => STS_INVALID_NS,
+        };
+        let comp = cmds::Completion::generic_err(status);
+        self.logs.push_completion(&sub, None, comp, cqe_permit, ctx);
+    }
+
+    /// Route all pending I/O commands to their respective namespaces.
+    fn route_pending_io(&self, ctx: &DispCtx) {
+        for sq in self.sqs.iter().skip(1).flatten() {
+            while let Some((sub, cqe_permit)) = sq.pop(ctx) {
+                self.route_io(sub, cqe_permit, ctx);
+            }
+        }
+    }
+
+    /// Report an asynchronous event to the host.
+    ///
+    /// The event is reported via the next outstanding Asynchronous Event
+    /// Request, or queued until the host issues one.
+    ///
+    /// See NVMe 1.0e Section 5.2 Asynchronous Event Request command
+    fn raise_event(&mut self, event: AsyncEvent, ctx: &DispCtx) {
+        if let AsyncEvent::SmartHealth(e) = event {
+            // Reflect the condition in the SMART / Health log
+            self.logs.raise_crit_warn(e.crit_warn());
+        }
+        if !self.ctrl.cc.enabled() {
+            return;
+        }
+        let config = self.features.async_event_cfg();
+        self.events.raise(event, config, ctx);
+    }
+
+    /// Report an invalid doorbell write by the host, for which there is no
+    /// command to fail instead.
+    fn doorbell_error(&mut self, err: ErrorEvent, ctx: &DispCtx) {
+        self.raise_event(AsyncEvent::Error(err), ctx);
+    }
+
+    fn export(&self) -> migrate::NvmeCtrlStateV1 {
+        let pending_cmds = self
+            .namespaces
+            .iter()
+            .flat_map(|ns| ns.dev.pending_cmds())
+            .map(|(sqid, sub)| migrate::NvmeSubmissionV1::from_raw(sqid, &sub))
+            .collect();
+
+        migrate::NvmeCtrlStateV1 {
+            regs: migrate::NvmeCtrlRegsV1 {
+                cc: self.ctrl.cc.0,
+                csts: self.ctrl.csts.0,
+                aqa: self.ctrl.aqa.0,
+                asq: self.ctrl.admin_sq_base,
+                acq: self.ctrl.admin_cq_base,
+            },
+            cqs: self.cqs.iter().flatten().map(|cq| cq.export()).collect(),
+            sqs: self.sqs.iter().flatten().map(|sq| sq.export()).collect(),
+            sq_prios: self
+                .arbiter
+                .priorities()
+                .map(|(sqid, prio)| (sqid, prio as u8))
+                .collect(),
+            features: self.features.export(),
+            num_queues: self.num_queues,
+            events: self.events.export(),
+            logs: self.logs.export(),
+            pending_cmds,
+            doorbell_bufs: self
+                .doorbell_bufs
+                .map(|bufs| (bufs.shadow.0, bufs.event_idx.0)),
+        }
+    }
+
+    /// Restore the state of the controller on the destination of a migration.
+    ///
+    /// Any commands which were outstanding on the source (Asynchronous Event
+    /// Requests and I/O commands) are taken up again here, reserving their
+    /// Completion Queue entries anew.
+    fn import(
+        &mut self,
+        saved: &migrate::NvmeCtrlStateV1,
+        ctx: &DispCtx,
+    ) -> Result<(), NvmeError> {
+        // Start from a clean slate, as if the host had just disabled us
+        self.reset();
+
+        self.ctrl.cc = Configuration(saved.regs.cc);
+        self.ctrl.csts = Status(saved.regs.csts);
+        self.ctrl.aqa = AdminQueueAttrs(saved.regs.aqa);
+        self.ctrl.admin_sq_base = saved.regs.asq;
+        self.ctrl.admin_cq_base = saved.regs.acq;
+
+        // The queues are created before the buffers are set so their shadow
+        // doorbells are left as the host last wrote them
+        let bufs =
+            saved.doorbell_bufs.map(|(shadow, event_idx)| DoorbellBuffers {
+                shadow: GuestAddr(shadow),
+                event_idx: GuestAddr(event_idx),
+            });
+
+        // CQs must exist before any of the SQs associated with them
+        for saved_cq in &saved.cqs {
+            let base = GuestAddr(saved_cq.base);
+            self.create_cq(saved_cq.id, saved_cq.iv, base, saved_cq.size, ctx)?;
+            let shadow = bufs.and_then(|b| b.cq(saved_cq.id));
+            self.get_cq(saved_cq.id)?.import(saved_cq, shadow)?;
+        }
+        for saved_sq in &saved.sqs {
+            let base = GuestAddr(saved_sq.base);
+            self.create_sq(
+                saved_sq.id,
+                saved_sq.cqid,
+                base,
+                saved_sq.size,
+                ctx,
+            )?;
+            let shadow = bufs.and_then(|b| b.sq(saved_sq.id));
+            self.get_sq(saved_sq.id)?.import(saved_sq, shadow)?;
+        }
+        self.doorbell_bufs = bufs;
+        for (sqid, prio) in &saved.sq_prios {
+            self.get_sq(*sqid)?;
+            self.arbiter.add_sq(*sqid, cmds::QueuePriority::from(*prio));
+        }
+
+        self.features.import(&saved.features);
+        self.num_queues = saved.num_queues;
+        self.logs.import(&saved.logs);
+
+        self.events.import(&saved.events);
+        if !saved.events.aer_cids.is_empty() {
+            let admin_sq = self.get_sq(queue::ADMIN_QUEUE_ID)?;
+            for cid in &saved.events.aer_cids {
+                let cqe_permit = admin_sq.reserve_entry().ok_or(
+                    NvmeError::CompQueueEntryUnavailable(queue::ADMIN_QUEUE_ID),
+                )?;
+                self.events.request(*cid, cqe_permit, ctx);
+            }
+        }
+
+        for cmd in &saved.pending_cmds {
+            let sq = self.get_sq(cmd.sqid)?;
+            let cqe_permit = sq
+                .reserve_entry()
+                .ok_or(NvmeError::CompQueueEntryUnavailable(cmd.sqid))?;
+            self.route_io(cmd.to_raw(), cqe_permit, ctx);
+        }
+
+        Ok(())
     }
 }
 
@@ -412,21 +734,21 @@ pub struct PciNvme {
     /// NVMe Controller
     state: Mutex<NvmeCtrl>,
 
-    /// Underlying Block Device notifier
-    notifier: block::Notifier,
-
     /// PCI device state
     pci_state: pci::DeviceState,
 }
 
 impl PciNvme {
     /// Create a new pci-nvme device with the given values
+    ///
+    /// The serial number, unless given in `ident`, is taken from `name`.
     pub fn create(
         vendor: u16,
         device: u16,
-        serial_number: String,
-        binfo: block::DeviceInfo,
-    ) -> Arc<Self> {
+        name: String,
+        version: NvmeVersion,
+        ident: &CtrlIdentity,
+    ) -> Result<Arc<Self>, NvmeError> {
         let builder = pci::Builder::new(pci::Ident {
             vendor_id: vendor,
             device_id: device,
@@ -445,53 +767,42 @@ impl PciNvme {
         let cqes = size_of::<RawCompletion>().trailing_zeros() as u8;
         let sqes = size_of::<RawSubmission>().trailing_zeros() as u8;
 
-        let sz = std::cmp::min(20, serial_number.len());
-        let mut sn: [u8; 20] = [0u8; 20];
-        sn[..sz].clone_from_slice(&serial_number.as_bytes()[..sz]);
-
         // Initialize the Identify structure returned when the host issues
         // an Identify Controller command.
-        let ctrl_ident = bits::IdentifyController {
+        let mut ctrl_ident = bits::IdentifyController {
             vid: vendor,
             ssvid: vendor,
-            sn,
-            ieee: [0xA8, 0x40, 0x25], // Oxide OUI
+            ieee: OXIDE_OUI,
             // We use standard Completion/Submission Queue Entry structures with no extra
             // data, so required (minimum) == maximum
             sqes: NvmQueueEntrySize(0).with_maximum(sqes).with_required(sqes),
             cqes: NvmQueueEntrySize(0).with_maximum(cqes).with_required(cqes),
-            // Supporting multiple namespaces complicates I/O dispatching,
-            // so for now we limit the device to a single namespace.
-            nn: 1,
+            // Allow up to 4 outstanding Asynchronous Event Requests
+            // (0's based value)
+            aerl: 3,
+            // Number of Error Information log entries we keep (0's based)
+            elpe: (NUM_ERROR_LOG_ENTRIES - 1) as u8,
+            // Updated as namespaces are attached
+            nn: 0,
             // bit 0 indicates volatile write cache is present
             vwc: 1,
+            // Dataset Management (Deallocate) and Write Zeroes
+            oncs: ONCS_DATASET_MGMT | ONCS_WRITE_ZEROES,
             ..Default::default()
         };
-
-        // Initialize the Identify structure returned when the  host issues
-        // an Identify Namespace command.
-        let nsze = binfo.total_size;
-        let mut ns_ident = bits::IdentifyNamespace {
-            // No thin provisioning so nsze == ncap == nuse
-            nsze,
-            ncap: nsze,
-            nuse: nsze,
-            nlbaf: 0, // We only support a single LBA format (1 but 0-based)
-            flbas: 0, // And it is at index 0 in the lbaf array
-            ..Default::default()
-        };
-
-        // Update the block format we support
-        debug_assert!(
-            binfo.block_size.is_power_of_two(),
-            "binfo.block_size must be a power of 2"
-        );
-        debug_assert!(
-            binfo.block_size >= 512,
-            "binfo.block_size must be at least 512 bytes"
-        );
-
-        ns_ident.lbaf[0].lbads = binfo.block_size.trailing_zeros() as u8;
+        ident.apply(&name, &mut ctrl_ident)?;
+        if version >= NvmeVersion::V1_3 {
+            ctrl_ident.ver = version.vs();
+            // Feature values may be saved, and the Select field of Get
+            // Features used, neither of which exist before NVMe 1.1
+            ctrl_ident.oncs |= ONCS_FEAT_SAVE_SELECT;
+            // We raise Namespace Attribute Changed events (e.g. when media is
+            // inserted or removed)
+            ctrl_ident.oaes = OAES_NS_ATTR;
+            ctrl_ident.subnqn = subnqn(&ctrl_ident);
+            // Guests may use shadow doorbells to avoid most doorbell writes
+            ctrl_ident.oacs = OACS_DOORBELL_BUF_CFG;
+        }
 
         // Initialize the CAP "register" leaving most values
         // at their defaults (0):
@@ -505,6 +816,8 @@ impl PciNvme {
             .with_mqes((queue::MAX_QUEUE_SIZE - 1) as u16)
             // I/O Queues must be physically contiguous
             .with_cqr(true)
+            // Weighted Round Robin with Urgent Priority Class arbitration
+            .with_ams_wrr(true)
             // We support the NVM command set
             .with_css_nvm(true);
 
@@ -530,9 +843,15 @@ impl PciNvme {
             msix_hdl: None,
             cqs: Default::default(),
             sqs: Default::default(),
+            arbiter: Arbiter::default(),
+            doorbell_bufs: None,
+            version,
             ctrl_ident,
-            ns_ident,
-            binfo,
+            namespaces: Vec::new(),
+            events: AsyncEvents::new(),
+            logs: Arc::new(LogPages::new()),
+            features: Features::default(),
+            num_queues: None,
             paused: false,
         };
 
@@ -545,11 +864,48 @@ impl PciNvme {
             .add_cap_msix(pci::BarN::BAR4, NVME_MSIX_COUNT)
             .finish();
 
-        Arc::new(PciNvme {
-            state: Mutex::new(state),
-            notifier: block::Notifier::new(),
-            pci_state,
-        })
+        Ok(Arc::new(PciNvme { state: Mutex::new(state), pci_state }))
+    }
+
+    /// Attach a namespace backed by a block device described by `binfo`.
+    ///
+    /// Namespaces are assigned NSIDs in the order they are attached, starting
+    /// from 1.  The returned [`NvmeNs`] is the [`block::Device`] to which the
+    /// backend for the namespace should be attached.
+    pub fn add_ns(
+        self: &Arc<Self>,
+        binfo: block::DeviceInfo,
+        ident: &NsIdentity,
+    ) -> Result<Arc<NvmeNs>, NvmeError> {
+        let mut state = self.state.lock().unwrap();
+        if state.namespaces.len() >= MAX_NUM_NAMESPACES {
+            return Err(NvmeError::TooManyNamespaces);
+        }
+        let nsid = state.namespaces.len() as u32 + 1;
+        let dev = Arc::new(NvmeNs::new(nsid, Arc::downgrade(self)));
+        let mut ns = NsState::new(binfo, Arc::clone(&dev));
+        let mut ids = NsIds::default();
+        if state.version >= NvmeVersion::V1_3 {
+            let ctrl_ident = &state.ctrl_ident;
+            ids = NsIds::derive(ctrl_ident.ieee, &ctrl_ident.sn, nsid);
+        }
+        // Any identifiers configured are reported whatever the version
+        ident.apply(&mut ids)?;
+        ns.set_ids(ids);
+        state.namespaces.push(ns);
+        state.ctrl_ident.nn = nsid;
+        Ok(dev)
+    }
+
+    /// Report an asynchronous event to the host.
+    ///
+    /// This allows the rest of the system (e.g. a block backend noticing the
+    /// failure of its underlying storage) to signal conditions to the host.
+    /// Events of a kind already reported are suppressed until the host reads
+    /// the associated log page.
+    pub fn raise_event(&self, event: AsyncEvent, ctx: &DispCtx) {
+        let mut state = self.state.lock().unwrap();
+        state.raise_event(event, ctx);
     }
 
     /// Service a write to the NVMe Controller Configuration from the VM
@@ -609,7 +965,8 @@ impl PciNvme {
                 ro.write_u64(state.ctrl.cap.0);
             }
             CtrlrReg::Version => {
-                ro.write_u32(NVME_VER_1_0);
+                let state = self.state.lock().unwrap();
+                ro.write_u32(state.version.vs());
             }
 
             CtrlrReg::IntrMaskSet | CtrlrReg::IntrMaskClear => {
@@ -700,18 +1057,24 @@ impl PciNvme {
 
             CtrlrReg::DoorBellAdminSQ => {
                 let val = wo.read_u32().try_into().unwrap();
-                let state = self.state.lock().unwrap();
+                let mut state = self.state.lock().unwrap();
                 let admin_sq = state.get_admin_sq();
-                admin_sq.notify_tail(val)?;
+                admin_sq.notify_tail(val).map_err(|e| {
+                    state.doorbell_error(ErrorEvent::InvalidDoorbellValue, ctx);
+                    e
+                })?;
 
                 // Process any new SQ entries
                 self.process_admin_queue(state, admin_sq, ctx)?;
             }
             CtrlrReg::DoorBellAdminCQ => {
                 let val = wo.read_u32().try_into().unwrap();
-                let state = self.state.lock().unwrap();
+                let mut state = self.state.lock().unwrap();
                 let admin_cq = state.get_admin_cq();
-                admin_cq.notify_head(val)?;
+                admin_cq.notify_head(val).map_err(|e| {
+                    state.doorbell_error(ErrorEvent::InvalidDoorbellValue, ctx);
+                    e
+                })?;
 
                 // We may have skipped pulling entries off the admin sq
                 // due to no available completion entry permit, so just
@@ -734,13 +1097,22 @@ impl PciNvme {
                 let off = wo.offset() - 0x1000;
 
                 let val: u16 = wo.read_u32().try_into().unwrap();
-                let state = self.state.lock().unwrap();
+                let mut state = self.state.lock().unwrap();
 
                 if (off >> 2) & 0b1 == 0b1 {
                     // Completion Queue y Head Doorbell
                     let y = (off - 4) >> 3;
-                    let cq = state.get_cq(y as u16)?;
-                    cq.notify_head(val)?;
+                    let cq = state.get_cq(y as u16).map_err(|e| {
+                        state.doorbell_error(ErrorEvent::InvalidDoorbell, ctx);
+                        e
+                    })?;
+                    cq.notify_head(val).map_err(|e| {
+                        state.doorbell_error(
+                            ErrorEvent::InvalidDoorbellValue,
+                            ctx,
+                        );
+                        e
+                    })?;
 
                     // We may have skipped pulling entries off some SQ due to this
                     // CQ having no available entry slots. Since we've just free'd
@@ -748,17 +1120,26 @@ impl PciNvme {
                     // TODO: worth kicking only the SQs specifically associated
                     //       with this CQ?
                     if !state.paused && cq.kick() {
-                        self.notifier.notify(self, ctx);
+                        self.notify_io(&state, ctx);
                     }
                 } else {
                     // Submission Queue y Tail Doorbell
                     let y = off >> 3;
-                    let sq = state.get_sq(y as u16)?;
-                    sq.notify_tail(val)?;
+                    let sq = state.get_sq(y as u16).map_err(|e| {
+                        state.doorbell_error(ErrorEvent::InvalidDoorbell, ctx);
+                        e
+                    })?;
+                    sq.notify_tail(val).map_err(|e| {
+                        state.doorbell_error(
+                            ErrorEvent::InvalidDoorbellValue,
+                            ctx,
+                        );
+                        e
+                    })?;
 
                     // Poke block device to service new requests
                     if !state.paused {
-                        self.notifier.notify(self, ctx);
+                        self.notify_io(&state, ctx);
                     }
                 }
             }
@@ -767,6 +1148,19 @@ impl PciNvme {
         Ok(())
     }
 
+    /// Notify the namespaces of new I/O requests, or fail them outright if
+    /// there is no media to service them.
+    fn notify_io(&self, state: &NvmeCtrl, ctx: &DispCtx) {
+        let mut notified = false;
+        for ns in state.namespaces.iter().filter(|ns| ns.binfo.is_some()) {
+            ns.dev.notifier.notify(&*ns.dev, ctx);
+            notified = true;
+        }
+        if !notified {
+            state.route_pending_io(ctx);
+        }
+    }
+
     /// Process any new entries in the Admin Submission Queue
     fn process_admin_queue(
         &self,
@@ -802,21 +1196,39 @@ impl PciNvme {
                 AdminCmd::SetFeatures(cmd) => {
                     state.acmd_set_features(&cmd, ctx)
                 }
+                AdminCmd::GetFeatures(cmd) => {
+                    state.acmd_get_features(&cmd, ctx)
+                }
                 AdminCmd::DeleteIOCompQ(cqid) => {
                     state.acmd_delete_io_cq(cqid, ctx)
                 }
                 AdminCmd::DeleteIOSubQ(sqid) => {
                     state.acmd_delete_io_sq(sqid, ctx)
                 }
-                AdminCmd::Abort
-                | AdminCmd::GetFeatures
-                | AdminCmd::AsyncEventReq
-                | AdminCmd::Unknown(_) => {
+                AdminCmd::DoorbellBufCfg(cmd) => {
+                    state.acmd_doorbell_buf_cfg(&cmd, ctx)
+                }
+                AdminCmd::AsyncEventReq => {
+                    if state.events.outstanding()
+                        > state.ctrl_ident.aerl as usize
+                    {
+                        cmds::Completion::specific_err(
+                            bits::StatusCodeType::CmdSpecific,
+                            bits::STS_ASYNC_EVENT_LIMIT_EXCEEDED,
+                        )
+                    } else {
+                        // Hold onto the request until there is an event to
+                        // report with it
+                        state.events.request(sub.cid(), cqe_permit, ctx);
+                        continue;
+                    }
+                }
+                AdminCmd::Abort | AdminCmd::Unknown(_) => {
                     cmds::Completion::generic_err(bits::STS_INTERNAL_ERR)
                 }
             };
 
-            cqe_permit.push_completion(sub.cid(), comp, ctx);
+            state.logs.push_completion(&sub, None, comp, cqe_permit, ctx);
         }
 
         // Notify for any newly added completions
@@ -826,6 +1238,29 @@ impl PciNvme {
     }
 }
 
+/// The NVM Subsystem NVMe Qualified Name for a controller which hasn't been
+/// assigned one, made up of its PCI Vendor & Subsystem Vendor IDs, Serial
+/// Number and Model Number.
+///
+/// See NVMe 1.3 Section 7.9 NVMe Qualified Names
+fn subnqn(ident: &IdentifyController) -> [u8; 256] {
+    let prefix = format!(
+        "nqn.2014.08.org.nvmexpress:{:04x}{:04x}",
+        ident.vid, ident.ssvid
+    );
+    let name = prefix
+        .bytes()
+        .chain(ident.sn.iter().copied())
+        .chain(ident.mn.iter().copied());
+
+    // Leave the remainder zeroed, which also terminates the string
+    let mut nqn = [0u8; 256];
+    for (b, c) in nqn.iter_mut().zip(name) {
+        *b = c;
+    }
+    nqn
+}
+
 impl pci::Device for PciNvme {
     fn bar_rw(&self, bar: pci::BarN, mut rwo: RWOp, ctx: &DispCtx) {
         assert_eq!(bar, pci::BarN::BAR0);
@@ -872,6 +1307,13 @@ impl Entity for PciNvme {
 
     fn reset(&self, _ctx: &DispCtx) {
         let mut ctrl = self.state.lock().unwrap();
+
+        // A reset of the machine is as good as a power cycle, which is unsafe
+        // if the host did not first shut the controller down.
+        let unsafe_shutdown = ctrl.ctrl.cc.enabled()
+            && ctrl.ctrl.csts.shst() != ShutdownStatus::Complete;
+        ctrl.logs.power_cycle(unsafe_shutdown);
+
         ctrl.reset();
         self.pci_state.reset(self);
     }
@@ -883,15 +1325,20 @@ impl Entity for PciNvme {
         assert!(!ctrl.paused);
         ctrl.paused = true;
 
-        self.notifier.pause();
+        for ns in &ctrl.namespaces {
+            ns.dev.notifier.pause();
+        }
     }
 
     fn paused(&self) -> BoxFuture<'static, ()> {
         let ctrl = self.state.lock().unwrap();
         assert!(ctrl.paused);
 
-        let block_paused = self.notifier.paused();
-        Box::pin(async move { block_paused.await })
+        let block_paused: Vec<_> =
+            ctrl.namespaces.iter().map(|ns| ns.dev.notifier.paused()).collect();
+        Box::pin(async move {
+            futures::future::join_all(block_paused).await;
+        })
     }
 
     fn migrate(&self) -> Migrator {
@@ -900,18 +1347,185 @@ impl Entity for PciNvme {
 }
 impl Migrate for PciNvme {
     fn export(&self, _ctx: &DispCtx) -> Box<dyn Serialize> {
-        Box::new(migrate::PciNvmeStateV1 { pci: self.pci_state.export() })
+        let ctrl = self.state.lock().unwrap();
+        Box::new(migrate::PciNvmeStateV1 {
+            pci: self.pci_state.export(),
+            ctrl: ctrl.export(),
+        })
+    }
+
+    fn import(
+        &self,
+        _dev: &str,
+        deserializer: &mut dyn Deserializer,
+        ctx: &DispCtx,
+    ) -> Result<(), MigrateStateError> {
+        let saved: migrate::PciNvmeStateV1 =
+            erased_serde::deserialize(deserializer)?;
+
+        self.pci_state.import(self, saved.pci)?;
+
+        let mut ctrl = self.state.lock().unwrap();
+        ctrl.import(&saved.ctrl, ctx)
+            .map_err(|e| MigrateStateError::ImportFailed(e.to_string()))
     }
 }
 
 pub mod migrate {
     use crate::hw::pci::migrate::PciStateV1;
-    use serde::Serialize;
+    use serde::{Deserialize, Serialize};
+
+    use super::bits::RawSubmission;
+    use super::events::AsyncEvent;
+    use super::queue::QueueId;
+
+    #[derive(Deserialize, Serialize)]
+    pub struct NvmeCtrlRegsV1 {
+        pub cc: u32,
+        pub csts: u32,
+        pub aqa: u32,
+        pub asq: u64,
+        pub acq: u64,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct NvmeCompQueueV1 {
+        pub id: u16,
+        pub iv: u16,
+        pub base: u64,
+        pub size: u32,
+        pub head: u16,
+        pub tail: u16,
+        pub phase: bool,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct NvmeSubQueueV1 {
+        pub id: u16,
+        pub cqid: u16,
+        pub base: u64,
+        pub size: u32,
+        pub head: u16,
+        pub tail: u16,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct NvmeFeatureValuesV1 {
+        pub arbitration: u32,
+        pub power_mgmt: u32,
+        pub temp_thresh: [u16; 2],
+        pub error_recovery: u32,
+        pub vwc: u32,
+        pub intr_coalescing: u32,
+        pub intr_coalescing_disabled: Vec<u16>,
+        pub write_atomicity: u32,
+        pub async_event_cfg: u32,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct NvmeFeaturesV1 {
+        pub current: NvmeFeatureValuesV1,
+        pub saved: NvmeFeatureValuesV1,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct NvmeAsyncEventsV1 {
+        /// Command Identifiers of the outstanding Asynchronous Event Requests
+        pub aer_cids: Vec<u16>,
+        pub pending: Vec<AsyncEvent>,
+        pub masked: Vec<u8>,
+        pub changed_ns: Vec<u32>,
+    }
 
-    #[derive(Serialize)]
+    #[derive(Deserialize, Serialize)]
+    pub struct NvmeLogPagesV1 {
+        pub crit_warn: u8,
+        pub bytes_read: u64,
+        pub bytes_written: u64,
+        pub host_read_cmds: u64,
+        pub host_write_cmds: u64,
+        pub media_errors: u64,
+        pub power_cycles: u64,
+        pub unsafe_shutdowns: u64,
+        pub error_count: u64,
+        /// Time for which the controller has been powered on, in seconds
+        pub power_on_secs: u64,
+    }
+
+    /// An I/O command taken off of its Submission Queue but yet to be
+    /// completed.
+    #[derive(Deserialize, Serialize)]
+    pub struct NvmeSubmissionV1 {
+        pub sqid: u16,
+        pub cdw0: u32,
+        pub nsid: u32,
+        pub mptr: u64,
+        pub prp1: u64,
+        pub prp2: u64,
+        pub cdw10: u32,
+        pub cdw11: u32,
+        pub cdw12: u32,
+        pub cdw13: u32,
+        pub cdw14: u32,
+        pub cdw15: u32,
+    }
+
+    impl NvmeSubmissionV1 {
+        pub(super) fn from_raw(sqid: QueueId, sub: &RawSubmission) -> Self {
+            Self {
+                sqid,
+                cdw0: sub.cdw0,
+                nsid: sub.nsid,
+                mptr: sub.mptr,
+                prp1: sub.prp1,
+                prp2: sub.prp2,
+                cdw10: sub.cdw10,
+                cdw11: sub.cdw11,
+                cdw12: sub.cdw12,
+                cdw13: sub.cdw13,
+                cdw14: sub.cdw14,
+                cdw15: sub.cdw15,
+            }
+        }
+
+        pub(super) fn to_raw(&self) -> RawSubmission {
+            RawSubmission {
+                cdw0: self.cdw0,
+                nsid: self.nsid,
+                rsvd: 0,
+                mptr: self.mptr,
+                prp1: self.prp1,
+                prp2: self.prp2,
+                cdw10: self.cdw10,
+                cdw11: self.cdw11,
+                cdw12: self.cdw12,
+                cdw13: self.cdw13,
+                cdw14: self.cdw14,
+                cdw15: self.cdw15,
+            }
+        }
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct NvmeCtrlStateV1 {
+        pub regs: NvmeCtrlRegsV1,
+        pub cqs: Vec<NvmeCompQueueV1>,
+        pub sqs: Vec<NvmeSubQueueV1>,
+        /// The Queue Priority (QPRIO) of each I/O Submission Queue, by ID
+        pub sq_prios: Vec<(u16, u8)>,
+        pub features: NvmeFeaturesV1,
+        pub num_queues: Option<u32>,
+        pub events: NvmeAsyncEventsV1,
+        pub logs: NvmeLogPagesV1,
+        pub pending_cmds: Vec<NvmeSubmissionV1>,
+        /// The base addresses of the Shadow Doorbell and EventIdx buffers
+        pub doorbell_bufs: Option<(u64, u64)>,
+    }
+
+    #[derive(Deserialize, Serialize)]
     pub struct PciNvmeStateV1 {
         pub pci: PciStateV1,
-        // TODO: Add the rest of the controller state
+        pub ctrl: NvmeCtrlStateV1,
     }
 }
 
@@ -1021,3 +1635,66 @@ lazy_static! {
         ), db_offset)
     };
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instance::Instance;
+
+    #[test]
+    fn feature_save_select_by_version() {
+        let instance = Instance::new_test(None).unwrap();
+        let vwc = || cmds::FeatureIdent::VolatileWriteCache;
+        for (version, advertised) in [
+            (NvmeVersion::V1_0, false),
+            (NvmeVersion::V1_3, true),
+            (NvmeVersion::V1_4, true),
+        ] {
+            let nvme = PciNvme::create(
+                0x1de,
+                0x1000,
+                "nvme".to_string(),
+                version,
+                &CtrlIdentity::default(),
+            )
+            .unwrap();
+            let mut state = nvme.state.lock().unwrap();
+            assert_eq!(
+                state.ctrl_ident.oncs & ONCS_FEAT_SAVE_SELECT != 0,
+                advertised
+            );
+
+            instance.disp.with_ctx(|ctx| {
+                // Disable the write cache, asking for the value to be saved
+                let set =
+                    cmds::SetFeaturesCmd { fid: vwc(), value: 0, save: true };
+                assert!(state.acmd_set_features(&set, ctx).is_success());
+
+                // Where the fields are reserved, the value is not saved and
+                // the current value is returned whatever is selected.
+                let get =
+                    |sel| cmds::GetFeaturesCmd { fid: vwc(), params: 0, sel };
+                let comp = state.acmd_get_features(&get(FEAT_SEL_DEFAULT), ctx);
+                assert!(comp.is_success());
+                assert_eq!(comp.dw0, if advertised { 1 } else { 0 });
+
+                // Its capabilities are reported, other than by a 1.0 controller
+                let comp =
+                    state.acmd_get_features(&get(FEAT_SEL_SUPPORTED), ctx);
+                assert!(comp.is_success());
+                assert_eq!(
+                    comp.dw0,
+                    match version {
+                        NvmeVersion::V1_0 => 0,
+                        _ => FEAT_CAP_SAVEABLE | FEAT_CAP_CHANGEABLE,
+                    }
+                );
+
+                // Only a saved value survives a Controller Reset
+                state.features.reset();
+                let comp = state.acmd_get_features(&get(FEAT_SEL_CURRENT), ctx);
+                assert_eq!(comp.dw0, if advertised { 0 } else { 1 });
+            });
+        }
+    }
+}
@@ -0,0 +1,272 @@
+//! NVMe namespaces, each backed by its own block backend.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::block;
+use crate::dispatch::DispCtx;
+
+use super::bits::{self, RawSubmission};
+use super::queue::CompQueueEntryPermit;
+use super::requests::Dealloc;
+use super::PciNvme;
+
+/// The max number of namespaces we support per controller
+pub(super) const MAX_NUM_NAMESPACES: usize = 16;
+
+/// IEEE OUI under which namespace EUI-64s and NGUIDs are assigned, unless
+/// the controller is configured with another
+pub(super) const OXIDE_OUI: [u8; 3] = [0xA8, 0x40, 0x25];
+
+/// Globally unique identifiers for a namespace, any of which are left zeroed
+/// if not reported.
+///
+/// See NVMe 1.3 Section 7.10 Unique Identifier
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub(super) struct NsIds {
+    /// IEEE Extended Unique Identifier (EUI-64)
+    pub eui64: [u8; 8],
+    /// Namespace Globally Unique Identifier (NGUID)
+    pub nguid: [u8; 16],
+    /// Namespace UUID
+    pub uuid: [u8; 16],
+}
+
+impl NsIds {
+    /// Derive the identifiers for a namespace from the IEEE OUI and serial
+    /// number of its controller and its NSID.
+    ///
+    /// These must stay the same for the life of the namespace (including
+    /// across migrations), so are computed rather than drawn at random.
+    pub fn derive(oui: [u8; 3], serial: &[u8], nsid: u32) -> Self {
+        // 64-bit FNV-1a over the serial number, NSID and a tag byte, so that
+        // each tag yields an independent value
+        let hash = |tag: u8| {
+            serial
+                .iter()
+                .chain(nsid.to_le_bytes().iter())
+                .chain(std::iter::once(&tag))
+                .fold(0xcbf29ce484222325u64, |h, b| {
+                    (h ^ *b as u64).wrapping_mul(0x100000001b3)
+                })
+                .to_be_bytes()
+        };
+
+        // The OUI followed by a 40-bit extension identifier
+        let mut eui64 = [0u8; 8];
+        eui64[..3].copy_from_slice(&oui);
+        eui64[3..].copy_from_slice(&hash(0)[..5]);
+
+        // A 64-bit vendor specific extension identifier followed by the EUI-64
+        let mut nguid = [0u8; 16];
+        nguid[..8].copy_from_slice(&hash(1));
+        nguid[8..].copy_from_slice(&eui64);
+
+        // Formatted as an RFC 4122 version 4 UUID
+        let mut uuid = [0u8; 16];
+        uuid[..8].copy_from_slice(&hash(2));
+        uuid[8..].copy_from_slice(&hash(3));
+        uuid[6] = (uuid[6] & 0x0f) | 0x40;
+        uuid[8] = (uuid[8] & 0x3f) | 0x80;
+
+        Self { eui64, nguid, uuid }
+    }
+}
+
+/// Controller-side state for an attached namespace
+pub(super) struct NsState {
+    /// The Identify structure returned for Identify namespace commands
+    pub ident: bits::IdentifyNamespace,
+
+    /// Identifiers reported through the Namespace Identification Descriptor
+    /// list
+    pub ids: NsIds,
+
+    /// Underlying Block Device info, if media is present
+    pub binfo: Option<block::DeviceInfo>,
+
+    /// Block device through which the namespace is serviced
+    pub dev: Arc<NvmeNs>,
+}
+
+impl NsState {
+    pub fn new(binfo: block::DeviceInfo, dev: Arc<NvmeNs>) -> Self {
+        let ident = bits::IdentifyNamespace {
+            nlbaf: 0, // We only support a single LBA format (1 but 0-based)
+            flbas: 0, // And it is at index 0 in the lbaf array
+            ..Default::default()
+        };
+        let mut this = Self { ident, ids: NsIds::default(), binfo: None, dev };
+        this.set_media(Some(binfo));
+        this
+    }
+
+    /// Assign the globally unique identifiers reported for the namespace.
+    pub fn set_ids(&mut self, ids: NsIds) {
+        self.ident.eui64 = ids.eui64;
+        self.ident.nguid = ids.nguid;
+        self.ids = ids;
+    }
+
+    /// The Namespace Identification Descriptor list for the namespace,
+    /// omitting any identifiers not reported.
+    ///
+    /// See NVMe 1.3 Section 5.15.2, Figure 116 Identify - Namespace
+    /// Identification Descriptor
+    pub fn id_descs(&self) -> Vec<u8> {
+        let descs = [
+            (bits::NIDT_EUI64, &self.ids.eui64[..]),
+            (bits::NIDT_NGUID, &self.ids.nguid[..]),
+            (bits::NIDT_UUID, &self.ids.uuid[..]),
+        ];
+        let mut list = Vec::new();
+        for (nidt, nid) in
+            descs.iter().filter(|(_, nid)| nid.iter().any(|b| *b != 0))
+        {
+            // Type, Length, 2 reserved bytes, and then the identifier itself
+            list.extend_from_slice(&[*nidt, nid.len() as u8, 0, 0]);
+            list.extend_from_slice(nid);
+        }
+        list
+    }
+
+    /// Update the namespace to reflect the given media (or lack thereof).
+    pub fn set_media(&mut self, binfo: Option<block::DeviceInfo>) {
+        // No thin provisioning so nsze == ncap == nuse
+        let nsze = binfo.map_or(0, |b| b.total_size);
+        self.ident.nsze = nsze;
+        self.ident.ncap = nsze;
+        self.ident.nuse = nsze;
+
+        if let Some(binfo) = binfo {
+            // Update the block format we support
+            debug_assert!(
+                binfo.block_size.is_power_of_two(),
+                "binfo.block_size must be a power of 2"
+            );
+            debug_assert!(
+                binfo.block_size >= 512,
+                "binfo.block_size must be at least 512 bytes"
+            );
+            self.ident.lbaf[0].lbads = binfo.block_size.trailing_zeros() as u8;
+        }
+        self.binfo = binfo;
+    }
+
+    /// Convert some number of logical blocks to bytes with the currently active LBA data size
+    pub fn nlb_to_size(&self, b: usize) -> usize {
+        b << (self.ident.lbaf[(self.ident.flbas & 0xF) as usize]).lbads
+    }
+}
+
+/// A namespace attached to a [`PciNvme`] controller.
+///
+/// This is the [`block::Device`] through which the backend of the namespace
+/// receives the I/O commands issued to it.
+pub struct NvmeNs {
+    /// Namespace ID (NSID)
+    pub(super) nsid: u32,
+
+    /// The controller to which the namespace is attached
+    ctrl: Weak<PciNvme>,
+
+    /// Underlying Block Device notifier
+    pub(super) notifier: block::Notifier,
+
+    /// I/O commands for this namespace which were taken off a Submission
+    /// Queue while servicing another namespace
+    pub(super) routed: Mutex<VecDeque<(RawSubmission, CompQueueEntryPermit)>>,
+
+    /// Ranges yet to be deallocated for Dataset Management commands spanning
+    /// more than one
+    pub(super) deallocs: Mutex<VecDeque<Dealloc>>,
+}
+
+impl NvmeNs {
+    pub(super) fn new(nsid: u32, ctrl: Weak<PciNvme>) -> Self {
+        Self {
+            nsid,
+            ctrl,
+            notifier: block::Notifier::new(),
+            routed: Mutex::new(VecDeque::new()),
+            deallocs: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// The Namespace ID by which the guest addresses this namespace
+    pub fn nsid(&self) -> u32 {
+        self.nsid
+    }
+}
+
+impl block::Device for NvmeNs {
+    fn next(&self, ctx: &DispCtx) -> Option<block::Request> {
+        let ctrl = self.ctrl.upgrade()?;
+        self.notifier.next_arming(|| ctrl.next_req(self, ctx))
+    }
+
+    fn set_notifier(&self, f: Option<Box<block::NotifierFn>>) {
+        self.notifier.set(f)
+    }
+
+    fn set_media(
+        &self,
+        info: Option<block::DeviceInfo>,
+        ctx: &DispCtx,
+    ) -> io::Result<()> {
+        let ctrl = self.ctrl.upgrade().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Other, "controller went away")
+        })?;
+        let mut state = ctrl.state.lock().unwrap();
+        state.set_media(self.nsid, info, ctx);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn derived_ids() {
+        let ids = NsIds::derive(OXIDE_OUI, b"serial", 1);
+        assert_eq!(ids, NsIds::derive(OXIDE_OUI, b"serial", 1));
+        assert_ne!(ids, NsIds::derive(OXIDE_OUI, b"serial", 2));
+        assert_ne!(ids, NsIds::derive(OXIDE_OUI, b"other", 1));
+
+        assert_eq!(ids.eui64[..3], OXIDE_OUI);
+        assert_eq!(ids.nguid[8..], ids.eui64);
+        // Version 4, RFC 4122 variant
+        assert_eq!(ids.uuid[6] >> 4, 4);
+        assert_eq!(ids.uuid[8] >> 6, 0b10);
+    }
+
+    #[test]
+    fn id_descs() {
+        let binfo = block::DeviceInfo {
+            block_size: 512,
+            total_size: 8,
+            writable: true,
+        };
+        let dev = Arc::new(NvmeNs::new(1, Weak::new()));
+        let mut ns = NsState::new(binfo, dev);
+
+        // Nothing to report until identifiers are assigned
+        assert!(ns.id_descs().is_empty());
+
+        let ids = NsIds::derive(OXIDE_OUI, b"serial", 1);
+        ns.set_ids(ids);
+        assert_eq!(ns.ident.eui64, ids.eui64);
+        assert_eq!(ns.ident.nguid, ids.nguid);
+
+        let descs = ns.id_descs();
+        assert_eq!(descs.len(), (4 + 8) + (4 + 16) + (4 + 16));
+        assert_eq!(descs[..4], [bits::NIDT_EUI64, 8, 0, 0]);
+        assert_eq!(descs[4..12], ids.eui64);
+        assert_eq!(descs[12..16], [bits::NIDT_NGUID, 16, 0, 0]);
+        assert_eq!(descs[16..32], ids.nguid);
+        assert_eq!(descs[32..36], [bits::NIDT_UUID, 16, 0, 0]);
+        assert_eq!(descs[36..], ids.uuid);
+    }
+}
@@ -1,8 +1,11 @@
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::atomic::{fence, Ordering};
 use std::sync::{Arc, Mutex, Weak};
 
 use super::bits::{self, RawCompletion, RawSubmission};
 use super::cmds::Completion;
+use super::migrate;
 use crate::common::*;
 use crate::dispatch::DispCtx;
 use crate::hw::pci;
@@ -74,6 +77,9 @@ struct CompQueueState {
     /// One may only pop something off the SQ if there's at least one space available in
     /// the corresponding CQ. If there isn't, we set the kick flag.
     kick: bool,
+
+    /// Where the host may instead post updates to the Head entry pointer.
+    shadow: Option<ShadowDoorbell>,
 }
 
 /// Submission Queue State
@@ -94,6 +100,9 @@ struct SubQueueState {
     ///
     /// See NVMe 1.0e Section 4.1 Submission Queue & Completion Queue Definition
     tail: u16,
+
+    /// Where the host may instead post updates to the Tail entry pointer.
+    shadow: Option<ShadowDoorbell>,
 }
 
 /// Helper for manipulating Completion/Submission Queues
@@ -179,6 +188,7 @@ impl QueueState<CompQueueState> {
             avail: (size - 1) as u16,
             phase: true,
             kick: false,
+            shadow: None,
         };
         Self { size, inner: Mutex::new(inner) }
     }
@@ -235,7 +245,7 @@ impl QueueState<SubQueueState> {
     /// Create a new `QueueState` for a Submission Queue
     fn new_submission_state(size: u32) -> QueueState<SubQueueState> {
         assert!(size >= MIN_QUEUE_SIZE && size <= MAX_QUEUE_SIZE);
-        let inner = SubQueueState { head: 0, tail: 0 };
+        let inner = SubQueueState { head: 0, tail: 0, shadow: None };
         Self { size, inner: Mutex::new(inner) }
     }
 
@@ -282,6 +292,105 @@ impl QueueState<SubQueueState> {
     }
 }
 
+/// The Shadow Doorbell and EventIdx buffers provided by the host through the
+/// Doorbell Buffer Config command.
+///
+/// Both are laid out like the doorbell registers (with CAP.DSTRD = 0), i.e.
+/// Submission Queue y at offset 2y * 4 and Completion Queue y at
+/// (2y + 1) * 4.
+///
+/// See NVMe 1.3 Section 5.7 Doorbell Buffer Config command
+#[derive(Clone, Copy, Debug)]
+pub struct DoorbellBuffers {
+    /// The base [`GuestAddr`] of the Shadow Doorbell buffer.
+    pub shadow: GuestAddr,
+
+    /// The base [`GuestAddr`] of the EventIdx buffer.
+    pub event_idx: GuestAddr,
+}
+
+impl DoorbellBuffers {
+    /// The shadow doorbell for the given Submission Queue, if any.
+    ///
+    /// The Admin Queues are left to the doorbell registers alone, as drivers
+    /// (e.g. Linux) only make use of the buffers for I/O Queues.
+    pub fn sq(&self, sqid: QueueId) -> Option<ShadowDoorbell> {
+        self.entry(sqid, 2 * sqid as u64)
+    }
+
+    /// The shadow doorbell for the given Completion Queue, if any.
+    ///
+    /// See [`DoorbellBuffers::sq`].
+    pub fn cq(&self, cqid: QueueId) -> Option<ShadowDoorbell> {
+        self.entry(cqid, 2 * cqid as u64 + 1)
+    }
+
+    fn entry(&self, qid: QueueId, idx: u64) -> Option<ShadowDoorbell> {
+        if qid == ADMIN_QUEUE_ID {
+            return None;
+        }
+        let off = idx * std::mem::size_of::<u32>() as u64;
+        Some(ShadowDoorbell {
+            db: GuestAddr(self.shadow.0 + off),
+            ei: GuestAddr(self.event_idx.0 + off),
+        })
+    }
+}
+
+/// The entries for a single queue in the [`DoorbellBuffers`].
+#[derive(Clone, Copy, Debug)]
+pub struct ShadowDoorbell {
+    /// Where the host writes the value it would otherwise have written to the
+    /// doorbell register.
+    db: GuestAddr,
+
+    /// Where we write the last value we picked up from the shadow doorbell.
+    ///
+    /// The host need only write the doorbell register proper once it moves
+    /// the value past this one.
+    ei: GuestAddr,
+}
+
+impl ShadowDoorbell {
+    /// Bring the shadow doorbell and EventIdx in line with the given value.
+    fn publish(&self, val: u16, ctx: &DispCtx) {
+        let mem = ctx.mctx.memctx();
+        mem.write(self.db, &(val as u32));
+        mem.write(self.ei, &(val as u32));
+    }
+
+    /// Pass the latest value written by the host to the shadow doorbell on
+    /// to `update`, then publish it as the EventIdx.
+    ///
+    /// The host may write a new value after we've read it but before seeing
+    /// the new EventIdx, in which case it won't have written the doorbell
+    /// register either, so go around again until the value holds still.
+    /// Values rejected by `update` are left for the host to sort out.
+    fn sync(
+        &self,
+        ctx: &DispCtx,
+        update: impl Fn(u16) -> Result<(), QueueUpdateError>,
+    ) {
+        let mem = ctx.mctx.memctx();
+        let mut last = None;
+        loop {
+            let val: u32 = match mem.read(self.db) {
+                Some(val) if Some(val) != last => val,
+                _ => return,
+            };
+            let idx = match u16::try_from(val) {
+                Ok(idx) if update(idx).is_ok() => idx,
+                _ => return,
+            };
+            mem.write(self.ei, &(idx as u32));
+            // Make sure the EventIdx is visible before we check the shadow
+            // doorbell again
+            fence(Ordering::SeqCst);
+            last = Some(val);
+        }
+    }
+}
+
 /// Errors that may be encountered during Queue creation.
 #[derive(Error, Debug)]
 pub enum QueueCreateErr {
@@ -370,14 +479,50 @@ impl SubQueue {
         self.state.push_tail_to(idx)
     }
 
+    /// Also pick up updates to the Tail entry pointer from the given shadow
+    /// doorbell, which is first brought in line with the current one.
+    pub fn set_shadow(&self, shadow: ShadowDoorbell, ctx: &DispCtx) {
+        let mut state = self.state.inner.lock().unwrap();
+        shadow.publish(state.tail, ctx);
+        state.shadow = Some(shadow);
+    }
+
+    /// Pick up any new entries the host has announced only through the
+    /// shadow doorbell.
+    fn sync_shadow(&self, ctx: &DispCtx) {
+        let shadow = self.state.inner.lock().unwrap().shadow;
+        if let Some(shadow) = shadow {
+            shadow.sync(ctx, |idx| self.state.push_tail_to(idx));
+        }
+    }
+
+    /// Reserve an entry on the Completion Queue for a command already taken
+    /// off of this queue (i.e. one carried over from the source of a
+    /// migration), or [`None`] if there is no room.
+    pub fn reserve_entry(self: &Arc<SubQueue>) -> Option<CompQueueEntryPermit> {
+        self.cq.reserve_entry(self.clone())
+    }
+
     /// Returns the next entry off of the Queue or [`None`] if it is empty.
     pub fn pop(
         self: &Arc<SubQueue>,
         ctx: &DispCtx,
     ) -> Option<(bits::RawSubmission, CompQueueEntryPermit)> {
-        // Attempt to reserve an entry on the Completion Queue
-        let cqe_permit = self.cq.reserve_entry(self.clone())?;
-        if let Some(idx) = self.state.pop_head() {
+        // Attempt to reserve an entry on the Completion Queue, checking for
+        // any the host freed up without ringing the doorbell if it's full
+        let cqe_permit = match self.cq.reserve_entry(self.clone()) {
+            Some(cqe_permit) => cqe_permit,
+            None if self.cq.sync_shadow(ctx) => {
+                self.cq.reserve_entry(self.clone())?
+            }
+            None => return None,
+        };
+        let idx = self.state.pop_head().or_else(|| {
+            // Likewise for new entries once we've caught up
+            self.sync_shadow(ctx);
+            self.state.pop_head()
+        });
+        if let Some(idx) = idx {
             let mem = ctx.mctx.memctx();
             let ent: Option<RawSubmission> = mem.read(self.entry_addr(idx));
             // XXX: handle a guest addr that becomes unmapped later
@@ -400,6 +545,40 @@ impl SubQueue {
         self.id
     }
 
+    pub fn export(&self) -> migrate::NvmeSubQueueV1 {
+        let state = self.state.inner.lock().unwrap();
+        migrate::NvmeSubQueueV1 {
+            id: self.id,
+            cqid: self.cq.id,
+            base: self.base.0,
+            size: self.state.size,
+            head: state.head,
+            tail: state.tail,
+        }
+    }
+
+    /// Restore the Head and Tail entry pointers of the queue from those of
+    /// its counterpart on the source of a migration.
+    ///
+    /// The shadow doorbell (if any) is taken up as it was left in guest
+    /// memory, so as not to lose any update from the host not yet picked up.
+    pub fn import(
+        &self,
+        saved: &migrate::NvmeSubQueueV1,
+        shadow: Option<ShadowDoorbell>,
+    ) -> Result<(), QueueUpdateError> {
+        if saved.head as u32 >= self.state.size
+            || saved.tail as u32 >= self.state.size
+        {
+            return Err(QueueUpdateError::InvalidEntry);
+        }
+        let mut state = self.state.inner.lock().unwrap();
+        state.head = saved.head;
+        state.tail = saved.tail;
+        state.shadow = shadow;
+        Ok(())
+    }
+
     /// Returns the corresponding [`GuestAddr`] for a given entry in
     /// the Submission Queue.
     fn entry_addr(&self, idx: u16) -> GuestAddr {
@@ -439,6 +618,9 @@ impl SubQueue {
 /// Type for manipulating Completion Queues.
 #[derive(Debug)]
 pub struct CompQueue {
+    /// The ID of this Completion Queue.
+    id: QueueId,
+
     /// The Interrupt Vector used to signal to the host (VM) upon pushing
     /// entries onto the Completion Queue.
     iv: u16,
@@ -469,6 +651,7 @@ impl CompQueue {
     ) -> Result<Self, QueueCreateErr> {
         Self::validate(id, base, size, ctx)?;
         Ok(Self {
+            id,
             iv,
             state: QueueState::new_completion_state(size),
             base,
@@ -482,6 +665,26 @@ impl CompQueue {
         self.state.pop_head_to(idx)
     }
 
+    /// Also pick up updates to the Head entry pointer from the given shadow
+    /// doorbell, which is first brought in line with the current one.
+    pub fn set_shadow(&self, shadow: ShadowDoorbell, ctx: &DispCtx) {
+        let mut state = self.state.inner.lock().unwrap();
+        shadow.publish(state.head, ctx);
+        state.shadow = Some(shadow);
+    }
+
+    /// Pick up any entries the host has freed up and announced only through
+    /// the shadow doorbell.
+    ///
+    /// Returns whether there is a shadow doorbell at all.
+    fn sync_shadow(&self, ctx: &DispCtx) -> bool {
+        let shadow = self.state.inner.lock().unwrap().shadow;
+        if let Some(shadow) = shadow {
+            shadow.sync(ctx, |idx| self.state.pop_head_to(idx));
+        }
+        shadow.is_some()
+    }
+
     /// Fires an interrupt to the guest with the associated interrupt vector
     /// if the queue is not currently empty.
     pub fn fire_interrupt(&self, ctx: &DispCtx) {
@@ -499,6 +702,48 @@ impl CompQueue {
         std::mem::replace(&mut state.kick, false)
     }
 
+    pub fn export(&self) -> migrate::NvmeCompQueueV1 {
+        let state = self.state.inner.lock().unwrap();
+        migrate::NvmeCompQueueV1 {
+            id: self.id,
+            iv: self.iv,
+            base: self.base.0,
+            size: self.state.size,
+            head: state.head,
+            tail: state.tail,
+            phase: state.phase,
+        }
+    }
+
+    /// Restore the Head and Tail entry pointers and Phase Tag of the queue
+    /// from those of its counterpart on the source of a migration.
+    ///
+    /// Entries for commands still outstanding must subsequently be reserved
+    /// again through [`SubQueue::reserve_entry`]. As with
+    /// [`SubQueue::import`], the shadow doorbell is taken up as is.
+    pub fn import(
+        &self,
+        saved: &migrate::NvmeCompQueueV1,
+        shadow: Option<ShadowDoorbell>,
+    ) -> Result<(), QueueUpdateError> {
+        if saved.head as u32 >= self.state.size
+            || saved.tail as u32 >= self.state.size
+        {
+            return Err(QueueUpdateError::InvalidEntry);
+        }
+        let mut state = self.state.inner.lock().unwrap();
+        state.head = saved.head;
+        state.tail = saved.tail;
+        state.phase = saved.phase;
+        state.shadow = shadow;
+        let occupied = self.state.avail_occupied(saved.head, saved.tail);
+        state.avail = (self.state.size - 1) as u16 - occupied;
+        // Whether any SQs were held up for a lack of entries isn't carried
+        // over, so err on the side of kicking them once some are freed.
+        state.kick = true;
+        Ok(())
+    }
+
     /// Returns the number of SQ's associated with this Completion Queue.
     pub fn associated_sqs(&self) -> usize {
         let sqs = self.sqs.lock().unwrap();
@@ -604,6 +849,12 @@ pub struct CompQueueEntryPermit {
 }
 
 impl CompQueueEntryPermit {
+    /// The ID of the Submission Queue for which the entry is reserved, if it
+    /// still exists.
+    pub fn sqid(&self) -> Option<QueueId> {
+        self.sq.upgrade().map(|sq| sq.id())
+    }
+
     /// Consume the permit by placing an entry into the Completion Queue.
     pub fn push_completion(self, cid: u16, comp: Completion, ctx: &DispCtx) {
         let cq = match self.cq.upgrade() {
@@ -931,6 +1182,140 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn shadow_doorbells() -> Result<(), Error> {
+        let instance = Instance::new_test(None)?;
+        let hdl = pci::MsixHdl::new_test();
+        let read_base = GuestAddr(0);
+        let write_base = GuestAddr(1024 * 1024);
+
+        instance.disp.with_ctx(|ctx| {
+            // Make the CQ smaller so it fills up
+            let cq = Arc::new(
+                CompQueue::new(1, 0, 2, write_base, ctx, hdl.clone()).unwrap(),
+            );
+            let sq = Arc::new(
+                SubQueue::new(1, cq.clone(), 4, read_base, ctx).unwrap(),
+            );
+
+            // We only ever read the shadow doorbells and write the EventIdxs
+            let bufs = DoorbellBuffers {
+                shadow: GuestAddr(read_base.0 + 0x8000),
+                event_idx: GuestAddr(write_base.0 + 0x8000),
+            };
+            assert!(bufs.sq(ADMIN_QUEUE_ID).is_none());
+            assert!(bufs.cq(ADMIN_QUEUE_ID).is_none());
+            let (sq_db, cq_db) = (bufs.sq(1).unwrap(), bufs.cq(1).unwrap());
+            assert_eq!(sq_db.db.0, bufs.shadow.0 + 8);
+            assert_eq!(cq_db.ei.0, bufs.event_idx.0 + 12);
+            sq.set_shadow(sq_db, ctx);
+            cq.set_shadow(cq_db, ctx);
+
+            // Replicate the guest VM updating the shadow doorbells, without
+            // writing the doorbell registers
+            let mem = ctx.mctx.memctx();
+            let host_write = |addr: GuestAddr, val: u32| {
+                let region = GuestRegion(addr, std::mem::size_of::<u32>());
+                let mapping = mem.direct_writable_region(&region).unwrap();
+                mapping.write(&val).unwrap();
+            };
+            let event_idx = |addr: GuestAddr| -> u32 {
+                let region = GuestRegion(addr, std::mem::size_of::<u32>());
+                let mapping = mem.direct_readable_region(&region).unwrap();
+                mapping.read().unwrap()
+            };
+
+            // New entries are picked up once we've caught up
+            host_write(sq_db.db, 2);
+            let pop = sq.pop(ctx);
+            assert_matches!(pop, Some(_));
+            pop.unwrap().1.push_completion_test(ctx);
+            assert_eq!(event_idx(sq_db.ei), 2);
+
+            // As are entries consumed off the CQ once it's full
+            assert_matches!(sq.pop(ctx), None);
+            host_write(cq_db.db, 1);
+            let pop = sq.pop(ctx);
+            assert_matches!(pop, Some(_));
+            pop.unwrap().1.push_completion_test(ctx);
+            assert_eq!(event_idx(cq_db.ei), 1);
+
+            // Nothing left to pop, but the EventIdxs are up to date
+            host_write(cq_db.db, 0);
+            assert_matches!(sq.pop(ctx), None);
+            assert_eq!(event_idx(sq_db.ei), 2);
+            assert_eq!(event_idx(cq_db.ei), 0);
+
+            // Invalid values are ignored
+            host_write(sq_db.db, 4);
+            assert_matches!(sq.pop(ctx), None);
+            assert_eq!(event_idx(sq_db.ei), 2);
+
+            // Writing the doorbell registers still works as before
+            assert_matches!(sq.notify_tail(3), Ok(_));
+            assert_matches!(sq.pop(ctx), Some(_));
+        });
+
+        Ok(())
+    }
+
+    #[test]
+    fn import_queues() -> Result<(), Error> {
+        let instance = Instance::new_test(None)?;
+        let hdl = pci::MsixHdl::new_test();
+        let read_base = GuestAddr(0);
+        let write_base = GuestAddr(1024 * 1024);
+
+        instance.disp.with_ctx(|ctx| {
+            let cq = Arc::new(
+                CompQueue::new(1, 0, 4, write_base, ctx, hdl.clone()).unwrap(),
+            );
+            let sq = Arc::new(
+                SubQueue::new(1, cq.clone(), 4, read_base, ctx).unwrap(),
+            );
+
+            // Indices beyond the end of the queue are rejected
+            let mut saved_cq = migrate::NvmeCompQueueV1 {
+                id: 1,
+                iv: 0,
+                base: write_base.0,
+                size: 4,
+                head: 4,
+                tail: 0,
+                phase: false,
+            };
+            assert_matches!(
+                cq.import(&saved_cq, None),
+                Err(QueueUpdateError::InvalidEntry)
+            );
+
+            // Two entries yet to be consumed by the host leave room for only
+            // one more out of the three usable
+            saved_cq.head = 1;
+            saved_cq.tail = 3;
+            assert_matches!(cq.import(&saved_cq, None), Ok(_));
+            assert!(!cq.export().phase);
+
+            let saved_sq = migrate::NvmeSubQueueV1 {
+                id: 1,
+                cqid: 1,
+                base: read_base.0,
+                size: 4,
+                head: 3,
+                tail: 1,
+            };
+            assert_matches!(sq.import(&saved_sq, None), Ok(_));
+            let exported = sq.export();
+            assert_eq!((exported.head, exported.tail), (3, 1));
+
+            assert_matches!(sq.reserve_entry(), Some(_));
+            assert_matches!(sq.reserve_entry(), None);
+            assert!(cq.kick());
+        });
+
+        Ok(())
+    }
+
     #[test]
     fn push_pop() -> Result<(), Error> {
         let instance = Instance::new_test(None)?;
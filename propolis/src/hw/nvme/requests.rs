@@ -1,3 +1,7 @@
+use std::collections::VecDeque;
+use std::mem::size_of_val;
+use std::sync::{Arc, Mutex};
+
 use crate::{
     block::{self, Operation, Request},
     dispatch::DispCtx,
@@ -5,8 +9,11 @@ use crate::{
 };
 
 use super::{
+    bits::{DsmRange, RawSubmission},
     cmds::{self, NvmCmd},
-    queue::CompQueueEntryPermit,
+    log_page::LogPages,
+    ns::{NsState, NvmeNs},
+    queue::{CompQueueEntryPermit, QueueId},
     NvmeCtrl, PciNvme,
 };
 
@@ -19,136 +26,467 @@ mod probes {
     fn nvme_write_complete(cid: u16) {}
 }
 
-impl block::Device for PciNvme {
-    fn next(&self, ctx: &DispCtx) -> Option<Request> {
-        self.notifier.next_arming(|| self.next_req(ctx))
-    }
-
-    fn set_notifier(&self, f: Option<Box<block::NotifierFn>>) {
-        self.notifier.set(f)
-    }
-}
-
 impl PciNvme {
-    /// Pop an available I/O request off of a Submission Queue to begin
-    /// processing by the underlying Block Device.
-    fn next_req(&self, ctx: &DispCtx) -> Option<Request> {
-        let state = self.state.lock().unwrap();
+    /// Pop an available I/O request for the namespace `ns` off of a
+    /// Submission Queue to begin processing by its underlying Block Device.
+    pub(super) fn next_req(
+        &self,
+        ns: &NvmeNs,
+        ctx: &DispCtx,
+    ) -> Option<Request> {
+        let mut guard = self.state.lock().unwrap();
+        // Borrow through a plain reference, so the arbiter and queues can be
+        // borrowed apart from each other
+        let state = &mut *guard;
 
         // We shouldn't be called while paused
         assert!(!state.paused, "I/O requested while device paused");
 
-        // Go through all the queues (skip admin as we just want I/O queues)
+        // Continue on with any Dataset Management commands already underway
+        if let Some(dealloc) = ns.deallocs.lock().unwrap().pop_front() {
+            return Some(dealloc.into_req(&state.logs));
+        }
+
+        // Then any commands routed here while servicing other namespaces
+        loop {
+            let routed = ns.routed.lock().unwrap().pop_front();
+            let (sub, cqe_permit) = match routed {
+                Some(routed) => routed,
+                None => break,
+            };
+            if let Some(req) = io_req(state, sub, cqe_permit, ctx) {
+                return Some(req);
+            }
+        }
+
+        // Go through the I/O queues in the order chosen by the arbiter,
         // looking for a request to service
-        for sq in state.sqs.iter().skip(1).flatten() {
-            while let Some((sub, cqe_permit)) = sq.pop(ctx) {
-                let cmd = NvmCmd::parse(sub);
-                match cmd {
-                    Ok(NvmCmd::Write(_)) if !state.binfo.writable => {
-                        let comp = Completion::specific_err(
-                            bits::StatusCodeType::CmdSpecific,
-                            bits::STS_WRITE_READ_ONLY_RANGE,
-                        );
-                        cqe_permit.push_completion(sub.cid(), comp, ctx);
-                    }
-                    Ok(NvmCmd::Write(cmd)) => {
-                        return Some(write_op(
-                            &state,
-                            sub.cid(),
-                            cmd,
-                            cqe_permit,
-                            ctx,
-                        ));
-                    }
-                    Ok(NvmCmd::Read(cmd)) => {
-                        return Some(read_op(
-                            &state,
-                            sub.cid(),
-                            cmd,
-                            cqe_permit,
-                            ctx,
-                        ));
-                    }
-                    Ok(NvmCmd::Flush) => {
-                        return Some(flush_op(&state, sub.cid(), cqe_permit));
-                    }
-                    Ok(NvmCmd::Unknown(_)) | Err(_) => {
-                        // For any other unrecognized or malformed command,
-                        // just immediately complete it with an error
-                        let comp =
-                            Completion::generic_err(bits::STS_INTERNAL_ERR);
-                        cqe_permit.push_completion(sub.cid(), comp, ctx);
-                    }
+        let mech = state.ctrl.cc.ams();
+        let params = state.features.arbitration();
+        loop {
+            let mut next = None;
+            for &sqid in state.arbiter.order(mech) {
+                let sq = match state.sqs.get(sqid as usize) {
+                    Some(Some(sq)) => sq,
+                    _ => continue,
+                };
+                if let Some(popped) = sq.pop(ctx) {
+                    next = Some((sqid, popped));
+                    break;
                 }
             }
+            let (sqid, (sub, cqe_permit)) = next?;
+
+            state.arbiter.serviced(sqid, mech, params);
+            if sub.nsid != ns.nsid {
+                state.route_io(sub, cqe_permit, ctx);
+            } else if let Some(req) = io_req(state, sub, cqe_permit, ctx) {
+                return Some(req);
+            }
+            // Arbitrate again for the next command
         }
+    }
+}
 
-        None
+impl NvmeNs {
+    /// I/O commands taken off of their Submission Queues, but yet to be issued
+    /// in their entirety to the Block Device, along with the ID of the queue
+    /// each was taken from.
+    ///
+    /// Dataset Management commands with ranges left to deallocate are included
+    /// as a whole, as deallocating a range again is harmless.
+    pub(super) fn pending_cmds(&self) -> Vec<(QueueId, RawSubmission)> {
+        let mut cmds: Vec<_> = self
+            .routed
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(sub, cqe_permit)| Some((cqe_permit.sqid()?, *sub)))
+            .collect();
+
+        let deallocs = self.deallocs.lock().unwrap();
+        let mut split_cmds: Vec<&Arc<SplitCmd>> = Vec::new();
+        for dealloc in deallocs.iter() {
+            if !split_cmds.iter().any(|c| Arc::ptr_eq(c, &dealloc.cmd)) {
+                split_cmds.push(&dealloc.cmd);
+            }
+        }
+        for split in split_cmds {
+            let state = split.state.lock().unwrap();
+            if let Some(sqid) = state.cqe_permit.as_ref().and_then(|p| p.sqid())
+            {
+                cmds.push((sqid, split.sub));
+            }
+        }
+        cmds
     }
 }
 
-fn read_op(
+/// Turn an I/O command into a request for the Block Device of the namespace
+/// it targets, unless it can be completed immediately.
+fn io_req(
     state: &NvmeCtrl,
-    cid: u16,
+    sub: RawSubmission,
+    cqe_permit: CompQueueEntryPermit,
+    ctx: &DispCtx,
+) -> Option<Request> {
+    let logs = &state.logs;
+    let (ns, binfo) = match state.ns(sub.nsid) {
+        Some(ns) => match ns.binfo {
+            Some(binfo) => (ns, binfo),
+            None => {
+                // No media to service the command
+                let comp = Completion::generic_err(bits::STS_NS_NOT_READY);
+                logs.push_completion(&sub, None, comp, cqe_permit, ctx);
+                return None;
+            }
+        },
+        None => {
+            let comp = Completion::generic_err(bits::STS_INVALID_NS);
+            logs.push_completion(&sub, None, comp, cqe_permit, ctx);
+            return None;
+        }
+    };
+
+    let cmd = NvmCmd::parse(sub);
+    match cmd {
+        Ok(NvmCmd::Write(cmd)) if !binfo.writable => {
+            let comp = Completion::specific_err(
+                bits::StatusCodeType::CmdSpecific,
+                bits::STS_WRITE_READ_ONLY_RANGE,
+            );
+            logs.push_completion(&sub, Some(cmd.slba), comp, cqe_permit, ctx);
+            None
+        }
+        Ok(NvmCmd::Write(cmd)) => {
+            Some(write_op(logs, ns, sub, cmd, cqe_permit, ctx))
+        }
+        Ok(NvmCmd::Read(cmd)) => {
+            Some(read_op(logs, ns, sub, cmd, cqe_permit, ctx))
+        }
+        Ok(NvmCmd::Flush) => Some(flush_op(logs, ns, sub, cqe_permit)),
+        Ok(NvmCmd::WriteZeroes(cmd)) if !binfo.writable => {
+            let comp = Completion::specific_err(
+                bits::StatusCodeType::CmdSpecific,
+                bits::STS_WRITE_READ_ONLY_RANGE,
+            );
+            logs.push_completion(&sub, Some(cmd.slba), comp, cqe_permit, ctx);
+            None
+        }
+        Ok(NvmCmd::WriteZeroes(cmd)) => {
+            write_zeroes_op(logs, ns, sub, cmd, cqe_permit, ctx)
+        }
+        Ok(NvmCmd::DatasetMgmt(cmd)) if !binfo.writable && cmd.deallocate() => {
+            let comp = Completion::specific_err(
+                bits::StatusCodeType::CmdSpecific,
+                bits::STS_WRITE_READ_ONLY_RANGE,
+            );
+            logs.push_completion(&sub, None, comp, cqe_permit, ctx);
+            None
+        }
+        Ok(NvmCmd::DatasetMgmt(cmd)) => {
+            dataset_mgmt_op(logs, ns, sub, cmd, cqe_permit, ctx)
+        }
+        Ok(NvmCmd::Unknown(_)) | Err(_) => {
+            // For any other unrecognized or malformed command,
+            // just immediately complete it with an error
+            let comp = Completion::generic_err(bits::STS_INTERNAL_ERR);
+            logs.push_completion(&sub, None, comp, cqe_permit, ctx);
+            None
+        }
+    }
+}
+
+fn read_op(
+    logs: &Arc<LogPages>,
+    ns: &NsState,
+    sub: RawSubmission,
     cmd: cmds::ReadCmd,
     cqe_permit: CompQueueEntryPermit,
     ctx: &DispCtx,
 ) -> Request {
-    probes::nvme_read_enqueue!(|| (cid, cmd.slba, cmd.nlb));
+    probes::nvme_read_enqueue!(|| (sub.cid(), cmd.slba, cmd.nlb));
 
-    let off = state.nlb_to_size(cmd.slba as usize);
-    let size = state.nlb_to_size(cmd.nlb as usize);
+    let off = ns.nlb_to_size(cmd.slba as usize);
+    let size = ns.nlb_to_size(cmd.nlb as usize);
     let bufs = cmd.data(size as u64, ctx.mctx.memctx()).collect();
 
+    let logs = Arc::clone(logs);
     Request::new_read(
         off,
         bufs,
         Box::new(move |op, res, ctx| {
-            complete_block_req(cid, op, res, cqe_permit, ctx)
+            logs.record_io(op, size, res);
+            complete_block_req(
+                &logs,
+                sub,
+                Some(cmd.slba),
+                op,
+                res,
+                cqe_permit,
+                ctx,
+            )
         }),
     )
 }
 
 fn write_op(
-    state: &NvmeCtrl,
-    cid: u16,
+    logs: &Arc<LogPages>,
+    ns: &NsState,
+    sub: RawSubmission,
     cmd: cmds::WriteCmd,
     cqe_permit: CompQueueEntryPermit,
     ctx: &DispCtx,
 ) -> Request {
-    probes::nvme_write_enqueue!(|| (cid, cmd.slba, cmd.nlb));
+    probes::nvme_write_enqueue!(|| (sub.cid(), cmd.slba, cmd.nlb));
 
-    let off = state.nlb_to_size(cmd.slba as usize);
-    let size = state.nlb_to_size(cmd.nlb as usize);
+    let off = ns.nlb_to_size(cmd.slba as usize);
+    let size = ns.nlb_to_size(cmd.nlb as usize);
     let bufs = cmd.data(size as u64, ctx.mctx.memctx()).collect();
+
+    let logs = Arc::clone(logs);
     Request::new_write(
         off,
         bufs,
         Box::new(move |op, res, ctx| {
-            complete_block_req(cid, op, res, cqe_permit, ctx)
+            logs.record_io(op, size, res);
+            complete_block_req(
+                &logs,
+                sub,
+                Some(cmd.slba),
+                op,
+                res,
+                cqe_permit,
+                ctx,
+            )
         }),
     )
 }
 
 fn flush_op(
-    _state: &NvmeCtrl,
-    cid: u16,
+    logs: &Arc<LogPages>,
+    _ns: &NsState,
+    sub: RawSubmission,
     cqe_permit: CompQueueEntryPermit,
 ) -> Request {
+    let logs = Arc::clone(logs);
     Request::new_flush(
         0,
         0, // TODO: is 0 enough or do we pass total size?
         Box::new(move |op, res, ctx| {
-            complete_block_req(cid, op, res, cqe_permit, ctx)
+            complete_block_req(&logs, sub, None, op, res, cqe_permit, ctx)
         }),
     )
 }
 
+fn write_zeroes_op(
+    logs: &Arc<LogPages>,
+    ns: &NsState,
+    sub: RawSubmission,
+    cmd: cmds::WriteZeroesCmd,
+    cqe_permit: CompQueueEntryPermit,
+    ctx: &DispCtx,
+) -> Option<Request> {
+    if !lba_range_valid(ns, cmd.slba, cmd.nlb) {
+        let comp = Completion::generic_err(bits::STS_LBA_RANGE);
+        logs.push_completion(&sub, Some(cmd.slba), comp, cqe_permit, ctx);
+        return None;
+    }
+
+    let off = ns.nlb_to_size(cmd.slba as usize);
+    let size = ns.nlb_to_size(cmd.nlb as usize);
+
+    let logs = Arc::clone(logs);
+    Some(Request::new_write_zeroes(
+        off,
+        size,
+        Box::new(move |op, res, ctx| {
+            complete_block_req(
+                &logs,
+                sub,
+                Some(cmd.slba),
+                op,
+                res,
+                cqe_permit,
+                ctx,
+            )
+        }),
+    ))
+}
+
+fn dataset_mgmt_op(
+    logs: &Arc<LogPages>,
+    ns: &NsState,
+    sub: RawSubmission,
+    cmd: cmds::DatasetMgmtCmd,
+    cqe_permit: CompQueueEntryPermit,
+    ctx: &DispCtx,
+) -> Option<Request> {
+    // Only Deallocate calls for any action on our part, the other attributes
+    // being mere hints as to how the ranges will be accessed.
+    if !cmd.deallocate() {
+        logs.push_completion(
+            &sub,
+            None,
+            Completion::success(),
+            cqe_permit,
+            ctx,
+        );
+        return None;
+    }
+
+    let ranges = match read_ranges(&cmd, ctx) {
+        Some(ranges) => ranges,
+        None => {
+            let comp = Completion::generic_err(bits::STS_DATA_XFER_ERR);
+            logs.push_completion(&sub, None, comp, cqe_permit, ctx);
+            return None;
+        }
+    };
+    if let Some(bad) =
+        ranges.iter().find(|r| !lba_range_valid(ns, r.slba, r.nlb))
+    {
+        let comp = Completion::generic_err(bits::STS_LBA_RANGE);
+        logs.push_completion(&sub, Some(bad.slba), comp, cqe_permit, ctx);
+        return None;
+    }
+
+    // Each (non-empty) range is deallocated by its own block request, with the
+    // command being completed once all of them are.
+    let ranges: Vec<_> = ranges.into_iter().filter(|r| r.nlb != 0).collect();
+    if ranges.is_empty() {
+        logs.push_completion(
+            &sub,
+            None,
+            Completion::success(),
+            cqe_permit,
+            ctx,
+        );
+        return None;
+    }
+    let split = Arc::new(SplitCmd {
+        sub,
+        state: Mutex::new(SplitState {
+            remaining: ranges.len(),
+            res: block::Result::Success,
+            cqe_permit: Some(cqe_permit),
+        }),
+    });
+    let mut deallocs: VecDeque<_> = ranges
+        .iter()
+        .map(|r| Dealloc {
+            off: ns.nlb_to_size(r.slba as usize),
+            len: ns.nlb_to_size(r.nlb as usize),
+            cmd: Arc::clone(&split),
+        })
+        .collect();
+
+    // Issue the first range now, leaving the rest to be picked up by
+    // subsequent requests for work from the namespace's block device.
+    let first = deallocs.pop_front().unwrap();
+    ns.dev.deallocs.lock().unwrap().extend(deallocs);
+    Some(first.into_req(logs))
+}
+
+/// Read the range definitions of a Dataset Management command out of guest
+/// memory.
+fn read_ranges(
+    cmd: &cmds::DatasetMgmtCmd,
+    ctx: &DispCtx,
+) -> Option<Vec<DsmRange>> {
+    let mem = ctx.mctx.memctx();
+    let mut ranges = vec![DsmRange::default(); cmd.nr];
+    // Safety: The range definitions are plain-old-data, so can be viewed as
+    // the bytes to be read in.
+    let raw = unsafe {
+        std::slice::from_raw_parts_mut(
+            ranges.as_mut_ptr() as *mut u8,
+            size_of_val(&ranges[..]),
+        )
+    };
+
+    let mut done = 0;
+    for region in cmd.data(ctx.mctx.memctx()) {
+        done += mem.read_into(region.0, &mut raw[done..], region.1)?;
+    }
+    if done != raw.len() {
+        return None;
+    }
+    Some(ranges)
+}
+
+/// Check that the `nlb` logical blocks starting at `slba` lie within the
+/// namespace.
+fn lba_range_valid(ns: &NsState, slba: u64, nlb: u32) -> bool {
+    slba.checked_add(nlb as u64).map_or(false, |end| end <= ns.ident.nsze)
+}
+
+/// An I/O command serviced through multiple block requests, which is only
+/// completed once all of them are.
+struct SplitCmd {
+    sub: RawSubmission,
+    state: Mutex<SplitState>,
+}
+
+struct SplitState {
+    /// Block requests yet to be completed
+    remaining: usize,
+    /// Result for the command as a whole: that of the first failed request
+    res: block::Result,
+    cqe_permit: Option<CompQueueEntryPermit>,
+}
+
+impl SplitCmd {
+    /// Note the completion of one of the block requests for the command,
+    /// completing the command itself should it be the last.
+    fn part_done(
+        &self,
+        logs: &LogPages,
+        op: Operation,
+        res: block::Result,
+        ctx: &DispCtx,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        if matches!(state.res, block::Result::Success) {
+            state.res = res;
+        }
+        state.remaining -= 1;
+        if state.remaining == 0 {
+            let cqe_permit = state.cqe_permit.take().unwrap();
+            complete_block_req(
+                logs, self.sub, None, op, state.res, cqe_permit, ctx,
+            );
+        }
+    }
+}
+
+/// A range of logical blocks to be deallocated on behalf of a Dataset
+/// Management command.
+pub(super) struct Dealloc {
+    off: usize,
+    len: usize,
+    cmd: Arc<SplitCmd>,
+}
+
+impl Dealloc {
+    fn into_req(self, logs: &Arc<LogPages>) -> Request {
+        let logs = Arc::clone(logs);
+        let cmd = self.cmd;
+        Request::new_discard(
+            self.off,
+            self.len,
+            Box::new(move |op, res, ctx| cmd.part_done(&logs, op, res, ctx)),
+        )
+    }
+}
+
 /// Callback invoked by the underlying Block Device once it has completed an I/O op.
 ///
 /// Place the operation result (success or failure) onto the corresponding Completion Queue.
 fn complete_block_req(
-    cid: u16,
+    logs: &LogPages,
+    sub: RawSubmission,
+    lba: Option<u64>,
     op: Operation,
     res: block::Result,
     cqe_permit: CompQueueEntryPermit,
@@ -159,12 +497,19 @@ fn complete_block_req(
         block::Result::Failure => {
             Completion::generic_err(bits::STS_DATA_XFER_ERR)
         }
-        block::Result::Unsupported => Completion::specific_err(
-            bits::StatusCodeType::CmdSpecific,
-            bits::STS_READ_CONFLICTING_ATTRS,
-        ),
+        block::Result::Unsupported => match op {
+            // The backend is unable to deallocate or zero blocks
+            Operation::Discard(..) | Operation::WriteZeroes(..) => {
+                Completion::generic_err(bits::STS_INVAL_OPC)
+            }
+            _ => Completion::specific_err(
+                bits::StatusCodeType::CmdSpecific,
+                bits::STS_READ_CONFLICTING_ATTRS,
+            ),
+        },
     };
 
+    let cid = sub.cid();
     match op {
         Operation::Read(..) => {
             probes::nvme_read_complete!(|| (cid));
@@ -175,5 +520,180 @@ fn complete_block_req(
         _ => {}
     }
 
-    cqe_permit.push_completion(cid, comp, ctx);
+    logs.push_completion(&sub, lba, comp, cqe_permit, ctx);
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test_util::{nvm_cmd, rw_cmd, status, TestNvme, SUCCESS};
+    use super::super::NvmeVersion;
+    use super::*;
+    use crate::block::Backend;
+
+    const BS: usize = 512;
+    const BLOCKS: usize = 16;
+    const LBA_RANGE: (u8, u8) =
+        (bits::StatusCodeType::Generic as u8, bits::STS_LBA_RANGE);
+
+    /// Read back the whole of the namespace `nsid`.
+    fn read_all(nvme: &mut TestNvme, nsid: u32) -> Vec<u8> {
+        let buf = nvme.writable(BS * BLOCKS);
+        let read =
+            rw_cmd(bits::NVM_OPC_READ, 0x99, nsid, 0, BLOCKS as u16, buf);
+        assert_eq!(nvme.issue(read), SUCCESS);
+        nvme.contents(buf, BS * BLOCKS)
+    }
+
+    /// Build a Dataset Management command deallocating `ranges` (as starting
+    /// LBA and length) listed in guest memory.
+    fn dealloc_cmd(
+        nvme: &mut TestNvme,
+        cid: u16,
+        ranges: &[(u64, u32)],
+    ) -> RawSubmission {
+        let mut list = Vec::new();
+        for (slba, nlb) in ranges {
+            list.extend_from_slice(&0u32.to_le_bytes());
+            list.extend_from_slice(&nlb.to_le_bytes());
+            list.extend_from_slice(&slba.to_le_bytes());
+        }
+        RawSubmission {
+            prp1: nvme.readable(&list).0,
+            // 0's based
+            cdw10: ranges.len() as u32 - 1,
+            cdw11: bits::DSM_ATTR_DEALLOCATE,
+            ..nvm_cmd(bits::NVM_OPC_DATASET_MGMT, cid, 1)
+        }
+    }
+
+    #[test]
+    fn io_routed_by_nsid() {
+        let backends: Vec<_> = (1..=3u8)
+            .map(|i| {
+                block::InMemoryBackend::create(vec![i; 8 * BS], false, BS)
+                    .unwrap()
+            })
+            .collect();
+        let refs: Vec<&dyn Backend> =
+            backends.iter().map(|be| be.as_ref() as &dyn Backend).collect();
+        let mut nvme = TestNvme::new(NvmeVersion::V1_3, &refs);
+
+        // Each namespace reads back the contents of its own backend
+        for nsid in 1..=3u32 {
+            let buf = nvme.writable(BS);
+            let read = rw_cmd(bits::NVM_OPC_READ, nsid as u16, nsid, 0, 1, buf);
+            assert_eq!(nvme.issue(read), SUCCESS);
+            assert_eq!(nvme.contents(buf, BS), vec![nsid as u8; BS]);
+        }
+
+        // Writes interleaved across the namespaces land only in the backend
+        // of the namespace addressed, whichever namespace's block device
+        // happens to take them off the queue.
+        for nsid in [3u32, 1, 2, 1] {
+            let buf = nvme.readable(&[0xf0 | nsid as u8; BS]);
+            let write =
+                rw_cmd(bits::NVM_OPC_WRITE, 0x10, nsid, nsid as u64, 1, buf);
+            nvme.submit(write);
+        }
+        for _ in 0..4 {
+            let comp = nvme.next_completion();
+            assert_eq!(status(&comp), SUCCESS);
+        }
+        for (idx, be) in backends.iter().enumerate() {
+            let nsid = idx as u32 + 1;
+            let buf = nvme.writable(8 * BS);
+            let read = rw_cmd(bits::NVM_OPC_READ, 0x20, nsid, 0, 8, buf);
+            assert_eq!(nvme.issue(read), SUCCESS);
+
+            let mut expect = vec![nsid as u8; 8 * BS];
+            let off = nsid as usize * BS;
+            expect[off..(off + BS)].fill(0xf0 | nsid as u8);
+            assert_eq!(nvme.contents(buf, 8 * BS), expect);
+            let stats = be.driver().unwrap().stats().snapshot();
+            assert_eq!(stats.write.ops, if nsid == 1 { 2 } else { 1 });
+        }
+    }
+
+    #[test]
+    fn write_zeroes() {
+        let be =
+            block::InMemoryBackend::create(vec![0xff; BS * BLOCKS], false, BS)
+                .unwrap();
+        let mut nvme = TestNvme::new(NvmeVersion::V1_3, &[be.as_ref()]);
+        let zeroes = |slba: u64, nlb: u32| RawSubmission {
+            cdw10: slba as u32,
+            cdw11: (slba >> 32) as u32,
+            // 0's based
+            cdw12: nlb - 1,
+            ..nvm_cmd(bits::NVM_OPC_WRITE_ZEROES, 1, 1)
+        };
+
+        assert_eq!(nvme.issue(zeroes(2, 3)), SUCCESS);
+        let mut expect = vec![0xff; BS * BLOCKS];
+        expect[(2 * BS)..(5 * BS)].fill(0);
+        assert_eq!(read_all(&mut nvme, 1), expect);
+
+        // Ranges running past the end of the namespace are refused outright
+        assert_eq!(nvme.issue(zeroes(BLOCKS as u64 - 2, 3)), LBA_RANGE);
+        assert_eq!(nvme.issue(zeroes(u64::MAX, 2)), LBA_RANGE);
+        assert_eq!(read_all(&mut nvme, 1), expect);
+        let stats = be.driver().unwrap().stats().snapshot();
+        assert_eq!(stats.write_zeroes.ops, 1);
+    }
+
+    #[test]
+    fn dataset_mgmt_deallocate() {
+        let be =
+            block::InMemoryBackend::create(vec![0xff; BS * BLOCKS], false, BS)
+                .unwrap();
+        let mut nvme = TestNvme::new(NvmeVersion::V1_3, &[be.as_ref()]);
+
+        // Each non-empty range is deallocated, with the command completed
+        // once all of them are.
+        let dsm = dealloc_cmd(&mut nvme, 1, &[(0, 2), (8, 0), (10, 4)]);
+        assert_eq!(nvme.issue(dsm), SUCCESS);
+        let mut expect = vec![0xff; BS * BLOCKS];
+        expect[..(2 * BS)].fill(0);
+        expect[(10 * BS)..(14 * BS)].fill(0);
+        assert_eq!(read_all(&mut nvme, 1), expect);
+        let stats = be.driver().unwrap().stats().snapshot();
+        assert_eq!(stats.discard.ops, 2);
+
+        // Should any range lie beyond the end of the namespace, none are
+        // deallocated.
+        let dsm = dealloc_cmd(&mut nvme, 2, &[(4, 2), (15, 2)]);
+        assert_eq!(nvme.issue(dsm), LBA_RANGE);
+        let dsm = dealloc_cmd(&mut nvme, 3, &[(u64::MAX, 1)]);
+        assert_eq!(nvme.issue(dsm), LBA_RANGE);
+        assert_eq!(read_all(&mut nvme, 1), expect);
+        let stats = be.driver().unwrap().stats().snapshot();
+        assert_eq!(stats.discard.ops, 2);
+
+        // Without the Deallocate attribute, there is nothing to be done
+        let hint =
+            RawSubmission { cdw11: 0, ..dealloc_cmd(&mut nvme, 4, &[(4, 2)]) };
+        assert_eq!(nvme.issue(hint), SUCCESS);
+        assert_eq!(read_all(&mut nvme, 1), expect);
+    }
+
+    #[test]
+    fn deallocate_read_only() {
+        let be =
+            block::InMemoryBackend::create(vec![0xff; BS * BLOCKS], true, BS)
+                .unwrap();
+        let mut nvme = TestNvme::new(NvmeVersion::V1_3, &[be.as_ref()]);
+        let read_only = (
+            bits::StatusCodeType::CmdSpecific as u8,
+            bits::STS_WRITE_READ_ONLY_RANGE,
+        );
+
+        let dsm = dealloc_cmd(&mut nvme, 1, &[(0, 2)]);
+        assert_eq!(nvme.issue(dsm), read_only);
+        let zeroes = RawSubmission {
+            cdw12: 1,
+            ..nvm_cmd(bits::NVM_OPC_WRITE_ZEROES, 2, 1)
+        };
+        assert_eq!(nvme.issue(zeroes), read_only);
+        assert_eq!(read_all(&mut nvme, 1), vec![0xff; BS * BLOCKS]);
+    }
 }
@@ -0,0 +1,213 @@
+//! Helpers for exercising NVMe controllers in tests.
+//!
+//! A [`TestNvme`] plays the part of the host driver for a [`PciNvme`], with a
+//! single pair of I/O queues laid out in the guest memory of a test instance
+//! (see [`crate::block::test_util`]): the Submission Queue where the
+//! controller may only read it, and the Completion Queue where it may write
+//! to it.
+
+use std::mem::size_of;
+use std::sync::Arc;
+use std::time::Instant;
+
+use super::bits::{self, RawCompletion, RawSubmission};
+use super::{cmds, CtrlIdentity, NsIdentity, NvmeVersion, PciNvme};
+use crate::block::test_util::{TestInstance, READ_BASE, TIMEOUT, WRITE_BASE};
+use crate::block::Backend;
+use crate::common::{GuestAddr, GuestRegion, RWOp, WriteOp, PAGE_SIZE};
+use crate::hw::pci::{self, Device};
+
+/// Offset of the queues within each region, with buffers allocated below it
+const QUEUE_OFF: usize = 768 * 1024;
+
+/// Entries in each of the I/O queues
+const QUEUE_SIZE: u16 = 16;
+
+/// ID of the I/O queues, and thus of their doorbells
+const IO_QID: u16 = 1;
+
+/// Host side of an NVMe controller with a namespace for each of a set of
+/// block backends.
+pub(crate) struct TestNvme {
+    pub test: TestInstance,
+    pub nvme: Arc<PciNvme>,
+
+    sq_tail: u16,
+    cq_head: u16,
+    phase: bool,
+
+    /// Offsets of the next free buffer space in the readable and writable
+    /// regions of guest memory
+    read_off: usize,
+    write_off: usize,
+}
+impl TestNvme {
+    /// Create a controller presenting `version`, with a namespace attached
+    /// to each of `backends` in turn.
+    pub fn new(version: NvmeVersion, backends: &[&dyn Backend]) -> Self {
+        let test = TestInstance::new();
+        let nvme = PciNvme::create(
+            0x1de,
+            0x1000,
+            "test-nvme".to_string(),
+            version,
+            &CtrlIdentity::default(),
+        )
+        .unwrap();
+        for be in backends {
+            let ns = nvme.add_ns(be.info(), &NsIdentity::default()).unwrap();
+            test.attach(*be, &ns).unwrap();
+        }
+
+        test.with_ctx(|ctx| {
+            let mut state = nvme.state.lock().unwrap();
+            state.msix_hdl = Some(pci::MsixHdl::new_test());
+            let cq = WRITE_BASE + QUEUE_OFF;
+            state.create_cq(IO_QID, 0, cq, QUEUE_SIZE as u32, ctx).unwrap();
+            let sq = READ_BASE + QUEUE_OFF;
+            state
+                .create_sq(IO_QID, IO_QID, sq, QUEUE_SIZE as u32, ctx)
+                .unwrap();
+            state.arbiter.add_sq(IO_QID, cmds::QueuePriority::Medium);
+        });
+
+        Self {
+            test,
+            nvme,
+            sq_tail: 0,
+            cq_head: 0,
+            phase: true,
+            read_off: 0,
+            write_off: 0,
+        }
+    }
+
+    /// Place `data` in guest memory the controller may read, returning its
+    /// (page-aligned) address.
+    pub fn readable(&mut self, data: &[u8]) -> GuestAddr {
+        let addr = READ_BASE + self.read_off;
+        self.read_off += round_page(data.len());
+        assert!(self.read_off <= QUEUE_OFF);
+        self.test.fill(addr, data);
+        addr
+    }
+
+    /// Allocate `len` bytes of guest memory the controller may write,
+    /// returning its (page-aligned) address.
+    pub fn writable(&mut self, len: usize) -> GuestAddr {
+        let addr = WRITE_BASE + self.write_off;
+        self.write_off += round_page(len);
+        assert!(self.write_off <= QUEUE_OFF);
+        addr
+    }
+
+    /// Read `len` bytes of guest memory at `addr`.
+    pub fn contents(&self, addr: GuestAddr, len: usize) -> Vec<u8> {
+        self.test.contents(addr, len)
+    }
+
+    /// Submit `sub` on the I/O Submission Queue and ring its doorbell.
+    pub fn submit(&mut self, sub: RawSubmission) {
+        let addr = READ_BASE
+            + QUEUE_OFF
+            + self.sq_tail as usize * size_of::<RawSubmission>();
+        self.test.with_ctx(|ctx| {
+            let mem = ctx.mctx.memctx();
+            let region = GuestRegion(addr, size_of::<RawSubmission>());
+            mem.direct_writable_region(&region).unwrap().write(&sub).unwrap();
+        });
+        self.sq_tail = (self.sq_tail + 1) % QUEUE_SIZE;
+        self.doorbell(0x1000 + 2 * 4 * IO_QID as usize, self.sq_tail);
+    }
+
+    /// Wait for the next entry to be posted to the I/O Completion Queue.
+    pub fn next_completion(&mut self) -> RawCompletion {
+        let addr = WRITE_BASE
+            + QUEUE_OFF
+            + self.cq_head as usize * size_of::<RawCompletion>();
+        let start = Instant::now();
+        let comp = loop {
+            let mut comp = None;
+            self.test.with_ctx(|ctx| {
+                let mem = ctx.mctx.memctx();
+                let region = GuestRegion(addr, size_of::<RawCompletion>());
+                let map = mem.direct_readable_region(&region).unwrap();
+                comp = Some(map.read::<RawCompletion>().unwrap());
+            });
+            let comp = comp.unwrap();
+            if (comp.status_phase & 1 == 1) == self.phase {
+                break comp;
+            }
+            assert!(start.elapsed() < TIMEOUT, "command did not complete");
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        };
+        self.cq_head = (self.cq_head + 1) % QUEUE_SIZE;
+        if self.cq_head == 0 {
+            self.phase = !self.phase;
+        }
+        self.doorbell(0x1000 + (2 * IO_QID as usize + 1) * 4, self.cq_head);
+        comp
+    }
+
+    /// Submit `sub` and wait for its completion, returning the Status Code
+    /// Type and Status Code it completed with.
+    pub fn issue(&mut self, sub: RawSubmission) -> (u8, u8) {
+        self.submit(sub);
+        let comp = self.next_completion();
+        assert_eq!(comp.cid, sub.cid());
+        status(&comp)
+    }
+
+    fn doorbell(&self, off: usize, val: u16) {
+        let buf = (val as u32).to_le_bytes();
+        self.test.with_ctx(|ctx| {
+            let mut wo = WriteOp::from_buf(off, &buf);
+            self.nvme.bar_rw(pci::BarN::BAR0, RWOp::Write(&mut wo), ctx);
+        });
+    }
+}
+
+/// Build an NVM command with opcode `opc` and identifier `cid`, addressed to
+/// the namespace `nsid`.
+pub(crate) fn nvm_cmd(opc: u8, cid: u16, nsid: u32) -> RawSubmission {
+    RawSubmission {
+        cdw0: (cid as u32) << 16 | opc as u32,
+        nsid,
+        ..Default::default()
+    }
+}
+
+/// Build a Read or Write command for the `nlb` logical blocks starting at
+/// `slba`, transferring data through the (at most two) pages at `buf`.
+pub(crate) fn rw_cmd(
+    opc: u8,
+    cid: u16,
+    nsid: u32,
+    slba: u64,
+    nlb: u16,
+    buf: GuestAddr,
+) -> RawSubmission {
+    RawSubmission {
+        prp1: buf.0,
+        prp2: buf.0 + PAGE_SIZE as u64,
+        cdw10: slba as u32,
+        cdw11: (slba >> 32) as u32,
+        // 0's based
+        cdw12: (nlb - 1) as u32,
+        ..nvm_cmd(opc, cid, nsid)
+    }
+}
+
+/// The Status Code Type and Status Code of a completion.
+pub(crate) fn status(comp: &RawCompletion) -> (u8, u8) {
+    let sf = comp.status_phase >> 1;
+    (((sf >> 8) & 0x7) as u8, sf as u8)
+}
+
+/// Status of a successful command
+pub(crate) const SUCCESS: (u8, u8) =
+    (bits::StatusCodeType::Generic as u8, bits::STS_SUCCESS);
+
+fn round_page(len: usize) -> usize {
+    (len + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
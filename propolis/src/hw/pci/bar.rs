@@ -2,6 +2,7 @@ use std::convert::TryFrom;
 
 use super::bits;
 use super::BarN;
+use crate::migrate::MigrateStateError;
 
 pub const BAR_COUNT: usize = 6;
 
@@ -214,25 +215,60 @@ impl Bars {
         }
         migrate::BarStateV1 { entries }
     }
+
+    pub(super) fn import(
+        &mut self,
+        state: migrate::BarStateV1,
+    ) -> Result<(), MigrateStateError> {
+        for bar in state.entries {
+            let ent =
+                self.entries.get_mut(bar.n as usize).ok_or_else(|| {
+                    MigrateStateError::ImportFailed(format!(
+                        "BAR{} out of range",
+                        bar.n
+                    ))
+                })?;
+            let matches = match (ent.kind, bar.kind) {
+                (EntryKind::Pio(sz), migrate::BarKindV1::Pio) => {
+                    sz as u64 == bar.size && bar.value <= u16::MAX as u64
+                }
+                (EntryKind::Mmio(sz), migrate::BarKindV1::Mmio) => {
+                    sz as u64 == bar.size && bar.value <= u32::MAX as u64
+                }
+                (EntryKind::Mmio64(sz), migrate::BarKindV1::Mmio64) => {
+                    sz == bar.size
+                }
+                _ => false,
+            };
+            if !matches {
+                return Err(MigrateStateError::ImportFailed(format!(
+                    "BAR{} definition mismatch",
+                    bar.n
+                )));
+            }
+            ent.value = bar.value;
+        }
+        Ok(())
+    }
 }
 
 pub mod migrate {
-    use serde::Serialize;
+    use serde::{Deserialize, Serialize};
 
-    #[derive(Serialize)]
+    #[derive(Deserialize, Serialize)]
     pub enum BarKindV1 {
         Pio,
         Mmio,
         Mmio64,
     }
-    #[derive(Serialize)]
+    #[derive(Deserialize, Serialize)]
     pub struct BarEntryV1 {
         pub n: u8,
         pub kind: BarKindV1,
         pub size: u64,
         pub value: u64,
     }
-    #[derive(Serialize)]
+    #[derive(Deserialize, Serialize)]
     pub struct BarStateV1 {
         pub entries: Vec<BarEntryV1>,
     }
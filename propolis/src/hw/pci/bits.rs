@@ -47,6 +47,7 @@ pub const CLASS_DISPLAY: u8 = 3;
 pub const CLASS_MULTIMEDIA: u8 = 4;
 pub const CLASS_MEMORY: u8 = 5;
 pub const CLASS_BRIDGE: u8 = 6;
+pub const CLASS_COMM: u8 = 7;
 
 pub const HEADER_TYPE_DEVICE: u8 = 0b0;
 pub const HEADER_TYPE_BRIDGE: u8 = 0b1;
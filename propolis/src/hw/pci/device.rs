@@ -6,6 +6,7 @@ use super::{bus, BarN, Endpoint};
 use crate::common::*;
 use crate::dispatch::DispCtx;
 use crate::intr_pins::IntrPin;
+use crate::migrate::MigrateStateError;
 use crate::util::regmap::{Flags, RegMap};
 
 use lazy_static::lazy_static;
@@ -40,9 +41,20 @@ pub trait Device: Send + Sync + 'static {
     fn interrupt_mode_change(&self, mode: IntrMode) {}
     #[allow(unused_variables)]
     fn msi_update(&self, info: MsiUpdate, ctx: &DispCtx) {}
-    // TODO
-    // fn cap_read(&self);
-    // fn cap_write(&self);
+    /// Access the body of a vendor-specific capability which was added (at
+    /// config space `offset`) with [`Builder::add_cap_vendor_custom`].  The
+    /// body begins with the capability length byte.
+    #[allow(unused_variables)]
+    fn cap_rw(&self, offset: u8, rwo: RWOp, ctx: &DispCtx) {
+        match rwo {
+            RWOp::Read(ro) => {
+                unimplemented!("CAP read ({:x} @ {:x})", offset, ro.offset())
+            }
+            RWOp::Write(wo) => {
+                unimplemented!("CAP write ({:x} @ {:x})", offset, wo.offset())
+            }
+        }
+    }
 }
 
 impl<D: Device + Send + Sync + 'static> Endpoint for D {
@@ -194,6 +206,8 @@ impl State {
 struct Cap {
     id: u8,
     offset: u8,
+    /// Fixed contents of the capability body, if it has no custom handling
+    data: Vec<u8>,
 }
 
 pub struct DeviceState {
@@ -511,6 +525,15 @@ impl DeviceState {
                     );
                 }
             }
+            CAP_ID_VENDOR if cap.data.is_empty() => {
+                dev.cap_rw(cap.offset, rwo, ctx);
+            }
+            CAP_ID_VENDOR => {
+                // Vendor-specific capabilities are otherwise read-only
+                if let RWOp::Read(ro) = rwo {
+                    ro.write_bytes(&cap.data);
+                }
+            }
             _ => {
                 slog::info!(ctx.log, "unhandled PCI cap access";
                     "id" => cap.id, "offset" => rwo.offset());
@@ -577,6 +600,40 @@ impl DeviceState {
             msix,
         }
     }
+
+    /// Restore the state of the device from that exported by its counterpart
+    /// on the source of a migration.
+    ///
+    /// BARs enabled by the command register are registered, and the device is
+    /// notified of its resulting interrupt mode.
+    pub fn import(
+        &self,
+        dev: &dyn Device,
+        state: migrate::PciStateV1,
+    ) -> Result<(), MigrateStateError> {
+        let mut inner = self.state.lock().unwrap();
+        match (self.msix_cfg.as_ref(), state.msix) {
+            (Some(cfg), Some(msix)) => cfg.import(msix)?,
+            (None, None) => {}
+            _ => {
+                return Err(MigrateStateError::ImportFailed(
+                    "MSI-X capability mismatch".to_string(),
+                ))
+            }
+        }
+        inner.bars.import(state.bars)?;
+        inner.reg_intr_line = state.reg_intr_line;
+        drop(inner);
+
+        // Apply the command register as if written by the guest, so as to
+        // register any enabled BARs.
+        self.reg_cmd_write(dev, RegCmd::from_bits_truncate(state.reg_command));
+
+        // The interrupt mode may have been changed by the MSI-X state as well.
+        let inner = self.state.lock().unwrap();
+        let _inner = self.affects_intr_mode(dev, inner, |_state| {});
+        Ok(())
+    }
 }
 
 #[derive(Copy, Clone, Eq, PartialEq)]
@@ -923,6 +980,31 @@ impl MsixCfg {
         drop(state);
         self.each_entry(|ent| ent.reset());
     }
+    fn import(
+        &self,
+        msix: migrate::MsixStateV1,
+    ) -> Result<(), MigrateStateError> {
+        if msix.count != self.count || msix.entries.len() != self.count as usize
+        {
+            return Err(MigrateStateError::ImportFailed(format!(
+                "MSI-X vector count mismatch: {} vs {}",
+                msix.count, self.count
+            )));
+        }
+        let mut state = self.state.lock().unwrap();
+        state.enabled = msix.is_enabled;
+        state.func_mask = msix.is_func_masked;
+        for (ent, saved) in self.entries.iter().zip(msix.entries.iter()) {
+            let mut ent = ent.lock().unwrap();
+            ent.addr = saved.addr;
+            ent.data = saved.data;
+            ent.pending = saved.is_pending;
+            ent.mask_vec = saved.is_vec_masked;
+            ent.mask_func = msix.is_func_masked;
+            ent.enabled = msix.is_enabled;
+        }
+        Ok(())
+    }
     fn export(&self) -> migrate::MsixStateV1 {
         let state = self.state.lock().unwrap();
         let mut entries = Vec::new();
@@ -1080,7 +1162,7 @@ impl Builder {
         self
     }
 
-    fn add_cap_raw(&mut self, id: u8, len: u8) {
+    fn add_cap_raw(&mut self, id: u8, len: u8, data: Vec<u8>) {
         // XXX: does not pay heed to any custom cfg sections which are added via
         // the `add_custom_cfg` interface.
         let end = self.cap_next_alloc + 2 + len as usize;
@@ -1088,7 +1170,7 @@ impl Builder {
         assert!(end % 4 == 0);
         assert!(end <= u8::MAX as usize);
         let idx = self.caps.len() as u8;
-        self.caps.push(Cap { id, offset: self.cap_next_alloc as u8 });
+        self.caps.push(Cap { id, offset: self.cap_next_alloc as u8, data });
         self.cfgmap.define(self.cap_next_alloc, 1, CfgReg::CapId(idx));
         self.cfgmap.define(self.cap_next_alloc + 1, 1, CfgReg::CapNext(idx));
         self.cfgmap.define(
@@ -1114,7 +1196,41 @@ impl Builder {
         assert!(bar_size < u32::MAX as usize);
         self = self.add_bar_mmio(bar, bar_size as u32);
         self.msix_cfg = Some(cfg);
-        self.add_cap_raw(CAP_ID_MSIX, 10);
+        self.add_cap_raw(CAP_ID_MSIX, 10, Vec::new());
+
+        self
+    }
+
+    /// Add a vendor-specific capability.  Its length field is filled in, with
+    /// `data` making up the (read-only) remainder of the capability.
+    ///
+    /// # Panics
+    ///
+    /// If the capability would not end on a 4-byte boundary, or would not fit
+    /// in the config space.
+    pub fn add_cap_vendor(mut self, data: &[u8]) -> Self {
+        let len = data.len() + 1;
+        assert!(len + 2 <= u8::MAX as usize);
+
+        let mut body = Vec::with_capacity(len);
+        body.push(len as u8 + 2);
+        body.extend_from_slice(data);
+        self.add_cap_raw(CAP_ID_VENDOR, len as u8, body);
+
+        self
+    }
+
+    /// Add a vendor-specific capability with a body (including its length
+    /// field) of `len` bytes, accesses to which are handled by the device
+    /// through [`Device::cap_rw`].
+    ///
+    /// # Panics
+    ///
+    /// If the capability would not end on a 4-byte boundary, or would not fit
+    /// in the config space.
+    pub fn add_cap_vendor_custom(mut self, len: u8) -> Self {
+        assert!(len != 0);
+        self.add_cap_raw(CAP_ID_VENDOR, len, Vec::new());
 
         self
     }
@@ -1134,9 +1250,9 @@ impl Builder {
 pub mod migrate {
     use crate::hw::pci::bar;
 
-    use serde::Serialize;
+    use serde::{Deserialize, Serialize};
 
-    #[derive(Serialize)]
+    #[derive(Deserialize, Serialize)]
     pub struct MsixEntryV1 {
         pub addr: u64,
         pub data: u32,
@@ -1144,7 +1260,7 @@ pub mod migrate {
         pub is_vec_masked: bool,
     }
 
-    #[derive(Serialize)]
+    #[derive(Deserialize, Serialize)]
     pub struct MsixStateV1 {
         pub count: u16,
         pub is_enabled: bool,
@@ -1152,7 +1268,7 @@ pub mod migrate {
         pub entries: Vec<MsixEntryV1>,
     }
 
-    #[derive(Serialize)]
+    #[derive(Deserialize, Serialize)]
     pub struct PciStateV1 {
         pub reg_command: u16,
         pub reg_intr_line: u8,
@@ -1,46 +1,66 @@
 pub const VIRTIO_DEV_NET: u16 = 0x1000;
 pub const VIRTIO_DEV_BLOCK: u16 = 0x1001;
+pub const VIRTIO_DEV_CONSOLE: u16 = 0x1003;
+pub const VIRTIO_DEV_RNG: u16 = 0x1005;
+
+// ISR status bits
+pub const VIRTIO_ISR_QUEUE: u8 = 1 << 0;
+pub const VIRTIO_ISR_CONFIG: u8 = 1 << 1;
+
+// PCI capability configuration types (modern interface)
+pub const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+pub const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+pub const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+pub const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+pub const VIRTIO_PCI_CAP_PCI_CFG: u8 = 5;
 
 // Legacy interface feature bits
-pub const VIRTIO_F_NOTIFY_ON_EMPTY: usize = 1 << 24;
-pub const VIRTIO_F_ANY_LAYOUT: usize = 1 << 27;
+pub const VIRTIO_F_NOTIFY_ON_EMPTY: u64 = 1 << 24;
+pub const VIRTIO_F_ANY_LAYOUT: u64 = 1 << 27;
 
 // Standard interface feature bits
-pub const VIRTIO_F_RING_INDIRECT_DESC: usize = 1 << 28;
-pub const VIRTIO_F_RING_EVENT_IDX: usize = 1 << 29;
-pub const VIRTIO_F_VERSION_1: usize = 1 << 32;
+pub const VIRTIO_F_RING_INDIRECT_DESC: u64 = 1 << 28;
+pub const VIRTIO_F_RING_EVENT_IDX: u64 = 1 << 29;
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+pub const VIRTIO_F_RING_PACKED: u64 = 1 << 34;
+pub const VIRTIO_F_IN_ORDER: u64 = 1 << 35;
 
 // virtio-net feature bits
-pub const VIRTIO_NET_F_CSUM: u32 = 1 << 0;
-pub const VIRTIO_NET_F_GUEST_CSUM: u32 = 1 << 1;
-pub const VIRTIO_NET_F_CTRL_GUEST_OFFLOADS: u32 = 1 << 2;
-pub const VIRTIO_NET_F_MTU: u32 = 1 << 3;
-pub const VIRTIO_NET_F_MAC: u32 = 1 << 5;
-pub const VIRTIO_NET_F_GUEST_TSO4: u32 = 1 << 7;
-pub const VIRTIO_NET_F_GUEST_TSO6: u32 = 1 << 8;
-pub const VIRTIO_NET_F_GUEST_ECN: u32 = 1 << 9;
-pub const VIRTIO_NET_F_GUEST_UFO: u32 = 1 << 10;
-pub const VIRTIO_NET_F_HOST_TSO4: u32 = 1 << 11;
-pub const VIRTIO_NET_F_HOST_TSO6: u32 = 1 << 12;
-pub const VIRTIO_NET_F_HOST_ECN: u32 = 1 << 13;
-pub const VIRTIO_NET_F_HOST_UFO: u32 = 1 << 14;
-pub const VIRTIO_NET_F_MGR_RXBUF: u32 = 1 << 15;
-pub const VIRTIO_NET_F_STATUS: u32 = 1 << 16;
-pub const VIRTIO_NET_F_CTRL_VQ: u32 = 1 << 17;
-pub const VIRTIO_NET_F_CTRL_RX: u32 = 1 << 18;
-pub const VIRTIO_NET_F_CTRL_VLAN: u32 = 1 << 19;
+pub const VIRTIO_NET_F_CSUM: u64 = 1 << 0;
+pub const VIRTIO_NET_F_GUEST_CSUM: u64 = 1 << 1;
+pub const VIRTIO_NET_F_CTRL_GUEST_OFFLOADS: u64 = 1 << 2;
+pub const VIRTIO_NET_F_MTU: u64 = 1 << 3;
+pub const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+pub const VIRTIO_NET_F_GUEST_TSO4: u64 = 1 << 7;
+pub const VIRTIO_NET_F_GUEST_TSO6: u64 = 1 << 8;
+pub const VIRTIO_NET_F_GUEST_ECN: u64 = 1 << 9;
+pub const VIRTIO_NET_F_GUEST_UFO: u64 = 1 << 10;
+pub const VIRTIO_NET_F_HOST_TSO4: u64 = 1 << 11;
+pub const VIRTIO_NET_F_HOST_TSO6: u64 = 1 << 12;
+pub const VIRTIO_NET_F_HOST_ECN: u64 = 1 << 13;
+pub const VIRTIO_NET_F_HOST_UFO: u64 = 1 << 14;
+pub const VIRTIO_NET_F_MGR_RXBUF: u64 = 1 << 15;
+pub const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+pub const VIRTIO_NET_F_CTRL_VQ: u64 = 1 << 17;
+pub const VIRTIO_NET_F_CTRL_RX: u64 = 1 << 18;
+pub const VIRTIO_NET_F_CTRL_VLAN: u64 = 1 << 19;
 
 // virtio-block feature bits
-pub const VIRTIO_BLK_F_SIZE_MAX: u32 = 1 << 1;
-pub const VIRTIO_BLK_F_SEG_MAX: u32 = 1 << 2;
-pub const VIRTIO_BLK_F_GEOMETRY: u32 = 1 << 4;
-pub const VIRTIO_BLK_F_RO: u32 = 1 << 5;
-pub const VIRTIO_BLK_F_BLK_SIZE: u32 = 1 << 6;
-pub const VIRTIO_BLK_F_FLUSH: u32 = 1 << 9;
-pub const VIRTIO_BLK_F_TOPOLOGY: u32 = 1 << 10;
-pub const VIRTIO_BLK_F_CONFIG_WCE: u32 = 1 << 11;
-pub const VIRTIO_BLK_F_DISCARD: u32 = 1 << 13;
-pub const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 1 << 14;
+pub const VIRTIO_BLK_F_SIZE_MAX: u64 = 1 << 1;
+pub const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+pub const VIRTIO_BLK_F_GEOMETRY: u64 = 1 << 4;
+pub const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+pub const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+pub const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+pub const VIRTIO_BLK_F_TOPOLOGY: u64 = 1 << 10;
+pub const VIRTIO_BLK_F_CONFIG_WCE: u64 = 1 << 11;
+pub const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+pub const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 1 << 14;
+
+// virtio-console feature bits
+pub const VIRTIO_CONSOLE_F_SIZE: u64 = 1 << 0;
+pub const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+pub const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
 
 // virtqueue descriptor bits
 pub const VIRTQ_DESC_F_NEXT: u16 = 1;
@@ -48,3 +68,12 @@ pub const VIRTQ_DESC_F_WRITE: u16 = 2;
 pub const VIRTQ_DESC_F_INDIRECT: u16 = 4;
 pub const VRING_AVAIL_F_NO_INTERRUPT: u16 = 1;
 pub const VRING_USED_F_NO_NOTIFY: u16 = 1;
+
+// packed virtqueue descriptor bits
+pub const VIRTQ_DESC_F_AVAIL: u16 = 1 << 7;
+pub const VIRTQ_DESC_F_USED: u16 = 1 << 15;
+
+// packed virtqueue event suppression flags
+pub const RING_EVENT_FLAGS_ENABLE: u16 = 0;
+pub const RING_EVENT_FLAGS_DISABLE: u16 = 1;
+pub const RING_EVENT_FLAGS_DESC: u16 = 2;
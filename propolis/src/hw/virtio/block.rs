@@ -1,5 +1,5 @@
 use std::num::NonZeroU16;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use crate::block;
 use crate::common::*;
@@ -20,11 +20,17 @@ use lazy_static::lazy_static;
 /// Sizing for virtio-block is specified in 512B sectors
 const SECTOR_SZ: usize = 512;
 
+/// Arbitrary limit on sectors per discard/write-zeroes request (2GiB)
+const MAX_DISCARD_SECTORS: u32 = 1 << 22;
+
 pub struct PciVirtioBlock {
     virtio_state: PciVirtioState,
     pci_state: pci::DeviceState,
 
-    info: block::DeviceInfo,
+    /// Currently inserted media, if any
+    media: Mutex<Option<block::DeviceInfo>>,
+    /// Whether the device (and thus any media inserted into it) is writable
+    writable: bool,
     notifier: block::Notifier,
 }
 impl PciVirtioBlock {
@@ -43,14 +49,26 @@ impl PciVirtioBlock {
             VIRTIO_DEV_BLOCK,
             pci::bits::CLASS_STORAGE,
             VIRTIO_BLK_CFG_SIZE,
+            true,
         );
 
         let notifier = block::Notifier::new();
-        Arc::new(Self { pci_state, virtio_state, info, notifier })
+        Arc::new(Self {
+            pci_state,
+            virtio_state,
+            media: Mutex::new(Some(info)),
+            writable: info.writable,
+            notifier,
+        })
     }
 
     fn block_cfg_read(&self, id: &BlockReg, ro: &mut ReadOp) {
-        let info = self.info;
+        // Without media, report a zero capacity (atop the minimum block size)
+        let info = self.media.lock().unwrap().unwrap_or(block::DeviceInfo {
+            block_size: SECTOR_SZ as u32,
+            total_size: 0,
+            writable: self.writable,
+        });
         let total_bytes = info.total_size * info.block_size as u64;
         match id {
             BlockReg::Capacity => {
@@ -61,6 +79,17 @@ impl PciVirtioBlock {
                 ro.write_u32(128 - 2);
             }
             BlockReg::BlockSize => ro.write_u32(info.block_size),
+            BlockReg::MaxDiscardSectors | BlockReg::MaxZeroSectors => {
+                ro.write_u32(MAX_DISCARD_SECTORS);
+            }
+            BlockReg::MaxDiscardSeg | BlockReg::MaxZeroSeg => {
+                // Only a single segment is accepted per request
+                ro.write_u32(1);
+            }
+            BlockReg::DiscardSectorAlign => {
+                ro.write_u32(info.block_size / SECTOR_SZ as u32);
+            }
+            BlockReg::ZeroMayUnmap => ro.write_u8(1),
             BlockReg::Unused => {
                 ro.fill(0);
             }
@@ -72,21 +101,51 @@ impl PciVirtioBlock {
     }
 
     fn next_req(&self, ctx: &DispCtx) -> Option<block::Request> {
+        let info = match *self.media.lock().unwrap() {
+            Some(info) => info,
+            None => {
+                self.fail_pending(ctx);
+                return None;
+            }
+        };
+        let capacity = info.total_size * info.block_size as u64;
+
         let vq = &self.virtio_state.queues[0];
         let mem = &ctx.mctx.memctx();
 
-        let mut chain = Chain::with_capacity(4);
-        let _clen = vq.pop_avail(&mut chain, mem)?;
+        // Requests which cannot be issued are completed here, moving on to
+        // the next available chain, so as not to strand those behind them.
+        loop {
+            let mut chain = Chain::with_capacity(4);
+            let _clen = vq.pop_avail(&mut chain, mem, ctx)?;
+            match self.chain_req(chain, capacity, vq, ctx) {
+                Ok(req) => return Some(req),
+                Err((chain, status)) => fail_chain(chain, status, vq, ctx),
+            }
+        }
+    }
+
+    /// Build the block request described by `chain`, or give it back along
+    /// with the status with which it should be failed.
+    fn chain_req(
+        &self,
+        mut chain: Chain,
+        capacity: u64,
+        vq: &Arc<VirtQueue>,
+        ctx: &DispCtx,
+    ) -> Result<block::Request, (Chain, u8)> {
+        let mem = &ctx.mctx.memctx();
 
         let mut breq = VbReq::default();
         if !chain.read(&mut breq, mem) {
-            todo!("error handling");
+            return Err((chain, VIRTIO_BLK_S_IOERR));
         }
-        let req = match breq.rtype {
+        match breq.rtype {
             VIRTIO_BLK_T_IN => {
                 // should be (blocksize * 512) + 1 remaining writable byte for status
                 // TODO: actually enforce block size
-                let blocks = (chain.remain_write_bytes() - 1) / SECTOR_SZ;
+                let blocks =
+                    chain.remain_write_bytes().saturating_sub(1) / SECTOR_SZ;
 
                 if let Some(regions) = chain.writable_bufs(blocks * SECTOR_SZ) {
                     let mvq = Arc::clone(vq);
@@ -98,7 +157,7 @@ impl PciVirtioBlock {
                         }),
                     ))
                 } else {
-                    Err(chain)
+                    Err((chain, VIRTIO_BLK_S_UNSUPP))
                 }
             }
             VIRTIO_BLK_T_OUT => {
@@ -115,23 +174,64 @@ impl PciVirtioBlock {
                         }),
                     ))
                 } else {
-                    Err(chain)
+                    Err((chain, VIRTIO_BLK_S_UNSUPP))
                 }
             }
-            _ => Err(chain),
-        };
-        match req {
-            Err(mut chain) => {
-                // try to set the status byte to failed
-                let remain = chain.remain_write_bytes();
-                if remain >= 1 {
-                    chain.write_skip(remain - 1);
-                    chain.write(&VIRTIO_BLK_S_UNSUPP, mem);
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                // should be a single segment descriptor, followed by 1
+                // writable byte for status
+                let mut seg = VbDiscardWriteZeroes::default();
+                if !self.writable
+                    || chain.remain_read_bytes()
+                        != std::mem::size_of::<VbDiscardWriteZeroes>()
+                    || !chain.read(&mut seg, mem)
+                {
+                    return Err((chain, VIRTIO_BLK_S_UNSUPP));
+                }
+
+                // An empty range has no meaning to the backends (and some
+                // would take it to mean "to the end of the disk"), so it is
+                // rejected along with any range beyond the disk.
+                let end = seg
+                    .sector
+                    .checked_add(seg.num_sectors as u64)
+                    .and_then(|end| end.checked_mul(SECTOR_SZ as u64));
+                match end {
+                    Some(end)
+                        if seg.num_sectors != 0
+                            && seg.num_sectors <= MAX_DISCARD_SECTORS
+                            && end <= capacity => {}
+                    _ => return Err((chain, VIRTIO_BLK_S_IOERR)),
+                }
+
+                let off = seg.sector as usize * SECTOR_SZ;
+                let len = seg.num_sectors as usize * SECTOR_SZ;
+                let mvq = Arc::clone(vq);
+                let donef: Box<block::CompleteFn> =
+                    Box::new(move |_op, res, ctx| {
+                        complete_blockreq(res, chain, mvq, ctx);
+                    });
+                if breq.rtype == VIRTIO_BLK_T_DISCARD {
+                    Ok(block::Request::new_discard(off, len, donef))
+                } else {
+                    Ok(block::Request::new_write_zeroes(off, len, donef))
                 }
-                vq.push_used(&mut chain, mem, ctx);
-                None
             }
-            Ok(r) => Some(r),
+            _ => Err((chain, VIRTIO_BLK_S_UNSUPP)),
+        }
+    }
+
+    /// Fail any requests pending in the queue, as there is no media present
+    /// to service them.
+    fn fail_pending(&self, ctx: &DispCtx) {
+        let vq = &self.virtio_state.queues[0];
+        let mem = &ctx.mctx.memctx();
+        loop {
+            let mut chain = Chain::with_capacity(4);
+            if vq.pop_avail(&mut chain, mem, ctx).is_none() {
+                break;
+            }
+            fail_chain(chain, VIRTIO_BLK_S_IOERR, vq, ctx);
         }
     }
 }
@@ -145,21 +245,29 @@ impl VirtioDevice for PciVirtioBlock {
             }
         });
     }
-    fn get_features(&self) -> u32 {
+    fn get_features(&self) -> u64 {
         let mut feat = VIRTIO_BLK_F_BLK_SIZE;
         feat |= VIRTIO_BLK_F_SEG_MAX;
+        feat |= VIRTIO_F_RING_EVENT_IDX;
 
-        if !self.info.writable {
+        if !self.writable {
             feat |= VIRTIO_BLK_F_RO;
+        } else {
+            feat |= VIRTIO_BLK_F_DISCARD;
+            feat |= VIRTIO_BLK_F_WRITE_ZEROES;
         }
         feat
     }
-    fn set_features(&self, _feat: u32) {
+    fn set_features(&self, _feat: u64) {
         // XXX: real features
     }
 
     fn queue_notify(&self, _vq: &Arc<VirtQueue>, ctx: &DispCtx) {
-        self.notifier.notify(self, ctx);
+        if self.media.lock().unwrap().is_none() {
+            self.fail_pending(ctx);
+        } else {
+            self.notifier.notify(self, ctx);
+        }
     }
 }
 impl PciVirtio for PciVirtioBlock {
@@ -178,6 +286,20 @@ impl block::Device for PciVirtioBlock {
     fn set_notifier(&self, val: Option<Box<block::NotifierFn>>) {
         self.notifier.set(val);
     }
+
+    fn set_media(
+        &self,
+        info: Option<block::DeviceInfo>,
+        ctx: &DispCtx,
+    ) -> std::io::Result<()> {
+        let removed = info.is_none();
+        *self.media.lock().unwrap() = info;
+        if removed {
+            self.fail_pending(ctx);
+        }
+        self.virtio_state.notify_config_change(&self.pci_state, ctx);
+        Ok(())
+    }
 }
 impl Entity for PciVirtioBlock {
     fn type_name(&self) -> &'static str {
@@ -205,6 +327,23 @@ impl Migrate for PciVirtioBlock {
     }
 }
 
+/// Complete a request chain with `status`, without having serviced it.
+fn fail_chain(
+    mut chain: Chain,
+    status: u8,
+    vq: &Arc<VirtQueue>,
+    ctx: &DispCtx,
+) {
+    let mem = &ctx.mctx.memctx();
+    // try to set the status byte to failed
+    let remain = chain.remain_write_bytes();
+    if remain >= 1 {
+        chain.write_skip(remain - 1);
+        chain.write(&status, mem);
+    }
+    vq.push_used(&mut chain, mem, ctx);
+}
+
 fn complete_blockreq(
     res: block::Result,
     mut chain: Chain,
@@ -228,6 +367,14 @@ struct VbReq {
     sector: u64,
 }
 
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VbDiscardWriteZeroes {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 enum BlockReg {
     Capacity,
@@ -308,3 +455,115 @@ mod bits {
     pub const VIRTIO_BLK_CFG_SIZE: usize = 0x3c;
 }
 use bits::*;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::test_util::{TestInstance, TIMEOUT};
+    use crate::hw::virtio::test_util::{Buf, TestVirtQueue};
+    use std::collections::HashMap;
+    use std::time::Instant;
+
+    fn seg_req(rtype: u32, sector: u64, num_sectors: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(rtype.to_le_bytes());
+        buf.extend(0u32.to_le_bytes());
+        buf.extend(0u64.to_le_bytes());
+        buf.extend(sector.to_le_bytes());
+        buf.extend(num_sectors.to_le_bytes());
+        buf.extend(0u32.to_le_bytes());
+        buf
+    }
+
+    /// Issue a request for each of `ranges`, returning the status with which
+    /// each completed.
+    fn issue(
+        test: &TestInstance,
+        blk: &Arc<PciVirtioBlock>,
+        tvq: &mut TestVirtQueue,
+        rtype: u32,
+        ranges: &[(u64, u32)],
+    ) -> Vec<u8> {
+        let mut heads = Vec::new();
+        test.with_ctx(|ctx| {
+            let mem = ctx.mctx.memctx();
+            for (sector, num) in ranges {
+                let req = seg_req(rtype, *sector, *num);
+                heads.push(
+                    tvq.push(&mem, &[Buf::Readable(&req), Buf::Writable(1)]),
+                );
+            }
+            blk.queue_notify(&blk.virtio_state.queues[0], ctx);
+        });
+
+        let mut status = HashMap::new();
+        let start = Instant::now();
+        while status.len() < heads.len() {
+            assert!(start.elapsed() < TIMEOUT, "requests did not complete");
+            test.with_ctx(|ctx| {
+                let mem = ctx.mctx.memctx();
+                while let Some((head, _len)) = tvq.next_used(&mem) {
+                    status.insert(head, tvq.written(&mem, head)[0]);
+                }
+            });
+            std::thread::yield_now();
+        }
+        heads.iter().map(|head| status[head]).collect()
+    }
+
+    #[test]
+    fn discard_and_write_zeroes_ranges() {
+        // A disk of 64 sectors
+        const SECTORS: usize = 64;
+        let test = TestInstance::new();
+        let backend = block::InMemoryBackend::create(
+            vec![0xaa; SECTORS * SECTOR_SZ],
+            false,
+            SECTOR_SZ,
+        )
+        .unwrap();
+        let blk = PciVirtioBlock::new(16, block::Backend::info(&*backend));
+        let mut tvq = TestVirtQueue::new(&blk.virtio_state.queues[0]);
+        test.attach(backend.as_ref(), &blk).unwrap();
+
+        for rtype in [VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_WRITE_ZEROES] {
+            // Ranges within the disk are issued, up to its very end
+            let status = issue(
+                &test,
+                &blk,
+                &mut tvq,
+                rtype,
+                &[(0, 8), (60, 4), (0, 64)],
+            );
+            assert_eq!(status, [VIRTIO_BLK_S_OK; 3]);
+
+            // Empty and out-of-range requests are failed without being
+            // issued, leaving those behind them to proceed
+            let status = issue(
+                &test,
+                &blk,
+                &mut tvq,
+                rtype,
+                &[
+                    (8, 0),
+                    (60, 5),
+                    (64, 1),
+                    (u64::MAX, 1),
+                    (0, MAX_DISCARD_SECTORS + 1),
+                    (4, 4),
+                ],
+            );
+            assert_eq!(
+                status,
+                [
+                    VIRTIO_BLK_S_IOERR,
+                    VIRTIO_BLK_S_IOERR,
+                    VIRTIO_BLK_S_IOERR,
+                    VIRTIO_BLK_S_IOERR,
+                    VIRTIO_BLK_S_IOERR,
+                    VIRTIO_BLK_S_OK
+                ]
+            );
+        }
+    }
+}
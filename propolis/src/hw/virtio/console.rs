@@ -0,0 +1,849 @@
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Result};
+use std::num::NonZeroU16;
+use std::sync::{Arc, Mutex};
+
+use crate::chardev::*;
+use crate::common::*;
+use crate::dispatch::DispCtx;
+use crate::hw::pci;
+use crate::migrate::{Migrate, MigrateStateError, Migrator};
+use crate::util::regmap::RegMap;
+
+use super::bits::*;
+use super::pci::{PciVirtio, PciVirtioState};
+use super::queue::{Chain, VirtQueue, VirtQueues};
+use super::VirtioDevice;
+
+use erased_serde::{Deserializer, Serialize};
+use lazy_static::lazy_static;
+
+/// Limit on the number of ports (including the console port) of a device
+const MAX_PORTS: usize = 32;
+
+/// Limit on the output from the guest buffered by a port, beyond which the
+/// oldest of it is discarded.
+const TX_BUF_SZ: usize = 0x4000;
+
+/// Size of the chunks in which output is read from the transmitq
+const TX_CHUNK_SZ: usize = 256;
+
+/// A virtio-console device, exposing one or more ports through which streams
+/// of bytes are passed to and from the guest.
+///
+/// Port 0 is always present, and is presented to the guest as a console.  Any
+/// further ports are given a name with which the guest can locate them, and
+/// are only usable by guests which negotiate the multiport feature.
+pub struct PciVirtioConsole {
+    virtio_state: PciVirtioState,
+    pci_state: pci::DeviceState,
+
+    ports: Vec<Arc<ConsolePort>>,
+    control: Mutex<Control>,
+}
+impl PciVirtioConsole {
+    /// Create a device with a console port, followed by a port for each of
+    /// `port_names`.
+    pub fn new(queue_size: u16, port_names: Vec<String>) -> Result<Arc<Self>> {
+        let nports = port_names.len() + 1;
+        if nports > MAX_PORTS {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("at most {} named ports are allowed", MAX_PORTS - 1),
+            ));
+        }
+        for (i, name) in port_names.iter().enumerate() {
+            if name.is_empty() || port_names[..i].contains(name) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid or duplicate port name {:?}", name),
+                ));
+            }
+        }
+
+        // Without additional ports, the control queues (and the multiport
+        // feature they are part of) are left out entirely.
+        let queue_count = match nports {
+            1 => 2,
+            n => 2 * n + 2,
+        };
+        let queues = VirtQueues::new(
+            NonZeroU16::new(queue_size).unwrap(),
+            NonZeroU16::new(queue_count as u16).unwrap(),
+        );
+        // One MSI-X entry for each queue, plus one for config changes
+        let msix_count = Some(queue_count as u16 + 1);
+        let (virtio_state, pci_state) = PciVirtioState::create(
+            queues,
+            msix_count,
+            VIRTIO_DEV_CONSOLE,
+            pci::bits::CLASS_COMM,
+            VIRTIO_CONSOLE_CFG_SIZE,
+            true,
+        );
+
+        let names =
+            std::iter::once(None).chain(port_names.into_iter().map(Some));
+        let ports = names
+            .enumerate()
+            .map(|(id, name)| {
+                let rxq = port_rx_queue(id);
+                ConsolePort::new(
+                    id as u32,
+                    name,
+                    Arc::clone(virtio_state.queues.get(rxq).unwrap()),
+                    Arc::clone(virtio_state.queues.get(rxq + 1).unwrap()),
+                )
+            })
+            .collect();
+
+        Ok(Arc::new(Self {
+            virtio_state,
+            pci_state,
+            ports,
+            control: Mutex::new(Control::default()),
+        }))
+    }
+
+    /// All ports of the device, indexed by their ID
+    pub fn ports(&self) -> &[Arc<ConsolePort>] {
+        &self.ports
+    }
+
+    /// The console port (port 0) of the device
+    pub fn console(&self) -> &Arc<ConsolePort> {
+        &self.ports[0]
+    }
+
+    /// Find a port by the name it was given
+    pub fn port_by_name(&self, name: &str) -> Option<&Arc<ConsolePort>> {
+        self.ports.iter().find(|p| p.name() == Some(name))
+    }
+
+    fn console_cfg_read(&self, id: &ConsoleReg, ro: &mut ReadOp) {
+        match id {
+            ConsoleReg::MaxNrPorts => ro.write_u32(self.ports.len() as u32),
+            ConsoleReg::Cols | ConsoleReg::Rows | ConsoleReg::EmergWr => {
+                // These go unused, as neither VIRTIO_CONSOLE_F_SIZE nor
+                // VIRTIO_CONSOLE_F_EMERG_WRITE are offered.
+                ro.fill(0);
+            }
+        }
+    }
+
+    /// Process the control messages sent by the driver.
+    fn control_recv(&self, ctx: &DispCtx) {
+        let vq = self.virtio_state.queues.get(CTRL_TX_QUEUE).unwrap();
+        let mem = &ctx.mctx.memctx();
+        let mut ctrl = self.control.lock().unwrap();
+        loop {
+            let mut chain = Chain::with_capacity(1);
+            if vq.pop_avail(&mut chain, mem, ctx).is_none() {
+                break;
+            }
+            let mut msg = VcControl::default();
+            if chain.read(&mut msg, mem) {
+                self.control_handle(&mut ctrl, &msg);
+            }
+            vq.push_used(&mut chain, mem, ctx);
+        }
+        self.control_flush(&mut ctrl, ctx);
+    }
+
+    fn control_handle(&self, ctrl: &mut Control, msg: &VcControl) {
+        match msg.event {
+            VIRTIO_CONSOLE_DEVICE_READY if msg.value == 1 => {
+                for port in self.ports.iter() {
+                    ctrl.queue(port.id, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if msg.value == 1 => {
+                let port = match self.ports.get(msg.id as usize) {
+                    Some(port) => port,
+                    None => return,
+                };
+                if port.id == 0 {
+                    ctrl.queue(port.id, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+                }
+                if let Some(name) = port.name() {
+                    ctrl.queue(
+                        port.id,
+                        VIRTIO_CONSOLE_PORT_NAME,
+                        0,
+                        name.as_bytes(),
+                    );
+                }
+                // The host side of each port is always considered connected,
+                // with data sent while nothing is attached to the port being
+                // discarded (or buffered) as it would be for a UART.
+                ctrl.queue(port.id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+            }
+            _ => {
+                // The driver opening or closing ports (PORT_OPEN), as well as
+                // any failures it reports, require no action on our part.
+            }
+        }
+    }
+
+    /// Deliver as many of the pending control messages to the driver as there
+    /// are buffers available for them.
+    fn control_flush(&self, ctrl: &mut Control, ctx: &DispCtx) {
+        let vq = self.virtio_state.queues.get(CTRL_RX_QUEUE).unwrap();
+        let mem = &ctx.mctx.memctx();
+        while let Some(msg) = ctrl.pending.front() {
+            let mut chain = Chain::with_capacity(1);
+            if vq.pop_avail(&mut chain, mem, ctx).is_none() {
+                break;
+            }
+            chain.write_bytes(msg, mem);
+            vq.push_used(&mut chain, mem, ctx);
+            ctrl.pending.pop_front();
+        }
+    }
+}
+
+impl VirtioDevice for PciVirtioConsole {
+    fn cfg_rw(&self, mut rwo: RWOp) {
+        CONSOLE_DEV_REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => self.console_cfg_read(id, ro),
+            RWOp::Write(_) => {
+                //ignore writes
+            }
+        });
+    }
+    fn get_features(&self) -> u64 {
+        match self.ports.len() {
+            1 => 0,
+            _ => VIRTIO_CONSOLE_F_MULTIPORT,
+        }
+    }
+    fn set_features(&self, _feat: u64) {
+        // Guests without multiport support simply use the console port alone
+    }
+
+    fn queue_notify(&self, vq: &Arc<VirtQueue>, ctx: &DispCtx) {
+        match vq.id {
+            CTRL_RX_QUEUE => {
+                let mut ctrl = self.control.lock().unwrap();
+                self.control_flush(&mut ctrl, ctx);
+            }
+            CTRL_TX_QUEUE => self.control_recv(ctx),
+            qid => {
+                let port = match qid {
+                    0 | 1 => &self.ports[0],
+                    _ => &self.ports[(qid as usize - 2) / 2],
+                };
+                if qid % 2 == 0 {
+                    port.rx_notify(ctx);
+                } else {
+                    port.tx_notify(ctx);
+                }
+            }
+        }
+    }
+
+    fn reset(&self, _ctx: &DispCtx) {
+        self.control.lock().unwrap().pending.clear();
+        for port in self.ports.iter() {
+            port.reset();
+        }
+    }
+}
+impl PciVirtio for PciVirtioConsole {
+    fn virtio_state(&self) -> &PciVirtioState {
+        &self.virtio_state
+    }
+    fn pci_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+impl Entity for PciVirtioConsole {
+    fn type_name(&self) -> &'static str {
+        "pci-virtio-console"
+    }
+    fn reset(&self, ctx: &DispCtx) {
+        self.virtio_state.reset(self, ctx);
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Custom(self)
+    }
+}
+impl Migrate for PciVirtioConsole {
+    fn export(&self, _ctx: &DispCtx) -> Box<dyn Serialize> {
+        let ctrl = self.control.lock().unwrap();
+        Box::new(migrate::PciVirtioConsoleV1 {
+            pci_virtio_state: self.virtio_state.export(&self.pci_state),
+            ports: self.ports.iter().map(|p| p.export()).collect(),
+            pending_ctrl: ctrl.pending.iter().cloned().collect(),
+        })
+    }
+
+    fn import(
+        &self,
+        _dev: &str,
+        deserializer: &mut dyn Deserializer,
+        ctx: &DispCtx,
+    ) -> std::result::Result<(), MigrateStateError> {
+        let saved: migrate::PciVirtioConsoleV1 =
+            erased_serde::deserialize(deserializer)?;
+
+        if saved.ports.len() != self.ports.len() {
+            return Err(MigrateStateError::ImportFailed(format!(
+                "console has {} ports, not {}",
+                self.ports.len(),
+                saved.ports.len()
+            )));
+        }
+        for (port, saved) in self.ports.iter().zip(saved.ports.iter()) {
+            port.import(saved)?;
+        }
+        let mut ctrl = self.control.lock().unwrap();
+        ctrl.pending = saved.pending_ctrl.into_iter().collect();
+        drop(ctrl);
+
+        self.virtio_state.import(self, saved.pci_virtio_state, ctx)
+    }
+}
+
+#[derive(Default)]
+struct Control {
+    /// Control messages awaiting buffers in the control receiveq
+    pending: VecDeque<Vec<u8>>,
+}
+impl Control {
+    fn queue(&mut self, id: u32, event: u16, value: u16, data: &[u8]) {
+        let mut msg = Vec::with_capacity(VC_CONTROL_SZ + data.len());
+        msg.extend_from_slice(&id.to_le_bytes());
+        msg.extend_from_slice(&event.to_le_bytes());
+        msg.extend_from_slice(&value.to_le_bytes());
+        msg.extend_from_slice(data);
+        self.pending.push_back(msg);
+    }
+}
+
+struct PortState {
+    /// Output from the guest awaiting a read from the port
+    tx_buf: VecDeque<u8>,
+    /// A write to the port has been refused for lack of receiveq buffers
+    rx_blocked: bool,
+    auto_discard: bool,
+    has_consumer: bool,
+}
+
+/// A port of a [`PciVirtioConsole`].
+///
+/// Data written to the port (as a [`Sink`]) is delivered to the guest through
+/// the receiveq of the port.  Output from the guest, sent through the
+/// transmitq, is read from the port as a [`Source`] or, should a consumer be
+/// attached, passed to it as a [`BlockingSource`].
+pub struct ConsolePort {
+    id: u32,
+    name: Option<String>,
+    rxq: Arc<VirtQueue>,
+    txq: Arc<VirtQueue>,
+
+    state: Mutex<PortState>,
+    notify_readable: NotifierCell<dyn Source>,
+    notify_writable: NotifierCell<dyn Sink>,
+    consumer: ConsumerCell,
+}
+impl ConsolePort {
+    fn new(
+        id: u32,
+        name: Option<String>,
+        rxq: Arc<VirtQueue>,
+        txq: Arc<VirtQueue>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            id,
+            name,
+            rxq,
+            txq,
+            state: Mutex::new(PortState {
+                tx_buf: VecDeque::with_capacity(TX_BUF_SZ),
+                rx_blocked: false,
+                auto_discard: true,
+                has_consumer: false,
+            }),
+            notify_readable: NotifierCell::new(),
+            notify_writable: NotifierCell::new(),
+            consumer: ConsumerCell::new(),
+        })
+    }
+
+    /// ID of the port, as known to the guest
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Name of the port, which the console port (port 0) lacks
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The driver has made buffers available in the receiveq
+    fn rx_notify(&self, ctx: &DispCtx) {
+        let mut state = self.state.lock().unwrap();
+        let write_notify = std::mem::replace(&mut state.rx_blocked, false);
+        drop(state);
+        if write_notify {
+            self.notify_writable.notify(self as &dyn Sink, ctx);
+        }
+    }
+
+    /// The driver has sent output through the transmitq
+    fn tx_notify(&self, ctx: &DispCtx) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let readable_before = !state.tx_buf.is_empty();
+
+        // Drivers may spin waiting for their output to be consumed (as Linux
+        // does for console writes), so the transmitq is always drained, rather
+        // than waiting on whatever may be reading from the port.
+        self.tx_drain(ctx, |data| {
+            if state.has_consumer {
+                self.consumer.consume(data, ctx);
+            } else if !state.auto_discard {
+                // Make room by dropping the oldest of any unread output
+                let len = state.tx_buf.len() + data.len();
+                state.tx_buf.drain(..len.saturating_sub(TX_BUF_SZ));
+                state.tx_buf.extend(data);
+            }
+        });
+        let read_notify = !readable_before && !state.tx_buf.is_empty();
+
+        // The state lock cannot be held while dispatching notifications since
+        // those callbacks could immediately attempt to read the pending data.
+        drop(guard);
+        if read_notify {
+            self.notify_readable.notify(self as &dyn Source, ctx);
+        }
+    }
+
+    /// Pass the contents of all available transmitq buffers to `f`, returning
+    /// the buffers to the driver as they are emptied.
+    fn tx_drain(&self, ctx: &DispCtx, mut f: impl FnMut(&[u8])) {
+        let mem = &ctx.mctx.memctx();
+        let mut buf = [0u8; TX_CHUNK_SZ];
+        loop {
+            let mut chain = Chain::with_capacity(4);
+            if self.txq.pop_avail(&mut chain, mem, ctx).is_none() {
+                break;
+            }
+            while chain.remain_read_bytes() != 0 {
+                let nread = chain.read_bytes(&mut buf, mem);
+                if nread == 0 {
+                    // A buffer which cannot be read is returned as-is
+                    break;
+                }
+                f(&buf[..nread]);
+            }
+            self.txq.push_used(&mut chain, mem, ctx);
+        }
+    }
+
+    fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.tx_buf.clear();
+        state.rx_blocked = false;
+    }
+
+    fn export(&self) -> migrate::ConsolePortV1 {
+        let state = self.state.lock().unwrap();
+        migrate::ConsolePortV1 {
+            id: self.id,
+            name: self.name.clone(),
+            tx_buf: state.tx_buf.iter().copied().collect(),
+            rx_blocked: state.rx_blocked,
+        }
+    }
+
+    fn import(
+        &self,
+        saved: &migrate::ConsolePortV1,
+    ) -> std::result::Result<(), MigrateStateError> {
+        if saved.id != self.id || saved.name != self.name {
+            return Err(MigrateStateError::ImportFailed(format!(
+                "console port {} ({:?}) mismatch with {} ({:?})",
+                self.id, self.name, saved.id, saved.name
+            )));
+        }
+        let mut state = self.state.lock().unwrap();
+        state.tx_buf = saved.tx_buf.iter().copied().collect();
+        state.rx_blocked = saved.rx_blocked;
+        Ok(())
+    }
+}
+
+impl Sink for ConsolePort {
+    fn write(&self, data: u8, ctx: &DispCtx) -> bool {
+        self.write_bytes(&[data], ctx) == 1
+    }
+    fn write_bytes(&self, data: &[u8], ctx: &DispCtx) -> usize {
+        let mem = &ctx.mctx.memctx();
+        // Hold the state lock throughout, so that data from concurrent writers
+        // is not interleaved in the receiveq.
+        let mut state = self.state.lock().unwrap();
+        let mut nwritten = 0;
+        while nwritten < data.len() {
+            let mut chain = Chain::with_capacity(4);
+            if self.rxq.pop_avail(&mut chain, mem, ctx).is_none() {
+                state.rx_blocked = true;
+                break;
+            }
+            // Fill each buffer as far as the data allows, rather than
+            // spending one (and an interrupt) on every byte.
+            nwritten += chain.write_bytes(&data[nwritten..], mem);
+            self.rxq.push_used(&mut chain, mem, ctx);
+        }
+        nwritten
+    }
+    fn set_notifier(&self, f: Option<SinkNotifier>) {
+        self.notify_writable.set(f);
+    }
+}
+impl Source for ConsolePort {
+    fn read(&self, _ctx: &DispCtx) -> Option<u8> {
+        let mut state = self.state.lock().unwrap();
+        state.tx_buf.pop_front()
+    }
+    fn discard(&self, count: usize, _ctx: &DispCtx) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let discarded = usize::min(count, state.tx_buf.len());
+        state.tx_buf.drain(..discarded);
+        discarded
+    }
+    fn set_notifier(&self, f: Option<SourceNotifier>) {
+        self.notify_readable.set(f);
+    }
+    fn set_autodiscard(&self, active: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.auto_discard = active;
+        if active {
+            state.tx_buf.clear();
+        }
+    }
+}
+impl BlockingSource for ConsolePort {
+    fn set_consumer(&self, f: Option<BlockingSourceConsumer>) {
+        let mut state = self.state.lock().unwrap();
+        state.has_consumer = f.is_some();
+        self.consumer.set(f);
+    }
+}
+
+/// Index of the receiveq for port `id`, with its transmitq following it.
+///
+/// The queues of the console port precede the control queues, which are in
+/// turn followed by those of any additional ports.
+fn port_rx_queue(id: usize) -> u16 {
+    match id {
+        0 => 0,
+        n => (2 * n + 2) as u16,
+    }
+}
+
+const CTRL_RX_QUEUE: u16 = 2;
+const CTRL_TX_QUEUE: u16 = 3;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VcControl {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+const VC_CONTROL_SZ: usize = std::mem::size_of::<VcControl>();
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum ConsoleReg {
+    Cols,
+    Rows,
+    MaxNrPorts,
+    EmergWr,
+}
+lazy_static! {
+    static ref CONSOLE_DEV_REGS: RegMap<ConsoleReg> = {
+        let layout = [
+            (ConsoleReg::Cols, 2),
+            (ConsoleReg::Rows, 2),
+            (ConsoleReg::MaxNrPorts, 4),
+            (ConsoleReg::EmergWr, 4),
+        ];
+        RegMap::create_packed(VIRTIO_CONSOLE_CFG_SIZE, &layout, None)
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::test_util::TestInstance;
+    use crate::hw::virtio::test_util::{pci_attach, Buf, TestVirtQueue};
+    use crate::vmm::MemCtx;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Driver side of each of the queues of `console`, by index
+    fn test_queues(console: &PciVirtioConsole) -> Vec<TestVirtQueue> {
+        let queues = &console.virtio_state.queues;
+        (0..queues.count().get() as usize)
+            .map(|i| TestVirtQueue::with_slot(&queues[i], i))
+            .collect()
+    }
+
+    fn notify(console: &PciVirtioConsole, qid: usize, ctx: &DispCtx) {
+        console.queue_notify(&console.virtio_state.queues[qid], ctx);
+    }
+
+    /// A control message, as laid out by both the driver and the device
+    fn ctrl_msg(id: u32, event: u16, value: u16, data: &[u8]) -> Vec<u8> {
+        [
+            &id.to_le_bytes()[..],
+            &event.to_le_bytes(),
+            &value.to_le_bytes(),
+            data,
+        ]
+        .concat()
+    }
+
+    /// Send a control message from the driver.
+    fn ctrl_send(
+        console: &PciVirtioConsole,
+        tvqs: &mut [TestVirtQueue],
+        msg: &[u8],
+        ctx: &DispCtx,
+    ) {
+        let mem = &ctx.mctx.memctx();
+        let txq = &mut tvqs[CTRL_TX_QUEUE as usize];
+        let head = txq.push(mem, &[Buf::Readable(msg)]);
+        notify(console, CTRL_TX_QUEUE as usize, ctx);
+        assert_eq!(txq.next_used(mem), Some((head, 0)));
+    }
+
+    /// Collect the control messages delivered to the driver.
+    fn ctrl_recv(tvqs: &mut [TestVirtQueue], mem: &MemCtx) -> Vec<Vec<u8>> {
+        let rxq = &mut tvqs[CTRL_RX_QUEUE as usize];
+        let mut msgs = Vec::new();
+        while let Some((head, len)) = rxq.next_used(mem) {
+            let mut msg = rxq.written(mem, head);
+            msg.truncate(len as usize);
+            msgs.push(msg);
+        }
+        msgs
+    }
+
+    #[test]
+    fn control_messages() {
+        let test = TestInstance::new();
+        let console =
+            PciVirtioConsole::new(16, vec!["a".to_string(), "b".to_string()])
+                .unwrap();
+        let mut tvqs = test_queues(&console);
+        test.with_ctx(|ctx| {
+            let mem = &ctx.mctx.memctx();
+            let ctrl_buf = |tvqs: &mut [TestVirtQueue]| {
+                tvqs[CTRL_RX_QUEUE as usize].push(mem, &[Buf::Writable(16)]);
+            };
+
+            // Each port is added once the driver is ready, with those
+            // messages for which there are no buffers yet held back
+            ctrl_buf(&mut tvqs);
+            ctrl_buf(&mut tvqs);
+            let ready = ctrl_msg(0, VIRTIO_CONSOLE_DEVICE_READY, 1, &[]);
+            ctrl_send(&console, &mut tvqs, &ready, ctx);
+            assert_eq!(
+                ctrl_recv(&mut tvqs, mem),
+                vec![
+                    ctrl_msg(0, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]),
+                    ctrl_msg(1, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]),
+                ]
+            );
+            ctrl_buf(&mut tvqs);
+            notify(&console, CTRL_RX_QUEUE as usize, ctx);
+            assert_eq!(
+                ctrl_recv(&mut tvqs, mem),
+                vec![ctrl_msg(2, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[])]
+            );
+
+            for _ in 0..4 {
+                ctrl_buf(&mut tvqs);
+            }
+            // The console port is identified as such once ready
+            let ready = ctrl_msg(0, VIRTIO_CONSOLE_PORT_READY, 1, &[]);
+            ctrl_send(&console, &mut tvqs, &ready, ctx);
+            assert_eq!(
+                ctrl_recv(&mut tvqs, mem),
+                vec![
+                    ctrl_msg(0, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]),
+                    ctrl_msg(0, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]),
+                ]
+            );
+            // ... while the others are given their names
+            let ready = ctrl_msg(2, VIRTIO_CONSOLE_PORT_READY, 1, &[]);
+            ctrl_send(&console, &mut tvqs, &ready, ctx);
+            assert_eq!(
+                ctrl_recv(&mut tvqs, mem),
+                vec![
+                    ctrl_msg(2, VIRTIO_CONSOLE_PORT_NAME, 0, b"b"),
+                    ctrl_msg(2, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]),
+                ]
+            );
+
+            // Messages about ports which do not exist, or reporting failures,
+            // go unanswered
+            let ready = ctrl_msg(5, VIRTIO_CONSOLE_PORT_READY, 1, &[]);
+            ctrl_send(&console, &mut tvqs, &ready, ctx);
+            let failed = ctrl_msg(1, VIRTIO_CONSOLE_PORT_READY, 0, &[]);
+            ctrl_send(&console, &mut tvqs, &failed, ctx);
+            assert!(ctrl_recv(&mut tvqs, mem).is_empty());
+        });
+    }
+
+    #[test]
+    fn port_multiplexing() {
+        let test = TestInstance::new();
+        let console =
+            PciVirtioConsole::new(16, vec!["a".to_string(), "b".to_string()])
+                .unwrap();
+        let mut tvqs = test_queues(&console);
+        let a = console.port_by_name("a").unwrap();
+        let b = console.port_by_name("b").unwrap();
+        assert_eq!((a.id(), b.id()), (1, 2));
+        let a_rx = port_rx_queue(1) as usize;
+        let b_rx = port_rx_queue(2) as usize;
+        test.with_ctx(|ctx| {
+            let mem = &ctx.mctx.memctx();
+
+            // Data written to a port reaches the guest through its receiveq
+            // alone, filling each buffer rather than taking one per byte
+            let heads: Vec<_> = (0..2)
+                .map(|_| tvqs[b_rx].push(mem, &[Buf::Writable(8)]))
+                .collect();
+            tvqs[a_rx].push(mem, &[Buf::Writable(8)]);
+            assert_eq!(b.write_bytes(b"hello, port b", ctx), 13);
+            assert_eq!(tvqs[b_rx].next_used(mem), Some((heads[0], 8)));
+            assert_eq!(tvqs[b_rx].next_used(mem), Some((heads[1], 5)));
+            assert_eq!(tvqs[b_rx].written(mem, heads[0]), b"hello, p");
+            assert_eq!(&tvqs[b_rx].written(mem, heads[1])[..5], b"ort b");
+            assert_eq!(tvqs[a_rx].next_used(mem), None);
+
+            // Out of buffers, the port refuses data until the driver makes
+            // more available, at which point the writer is notified
+            let writable = Arc::new(AtomicBool::new(false));
+            let flag = Arc::clone(&writable);
+            Sink::set_notifier(
+                b.as_ref(),
+                Some(Box::new(move |_, _| flag.store(true, Ordering::SeqCst))),
+            );
+            assert_eq!(b.write_bytes(b"more", ctx), 0);
+            assert!(!b.write(b'!', ctx));
+            let head = tvqs[b_rx].push(mem, &[Buf::Writable(8)]);
+            notify(&console, b_rx, ctx);
+            assert!(writable.load(Ordering::SeqCst));
+            assert!(b.write(b'!', ctx));
+            assert_eq!(tvqs[b_rx].next_used(mem), Some((head, 1)));
+
+            // Output from the guest is read from the port it was sent through
+            for port in console.ports() {
+                port.set_autodiscard(false);
+            }
+            let head = tvqs[a_rx + 1].push(mem, &[Buf::Readable(b"to a")]);
+            notify(&console, a_rx + 1, ctx);
+            assert_eq!(tvqs[a_rx + 1].next_used(mem), Some((head, 0)));
+            let read = |port: &ConsolePort| {
+                std::iter::from_fn(|| port.read(ctx)).collect::<Vec<_>>()
+            };
+            assert_eq!(read(a), b"to a");
+            assert!(read(b).is_empty());
+            assert!(read(console.console()).is_empty());
+        });
+    }
+
+    #[test]
+    fn migrate_roundtrip() {
+        let test = TestInstance::new();
+        let src = PciVirtioConsole::new(16, vec!["a".to_string()]).unwrap();
+        let mut tvqs = test_queues(&src);
+        let a_tx = port_rx_queue(1) as usize + 1;
+        test.with_ctx(|ctx| {
+            let mem = &ctx.mctx.memctx();
+
+            // Output awaiting a reader, and control messages awaiting
+            // buffers, are carried over
+            src.port_by_name("a").unwrap().set_autodiscard(false);
+            let head = tvqs[a_tx].push(mem, &[Buf::Readable(b"held")]);
+            notify(&src, a_tx, ctx);
+            assert_eq!(tvqs[a_tx].next_used(mem), Some((head, 0)));
+            let ready = ctrl_msg(0, VIRTIO_CONSOLE_DEVICE_READY, 1, &[]);
+            ctrl_send(&src, &mut tvqs, &ready, ctx);
+
+            let saved = serde_json::to_string(&src.export(ctx)).unwrap();
+            let import = |dst: &PciVirtioConsole| {
+                let mut de = serde_json::Deserializer::from_str(&saved);
+                let mut de = <dyn Deserializer>::erase(&mut de);
+                dst.import("console", &mut de, ctx)
+            };
+
+            let dst = PciVirtioConsole::new(16, vec!["a".to_string()]).unwrap();
+            let _bus = pci_attach(Arc::clone(&dst) as Arc<dyn pci::Endpoint>);
+            import(&dst).unwrap();
+            let a = dst.port_by_name("a").unwrap();
+            let read: Vec<_> = std::iter::from_fn(|| a.read(ctx)).collect();
+            assert_eq!(read, b"held");
+
+            // The driver carries on with the queues where it left off
+            for _ in 0..2 {
+                tvqs[CTRL_RX_QUEUE as usize].push(mem, &[Buf::Writable(16)]);
+            }
+            notify(&dst, CTRL_RX_QUEUE as usize, ctx);
+            assert_eq!(
+                ctrl_recv(&mut tvqs, mem),
+                vec![
+                    ctrl_msg(0, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]),
+                    ctrl_msg(1, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]),
+                ]
+            );
+
+            // Ports must match those of the source
+            let other = PciVirtioConsole::new(16, vec!["b".to_string()]);
+            assert!(import(&other.unwrap()).is_err());
+            let other = PciVirtioConsole::new(16, Vec::new());
+            assert!(import(&other.unwrap()).is_err());
+        });
+    }
+}
+
+pub mod migrate {
+    use crate::hw::virtio::pci::migrate::PciVirtioStateV1;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct ConsolePortV1 {
+        pub id: u32,
+        pub name: Option<String>,
+        pub tx_buf: Vec<u8>,
+        pub rx_blocked: bool,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct PciVirtioConsoleV1 {
+        pub pci_virtio_state: PciVirtioStateV1,
+        pub ports: Vec<ConsolePortV1>,
+        pub pending_ctrl: Vec<Vec<u8>>,
+    }
+}
+
+mod bits {
+    #![allow(unused)]
+
+    pub const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+    pub const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+    pub const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+    pub const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+    pub const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+    pub const VIRTIO_CONSOLE_RESIZE: u16 = 5;
+    pub const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+    pub const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+    pub const VIRTIO_CONSOLE_CFG_SIZE: usize = 0xc;
+}
+use bits::*;
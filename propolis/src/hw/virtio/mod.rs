@@ -4,8 +4,12 @@ use std::sync::Arc;
 mod bits;
 
 pub mod block;
+pub mod console;
 pub mod pci;
 mod queue;
+pub mod rng;
+#[cfg(test)]
+mod test_util;
 pub mod viona;
 
 use crate::common::*;
@@ -13,15 +17,28 @@ use crate::dispatch::DispCtx;
 use queue::VirtQueue;
 
 pub use block::PciVirtioBlock;
+pub use console::PciVirtioConsole;
+pub use rng::PciVirtioRng;
 pub use viona::PciVirtioViona;
 
 pub trait VirtioDevice: Send + Sync + 'static + Entity {
     /// Read/write device-specific virtio configuration space
     fn cfg_rw(&self, ro: RWOp);
     /// Get the device-specific virtio feature bits
-    fn get_features(&self) -> u32;
+    fn get_features(&self) -> u64;
     /// Set the device-specific virtio feature bits
-    fn set_features(&self, feat: u32);
+    fn set_features(&self, feat: u64);
+    /// Filter the transport feature bits `feat` (such as those for the ring
+    /// layouts) offered alongside the device-specific ones
+    fn transport_features(&self, feat: u64) -> u64 {
+        feat
+    }
+    #[allow(unused_variables)]
+    /// Whether the device can operate with the features `feat` accepted by
+    /// the driver, checked as the driver sets FEATURES_OK
+    fn features_ok(&self, feat: u64) -> bool {
+        true
+    }
     /// Service driver notification for a given virtqueue
     fn queue_notify(&self, vq: &Arc<VirtQueue>, ctx: &DispCtx);
 
@@ -31,12 +48,16 @@ pub trait VirtioDevice: Send + Sync + 'static + Entity {
 
     #[allow(unused_variables)]
     /// Notification of virtqueue configuration change
+    ///
+    /// An error indicates the device could not put the change into effect,
+    /// leaving it in need of a reset by the driver.
     fn queue_change(
         &self,
         vq: &Arc<VirtQueue>,
         change: VqChange,
         ctx: &DispCtx,
-    ) {
+    ) -> Result<(), ()> {
+        Ok(())
     }
 }
 
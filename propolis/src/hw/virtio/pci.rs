@@ -10,7 +10,8 @@ use crate::common::*;
 use crate::dispatch::DispCtx;
 use crate::hw::pci;
 use crate::intr_pins::IntrPin;
-use crate::util::regmap::RegMap;
+use crate::migrate::MigrateStateError;
+use crate::util::regmap::{Flags, RegMap};
 
 use lazy_static::lazy_static;
 
@@ -18,6 +19,13 @@ const VIRTIO_VENDOR: u16 = 0x1af4;
 
 const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
 
+/// BAR holding the configuration structures of the modern interface
+const MODERN_BAR: pci::BarN = pci::BarN::BAR2;
+/// Each of the modern configuration structures is given its own page of the
+/// BAR, in the order they are listed in [`ModernTop`].
+const MODERN_REGION_SZ: usize = 0x1000;
+const MODERN_BAR_SZ: usize = 4 * MODERN_REGION_SZ;
+
 bitflags! {
     #[derive(Default)]
     pub struct Status: u8 {
@@ -41,7 +49,10 @@ enum IntrMode {
 struct VirtioState {
     status: Status,
     queue_sel: u16,
-    nego_feat: u32,
+    nego_feat: u64,
+    device_feat_sel: u32,
+    driver_feat_sel: u32,
+    config_gen: u8,
     intr_mode: IntrMode,
     intr_mode_updating: bool,
     msix_cfg_vec: u16,
@@ -56,6 +67,9 @@ impl VirtioState {
             status: Status::RESET,
             queue_sel: 0,
             nego_feat: 0,
+            device_feat_sel: 0,
+            driver_feat_sel: 0,
+            config_gen: 0,
             intr_mode: IntrMode::IsrOnly,
             intr_mode_updating: false,
             msix_cfg_vec: VIRTIO_MSI_NO_VECTOR,
@@ -66,6 +80,8 @@ impl VirtioState {
         self.status = Status::RESET;
         self.queue_sel = 0;
         self.nego_feat = 0;
+        self.device_feat_sel = 0;
+        self.driver_feat_sel = 0;
         self.msix_cfg_vec = VIRTIO_MSI_NO_VECTOR;
     }
     pub fn export(&self) -> migrate::VirtioStateV1 {
@@ -73,10 +89,32 @@ impl VirtioState {
             status: self.status.bits(),
             queue_sel: self.queue_sel,
             nego_feat: self.nego_feat,
+            device_feat_sel: self.device_feat_sel,
+            driver_feat_sel: self.driver_feat_sel,
+            config_gen: self.config_gen,
             msix_cfg_vec: self.msix_cfg_vec,
             msix_queue_vec: self.msix_queue_vec.clone(),
         }
     }
+    fn import(
+        &mut self,
+        saved: &migrate::VirtioStateV1,
+    ) -> Result<(), MigrateStateError> {
+        if saved.msix_queue_vec.len() != self.msix_queue_vec.len() {
+            return Err(MigrateStateError::ImportFailed(
+                "virtqueue count mismatch".to_string(),
+            ));
+        }
+        self.status = Status::from_bits_truncate(saved.status);
+        self.queue_sel = saved.queue_sel;
+        self.nego_feat = saved.nego_feat;
+        self.device_feat_sel = saved.device_feat_sel;
+        self.driver_feat_sel = saved.driver_feat_sel;
+        self.config_gen = saved.config_gen;
+        self.msix_cfg_vec = saved.msix_cfg_vec;
+        self.msix_queue_vec.copy_from_slice(&saved.msix_queue_vec);
+        Ok(())
+    }
 }
 
 pub trait PciVirtio: VirtioDevice + Send + Sync + 'static {
@@ -91,6 +129,11 @@ impl<D: PciVirtio + Send + Sync + 'static> pci::Device for D {
     fn bar_rw(&self, bar: pci::BarN, mut rwo: RWOp, ctx: &DispCtx) {
         let vs = self.virtio_state();
 
+        if bar == MODERN_BAR {
+            vs.modern_rw(self.pci_state(), self, rwo, ctx);
+            return;
+        }
+
         assert_eq!(bar, pci::BarN::BAR0);
         let map = match vs.map_which.load(Ordering::SeqCst) {
             false => &vs.map_nomsix,
@@ -108,6 +151,12 @@ impl<D: PciVirtio + Send + Sync + 'static> pci::Device for D {
             VirtioTop::DeviceConfig => self.cfg_rw(rwo),
         });
     }
+    fn cap_rw(&self, _offset: u8, rwo: RWOp, ctx: &DispCtx) {
+        // The PCI configuration access capability is the only one with custom
+        // handling
+        let vs = self.virtio_state();
+        vs.pci_cfg_rw(self.pci_state(), self, rwo, ctx);
+    }
     fn attach(&self) {
         let ps = self.pci_state();
         if let Some(pin) = ps.lintr_pin() {
@@ -145,19 +194,22 @@ impl<D: PciVirtio + Send + Sync + 'static> pci::Device for D {
             // avoid deadlock while modify per-VQ interrupt config
             drop(state);
 
-            match info {
+            let res = match info {
                 pci::MsiUpdate::MaskAll | pci::MsiUpdate::UnmaskAll
                     if val != VIRTIO_MSI_NO_VECTOR =>
                 {
-                    self.queue_change(vq, VqChange::IntrCfg, ctx);
+                    self.queue_change(vq, VqChange::IntrCfg, ctx)
                 }
                 pci::MsiUpdate::Modify(idx) if val == idx => {
-                    self.queue_change(vq, VqChange::IntrCfg, ctx);
+                    self.queue_change(vq, VqChange::IntrCfg, ctx)
                 }
-                _ => {}
-            }
+                _ => Ok(()),
+            };
 
             state = vs.state.lock().unwrap();
+            if res.is_err() {
+                state.status |= Status::NEEDS_RESET;
+            }
         }
         state.intr_mode_updating = false;
         vs.state_cv.notify_all();
@@ -176,14 +228,27 @@ pub struct PciVirtioState {
 
     map: RegMap<VirtioTop>,
     map_nomsix: RegMap<VirtioTop>,
+
+    /// Register map for the modern interface, if it is offered
+    map_modern: Option<RegMap<ModernTop>>,
+
+    /// Window into the BARs selected through the PCI configuration access
+    /// capability of the modern interface
+    pci_cfg: Mutex<PciCfgWindow>,
 }
 impl PciVirtioState {
+    /// Create the virtio state for a device, along with the PCI state through
+    /// which it is exposed.
+    ///
+    /// The legacy interface is always offered.  With `modern`, the device is
+    /// transitional, also offering the modern (virtio 1.0+) interface.
     pub(super) fn create(
         queues: VirtQueues,
         msix_count: Option<u16>,
         dev_id: u16,
         dev_class: u8,
         cfg_sz: usize,
+        modern: bool,
     ) -> (Self, pci::DeviceState) {
         let mut builder = pci::Builder::new(pci::Ident {
             vendor_id: VIRTIO_VENDOR,
@@ -201,16 +266,42 @@ impl PciVirtioState {
 
         // XXX: properly size the legacy cfg BAR
         builder = builder.add_bar_io(pci::BarN::BAR0, 0x200);
-        let pci_state = builder.finish();
 
-        let layout = [
-            (VirtioTop::LegacyConfig, LEGACY_REG_SZ),
-            (VirtioTop::DeviceConfig, cfg_sz),
-        ];
-        let layout_nomsix = [
-            (VirtioTop::LegacyConfig, LEGACY_REG_SZ_NO_MSIX),
-            (VirtioTop::DeviceConfig, cfg_sz),
-        ];
+        if modern {
+            builder = builder
+                .add_bar_mmio(MODERN_BAR, MODERN_BAR_SZ as u32)
+                .add_cap_vendor(&modern_cap(
+                    VIRTIO_PCI_CAP_COMMON_CFG,
+                    ModernTop::CommonConfig,
+                    COMMON_REG_SZ,
+                ))
+                .add_cap_vendor(&modern_cap(
+                    VIRTIO_PCI_CAP_ISR_CFG,
+                    ModernTop::IsrConfig,
+                    ISR_REG_SZ,
+                ));
+            if cfg_sz != 0 {
+                builder = builder.add_cap_vendor(&modern_cap(
+                    VIRTIO_PCI_CAP_DEVICE_CFG,
+                    ModernTop::DeviceConfig,
+                    cfg_sz,
+                ));
+            }
+
+            // All queues share the same notification address, with the
+            // driver writing the index of the queue to it.
+            let notify_off_multiplier: u32 = 0;
+            let mut notify_cap = modern_cap(
+                VIRTIO_PCI_CAP_NOTIFY_CFG,
+                ModernTop::NotifyConfig,
+                NOTIFY_REG_SZ,
+            );
+            notify_cap.extend_from_slice(&notify_off_multiplier.to_le_bytes());
+            builder = builder
+                .add_cap_vendor(&notify_cap)
+                .add_cap_vendor_custom(PCI_CFG_CAP_SZ as u8);
+        }
+        let pci_state = builder.finish();
 
         let queue_count = queues.count().get();
         let this = Self {
@@ -220,15 +311,15 @@ impl PciVirtioState {
             state_cv: Condvar::new(),
             isr_state: IsrState::new(),
 
-            map: RegMap::create_packed_passthru(
-                cfg_sz + LEGACY_REG_SZ,
-                &layout,
-            ),
-            map_nomsix: RegMap::create_packed_passthru(
-                cfg_sz + LEGACY_REG_SZ_NO_MSIX,
-                &layout_nomsix,
-            ),
+            map: VirtioTop::create_map(LEGACY_REG_SZ, cfg_sz),
+            map_nomsix: VirtioTop::create_map(LEGACY_REG_SZ_NO_MSIX, cfg_sz),
             map_which: AtomicBool::new(false),
+
+            map_modern: match modern {
+                true => Some(ModernTop::create_map(cfg_sz)),
+                false => None,
+            },
+            pci_cfg: Mutex::new(PciCfgWindow::default()),
         };
 
         for queue in this.queues[..].iter() {
@@ -247,11 +338,11 @@ impl PciVirtioState {
     ) {
         match id {
             LegacyReg::FeatDevice => {
-                ro.write_u32(self.features_supported(dev));
+                ro.write_u32(self.features_supported(dev) as u32);
             }
             LegacyReg::FeatDriver => {
                 let state = self.state.lock().unwrap();
-                ro.write_u32(state.nego_feat);
+                ro.write_u32(state.nego_feat as u32);
             }
             LegacyReg::QueuePfn => {
                 let state = self.state.lock().unwrap();
@@ -304,18 +395,20 @@ impl PciVirtioState {
     ) {
         match id {
             LegacyReg::FeatDriver => {
-                let nego = wo.read_u32() & self.features_supported(dev);
+                let nego = wo.read_u32() as u64 & self.features_supported(dev);
                 let mut state = self.state.lock().unwrap();
                 state.nego_feat = nego;
-                dev.set_features(nego);
+                self.set_features(dev, nego);
             }
             LegacyReg::QueuePfn => {
                 let mut state = self.state.lock().unwrap();
                 let mut success = false;
                 let pfn = wo.read_u32();
                 if let Some(queue) = self.queues.get(state.queue_sel) {
-                    success = queue.map_legacy((pfn as u64) << PAGE_SHIFT);
-                    dev.queue_change(queue, VqChange::Address, ctx);
+                    success = queue.map_legacy((pfn as u64) << PAGE_SHIFT)
+                        && dev
+                            .queue_change(queue, VqChange::Address, ctx)
+                            .is_ok();
                 }
                 if !success {
                     // XXX: interrupt needed?
@@ -337,52 +430,376 @@ impl PciVirtioState {
                 state.msix_cfg_vec = wo.read_u16();
             }
             LegacyReg::MsixVectorQueue => {
-                let hdl = pci_state.msix_hdl().unwrap();
+                self.set_queue_msix_vec(pci_state, wo.read_u16());
+            }
+            LegacyReg::FeatDevice
+            | LegacyReg::QueueSize
+            | LegacyReg::IsrStatus => {
+                // Read-only regs
+            }
+        }
+    }
+
+    fn modern_rw(
+        &self,
+        pci_state: &pci::DeviceState,
+        dev: &dyn VirtioDevice,
+        mut rwo: RWOp,
+        ctx: &DispCtx,
+    ) {
+        let map = match self.map_modern.as_ref() {
+            Some(map) => map,
+            None => {
+                // The modern BAR is not defined without the modern interface
+                return;
+            }
+        };
+        map.process(&mut rwo, |id, mut rwo| match id {
+            ModernTop::CommonConfig => {
+                COMMON_REGS.process(&mut rwo, |id, rwo| match rwo {
+                    RWOp::Read(ro) => self.common_read(dev, id, ro),
+                    RWOp::Write(wo) => {
+                        self.common_write(pci_state, dev, id, wo, ctx)
+                    }
+                })
+            }
+            ModernTop::IsrConfig => {
+                if let RWOp::Read(ro) = rwo {
+                    // reading ISR Status clears it as well
+                    ro.write_u8(self.isr_state.read_clear());
+                }
+            }
+            ModernTop::DeviceConfig => dev.cfg_rw(rwo),
+            ModernTop::NotifyConfig => {
+                // Notifications are expected as 16-bit writes of the queue
+                // index, so ignore anything else.
+                if let RWOp::Write(wo) = rwo {
+                    if wo.len() == NOTIFY_REG_SZ {
+                        self.queue_notify(dev, wo.read_u16(), ctx);
+                    }
+                }
+            }
+            ModernTop::Unused => {
+                if let RWOp::Read(ro) = rwo {
+                    ro.fill(0);
+                }
+            }
+        });
+    }
+    /// Handle access to the body of the PCI configuration access capability,
+    /// through which the driver may reach the modern BAR from config space.
+    ///
+    /// See virtio 1.1 Section 4.1.4.7 PCI configuration access capability
+    fn pci_cfg_rw(
+        &self,
+        pci_state: &pci::DeviceState,
+        dev: &dyn VirtioDevice,
+        mut rwo: RWOp,
+        ctx: &DispCtx,
+    ) {
+        PCI_CFG_REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => {
+                let win = self.pci_cfg.lock().unwrap();
+                match id {
+                    PciCfgReg::CapLen => ro.write_u8(PCI_CFG_CAP_SZ as u8 + 2),
+                    PciCfgReg::CfgType => ro.write_u8(VIRTIO_PCI_CAP_PCI_CFG),
+                    PciCfgReg::Bar => ro.write_u8(win.bar),
+                    PciCfgReg::Offset => ro.write_u32(win.offset),
+                    PciCfgReg::Length => ro.write_u32(win.length),
+                    PciCfgReg::Data => {
+                        let mut buf = [0u8; 4];
+                        if let Some((off, len)) = win.target() {
+                            drop(win);
+                            let mut bro =
+                                ReadOp::from_buf(off, &mut buf[..len]);
+                            self.modern_rw(
+                                pci_state,
+                                dev,
+                                RWOp::Read(&mut bro),
+                                ctx,
+                            );
+                        }
+                        ro.write_bytes(&buf);
+                    }
+                    PciCfgReg::Reserved => ro.fill(0),
+                }
+            }
+            RWOp::Write(wo) => {
+                let mut win = self.pci_cfg.lock().unwrap();
+                match id {
+                    PciCfgReg::Bar => win.bar = wo.read_u8(),
+                    PciCfgReg::Offset => win.offset = wo.read_u32(),
+                    PciCfgReg::Length => win.length = wo.read_u32(),
+                    PciCfgReg::Data => {
+                        let mut buf = [0u8; 4];
+                        wo.read_bytes(&mut buf);
+                        if let Some((off, len)) = win.target() {
+                            drop(win);
+                            let mut bwo = WriteOp::from_buf(off, &buf[..len]);
+                            self.modern_rw(
+                                pci_state,
+                                dev,
+                                RWOp::Write(&mut bwo),
+                                ctx,
+                            );
+                        }
+                    }
+                    PciCfgReg::CapLen
+                    | PciCfgReg::CfgType
+                    | PciCfgReg::Reserved => {
+                        // Read-only regs
+                    }
+                }
+            }
+        });
+    }
+    fn common_read(
+        &self,
+        dev: &dyn VirtioDevice,
+        id: &CommonReg,
+        ro: &mut ReadOp,
+    ) {
+        let state = self.state.lock().unwrap();
+        match id {
+            CommonReg::DeviceFeatureSelect => {
+                ro.write_u32(state.device_feat_sel);
+            }
+            CommonReg::DeviceFeature => {
+                let feat = self.features_supported(dev);
+                ro.write_u32(feat_half(feat, state.device_feat_sel));
+            }
+            CommonReg::DriverFeatureSelect => {
+                ro.write_u32(state.driver_feat_sel);
+            }
+            CommonReg::DriverFeature => {
+                ro.write_u32(feat_half(state.nego_feat, state.driver_feat_sel));
+            }
+            CommonReg::MsixConfig => ro.write_u16(state.msix_cfg_vec),
+            CommonReg::NumQueues => ro.write_u16(self.queues.count().get()),
+            CommonReg::DeviceStatus => ro.write_u8(state.status.bits()),
+            CommonReg::ConfigGeneration => ro.write_u8(state.config_gen),
+            CommonReg::QueueSelect => ro.write_u16(state.queue_sel),
+            CommonReg::QueueMsixVector => {
+                let val = state
+                    .msix_queue_vec
+                    .get(state.queue_sel as usize)
+                    .unwrap_or(&VIRTIO_MSI_NO_VECTOR);
+                ro.write_u16(*val);
+            }
+            CommonReg::QueueNotifyOff => {
+                // With a notify_off_multiplier of 0, this is of no
+                // consequence to the driver.
+                ro.write_u16(0);
+            }
+            CommonReg::QueueSize
+            | CommonReg::QueueEnable
+            | CommonReg::QueueDesc
+            | CommonReg::QueueDriver
+            | CommonReg::QueueDevice => {
+                let queue = match self.queues.get(state.queue_sel) {
+                    Some(queue) => queue,
+                    None => {
+                        // bogus queue
+                        ro.fill(0);
+                        return;
+                    }
+                };
+                match id {
+                    CommonReg::QueueSize => ro.write_u16(queue.size),
+                    CommonReg::QueueEnable => {
+                        ro.write_u16(queue.map_info().is_some() as u16);
+                    }
+                    CommonReg::QueueDesc => {
+                        ro.write_u64(queue.ctrl.lock().unwrap().gpa_desc.0);
+                    }
+                    CommonReg::QueueDriver => {
+                        ro.write_u64(queue.ctrl.lock().unwrap().gpa_avail.0);
+                    }
+                    CommonReg::QueueDevice => {
+                        ro.write_u64(queue.ctrl.lock().unwrap().gpa_used.0);
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+    fn common_write(
+        &self,
+        pci_state: &pci::DeviceState,
+        dev: &dyn VirtioDevice,
+        id: &CommonReg,
+        wo: &mut WriteOp,
+        ctx: &DispCtx,
+    ) {
+        match id {
+            CommonReg::DeviceFeatureSelect => {
+                let mut state = self.state.lock().unwrap();
+                state.device_feat_sel = wo.read_u32();
+            }
+            CommonReg::DriverFeatureSelect => {
+                let mut state = self.state.lock().unwrap();
+                state.driver_feat_sel = wo.read_u32();
+            }
+            CommonReg::DriverFeature => {
+                let val = wo.read_u32() as u64;
+                let supported = self.features_supported(dev);
+                let mut state = self.state.lock().unwrap();
+                let nego = match state.driver_feat_sel {
+                    0 => (state.nego_feat & !0xffff_ffff) | val,
+                    1 => (state.nego_feat & 0xffff_ffff) | (val << 32),
+                    _ => state.nego_feat,
+                };
+                // The device is informed of the negotiated features once the
+                // driver sets FEATURES_OK.
+                state.nego_feat = nego & supported;
+            }
+            CommonReg::MsixConfig => {
                 let mut state = self.state.lock().unwrap();
-                let sel = state.queue_sel as usize;
+                state.msix_cfg_vec = wo.read_u16();
+            }
+            CommonReg::DeviceStatus => {
+                self.set_status(dev, wo.read_u8(), ctx);
+            }
+            CommonReg::QueueSelect => {
+                let mut state = self.state.lock().unwrap();
+                state.queue_sel = wo.read_u16();
+            }
+            CommonReg::QueueSize => {
+                let mut state = self.state.lock().unwrap();
+                if let Some(queue) = self.queues.get(state.queue_sel) {
+                    // XXX: Queues cannot yet be shrunk by the driver
+                    if wo.read_u16() != queue.size {
+                        state.status |= Status::NEEDS_RESET;
+                    }
+                }
+            }
+            CommonReg::QueueMsixVector => {
+                self.set_queue_msix_vec(pci_state, wo.read_u16());
+            }
+            CommonReg::QueueEnable => {
+                let mut state = self.state.lock().unwrap();
+                // Queues cannot be disabled by writing 0
+                if wo.read_u16() != 1 {
+                    return;
+                }
                 if let Some(queue) = self.queues.get(state.queue_sel) {
-                    let val = wo.read_u16();
-
-                    if state.intr_mode != IntrMode::Msi {
-                        // Store the vector information for later
-                        state.msix_queue_vec[sel] = val;
-                    } else {
-                        state = self
-                            .state_cv
-                            .wait_while(state, |s| s.intr_mode_updating)
-                            .unwrap();
-                        state.intr_mode_updating = true;
-                        state.msix_queue_vec[sel] = val;
-
-                        // State lock cannot be held while updating queue
-                        // interrupt handlers due to deadlock possibility.
-                        drop(state);
-                        queue.set_interrupt(MsiIntr::new(hdl, val));
-                        state = self.state.lock().unwrap();
-
-                        state.intr_mode_updating = false;
-                        self.state_cv.notify_all();
+                    let (desc, avail, used) = {
+                        let ctrl = queue.ctrl.lock().unwrap();
+                        (ctrl.gpa_desc.0, ctrl.gpa_avail.0, ctrl.gpa_used.0)
+                    };
+                    let mapped = match state.nego_feat & VIRTIO_F_RING_PACKED {
+                        0 => queue.map_split(desc, avail, used),
+                        _ => queue.map_packed(desc, avail, used),
+                    };
+                    let changed = mapped
+                        && dev
+                            .queue_change(queue, VqChange::Address, ctx)
+                            .is_ok();
+                    if !changed {
+                        // XXX: interrupt needed?
+                        state.status |= Status::NEEDS_RESET;
+                    }
+                }
+            }
+            CommonReg::QueueDesc
+            | CommonReg::QueueDriver
+            | CommonReg::QueueDevice => {
+                let state = self.state.lock().unwrap();
+                if let Some(queue) = self.queues.get(state.queue_sel) {
+                    // The addresses are only put to use once the queue is
+                    // enabled.
+                    let addr = GuestAddr(wo.read_u64());
+                    let mut ctrl = queue.ctrl.lock().unwrap();
+                    match id {
+                        CommonReg::QueueDesc => ctrl.gpa_desc = addr,
+                        CommonReg::QueueDriver => ctrl.gpa_avail = addr,
+                        CommonReg::QueueDevice => ctrl.gpa_used = addr,
+                        _ => unreachable!(),
                     }
                 }
             }
 
-            LegacyReg::FeatDevice
-            | LegacyReg::QueueSize
-            | LegacyReg::IsrStatus => {
+            CommonReg::DeviceFeature
+            | CommonReg::NumQueues
+            | CommonReg::ConfigGeneration
+            | CommonReg::QueueNotifyOff => {
                 // Read-only regs
             }
         }
     }
 
-    fn features_supported(&self, dev: &dyn VirtioDevice) -> u32 {
-        dev.get_features() | VIRTIO_F_RING_INDIRECT_DESC as u32
+    fn set_queue_msix_vec(&self, pci_state: &pci::DeviceState, val: u16) {
+        let mut state = self.state.lock().unwrap();
+        let sel = state.queue_sel as usize;
+        if let Some(queue) = self.queues.get(state.queue_sel) {
+            if state.intr_mode != IntrMode::Msi {
+                // Store the vector information for later
+                state.msix_queue_vec[sel] = val;
+            } else {
+                let hdl = pci_state.msix_hdl().unwrap();
+                state = self
+                    .state_cv
+                    .wait_while(state, |s| s.intr_mode_updating)
+                    .unwrap();
+                state.intr_mode_updating = true;
+                state.msix_queue_vec[sel] = val;
+
+                // State lock cannot be held while updating queue
+                // interrupt handlers due to deadlock possibility.
+                drop(state);
+                queue.set_interrupt(MsiIntr::new(hdl, val));
+                state = self.state.lock().unwrap();
+
+                state.intr_mode_updating = false;
+                self.state_cv.notify_all();
+            }
+        }
+    }
+
+    fn features_supported(&self, dev: &dyn VirtioDevice) -> u64 {
+        let mut transport = VIRTIO_F_RING_INDIRECT_DESC;
+        if self.map_modern.is_some() {
+            // The packed layout is handled by the virtqueues themselves, and so
+            // can be offered for any device processing its own queues.
+            transport |=
+                VIRTIO_F_VERSION_1 | VIRTIO_F_RING_PACKED | VIRTIO_F_IN_ORDER;
+        }
+        dev.get_features() | dev.transport_features(transport)
+    }
+    /// Put negotiated features into effect, both for the device and for the
+    /// virtqueues it uses.
+    fn set_features(&self, dev: &dyn VirtioDevice, feat: u64) {
+        let event_idx = feat & VIRTIO_F_RING_EVENT_IDX != 0;
+        let in_order = feat & VIRTIO_F_IN_ORDER != 0;
+        for queue in self.queues[..].iter() {
+            queue.set_event_idx(event_idx);
+            queue.set_in_order(in_order);
+        }
+        dev.set_features(feat);
     }
     fn set_status(&self, dev: &dyn VirtioDevice, status: u8, ctx: &DispCtx) {
         let mut state = self.state.lock().unwrap();
-        let val = Status::from_bits_truncate(status);
+        let mut val = Status::from_bits_truncate(status);
         if val == Status::RESET && state.status != Status::RESET {
             self.virtio_reset(dev, state, ctx)
         } else {
+            if val.contains(Status::FEATURES_OK)
+                && !state.status.contains(Status::FEATURES_OK)
+            {
+                // Features negotiated through the modern interface take
+                // effect once the driver has accepted them, provided they
+                // include VIRTIO_F_VERSION_1 and the device is content with
+                // them.  Otherwise FEATURES_OK is left clear, for the driver
+                // to find when reading back the status.
+                let feat = state.nego_feat;
+                let modern_ok =
+                    self.map_modern.is_none() || feat & VIRTIO_F_VERSION_1 != 0;
+                if modern_ok && dev.features_ok(feat) {
+                    self.set_features(dev, feat);
+                } else {
+                    val.remove(Status::FEATURES_OK);
+                }
+            }
             // XXX: better device status FSM
             state.status = val;
         }
@@ -397,6 +814,28 @@ impl PciVirtioState {
         }
     }
 
+    /// Notify the driver that the device-specific configuration has changed
+    pub fn notify_config_change(
+        &self,
+        pci_state: &pci::DeviceState,
+        ctx: &DispCtx,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        state.config_gen = state.config_gen.wrapping_add(1);
+        if state.intr_mode == IntrMode::Msi {
+            let vec = state.msix_cfg_vec;
+            drop(state);
+            if let Some(hdl) = pci_state.msix_hdl() {
+                if vec != VIRTIO_MSI_NO_VECTOR && vec < hdl.count() {
+                    hdl.fire(vec, ctx);
+                }
+            }
+        } else {
+            drop(state);
+            self.isr_state.raise(VIRTIO_ISR_CONFIG);
+        }
+    }
+
     /// Reset the virtio portion of the device
     ///
     /// This leaves PCI state (such as configured BARs) unchanged
@@ -408,7 +847,8 @@ impl PciVirtioState {
     ) {
         for queue in self.queues[..].iter() {
             queue.reset();
-            dev.queue_change(queue, VqChange::Reset, ctx);
+            // The reset clears any need for one the device had
+            let _ = dev.queue_change(queue, VqChange::Reset, ctx);
         }
         state.reset();
         let _ = self.isr_state.read_clear();
@@ -494,6 +934,59 @@ impl PciVirtioState {
             isr: isr_inner.value != 0,
         }
     }
+
+    /// Restore the state of the device from that exported by its counterpart
+    /// on the source of a migration.
+    ///
+    /// Negotiated features are put into effect, and each mapped queue is
+    /// presented to the device as if its address had been set by the driver.
+    pub fn import<D>(
+        &self,
+        dev: &D,
+        saved: migrate::PciVirtioStateV1,
+        ctx: &DispCtx,
+    ) -> Result<(), MigrateStateError>
+    where
+        D: pci::Device + PciVirtio,
+    {
+        if saved.queues.len() != self.queues.count().get() as usize {
+            return Err(MigrateStateError::ImportFailed(
+                "virtqueue count mismatch".to_string(),
+            ));
+        }
+
+        // The queue MSI-X vectors must be in place before the PCI state, as
+        // restoring its interrupt mode wires them up to the queues.
+        let mut state = self.state.lock().unwrap();
+        state.import(&saved.state)?;
+        let feat = state.nego_feat;
+        // Features negotiated through the modern interface are only in effect
+        // once accepted by the driver.
+        let feat_ok = state.status.contains(Status::FEATURES_OK)
+            || feat & VIRTIO_F_VERSION_1 == 0;
+        drop(state);
+
+        if feat_ok {
+            self.set_features(dev, feat);
+        }
+        for (queue, saved) in self.queues[..].iter().zip(saved.queues.iter()) {
+            queue.import(saved)?;
+            if saved.avail_valid
+                && dev.queue_change(queue, VqChange::Address, ctx).is_err()
+            {
+                return Err(MigrateStateError::ImportFailed(format!(
+                    "virtqueue {} refused by device",
+                    queue.id
+                )));
+            }
+        }
+
+        dev.pci_state().import(dev, saved.pci)?;
+        if saved.isr {
+            self.isr_state.raise(VIRTIO_ISR_QUEUE);
+        }
+        Ok(())
+    }
 }
 
 #[derive(Default)]
@@ -509,9 +1002,9 @@ impl IsrState {
     fn new() -> Arc<Self> {
         Arc::new(Self { inner: Mutex::new(IsrInner::default()) })
     }
-    fn raise(&self) {
+    fn raise(&self, bits: u8) {
         let mut inner = self.inner.lock().unwrap();
-        inner.value |= 1;
+        inner.value |= bits;
         if !inner.disabled {
             if let Some(pin) = inner.pin.as_ref() {
                 pin.assert()
@@ -568,7 +1061,7 @@ impl IsrIntr {
 impl VirtioIntr for IsrIntr {
     fn notify(&self, _ctx: &DispCtx) {
         if let Some(state) = Weak::upgrade(&self.state) {
-            state.raise()
+            state.raise(VIRTIO_ISR_QUEUE)
         }
     }
     fn read(&self) -> VqIntr {
@@ -606,6 +1099,16 @@ enum VirtioTop {
     LegacyConfig,
     DeviceConfig,
 }
+impl VirtioTop {
+    fn create_map(legacy_sz: usize, cfg_sz: usize) -> RegMap<Self> {
+        let mut layout = vec![(VirtioTop::LegacyConfig, legacy_sz)];
+        // Devices without any device-specific configuration leave it out
+        if cfg_sz != 0 {
+            layout.push((VirtioTop::DeviceConfig, cfg_sz));
+        }
+        RegMap::create_packed_passthru(legacy_sz + cfg_sz, &layout)
+    }
+}
 
 const LEGACY_REG_SZ: usize = 0x18;
 const LEGACY_REG_SZ_NO_MSIX: usize = 0x14;
@@ -641,21 +1144,493 @@ lazy_static! {
     };
 }
 
+/// Configuration structures of the modern interface, each of which is placed
+/// in its own region of [`MODERN_BAR`] and located by a virtio PCI capability.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum ModernTop {
+    CommonConfig,
+    IsrConfig,
+    DeviceConfig,
+    NotifyConfig,
+    Unused,
+}
+impl ModernTop {
+    fn region_offset(self) -> usize {
+        let region = match self {
+            ModernTop::CommonConfig => 0,
+            ModernTop::IsrConfig => 1,
+            ModernTop::DeviceConfig => 2,
+            ModernTop::NotifyConfig => 3,
+            ModernTop::Unused => panic!("unused region has no offset"),
+        };
+        region * MODERN_REGION_SZ
+    }
+    fn create_map(cfg_sz: usize) -> RegMap<Self> {
+        assert!(cfg_sz <= MODERN_REGION_SZ);
+        let mut layout = Vec::new();
+        for (id, sz) in [
+            (ModernTop::CommonConfig, COMMON_REG_SZ),
+            (ModernTop::IsrConfig, ISR_REG_SZ),
+            (ModernTop::DeviceConfig, cfg_sz),
+            (ModernTop::NotifyConfig, NOTIFY_REG_SZ),
+        ] {
+            // Pad out each structure to fill its region
+            if sz != 0 {
+                layout.push((id, sz));
+            }
+            if sz != MODERN_REGION_SZ {
+                layout.push((ModernTop::Unused, MODERN_REGION_SZ - sz));
+            }
+        }
+        RegMap::create_packed_passthru(MODERN_BAR_SZ, &layout)
+    }
+}
+
+/// Body of the virtio PCI capability (following its length) which locates
+/// the `top` configuration structure, of `len` bytes, in the modern BAR.
+///
+/// See virtio 1.1 Section 4.1.4 Virtio Structure PCI Capabilities
+fn modern_cap(cfg_type: u8, top: ModernTop, len: usize) -> Vec<u8> {
+    let mut cap = vec![cfg_type, MODERN_BAR as u8, 0, 0, 0];
+    cap.extend_from_slice(&(top.region_offset() as u32).to_le_bytes());
+    cap.extend_from_slice(&(len as u32).to_le_bytes());
+    cap
+}
+
+/// Window into a BAR, as selected by the driver through the PCI configuration
+/// access capability.
+#[derive(Default)]
+struct PciCfgWindow {
+    bar: u8,
+    offset: u32,
+    length: u32,
+}
+impl PciCfgWindow {
+    /// Offset and length of the access to make through the window, if it is
+    /// valid.  Only the modern BAR is reachable, with accesses of 1, 2 or 4
+    /// bytes aligned to their length.
+    fn target(&self) -> Option<(usize, usize)> {
+        let (off, len) = (self.offset as usize, self.length as usize);
+        if self.bar != MODERN_BAR as u8
+            || !matches!(len, 1 | 2 | 4)
+            || off % len != 0
+            || off + len > MODERN_BAR_SZ
+        {
+            return None;
+        }
+        Some((off, len))
+    }
+}
+
+/// Size of the body (beginning with its length) of the PCI configuration
+/// access capability
+const PCI_CFG_CAP_SZ: usize = 18;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum PciCfgReg {
+    CapLen,
+    CfgType,
+    Bar,
+    Reserved,
+    Offset,
+    Length,
+    Data,
+}
+lazy_static! {
+    static ref PCI_CFG_REGS: RegMap<PciCfgReg> = {
+        let mut map = RegMap::new(PCI_CFG_CAP_SZ);
+        map.define(0, 1, PciCfgReg::CapLen);
+        map.define(1, 1, PciCfgReg::CfgType);
+        map.define(2, 1, PciCfgReg::Bar);
+        map.define_with_flags(3, 3, PciCfgReg::Reserved, Flags::PASSTHRU);
+        map.define(6, 4, PciCfgReg::Offset);
+        map.define(10, 4, PciCfgReg::Length);
+        // Partial writes of the data must not read through the window (with
+        // whatever side effects that has) to fill out the rest.
+        map.define_with_flags(14, 4, PciCfgReg::Data, Flags::NO_READ_MOD_WRITE);
+        map
+    };
+}
+
+/// Select the 32-bit half of the feature bits `feat` exposed through the
+/// modern interface by `sel`.
+fn feat_half(feat: u64, sel: u32) -> u32 {
+    match sel {
+        0 => feat as u32,
+        1 => (feat >> 32) as u32,
+        _ => 0,
+    }
+}
+
+const COMMON_REG_SZ: usize = 0x38;
+const ISR_REG_SZ: usize = 1;
+const NOTIFY_REG_SZ: usize = 2;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum CommonReg {
+    DeviceFeatureSelect,
+    DeviceFeature,
+    DriverFeatureSelect,
+    DriverFeature,
+    MsixConfig,
+    NumQueues,
+    DeviceStatus,
+    ConfigGeneration,
+    QueueSelect,
+    QueueSize,
+    QueueMsixVector,
+    QueueEnable,
+    QueueNotifyOff,
+    QueueDesc,
+    QueueDriver,
+    QueueDevice,
+}
+lazy_static! {
+    static ref COMMON_REGS: RegMap<CommonReg> = {
+        let layout = [
+            (CommonReg::DeviceFeatureSelect, 4),
+            (CommonReg::DeviceFeature, 4),
+            (CommonReg::DriverFeatureSelect, 4),
+            (CommonReg::DriverFeature, 4),
+            (CommonReg::MsixConfig, 2),
+            (CommonReg::NumQueues, 2),
+            (CommonReg::DeviceStatus, 1),
+            (CommonReg::ConfigGeneration, 1),
+            (CommonReg::QueueSelect, 2),
+            (CommonReg::QueueSize, 2),
+            (CommonReg::QueueMsixVector, 2),
+            (CommonReg::QueueEnable, 2),
+            (CommonReg::QueueNotifyOff, 2),
+            (CommonReg::QueueDesc, 8),
+            (CommonReg::QueueDriver, 8),
+            (CommonReg::QueueDevice, 8),
+        ];
+        RegMap::create_packed(COMMON_REG_SZ, &layout, None)
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use std::num::NonZeroU16;
+
+    use super::*;
+    use crate::block::test_util::TestInstance;
+    use crate::hw::pci::Device;
+    use crate::hw::virtio::queue::{MapInfo, VirtQueue};
+
+    // Offsets of the common configuration registers within the modern BAR
+    const DEVICE_FEATURE_SELECT: usize = 0x00;
+    const DEVICE_FEATURE: usize = 0x04;
+    const DRIVER_FEATURE_SELECT: usize = 0x08;
+    const DRIVER_FEATURE: usize = 0x0c;
+    const MSIX_CONFIG: usize = 0x10;
+    const NUM_QUEUES: usize = 0x12;
+    const DEVICE_STATUS: usize = 0x14;
+    const QUEUE_SELECT: usize = 0x16;
+    const QUEUE_SIZE: usize = 0x18;
+    const QUEUE_MSIX_VECTOR: usize = 0x1a;
+    const QUEUE_ENABLE: usize = 0x1c;
+    const QUEUE_DESC: usize = 0x20;
+    const QUEUE_DRIVER: usize = 0x28;
+    const QUEUE_DEVICE: usize = 0x30;
+
+    /// Feature bit of the test device which it refuses to operate with
+    const FEAT_REFUSED: u64 = 1 << 1;
+
+    /// Device with two queues, offering a couple of features of its own
+    struct TestDev {
+        virtio_state: PciVirtioState,
+        pci_state: pci::DeviceState,
+        /// Features put into effect, if any
+        accepted: Mutex<Option<u64>>,
+    }
+    impl TestDev {
+        fn new() -> Arc<Self> {
+            let queues = VirtQueues::new(
+                NonZeroU16::new(16).unwrap(),
+                NonZeroU16::new(2).unwrap(),
+            );
+            let (virtio_state, pci_state) =
+                PciVirtioState::create(queues, None, 0x1041, 0, 0, true);
+            Arc::new(Self {
+                virtio_state,
+                pci_state,
+                accepted: Mutex::new(None),
+            })
+        }
+    }
+    impl VirtioDevice for TestDev {
+        fn cfg_rw(&self, _rwo: RWOp) {}
+        fn get_features(&self) -> u64 {
+            1 | FEAT_REFUSED
+        }
+        fn features_ok(&self, feat: u64) -> bool {
+            feat & FEAT_REFUSED == 0
+        }
+        fn set_features(&self, feat: u64) {
+            *self.accepted.lock().unwrap() = Some(feat);
+        }
+        fn queue_notify(&self, _vq: &Arc<VirtQueue>, _ctx: &DispCtx) {}
+    }
+    impl PciVirtio for TestDev {
+        fn virtio_state(&self) -> &PciVirtioState {
+            &self.virtio_state
+        }
+        fn pci_state(&self) -> &pci::DeviceState {
+            &self.pci_state
+        }
+    }
+    impl Entity for TestDev {
+        fn type_name(&self) -> &'static str {
+            "test-virtio"
+        }
+    }
+
+    fn read(test: &TestInstance, dev: &TestDev, off: usize, len: usize) -> u64 {
+        let mut buf = [0u8; 8];
+        test.with_ctx(|ctx| {
+            let mut ro = ReadOp::from_buf(off, &mut buf[..len]);
+            dev.bar_rw(MODERN_BAR, RWOp::Read(&mut ro), ctx);
+        });
+        u64::from_le_bytes(buf)
+    }
+    fn write(
+        test: &TestInstance,
+        dev: &TestDev,
+        off: usize,
+        len: usize,
+        val: u64,
+    ) {
+        let buf = val.to_le_bytes();
+        test.with_ctx(|ctx| {
+            let mut wo = WriteOp::from_buf(off, &buf[..len]);
+            dev.bar_rw(MODERN_BAR, RWOp::Write(&mut wo), ctx);
+        });
+    }
+
+    /// Offer the features `feat` through the common configuration, then set
+    /// FEATURES_OK, returning the status read back.
+    fn negotiate(test: &TestInstance, dev: &TestDev, feat: u64) -> Status {
+        let status = Status::ACK | Status::DRIVER;
+        write(test, dev, DEVICE_STATUS, 1, status.bits() as u64);
+        for sel in 0..2 {
+            write(test, dev, DRIVER_FEATURE_SELECT, 4, sel);
+            write(
+                test,
+                dev,
+                DRIVER_FEATURE,
+                4,
+                feat >> (32 * sel) & 0xffff_ffff,
+            );
+        }
+        let status = status | Status::FEATURES_OK;
+        write(test, dev, DEVICE_STATUS, 1, status.bits() as u64);
+        Status::from_bits_truncate(read(test, dev, DEVICE_STATUS, 1) as u8)
+    }
+
+    #[test]
+    fn feature_negotiation() {
+        let test = TestInstance::new();
+        let dev = TestDev::new();
+
+        // Device features are read a 32-bit half at a time, with those of the
+        // transport offered alongside
+        let mut offered = 0;
+        for sel in 0..2 {
+            write(&test, &dev, DEVICE_FEATURE_SELECT, 4, sel);
+            assert_eq!(read(&test, &dev, DEVICE_FEATURE_SELECT, 4), sel);
+            offered |= read(&test, &dev, DEVICE_FEATURE, 4) << (32 * sel);
+        }
+        assert_eq!(
+            offered,
+            1 | FEAT_REFUSED
+                | VIRTIO_F_RING_INDIRECT_DESC
+                | VIRTIO_F_VERSION_1
+                | VIRTIO_F_RING_PACKED
+                | VIRTIO_F_IN_ORDER
+        );
+
+        // Features the device doesn't offer are dropped from those the
+        // driver writes, and those it accepts take effect with FEATURES_OK
+        let status = negotiate(&test, &dev, 1 | VIRTIO_F_VERSION_1 | 1 << 40);
+        assert!(status.contains(Status::FEATURES_OK));
+        assert_eq!(*dev.accepted.lock().unwrap(), Some(1 | VIRTIO_F_VERSION_1));
+        write(&test, &dev, DRIVER_FEATURE_SELECT, 4, 1);
+        assert_eq!(read(&test, &dev, DRIVER_FEATURE, 4), 1);
+    }
+
+    #[test]
+    fn features_ok_refused() {
+        let test = TestInstance::new();
+        let dev = TestDev::new();
+
+        // FEATURES_OK is refused without VIRTIO_F_VERSION_1 ...
+        let status = negotiate(&test, &dev, 1);
+        assert!(!status.contains(Status::FEATURES_OK));
+        assert_eq!(*dev.accepted.lock().unwrap(), None);
+
+        // ... and for features the device cannot operate with
+        write(&test, &dev, DEVICE_STATUS, 1, 0);
+        let status = negotiate(&test, &dev, FEAT_REFUSED | VIRTIO_F_VERSION_1);
+        assert!(!status.contains(Status::FEATURES_OK));
+        assert_eq!(*dev.accepted.lock().unwrap(), None);
+    }
+
+    #[test]
+    fn queue_config() {
+        let test = TestInstance::new();
+        let dev = TestDev::new();
+        assert_eq!(read(&test, &dev, NUM_QUEUES, 2), 2);
+        negotiate(&test, &dev, VIRTIO_F_VERSION_1);
+
+        write(&test, &dev, QUEUE_SELECT, 2, 1);
+        assert_eq!(read(&test, &dev, QUEUE_SELECT, 2), 1);
+        assert_eq!(read(&test, &dev, QUEUE_SIZE, 2), 16);
+        assert_eq!(read(&test, &dev, QUEUE_ENABLE, 2), 0);
+
+        // The ring addresses may be placed anywhere suitably aligned, taking
+        // effect once the queue is enabled
+        let info = MapInfo {
+            desc_addr: 0x10000,
+            avail_addr: 0x10100,
+            used_addr: 0x10200,
+        };
+        write(&test, &dev, QUEUE_DESC, 8, info.desc_addr);
+        write(&test, &dev, QUEUE_DRIVER, 8, info.avail_addr);
+        write(&test, &dev, QUEUE_DEVICE, 8, info.used_addr);
+        assert_eq!(read(&test, &dev, QUEUE_DRIVER, 8), info.avail_addr);
+        assert!(dev.virtio_state.queues[1].map_info().is_none());
+        write(&test, &dev, QUEUE_ENABLE, 2, 1);
+        assert_eq!(read(&test, &dev, QUEUE_ENABLE, 2), 1);
+        assert_eq!(dev.virtio_state.queues[1].map_info(), Some(info));
+        assert!(dev.virtio_state.queues[0].map_info().is_none());
+
+        // MSI-X vectors are recorded for later use
+        write(&test, &dev, MSIX_CONFIG, 2, 0);
+        write(&test, &dev, QUEUE_MSIX_VECTOR, 2, 1);
+        assert_eq!(read(&test, &dev, MSIX_CONFIG, 2), 0);
+        assert_eq!(read(&test, &dev, QUEUE_MSIX_VECTOR, 2), 1);
+
+        // A queue beyond those of the device reads as zero
+        write(&test, &dev, QUEUE_SELECT, 2, 2);
+        assert_eq!(read(&test, &dev, QUEUE_SIZE, 2), 0);
+
+        // Queues cannot (yet) be resized
+        write(&test, &dev, QUEUE_SELECT, 2, 0);
+        write(&test, &dev, QUEUE_SIZE, 2, 8);
+        let status = read(&test, &dev, DEVICE_STATUS, 1) as u8;
+        assert!(
+            Status::from_bits_truncate(status).contains(Status::NEEDS_RESET)
+        );
+    }
+
+    #[test]
+    fn legacy_layout() {
+        // The used ring follows on the page after the avail ring
+        let info = MapInfo {
+            desc_addr: 0x10000,
+            avail_addr: 0x10100,
+            used_addr: 0x11000,
+        };
+        assert!(info.is_legacy(16));
+        assert!(!info.is_legacy(32));
+        let moved = MapInfo { used_addr: 0x10200, ..info };
+        assert!(!moved.is_legacy(16));
+    }
+
+    fn cfg_read(
+        test: &TestInstance,
+        dev: &TestDev,
+        off: usize,
+        len: usize,
+    ) -> u32 {
+        let mut buf = [0u8; 4];
+        test.with_ctx(|ctx| {
+            let mut ro = ReadOp::from_buf(off, &mut buf[..len]);
+            pci::Endpoint::cfg_rw(dev, RWOp::Read(&mut ro), ctx);
+        });
+        u32::from_le_bytes(buf)
+    }
+    fn cfg_write(
+        test: &TestInstance,
+        dev: &TestDev,
+        off: usize,
+        len: usize,
+        val: u32,
+    ) {
+        let buf = val.to_le_bytes();
+        test.with_ctx(|ctx| {
+            let mut wo = WriteOp::from_buf(off, &buf[..len]);
+            pci::Endpoint::cfg_rw(dev, RWOp::Write(&mut wo), ctx);
+        });
+    }
+
+    #[test]
+    fn pci_cfg_window() {
+        let test = TestInstance::new();
+        let dev = TestDev::new();
+
+        // Walk the capability list for the PCI configuration access cap
+        let mut cap = cfg_read(&test, &dev, 0x34, 1) as usize;
+        while cap != 0 {
+            if cfg_read(&test, &dev, cap, 1) == 0x09
+                && cfg_read(&test, &dev, cap + 3, 1)
+                    == VIRTIO_PCI_CAP_PCI_CFG as u32
+            {
+                break;
+            }
+            cap = cfg_read(&test, &dev, cap + 1, 1) as usize;
+        }
+        assert_ne!(cap, 0, "PCI_CFG capability not found");
+        assert_eq!(cfg_read(&test, &dev, cap + 2, 1), 20);
+        let (bar, offset, length, data) =
+            (cap + 4, cap + 8, cap + 12, cap + 16);
+
+        // Select the device status register through the window
+        cfg_write(&test, &dev, bar, 1, MODERN_BAR as u32);
+        cfg_write(&test, &dev, offset, 4, DEVICE_STATUS as u32);
+        cfg_write(&test, &dev, length, 4, 1);
+        assert_eq!(cfg_read(&test, &dev, offset, 4), DEVICE_STATUS as u32);
+
+        let status = Status::ACK | Status::DRIVER;
+        cfg_write(&test, &dev, data, 1, status.bits() as u32);
+        assert_eq!(read(&test, &dev, DEVICE_STATUS, 1), status.bits() as u64);
+        assert_eq!(cfg_read(&test, &dev, data, 1), status.bits() as u32);
+
+        // Wider registers are reached with wider windows
+        cfg_write(&test, &dev, offset, 4, NUM_QUEUES as u32);
+        cfg_write(&test, &dev, length, 4, 2);
+        assert_eq!(cfg_read(&test, &dev, data, 2), 2);
+
+        // Misaligned and other BAR windows go nowhere
+        cfg_write(&test, &dev, offset, 4, DEVICE_STATUS as u32 + 1);
+        assert_eq!(cfg_read(&test, &dev, data, 2), 0);
+        cfg_write(&test, &dev, bar, 1, 0);
+        cfg_write(&test, &dev, offset, 4, DEVICE_STATUS as u32);
+        cfg_write(&test, &dev, length, 4, 1);
+        cfg_write(&test, &dev, data, 1, 0);
+        assert_eq!(read(&test, &dev, DEVICE_STATUS, 1), status.bits() as u64);
+    }
+}
+
 pub mod migrate {
     use crate::hw::pci::migrate::PciStateV1;
     use crate::hw::virtio::queue;
-    use serde::Serialize;
+    use serde::{Deserialize, Serialize};
 
-    #[derive(Serialize)]
+    #[derive(Deserialize, Serialize)]
     pub struct VirtioStateV1 {
         pub status: u8,
         pub queue_sel: u16,
-        pub nego_feat: u32,
+        pub nego_feat: u64,
+        pub device_feat_sel: u32,
+        pub driver_feat_sel: u32,
+        pub config_gen: u8,
         pub msix_cfg_vec: u16,
         pub msix_queue_vec: Vec<u16>,
     }
 
-    #[derive(Serialize)]
+    #[derive(Deserialize, Serialize)]
     pub struct PciVirtioStateV1 {
         pub pci: PciStateV1,
         pub state: VirtioStateV1,
@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::mem;
 use std::num::{NonZeroU16, Wrapping};
 use std::ops::Index;
@@ -10,6 +11,7 @@ use super::probes;
 use super::VirtioIntr;
 use crate::common::*;
 use crate::dispatch::DispCtx;
+use crate::migrate::MigrateStateError;
 use crate::vmm::MemCtx;
 
 #[repr(C)]
@@ -26,9 +28,25 @@ struct VqdUsed {
     id: u32,
     len: u32,
 }
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct VqdPackedDesc {
+    addr: u64,
+    len: u32,
+    id: u16,
+    flags: u16,
+}
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct VqdEventSuppress {
+    off_wrap: u16,
+    flags: u16,
+}
 
 pub struct VqControl {
     pub(super) gpa_desc: GuestAddr,
+    pub(super) gpa_avail: GuestAddr,
+    pub(super) gpa_used: GuestAddr,
 }
 
 struct VqAvail {
@@ -39,13 +57,36 @@ struct VqAvail {
     cur_avail_idx: Wrapping<u16>,
 
     gpa_desc: GuestAddr,
+
+    /// Whether VIRTIO_F_RING_EVENT_IDX is in effect
+    event_idx: bool,
+    /// Address of the avail_event field, which resides in the used ring
+    ///
+    /// For a packed ring, this is the device event suppression structure.
+    gpa_avail_event: GuestAddr,
+
+    /// Whether the queue uses the packed layout, in which case only the
+    /// descriptor ring address is used, with `cur_avail_idx` being the index
+    /// of the next descriptor in it.
+    packed: bool,
+    /// Wrap counter of the next descriptor in a packed ring
+    avail_wrap: bool,
 }
 impl VqAvail {
     fn read_next_avail(&mut self, rsize: u16, mem: &MemCtx) -> Option<u16> {
         if !self.valid {
             return None;
         }
-        if let Some(idx) = mem.read::<u16>(self.gpa_idx) {
+        let mut idx = mem.read::<u16>(self.gpa_idx);
+        if self.event_idx && idx == Some(self.cur_avail_idx.0) {
+            // With the ring seemingly empty, ask to be notified once the next
+            // entry is made available.  The driver may have already done so
+            // without seeing that request, so check again afterwards.
+            mem.write(self.gpa_avail_event, &self.cur_avail_idx.0);
+            fence(Ordering::SeqCst);
+            idx = mem.read::<u16>(self.gpa_idx);
+        }
+        if let Some(idx) = idx {
             let ndesc = Wrapping(idx) - self.cur_avail_idx;
             if ndesc.0 != 0 && ndesc.0 < rsize {
                 let read_idx = self.cur_avail_idx.0 & (rsize - 1);
@@ -69,6 +110,59 @@ impl VqAvail {
         let addr = self.gpa_desc + (id as usize * mem::size_of::<VqdDesc>());
         mem.read::<VqdDesc>(addr)
     }
+
+    /// Read the next descriptor of a packed ring, if the driver has made it
+    /// available.
+    fn read_next_avail_packed(
+        &mut self,
+        mem: &MemCtx,
+    ) -> Option<VqdPackedDesc> {
+        if !self.valid {
+            return None;
+        }
+        let addr = self.gpa_desc
+            + (self.cur_avail_idx.0 as usize * mem::size_of::<VqdPackedDesc>());
+        let flags_addr = addr + (mem::size_of::<VqdPackedDesc>() - 2);
+
+        let mut flags = mem.read::<u16>(flags_addr)?;
+        if self.event_idx && !self.packed_is_avail(flags) {
+            // As with a split ring, ask to be notified once the next
+            // descriptor is made available, then check again.
+            let evt = VqdEventSuppress {
+                off_wrap: self.cur_avail_idx.0
+                    | ((self.avail_wrap as u16) << 15),
+                flags: RING_EVENT_FLAGS_DESC,
+            };
+            mem.write(self.gpa_avail_event, &evt);
+            fence(Ordering::SeqCst);
+            flags = mem.read::<u16>(flags_addr)?;
+        }
+        if !self.packed_is_avail(flags) {
+            return None;
+        }
+
+        // The rest of the descriptor must not be read ahead of its flags
+        fence(Ordering::Acquire);
+        mem.read::<VqdPackedDesc>(addr)
+    }
+    /// Whether a packed ring descriptor with `flags` is available, as opposed
+    /// to being used or left over from the previous lap of the ring.
+    fn packed_is_avail(&self, flags: u16) -> bool {
+        let avail = flags & VIRTQ_DESC_F_AVAIL != 0;
+        let used = flags & VIRTQ_DESC_F_USED != 0;
+        avail == self.avail_wrap && used != self.avail_wrap
+    }
+    fn read_packed_descr(
+        &self,
+        idx: u16,
+        rsize: u16,
+        mem: &MemCtx,
+    ) -> Option<VqdPackedDesc> {
+        assert!(idx < rsize);
+        let addr =
+            self.gpa_desc + (idx as usize * mem::size_of::<VqdPackedDesc>());
+        mem.read::<VqdPackedDesc>(addr)
+    }
 }
 
 struct VqUsed {
@@ -78,8 +172,87 @@ struct VqUsed {
     gpa_ring: GuestAddr,
     used_idx: Wrapping<u16>,
     interrupt: Option<Box<dyn VirtioIntr>>,
+
+    /// Address of the flags of the avail ring
+    gpa_avail_flags: GuestAddr,
+    /// Whether VIRTIO_F_RING_EVENT_IDX is in effect
+    event_idx: bool,
+    /// Address of the used_event field, which resides in the avail ring
+    ///
+    /// For a packed ring, this is the driver event suppression structure.
+    gpa_used_event: GuestAddr,
+
+    /// Whether the queue uses the packed layout, in which case `gpa_ring` is
+    /// the descriptor ring, with `used_idx` being the index in it of the next
+    /// used descriptor.
+    packed: bool,
+    /// Wrap counter of the next used descriptor in a packed ring
+    used_wrap: bool,
+
+    /// Whether VIRTIO_F_IN_ORDER is in effect
+    in_order: bool,
+    /// Chains taken from the avail ring while VIRTIO_F_IN_ORDER is in effect,
+    /// in the order they were made available
+    in_flight: VecDeque<InFlight>,
+}
+/// A chain yet to be returned to the driver under VIRTIO_F_IN_ORDER.
+struct InFlight {
+    id: u16,
+    /// Descriptors taken up by the chain in a packed ring
+    count: u16,
+    /// Length written to the chain, once the device is done with it
+    len: Option<u32>,
 }
 impl VqUsed {
+    /// Return the chain `id`, which took up `count` descriptors of a packed
+    /// ring, to the driver with `len` bytes written to it.
+    ///
+    /// Under VIRTIO_F_IN_ORDER, a chain is held back until those made
+    /// available ahead of it have been returned as well.  Returns whether any
+    /// entries were placed in the used ring.
+    fn use_chain(
+        &mut self,
+        id: u16,
+        count: u16,
+        len: u32,
+        rsize: u16,
+        mem: &MemCtx,
+    ) -> bool {
+        if !self.in_order {
+            self.write_any(id, len, count, rsize, mem);
+            return true;
+        }
+
+        if let Some(ent) =
+            self.in_flight.iter_mut().find(|e| e.id == id && e.len.is_none())
+        {
+            ent.len = Some(len);
+        }
+        let mut wrote = false;
+        while let Some(InFlight { id, count, len: Some(len) }) =
+            self.in_flight.front()
+        {
+            let (id, count, len) = (*id, *count, *len);
+            self.in_flight.pop_front();
+            self.write_any(id, len, count, rsize, mem);
+            wrote = true;
+        }
+        wrote
+    }
+    fn write_any(
+        &mut self,
+        id: u16,
+        len: u32,
+        count: u16,
+        rsize: u16,
+        mem: &MemCtx,
+    ) {
+        if self.packed {
+            self.write_used_packed(id, len, count, rsize, mem);
+        } else {
+            self.write_used(id, len, rsize, mem);
+        }
+    }
     fn write_used(&mut self, id: u16, len: u32, rsize: u16, mem: &MemCtx) {
         let idx = self.used_idx.0 & (rsize - 1);
         self.used_idx += Wrapping(1);
@@ -92,9 +265,81 @@ impl VqUsed {
         fence(Ordering::Release);
         mem.write(self.gpa_idx, &self.used_idx.0);
     }
-    fn suppress_intr(&self, mem: &MemCtx) -> bool {
-        let flags: u16 = mem.read(self.gpa_flags).unwrap();
-        flags & VRING_AVAIL_F_NO_INTERRUPT != 0
+    /// Mark the chain `id`, which took up `count` descriptors of the packed
+    /// ring, as used.
+    fn write_used_packed(
+        &mut self,
+        id: u16,
+        len: u32,
+        count: u16,
+        rsize: u16,
+        mem: &MemCtx,
+    ) {
+        let addr = self.gpa_ring
+            + (self.used_idx.0 as usize * mem::size_of::<VqdPackedDesc>());
+
+        // The used descriptor goes in place of the first one of the chain,
+        // with its flags written only once the rest of it is visible.
+        mem.write(addr + 8, &len);
+        mem.write(addr + 12, &id);
+        let mut flags = 0;
+        if self.used_wrap {
+            flags |= VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED;
+        }
+        if len != 0 {
+            flags |= VIRTQ_DESC_F_WRITE;
+        }
+        fence(Ordering::Release);
+        mem.write(addr + 14, &flags);
+
+        let (idx, wrap) =
+            packed_advance(self.used_idx.0, self.used_wrap, count, rsize);
+        self.used_idx = Wrapping(idx);
+        self.used_wrap = wrap;
+    }
+    /// Whether the driver should be interrupted for the used descriptors of a
+    /// packed ring having moved on from `old_idx` (in the lap of `old_wrap`).
+    fn intr_needed_packed(
+        &self,
+        old_idx: Wrapping<u16>,
+        old_wrap: bool,
+        rsize: u16,
+        mem: &MemCtx,
+    ) -> bool {
+        // As with a split ring, the used descriptor must be visible to the
+        // driver before we check whether it asked to be interrupted for it.
+        fence(Ordering::SeqCst);
+        let evt: VqdEventSuppress = mem.read(self.gpa_used_event).unwrap();
+        match evt.flags {
+            RING_EVENT_FLAGS_DISABLE => false,
+            RING_EVENT_FLAGS_DESC if self.event_idx => {
+                // Treat both laps of the ring as one of twice the size, so
+                // the event and the indexes can be compared by position.
+                let full = 2 * rsize as u32;
+                let pos = |idx: u16, wrap: bool| {
+                    idx as u32 + if wrap { rsize as u32 } else { 0 }
+                };
+                let event = pos(evt.off_wrap & 0x7fff, evt.off_wrap >> 15 != 0);
+                let new = pos(self.used_idx.0, self.used_wrap);
+                let old = pos(old_idx.0, old_wrap);
+                (new + full - event - 1) % full < (new + full - old) % full
+            }
+            _ => true,
+        }
+    }
+    /// Whether the driver should be interrupted for the used ring index
+    /// having moved on from `old_idx`.
+    fn intr_needed(&self, old_idx: Wrapping<u16>, mem: &MemCtx) -> bool {
+        if self.event_idx {
+            // The used index must be visible to the driver before we check
+            // whether it asked to be interrupted for it.
+            fence(Ordering::SeqCst);
+            let event: u16 = mem.read(self.gpa_used_event).unwrap();
+            vring_need_event(event, self.used_idx.0, old_idx.0)
+        } else {
+            let flags: u16 = mem.read(self.gpa_avail_flags).unwrap();
+            flags & VRING_AVAIL_F_NO_INTERRUPT == 0
+        }
     }
 }
 
@@ -106,17 +351,43 @@ pub struct VirtQueue {
     used: Mutex<VqUsed>,
 }
 const LEGACY_QALIGN: u64 = PAGE_SIZE as u64;
+const SPLIT_DESC_ALIGN: u64 = 16;
+const SPLIT_AVAIL_ALIGN: u64 = 2;
+const SPLIT_USED_ALIGN: u64 = 4;
+const PACKED_DESC_ALIGN: u64 = 16;
+const PACKED_EVENT_ALIGN: u64 = 4;
 fn qalign(addr: u64, align: u64) -> u64 {
     let mask = align - 1;
     (addr + mask) & !mask
 }
+/// Whether a notification is called for by the ring index moving from `old`
+/// to `new`, given the `event` index at which one was requested.
+///
+/// See virtio 1.1 Section 2.6.7.2 Used Buffer Notification Suppression
+fn vring_need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+/// Advance the index `idx` of a packed ring of `rsize` descriptors by `count`,
+/// flipping the wrap counter `wrap` if it wraps around.
+fn packed_advance(idx: u16, wrap: bool, count: u16, rsize: u16) -> (u16, bool) {
+    let next = idx as u32 + count as u32;
+    if next >= rsize as u32 {
+        ((next - rsize as u32) as u16, !wrap)
+    } else {
+        (next as u16, wrap)
+    }
+}
 impl VirtQueue {
     pub fn new(id: u16, size: u16) -> Self {
         assert!(size.is_power_of_two());
         Self {
             id,
             size,
-            ctrl: Mutex::new(VqControl { gpa_desc: GuestAddr(0) }),
+            ctrl: Mutex::new(VqControl {
+                gpa_desc: GuestAddr(0),
+                gpa_avail: GuestAddr(0),
+                gpa_used: GuestAddr(0),
+            }),
             avail: Mutex::new(VqAvail {
                 valid: false,
                 gpa_flags: GuestAddr(0),
@@ -124,6 +395,10 @@ impl VirtQueue {
                 gpa_ring: GuestAddr(0),
                 cur_avail_idx: Wrapping(0),
                 gpa_desc: GuestAddr(0),
+                event_idx: false,
+                gpa_avail_event: GuestAddr(0),
+                packed: false,
+                avail_wrap: true,
             }),
             used: Mutex::new(VqUsed {
                 valid: false,
@@ -132,6 +407,13 @@ impl VirtQueue {
                 gpa_ring: GuestAddr(0),
                 used_idx: Wrapping(0),
                 interrupt: None,
+                gpa_avail_flags: GuestAddr(0),
+                event_idx: false,
+                gpa_used_event: GuestAddr(0),
+                packed: false,
+                used_wrap: true,
+                in_order: false,
+                in_flight: VecDeque::new(),
             }),
         }
     }
@@ -142,28 +424,72 @@ impl VirtQueue {
 
         // XXX verify no outstanding chains
         state.gpa_desc = GuestAddr(0);
+        state.gpa_avail = GuestAddr(0);
+        state.gpa_used = GuestAddr(0);
         avail.valid = false;
         used.valid = false;
         avail.cur_avail_idx = Wrapping(0);
         used.used_idx = Wrapping(0);
+        avail.event_idx = false;
+        used.event_idx = false;
+        avail.packed = false;
+        used.packed = false;
+        avail.avail_wrap = true;
+        used.used_wrap = true;
+        used.in_order = false;
+        used.in_flight.clear();
     }
+    /// Set whether VIRTIO_F_RING_EVENT_IDX has been negotiated for the queue
+    pub(super) fn set_event_idx(&self, enabled: bool) {
+        let mut avail = self.avail.lock().unwrap();
+        let mut used = self.used.lock().unwrap();
+        avail.event_idx = enabled;
+        used.event_idx = enabled;
+    }
+    /// Set whether VIRTIO_F_IN_ORDER has been negotiated for the queue
+    pub(super) fn set_in_order(&self, enabled: bool) {
+        let mut used = self.used.lock().unwrap();
+        used.in_order = enabled;
+        used.in_flight.clear();
+    }
+    /// Map the queue at the single address used by the legacy interface,
+    /// with its rings laid out contiguously.
     pub fn map_legacy(&self, addr: u64) -> bool {
         assert_eq!(addr & (LEGACY_QALIGN - 1), 0);
+
+        let info = MapInfo::legacy(addr, self.size);
+        self.map_split(info.desc_addr, info.avail_addr, info.used_addr)
+    }
+    /// Map the queue with its descriptor table, avail (driver) ring and used
+    /// (device) ring at the addresses provided by the modern interface.
+    pub fn map_split(
+        &self,
+        desc_addr: u64,
+        avail_addr: u64,
+        used_addr: u64,
+    ) -> bool {
         let mut state = self.ctrl.lock().unwrap();
         let mut avail = self.avail.lock().unwrap();
         let mut used = self.used.lock().unwrap();
 
-        // even if the map is unsuccessful, track the address provided
-        state.gpa_desc = GuestAddr(addr);
+        // even if the map is unsuccessful, track the addresses provided
+        state.gpa_desc = GuestAddr(desc_addr);
+        state.gpa_avail = GuestAddr(avail_addr);
+        state.gpa_used = GuestAddr(used_addr);
 
-        let size = self.size as usize;
+        // See virtio 1.1 Section 2.6 Split Virtqueues for the alignment
+        // required of each part
+        if desc_addr & (SPLIT_DESC_ALIGN - 1) != 0
+            || avail_addr & (SPLIT_AVAIL_ALIGN - 1) != 0
+            || used_addr & (SPLIT_USED_ALIGN - 1) != 0
+        {
+            avail.valid = false;
+            used.valid = false;
+            return false;
+        }
 
-        let desc_addr = addr;
-        let desc_len = mem::size_of::<VqdDesc>() * size;
-        let avail_addr = desc_addr + desc_len as u64;
-        let avail_len = 2 * (size + 3);
-        let used_addr = qalign(avail_addr + avail_len as u64, LEGACY_QALIGN);
-        let _used_len = mem::size_of::<VqUsed>() * size + 2 * 3;
+        avail.packed = false;
+        used.packed = false;
 
         avail.gpa_flags = GuestAddr(avail_addr);
         avail.gpa_idx = GuestAddr(avail_addr + 2);
@@ -176,6 +502,59 @@ impl VirtQueue {
         used.gpa_idx = GuestAddr(used_addr + 2);
         used.gpa_ring = GuestAddr(used_addr + 4);
 
+        // Likewise for the fields each ring holds on behalf of the other,
+        // which follow their ring entries
+        let size = self.size as usize;
+        avail.gpa_avail_event =
+            used.gpa_ring + size * mem::size_of::<VqdUsed>();
+        used.gpa_avail_flags = avail.gpa_flags;
+        used.gpa_used_event = avail.gpa_ring + size * mem::size_of::<u16>();
+
+        avail.valid = true;
+        used.valid = true;
+
+        true
+    }
+    /// Map the queue with the packed layout, with its descriptor ring and the
+    /// driver and device event suppression structures at the addresses
+    /// provided by the modern interface.
+    pub fn map_packed(
+        &self,
+        desc_addr: u64,
+        driver_addr: u64,
+        device_addr: u64,
+    ) -> bool {
+        let mut state = self.ctrl.lock().unwrap();
+        let mut avail = self.avail.lock().unwrap();
+        let mut used = self.used.lock().unwrap();
+
+        // even if the map is unsuccessful, track the addresses provided
+        state.gpa_desc = GuestAddr(desc_addr);
+        state.gpa_avail = GuestAddr(driver_addr);
+        state.gpa_used = GuestAddr(device_addr);
+
+        // See virtio 1.1 Section 2.7.10.1 Structure Size and Alignment
+        if desc_addr & (PACKED_DESC_ALIGN - 1) != 0
+            || driver_addr & (PACKED_EVENT_ALIGN - 1) != 0
+            || device_addr & (PACKED_EVENT_ALIGN - 1) != 0
+        {
+            avail.valid = false;
+            used.valid = false;
+            return false;
+        }
+
+        avail.packed = true;
+        avail.gpa_desc = GuestAddr(desc_addr);
+        avail.gpa_avail_event = GuestAddr(device_addr);
+        avail.cur_avail_idx = Wrapping(0);
+        avail.avail_wrap = true;
+
+        used.packed = true;
+        used.gpa_ring = GuestAddr(desc_addr);
+        used.gpa_used_event = GuestAddr(driver_addr);
+        used.used_idx = Wrapping(0);
+        used.used_wrap = true;
+
         avail.valid = true;
         used.valid = true;
 
@@ -189,16 +568,42 @@ impl VirtQueue {
         if avail.valid && used.valid {
             Some(MapInfo {
                 desc_addr: state.gpa_desc.0,
-                avail_addr: avail.gpa_flags.0,
-                used_addr: used.gpa_flags.0,
+                avail_addr: state.gpa_avail.0,
+                used_addr: state.gpa_used.0,
             })
         } else {
             None
         }
     }
-    pub fn pop_avail(&self, chain: &mut Chain, mem: &MemCtx) -> Option<u32> {
+    pub fn pop_avail(
+        &self,
+        chain: &mut Chain,
+        mem: &MemCtx,
+        ctx: &DispCtx,
+    ) -> Option<u32> {
         assert!(chain.idx.is_none());
         let mut avail = self.avail.lock().unwrap();
+        let len = match avail.packed {
+            true => self.pop_avail_packed(&mut avail, chain, mem, ctx),
+            false => self.pop_avail_split(&mut avail, chain, mem),
+        }?;
+
+        let mut used = self.used.lock().unwrap();
+        if used.in_order {
+            used.in_flight.push_back(InFlight {
+                id: chain.idx.unwrap(),
+                count: chain.ring_count,
+                len: None,
+            });
+        }
+        Some(len)
+    }
+    fn pop_avail_split(
+        &self,
+        avail: &mut VqAvail,
+        chain: &mut Chain,
+        mem: &MemCtx,
+    ) -> Option<u32> {
         let id = avail.read_next_avail(self.size, mem)?;
 
         let mut desc = avail.read_ring_descr(id, self.size, mem)?;
@@ -272,6 +677,155 @@ impl VirtQueue {
         }
         Some(len)
     }
+    /// Pop the next chain from a packed ring, the descriptors of which are
+    /// laid out in order in the ring itself.
+    ///
+    /// A malformed chain is consumed, being returned to the driver as used
+    /// with nothing written to it, rather than left to stall the queue.
+    fn pop_avail_packed(
+        &self,
+        avail: &mut VqAvail,
+        chain: &mut Chain,
+        mem: &MemCtx,
+        ctx: &DispCtx,
+    ) -> Option<u32> {
+        loop {
+            let first = avail.cur_avail_idx.0;
+            let desc = avail.read_next_avail_packed(mem)?;
+            match self.read_chain_packed(avail, first, desc, chain, mem) {
+                Some(len) => {
+                    let (idx, wrap) = packed_advance(
+                        first,
+                        avail.avail_wrap,
+                        chain.ring_count,
+                        self.size,
+                    );
+                    avail.cur_avail_idx = Wrapping(idx);
+                    avail.avail_wrap = wrap;
+                    return Some(len);
+                }
+                None => {
+                    chain.reset();
+                    self.discard_packed(avail, first, desc, mem, ctx);
+                }
+            }
+        }
+    }
+    /// Gather the chain starting with the descriptor `desc`, at index `first`
+    /// of a packed ring, into `chain`, returning its length.
+    fn read_chain_packed(
+        &self,
+        avail: &VqAvail,
+        first: u16,
+        mut desc: VqdPackedDesc,
+        chain: &mut Chain,
+        mem: &MemCtx,
+    ) -> Option<u32> {
+        let mut count = 0;
+        let mut len = 0;
+        loop {
+            let flags = DescFlag::from_bits_truncate(desc.flags);
+            count += 1;
+
+            if flags.contains(DescFlag::INDIRECT) {
+                // The indirect table holds the entire remainder of the chain,
+                // in order.
+                if (desc.len as usize) < mem::size_of::<VqdPackedDesc>()
+                    || desc.len as usize & (mem::size_of::<VqdPackedDesc>() - 1)
+                        != 0
+                {
+                    return None;
+                }
+                let indirect_count =
+                    desc.len as usize / mem::size_of::<VqdPackedDesc>();
+                let idescs = mem.read_many::<VqdPackedDesc>(
+                    GuestAddr(desc.addr),
+                    indirect_count,
+                )?;
+                for i in 0..indirect_count {
+                    let idesc = idescs.get(i).unwrap();
+                    let iflags = DescFlag::from_bits_truncate(idesc.flags);
+                    let buf = match iflags.contains(DescFlag::WRITE) {
+                        true => {
+                            ChainBuf::Writable(GuestAddr(idesc.addr), idesc.len)
+                        }
+                        false => {
+                            ChainBuf::Readable(GuestAddr(idesc.addr), idesc.len)
+                        }
+                    };
+                    len += idesc.len;
+                    chain.push_buf(buf);
+                }
+            } else {
+                let buf = match flags.contains(DescFlag::WRITE) {
+                    true => ChainBuf::Writable(GuestAddr(desc.addr), desc.len),
+                    false => ChainBuf::Readable(GuestAddr(desc.addr), desc.len),
+                };
+                len += desc.len;
+                chain.push_buf(buf);
+            }
+
+            if !flags.contains(DescFlag::NEXT) {
+                break;
+            }
+            if count == self.size {
+                return None;
+            }
+            // The driver makes the rest of the chain available before its
+            // first descriptor, so there is no need to check their flags.
+            let (idx, _) = packed_advance(first, false, count, self.size);
+            desc = avail.read_packed_descr(idx, self.size, mem)?;
+        }
+
+        // The buffer ID is that of the last descriptor in the chain
+        chain.idx = Some(desc.id);
+        chain.ring_count = count;
+        probes::virtio_vq_pop!(|| (self as *const VirtQueue as u64, desc.id));
+        Some(len)
+    }
+    /// Consume the malformed chain starting with the descriptor `desc`, at
+    /// index `first` of a packed ring, returning it to the driver as used.
+    fn discard_packed(
+        &self,
+        avail: &mut VqAvail,
+        first: u16,
+        mut desc: VqdPackedDesc,
+        mem: &MemCtx,
+        ctx: &DispCtx,
+    ) {
+        // Find the end of the chain, as best it can be made out
+        let mut count = 1;
+        while desc.flags & VIRTQ_DESC_F_NEXT != 0 && count < self.size {
+            let (idx, _) = packed_advance(first, false, count, self.size);
+            match avail.read_packed_descr(idx, self.size, mem) {
+                Some(next) => desc = next,
+                None => break,
+            }
+            count += 1;
+        }
+        let (idx, wrap) =
+            packed_advance(first, avail.avail_wrap, count, self.size);
+        avail.cur_avail_idx = Wrapping(idx);
+        avail.avail_wrap = wrap;
+
+        let mut used = self.used.lock().unwrap();
+        if used.in_order {
+            used.in_flight.push_back(InFlight {
+                id: desc.id,
+                count,
+                len: None,
+            });
+        }
+        let old_idx = used.used_idx;
+        let old_wrap = used.used_wrap;
+        if used.use_chain(desc.id, count, 0, self.size, mem)
+            && used.intr_needed_packed(old_idx, old_wrap, self.size, mem)
+        {
+            if let Some(i) = used.interrupt.as_ref() {
+                i.notify(ctx)
+            }
+        }
+    }
     pub fn push_used(&self, chain: &mut Chain, mem: &MemCtx, ctx: &DispCtx) {
         assert!(chain.idx.is_some());
         let mut used = self.used.lock().unwrap();
@@ -279,8 +833,17 @@ impl VirtQueue {
         // XXX: for now, just go off of the write stats
         let len = chain.write_stat.bytes - chain.write_stat.bytes_remain;
         probes::virtio_vq_push!(|| (self as *const VirtQueue as u64, id, len));
-        used.write_used(id, len, self.size, mem);
-        if !used.suppress_intr(mem) {
+        let old_idx = used.used_idx;
+        let old_wrap = used.used_wrap;
+        let wrote = used.use_chain(id, chain.ring_count, len, self.size, mem);
+        let intr_needed = wrote
+            && match used.packed {
+                true => {
+                    used.intr_needed_packed(old_idx, old_wrap, self.size, mem)
+                }
+                false => used.intr_needed(old_idx, mem),
+            };
+        if intr_needed {
             if let Some(i) = used.interrupt.as_ref() {
                 i.notify(ctx)
             }
@@ -308,15 +871,68 @@ impl VirtQueue {
             size: self.size,
             descr_gpa: ctrl.gpa_desc.0,
 
-            avail_gpa: avail.gpa_idx.0,
+            avail_gpa: ctrl.gpa_avail.0,
             avail_valid: avail.valid,
             avail_cur_idx: avail.cur_avail_idx.0,
 
-            used_gpa: used.gpa_idx.0,
+            used_gpa: ctrl.gpa_used.0,
             used_valid: used.valid,
             used_idx: used.used_idx.0,
+
+            event_idx: used.event_idx,
+            packed: used.packed,
+            avail_wrap: avail.avail_wrap,
+            used_wrap: used.used_wrap,
+            in_order: used.in_order,
         }
     }
+
+    /// Restore the state of the queue from that exported by its counterpart
+    /// on the source of a migration.
+    ///
+    /// Chains held back under VIRTIO_F_IN_ORDER are not carried over, so the
+    /// device must have returned all of those it took before being exported.
+    pub fn import(
+        &self,
+        saved: &migrate::VirtQueueV1,
+    ) -> Result<(), MigrateStateError> {
+        if saved.id != self.id || saved.size != self.size {
+            return Err(MigrateStateError::ImportFailed(format!(
+                "virtqueue {} (size {}) mismatch with {} (size {})",
+                self.id, self.size, saved.id, saved.size
+            )));
+        }
+
+        self.reset();
+        let mapped = match saved.packed {
+            true => self.map_packed(
+                saved.descr_gpa,
+                saved.avail_gpa,
+                saved.used_gpa,
+            ),
+            false => {
+                self.map_split(saved.descr_gpa, saved.avail_gpa, saved.used_gpa)
+            }
+        };
+        if !mapped && (saved.avail_valid || saved.used_valid) {
+            return Err(MigrateStateError::ImportFailed(format!(
+                "virtqueue {} addresses invalid",
+                self.id
+            )));
+        }
+        self.set_event_idx(saved.event_idx);
+        self.set_in_order(saved.in_order);
+
+        let mut avail = self.avail.lock().unwrap();
+        let mut used = self.used.lock().unwrap();
+        avail.valid = saved.avail_valid;
+        avail.cur_avail_idx = Wrapping(saved.avail_cur_idx);
+        avail.avail_wrap = saved.avail_wrap;
+        used.valid = saved.used_valid;
+        used.used_idx = Wrapping(saved.used_idx);
+        used.used_wrap = saved.used_wrap;
+        Ok(())
+    }
 }
 
 bitflags! {
@@ -325,6 +941,8 @@ bitflags! {
         const NEXT = VIRTQ_DESC_F_NEXT;
         const WRITE = VIRTQ_DESC_F_WRITE;
         const INDIRECT = VIRTQ_DESC_F_INDIRECT;
+        const AVAIL = VIRTQ_DESC_F_AVAIL;
+        const USED = VIRTQ_DESC_F_USED;
     }
 }
 
@@ -357,6 +975,8 @@ struct ChainStat {
 #[derive(Debug)]
 pub struct Chain {
     idx: Option<u16>,
+    /// Descriptors taken up by the chain in a packed ring
+    ring_count: u16,
     read_stat: ChainStat,
     write_stat: ChainStat,
     bufs: Vec<ChainBuf>,
@@ -366,6 +986,7 @@ impl Chain {
         assert!(size <= u16::MAX as usize);
         Self {
             idx: None,
+            ring_count: 0,
             read_stat: Default::default(),
             write_stat: Default::default(),
             bufs: Vec::with_capacity(size),
@@ -383,6 +1004,7 @@ impl Chain {
     }
     fn reset(&mut self) {
         self.idx = None;
+        self.ring_count = 0;
         self.read_stat = Default::default();
         self.write_stat = Default::default();
         self.bufs.clear();
@@ -414,6 +1036,26 @@ impl Chain {
         });
         total == item_sz
     }
+    /// Read as much of `buf` as the readable portion of the chain can fill,
+    /// returning the number of bytes copied.
+    pub fn read_bytes(&mut self, buf: &mut [u8], mem: &MemCtx) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
+        let mut done = 0;
+        self.for_remaining_type(true, |addr, len| {
+            let remain = &mut buf[done..];
+            if let Some(copied) = mem.read_into(addr, remain, len) {
+                let need_more = copied != remain.len();
+
+                done += copied;
+                (copied, need_more)
+            } else {
+                // Copy failed, so do not attempt anything else
+                (0, false)
+            }
+        })
+    }
     /// Fetch a string of readable guest regions from the chain, provided there
     /// are enough to cover a specified length.
     pub fn readable_bufs(&mut self, len: usize) -> Option<Vec<GuestRegion>> {
@@ -462,6 +1104,26 @@ impl Chain {
         });
         total == item_sz
     }
+    /// Write as much of `buf` as fits in the writable portion of the chain,
+    /// returning the number of bytes copied.
+    pub fn write_bytes(&mut self, buf: &[u8], mem: &MemCtx) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
+        let mut done = 0;
+        self.for_remaining_type(false, |addr, len| {
+            let remain = &buf[done..];
+            if let Some(copied) = mem.write_from(addr, remain, len) {
+                let need_more = copied != remain.len();
+
+                done += copied;
+                (copied, need_more)
+            } else {
+                // Copy failed, so do not attempt anything else
+                (0, false)
+            }
+        })
+    }
 
     pub fn write_skip(&mut self, len: usize) -> bool {
         if len == 0 {
@@ -576,11 +1238,35 @@ impl Chain {
     }
 }
 
+#[derive(Debug, PartialEq, Eq)]
 pub struct MapInfo {
     pub desc_addr: u64,
     pub avail_addr: u64,
     pub used_addr: u64,
 }
+impl MapInfo {
+    /// Addresses of the rings of a split queue of `size` descriptors laid out
+    /// contiguously from `addr`, as the legacy interface requires.
+    fn legacy(addr: u64, size: u16) -> Self {
+        let size = size as usize;
+
+        let desc_addr = addr;
+        let desc_len = mem::size_of::<VqdDesc>() * size;
+        let avail_addr = desc_addr + desc_len as u64;
+        let avail_len = 2 * (size + 3);
+        let used_addr = qalign(avail_addr + avail_len as u64, LEGACY_QALIGN);
+        let _used_len = mem::size_of::<VqUsed>() * size + 2 * 3;
+
+        Self { desc_addr, avail_addr, used_addr }
+    }
+
+    /// Whether the rings of a split queue of `size` descriptors are laid out
+    /// as the legacy interface would have them.
+    pub fn is_legacy(&self, size: u16) -> bool {
+        self.desc_addr & (LEGACY_QALIGN - 1) == 0
+            && *self == Self::legacy(self.desc_addr, size)
+    }
+}
 
 pub struct VirtQueues {
     queues: Vec<Arc<VirtQueue>>,
@@ -615,10 +1301,376 @@ impl<S: SliceIndex<[Arc<VirtQueue>]>> Index<S> for VirtQueues {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::test_util::{TestInstance, READ_BASE};
+    use crate::hw::virtio::test_util::{Buf, TestPackedQueue, TestVirtQueue};
+    use crate::hw::virtio::VqIntr;
+    use std::sync::atomic::AtomicUsize;
+
+    /// Interrupt which counts the times it is raised
+    struct CountIntr(Arc<AtomicUsize>);
+    impl VirtioIntr for CountIntr {
+        fn notify(&self, _ctx: &DispCtx) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+        fn read(&self) -> VqIntr {
+            VqIntr::Pin
+        }
+    }
+    fn count_intr(vq: &VirtQueue) -> Arc<AtomicUsize> {
+        let count = Arc::new(AtomicUsize::new(0));
+        vq.set_interrupt(Box::new(CountIntr(count.clone())));
+        count
+    }
+
+    /// Pop the next chain from `vq`, which must have one available.
+    fn pop(vq: &VirtQueue, mem: &MemCtx, ctx: &DispCtx) -> (Chain, u32) {
+        let mut chain = Chain::with_capacity(vq.size as usize);
+        let len =
+            vq.pop_avail(&mut chain, mem, ctx).expect("chain is available");
+        (chain, len)
+    }
+
+    /// Write `data` to `chain` and return it to the driver.
+    fn finish(
+        vq: &VirtQueue,
+        mut chain: Chain,
+        data: &[u8],
+        mem: &MemCtx,
+        ctx: &DispCtx,
+    ) {
+        assert_eq!(chain.write_bytes(data, mem), data.len());
+        vq.push_used(&mut chain, mem, ctx);
+    }
+
+    #[test]
+    fn need_event() {
+        // An event is due once the index moves past it
+        assert!(vring_need_event(3, 4, 3));
+        assert!(vring_need_event(3, 8, 2));
+        assert!(!vring_need_event(4, 4, 3));
+        assert!(!vring_need_event(2, 4, 3));
+
+        // Nothing is due without the index moving, whatever the event
+        for event in [0, 3, 4, u16::MAX] {
+            assert!(!vring_need_event(event, 4, 4));
+        }
+        assert!(!vring_need_event(0, 0, 0));
+
+        // Indexes and events wrap around
+        let (old, new) = (u16::MAX - 1, 1);
+        for event in [u16::MAX - 1, u16::MAX, 0] {
+            assert!(vring_need_event(event, new, old));
+        }
+        for event in [1, 2, u16::MAX - 2] {
+            assert!(!vring_need_event(event, new, old));
+        }
+        assert!(vring_need_event(u16::MAX, 0, u16::MAX));
+        assert!(!vring_need_event(0, 0, u16::MAX));
+    }
+
+    #[test]
+    fn intr_needed_split() {
+        let test = TestInstance::new();
+        let vq = VirtQueue::new(0, 16);
+        let tvq = TestVirtQueue::new(&vq);
+        test.with_ctx(|ctx| {
+            let mem = ctx.mctx.memctx();
+            let needed = |old: u16, new: u16| {
+                let mut used = vq.used.lock().unwrap();
+                used.used_idx = Wrapping(new);
+                used.intr_needed(Wrapping(old), &mem)
+            };
+
+            // Without VIRTIO_F_RING_EVENT_IDX, only the avail ring flags
+            // matter
+            assert!(needed(0, 1));
+            tvq.set_avail_flags(&mem, VRING_AVAIL_F_NO_INTERRUPT);
+            assert!(!needed(0, 1));
+            tvq.set_avail_flags(&mem, 0);
+
+            // With it, the flags are ignored in favor of used_event
+            vq.set_event_idx(true);
+            tvq.set_avail_flags(&mem, VRING_AVAIL_F_NO_INTERRUPT);
+            tvq.set_used_event(&mem, 2);
+            assert!(!needed(0, 2));
+            assert!(needed(0, 3));
+            assert!(needed(2, 3));
+            assert!(!needed(3, 4));
+            assert!(!needed(3, 3));
+
+            // Including across the wrap of the used index
+            tvq.set_used_event(&mem, u16::MAX);
+            assert!(needed(u16::MAX - 1, 0));
+            assert!(needed(u16::MAX, 1));
+            assert!(!needed(0, 1));
+            assert!(!needed(u16::MAX, u16::MAX));
+            tvq.set_used_event(&mem, 0);
+            assert!(needed(u16::MAX, 1));
+            assert!(!needed(u16::MAX, 0));
+        });
+    }
+
+    #[test]
+    fn packed_chained() {
+        let test = TestInstance::new();
+        let vq = VirtQueue::new(0, 16);
+        let mut tpq = TestPackedQueue::new(&vq);
+        test.with_ctx(|ctx| {
+            let mem = ctx.mctx.memctx();
+            let id = tpq.push(
+                &mem,
+                &[
+                    Buf::Readable(b"hello"),
+                    Buf::Readable(b" world"),
+                    Buf::Writable(4),
+                    Buf::Writable(4),
+                ],
+            );
+
+            let (mut chain, len) = pop(&vq, &mem, ctx);
+            assert_eq!(len, 19);
+            assert_eq!(chain.idx, Some(id));
+            assert_eq!(chain.ring_count, 4);
+            let mut buf = [0u8; 11];
+            assert_eq!(chain.read_bytes(&mut buf, &mem), 11);
+            assert_eq!(&buf, b"hello world");
+            assert_eq!(chain.remain_read_bytes(), 0);
+            finish(&vq, chain, b"abcdefgh", &mem, ctx);
+
+            assert_eq!(tpq.next_used(&mem), Some((id, 8)));
+            assert_eq!(tpq.written(&mem, id), b"abcdefgh");
+            assert_eq!(tpq.next_used(&mem), None);
+            assert!(vq
+                .pop_avail(&mut Chain::with_capacity(16), &mem, ctx)
+                .is_none());
+        });
+    }
+
+    #[test]
+    fn packed_wrap() {
+        let test = TestInstance::new();
+        let vq = VirtQueue::new(0, 4);
+        let mut tpq = TestPackedQueue::new(&vq);
+        test.with_ctx(|ctx| {
+            let mem = ctx.mctx.memctx();
+            // Chains of varying length, so their descriptors straddle the
+            // end of the ring, over a number of laps of it in either wrap
+            // state
+            for lap in 0..12u8 {
+                let count = lap % 3 + 1;
+                let bufs: Vec<_> =
+                    (0..count).map(|_| Buf::Writable(1)).collect();
+                let id = tpq.push(&mem, &bufs);
+
+                let (chain, len) = pop(&vq, &mem, ctx);
+                assert_eq!(len, count as u32);
+                assert_eq!(chain.idx, Some(id));
+                assert_eq!(chain.ring_count, count as u16);
+                let data = vec![lap; count as usize];
+                finish(&vq, chain, &data, &mem, ctx);
+
+                assert_eq!(tpq.next_used(&mem), Some((id, count as u32)));
+                assert_eq!(tpq.written(&mem, id), data);
+                assert!(vq
+                    .pop_avail(&mut Chain::with_capacity(4), &mem, ctx)
+                    .is_none());
+            }
+
+            // With more than one chain outstanding at once
+            let first = tpq.push(&mem, &[Buf::Writable(1), Buf::Writable(1)]);
+            let second = tpq.push(&mem, &[Buf::Writable(1), Buf::Writable(1)]);
+            let (c1, _) = pop(&vq, &mem, ctx);
+            let (c2, _) = pop(&vq, &mem, ctx);
+            assert_eq!((c1.idx, c2.idx), (Some(first), Some(second)));
+            finish(&vq, c1, b"ab", &mem, ctx);
+            finish(&vq, c2, b"cd", &mem, ctx);
+            assert_eq!(tpq.next_used(&mem), Some((first, 2)));
+            assert_eq!(tpq.next_used(&mem), Some((second, 2)));
+            assert_eq!(tpq.written(&mem, second), b"cd");
+        });
+    }
+
+    #[test]
+    fn packed_indirect() {
+        let test = TestInstance::new();
+        let vq = VirtQueue::new(0, 8);
+        let mut tpq = TestPackedQueue::new(&vq);
+        test.with_ctx(|ctx| {
+            let mem = ctx.mctx.memctx();
+            let id = tpq.push_indirect(
+                &mem,
+                &[Buf::Readable(b"req"), Buf::Writable(2), Buf::Writable(4)],
+            );
+            let next = tpq.push(&mem, &[Buf::Writable(1)]);
+
+            let (mut chain, len) = pop(&vq, &mem, ctx);
+            assert_eq!(len, 9);
+            assert_eq!(chain.idx, Some(id));
+            // The indirect table takes up a single descriptor in the ring
+            assert_eq!(chain.ring_count, 1);
+            let mut buf = [0u8; 3];
+            assert_eq!(chain.read_bytes(&mut buf, &mem), 3);
+            assert_eq!(&buf, b"req");
+            finish(&vq, chain, b"resp!!", &mem, ctx);
+            assert_eq!(tpq.next_used(&mem), Some((id, 6)));
+            assert_eq!(tpq.written(&mem, id), b"resp!!");
+
+            let (chain, _) = pop(&vq, &mem, ctx);
+            assert_eq!(chain.idx, Some(next));
+            finish(&vq, chain, b"x", &mem, ctx);
+            assert_eq!(tpq.next_used(&mem), Some((next, 1)));
+        });
+    }
+
+    #[test]
+    fn packed_event_suppression() {
+        let test = TestInstance::new();
+        let vq = VirtQueue::new(0, 8);
+        let mut tpq = TestPackedQueue::new(&vq);
+        let intrs = count_intr(&vq);
+        test.with_ctx(|ctx| {
+            let mem = ctx.mctx.memctx();
+            let service = |tpq: &mut TestPackedQueue| {
+                tpq.push(&mem, &[Buf::Writable(1)]);
+                let (chain, _) = pop(&vq, &mem, ctx);
+                finish(&vq, chain, b"x", &mem, ctx);
+                intrs.load(Ordering::SeqCst)
+            };
+
+            // Descriptors 0 and 1
+            tpq.set_driver_event(&mem, RING_EVENT_FLAGS_ENABLE, 0, false);
+            assert_eq!(service(&mut tpq), 1);
+            assert_eq!(service(&mut tpq), 2);
+            // Descriptors 2 and 3
+            tpq.set_driver_event(&mem, RING_EVENT_FLAGS_DISABLE, 0, false);
+            assert_eq!(service(&mut tpq), 2);
+            assert_eq!(service(&mut tpq), 2);
+            // Without VIRTIO_F_RING_EVENT_IDX, a descriptor event is taken
+            // to enable interrupts: descriptor 4
+            tpq.set_driver_event(&mem, RING_EVENT_FLAGS_DESC, 6, true);
+            assert_eq!(service(&mut tpq), 3);
+
+            // With it, the interrupt waits on the descriptor: 5 to 7
+            vq.set_event_idx(true);
+            assert_eq!(service(&mut tpq), 3);
+            assert_eq!(service(&mut tpq), 4);
+            assert_eq!(service(&mut tpq), 4);
+            // Including for one in the next lap of the ring: 0 to 2
+            tpq.set_driver_event(&mem, RING_EVENT_FLAGS_DESC, 1, false);
+            assert_eq!(service(&mut tpq), 4);
+            assert_eq!(service(&mut tpq), 5);
+            assert_eq!(service(&mut tpq), 5);
+
+            // Finding the ring empty, the device asks to be notified of the
+            // next descriptor
+            let mut chain = Chain::with_capacity(8);
+            assert!(vq.pop_avail(&mut chain, &mem, ctx).is_none());
+            assert_eq!(
+                tpq.device_event(&mem),
+                (RING_EVENT_FLAGS_DESC, 3, false)
+            );
+        });
+    }
+
+    #[test]
+    fn packed_malformed_chain() {
+        let test = TestInstance::new();
+        let vq = VirtQueue::new(0, 8);
+        let mut tpq = TestPackedQueue::new(&vq);
+        let intrs = count_intr(&vq);
+        test.with_ctx(|ctx| {
+            let mem = ctx.mctx.memctx();
+            // An indirect table not made up of whole descriptors
+            let bad = tpq.push_descs(
+                &mem,
+                &[(READ_BASE, 7, VIRTQ_DESC_F_INDIRECT)],
+                Vec::new(),
+            );
+            let good = tpq.push(&mem, &[Buf::Writable(2)]);
+
+            // The malformed chain is consumed, rather than stalling the queue
+            // and the driver interrupted for it
+            let (chain, _) = pop(&vq, &mem, ctx);
+            assert_eq!(chain.idx, Some(good));
+            assert_eq!(tpq.next_used(&mem), Some((bad, 0)));
+            assert_eq!(intrs.load(Ordering::SeqCst), 1);
+            finish(&vq, chain, b"ok", &mem, ctx);
+            assert_eq!(tpq.next_used(&mem), Some((good, 2)));
+            assert_eq!(tpq.written(&mem, good), b"ok");
+        });
+    }
+
+    #[test]
+    fn in_order_split() {
+        let test = TestInstance::new();
+        let vq = VirtQueue::new(0, 8);
+        let mut tvq = TestVirtQueue::new(&vq);
+        let intrs = count_intr(&vq);
+        vq.set_in_order(true);
+        test.with_ctx(|ctx| {
+            let mem = ctx.mctx.memctx();
+            let heads: Vec<_> =
+                (0..3).map(|_| tvq.push(&mem, &[Buf::Writable(1)])).collect();
+            let mut chains: Vec<_> =
+                (0..3).map(|_| Some(pop(&vq, &mem, ctx).0)).collect();
+
+            // Chains are held back until those ahead of them are used
+            finish(&vq, chains[2].take().unwrap(), b"c", &mem, ctx);
+            assert_eq!(tvq.next_used(&mem), None);
+            assert_eq!(intrs.load(Ordering::SeqCst), 0);
+
+            finish(&vq, chains[0].take().unwrap(), b"a", &mem, ctx);
+            assert_eq!(tvq.next_used(&mem), Some((heads[0], 1)));
+            assert_eq!(tvq.next_used(&mem), None);
+            assert_eq!(intrs.load(Ordering::SeqCst), 1);
+
+            finish(&vq, chains[1].take().unwrap(), b"b", &mem, ctx);
+            assert_eq!(tvq.next_used(&mem), Some((heads[1], 1)));
+            assert_eq!(tvq.next_used(&mem), Some((heads[2], 1)));
+            assert_eq!(intrs.load(Ordering::SeqCst), 2);
+            assert_eq!(tvq.written(&mem, heads[2]), b"c");
+        });
+    }
+
+    #[test]
+    fn in_order_packed() {
+        let test = TestInstance::new();
+        let vq = VirtQueue::new(0, 8);
+        let mut tpq = TestPackedQueue::new(&vq);
+        vq.set_in_order(true);
+        test.with_ctx(|ctx| {
+            let mem = ctx.mctx.memctx();
+            let ids: Vec<_> = [1, 2, 1]
+                .iter()
+                .map(|n| {
+                    let bufs: Vec<_> =
+                        (0..*n).map(|_| Buf::Writable(1)).collect();
+                    tpq.push(&mem, &bufs)
+                })
+                .collect();
+            let mut chains: Vec<_> =
+                (0..3).map(|_| Some(pop(&vq, &mem, ctx).0)).collect();
+
+            finish(&vq, chains[1].take().unwrap(), b"bb", &mem, ctx);
+            finish(&vq, chains[2].take().unwrap(), b"c", &mem, ctx);
+            assert_eq!(tpq.next_used(&mem), None);
+
+            finish(&vq, chains[0].take().unwrap(), b"a", &mem, ctx);
+            assert_eq!(tpq.next_used(&mem), Some((ids[0], 1)));
+            assert_eq!(tpq.next_used(&mem), Some((ids[1], 2)));
+            assert_eq!(tpq.next_used(&mem), Some((ids[2], 1)));
+            assert_eq!(tpq.next_used(&mem), None);
+        });
+    }
+}
+
 pub mod migrate {
-    use serde::Serialize;
+    use serde::{Deserialize, Serialize};
 
-    #[derive(Serialize)]
+    #[derive(Deserialize, Serialize)]
     pub struct VirtQueueV1 {
         pub id: u16,
         pub size: u16,
@@ -631,5 +1683,11 @@ pub mod migrate {
         pub used_gpa: u64,
         pub used_valid: bool,
         pub used_idx: u16,
+
+        pub event_idx: bool,
+        pub packed: bool,
+        pub avail_wrap: bool,
+        pub used_wrap: bool,
+        pub in_order: bool,
     }
 }
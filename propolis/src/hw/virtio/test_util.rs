@@ -0,0 +1,407 @@
+//! Helpers for exercising virtio devices in tests.
+//!
+//! A [`TestVirtQueue`] plays the part of the driver for a [`VirtQueue`],
+//! laying out a split ring in the guest memory of a test instance (see
+//! [`crate::block::test_util`]): the descriptor table and avail ring where the
+//! device may only read them, and the used ring where it may write to it.
+//! A [`TestPackedQueue`] does likewise for a packed ring, placing the
+//! descriptor ring, which the device writes back to as it uses descriptors,
+//! where it may both read and write it.
+
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::sync::Arc;
+
+use super::bits::*;
+use super::queue::VirtQueue;
+use crate::block::test_util::{READ_BASE, RW_BASE, WRITE_BASE};
+use crate::common::{GuestAddr, GuestRegion};
+use crate::hw::pci;
+use crate::mmio::MmioBus;
+use crate::pio::PioBus;
+use crate::vmm::MemCtx;
+
+/// Offset of the rings within each region, with buffers allocated below it
+const RING_OFF: usize = 768 * 1024;
+
+/// Number of queues which may be laid out in guest memory at once, each in a
+/// slot of its own within the buffer and ring space
+const SLOTS: usize = 8;
+const BUF_SLOT_SZ: usize = RING_OFF / SLOTS;
+const RING_SLOT_SZ: usize = (1024 * 1024 - RING_OFF) / SLOTS;
+
+/// Buffer making up part of a chain
+pub(crate) enum Buf<'a> {
+    /// Buffer holding the provided data, for the device to read
+    Readable(&'a [u8]),
+    /// Buffer of the given length, for the device to write
+    Writable(u32),
+}
+
+/// Space for buffers in the readable and writable regions of guest memory.
+struct BufSpace {
+    /// Offsets of the next free space in each region
+    read_off: usize,
+    write_off: usize,
+    /// Offset at which the space ends
+    limit: usize,
+}
+impl BufSpace {
+    fn new(slot: usize) -> Self {
+        assert!(slot < SLOTS);
+        let off = slot * BUF_SLOT_SZ;
+        Self { read_off: off, write_off: off, limit: off + BUF_SLOT_SZ }
+    }
+
+    /// Place `buf` in guest memory, returning its address, length and
+    /// descriptor flags.  Writable buffers are noted in `writable`.
+    fn place(
+        &mut self,
+        mem: &MemCtx,
+        buf: &Buf,
+        writable: &mut Vec<GuestRegion>,
+    ) -> (GuestAddr, u32, u16) {
+        match buf {
+            Buf::Readable(data) => {
+                let addr = self.alloc_read(data.len());
+                let region = GuestRegion(addr, data.len());
+                let map = mem.direct_writable_region(&region).unwrap();
+                assert_eq!(map.write_bytes(data).unwrap(), data.len());
+                (addr, data.len() as u32, 0)
+            }
+            Buf::Writable(len) => {
+                let addr = self.alloc_write(*len as usize);
+                writable.push(GuestRegion(addr, *len as usize));
+                (addr, *len, VIRTQ_DESC_F_WRITE)
+            }
+        }
+    }
+
+    fn alloc_read(&mut self, len: usize) -> GuestAddr {
+        let addr = READ_BASE + self.read_off;
+        self.read_off += len;
+        assert!(self.read_off <= self.limit);
+        addr
+    }
+    fn alloc_write(&mut self, len: usize) -> GuestAddr {
+        let addr = WRITE_BASE + self.write_off;
+        self.write_off += len;
+        assert!(self.write_off <= self.limit);
+        addr
+    }
+}
+
+/// Attach `dev` to a PCI bus of its own, as its PCI state must be for it to
+/// register BARs (such as when importing migrated state).  The bus must be
+/// kept for as long as the device is in use.
+pub(crate) fn pci_attach(dev: Arc<dyn pci::Endpoint>) -> pci::Bus {
+    let pio = Arc::new(PioBus::new());
+    let mmio = Arc::new(MmioBus::new(u32::MAX as usize));
+    let bus = pci::Bus::new(pci::BusNum::new(0).unwrap(), &pio, &mmio);
+    bus.attach(pci::Bdf::new(0, 4, 0).unwrap(), dev, None);
+    bus
+}
+
+/// Contents of the writable buffers `regions`, one after another.
+fn contents(mem: &MemCtx, regions: &[GuestRegion]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for region in regions.iter() {
+        let mut buf = vec![0u8; region.1];
+        assert_eq!(
+            mem.direct_read_into(region.0, &mut buf, region.1),
+            Some(region.1)
+        );
+        out.extend(buf);
+    }
+    out
+}
+
+/// Driver side of a split virtqueue.
+pub(crate) struct TestVirtQueue {
+    size: u16,
+    desc: GuestAddr,
+    avail: GuestAddr,
+    used: GuestAddr,
+
+    next_desc: u16,
+    avail_idx: u16,
+    used_idx: u16,
+
+    space: BufSpace,
+    /// Writable buffers of the chains made available, by head descriptor
+    writable: HashMap<u16, Vec<GuestRegion>>,
+}
+impl TestVirtQueue {
+    /// Map `vq` to rings laid out in guest memory.
+    pub fn new(vq: &VirtQueue) -> Self {
+        Self::with_slot(vq, 0)
+    }
+
+    /// Map `vq` to rings laid out in the `slot`th of the areas of guest
+    /// memory set aside for each queue, so that several queues of a device
+    /// may be driven at once.
+    pub fn with_slot(vq: &VirtQueue, slot: usize) -> Self {
+        let size = vq.size;
+        let ring_off = RING_OFF + slot * RING_SLOT_SZ;
+        let desc = READ_BASE + ring_off;
+        let avail = desc + 16 * size as usize;
+        let used = WRITE_BASE + ring_off;
+        assert!(vq.map_split(desc.0, avail.0, used.0));
+        Self {
+            size,
+            desc,
+            avail,
+            used,
+            next_desc: 0,
+            avail_idx: 0,
+            used_idx: 0,
+            space: BufSpace::new(slot),
+            writable: HashMap::new(),
+        }
+    }
+
+    /// Make a chain of `bufs` available, returning its head descriptor.
+    pub fn push(&mut self, mem: &MemCtx, bufs: &[Buf]) -> u16 {
+        assert!(!bufs.is_empty() && bufs.len() <= self.size as usize);
+        let head = self.next_desc;
+        let mut writable = Vec::new();
+        for (i, buf) in bufs.iter().enumerate() {
+            let id = self.next_desc;
+            self.next_desc = (self.next_desc + 1) % self.size;
+
+            let (addr, len, mut flags) =
+                self.space.place(mem, buf, &mut writable);
+            if i + 1 < bufs.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            let daddr = self.desc + 16 * id as usize;
+            poke(mem, daddr, addr.0);
+            poke(mem, daddr + 8, len);
+            poke(mem, daddr + 12, flags);
+            poke(mem, daddr + 14, self.next_desc);
+        }
+        self.writable.insert(head, writable);
+
+        let slot = self.avail_idx % self.size;
+        poke(mem, self.avail + 4 + 2 * slot as usize, head);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        poke(mem, self.avail + 2, self.avail_idx);
+        head
+    }
+
+    /// Take the next chain the device has placed in the used ring, if any,
+    /// returning its head descriptor and the length written to it.
+    pub fn next_used(&mut self, mem: &MemCtx) -> Option<(u16, u32)> {
+        let idx: u16 = peek(mem, self.used + 2);
+        if idx == self.used_idx {
+            return None;
+        }
+        let slot = self.used_idx % self.size;
+        self.used_idx = self.used_idx.wrapping_add(1);
+        let entry = self.used + 4 + 8 * slot as usize;
+        let id: u32 = peek(mem, entry);
+        Some((id as u16, peek(mem, entry + 4)))
+    }
+
+    /// Contents of the writable buffers of the chain headed by `head`.
+    pub fn written(&self, mem: &MemCtx, head: u16) -> Vec<u8> {
+        contents(mem, &self.writable[&head])
+    }
+
+    /// Set the flags of the avail ring, such as VRING_AVAIL_F_NO_INTERRUPT.
+    pub fn set_avail_flags(&self, mem: &MemCtx, flags: u16) {
+        poke(mem, self.avail, flags);
+    }
+
+    /// Set the used_event field of the avail ring, asking to be interrupted
+    /// once the device has used the entry at `idx`.
+    pub fn set_used_event(&self, mem: &MemCtx, idx: u16) {
+        poke(mem, self.avail + 4 + 2 * self.size as usize, idx);
+    }
+}
+
+/// Driver side of a packed virtqueue.
+pub(crate) struct TestPackedQueue {
+    size: u16,
+    desc: GuestAddr,
+    /// Driver event suppression structure, which the device only reads
+    driver_event: GuestAddr,
+    /// Device event suppression structure, which the device writes
+    device_event: GuestAddr,
+
+    /// Index and wrap counter of the next descriptor to make available
+    avail_idx: u16,
+    avail_wrap: bool,
+    /// Index and wrap counter of the next descriptor the device is to use
+    used_idx: u16,
+    used_wrap: bool,
+    next_id: u16,
+
+    space: BufSpace,
+    /// Descriptors taken up in the ring, and writable buffers, of the chains
+    /// made available, by buffer ID
+    chains: HashMap<u16, (u16, Vec<GuestRegion>)>,
+}
+impl TestPackedQueue {
+    /// Map `vq` to a packed ring laid out in guest memory.
+    pub fn new(vq: &VirtQueue) -> Self {
+        let size = vq.size;
+        let desc = RW_BASE;
+        let device_event = WRITE_BASE + RING_OFF;
+        let driver_event = READ_BASE + RING_OFF;
+        assert!(vq.map_packed(desc.0, driver_event.0, device_event.0));
+        Self {
+            size,
+            desc,
+            driver_event,
+            device_event,
+            avail_idx: 0,
+            avail_wrap: true,
+            used_idx: 0,
+            used_wrap: true,
+            next_id: 0,
+            space: BufSpace::new(0),
+            chains: HashMap::new(),
+        }
+    }
+
+    /// Make a chain of `bufs` available, returning its buffer ID.
+    pub fn push(&mut self, mem: &MemCtx, bufs: &[Buf]) -> u16 {
+        let mut writable = Vec::new();
+        let descs: Vec<_> = bufs
+            .iter()
+            .map(|buf| self.space.place(mem, buf, &mut writable))
+            .collect();
+        self.push_descs(mem, &descs, writable)
+    }
+
+    /// Make a chain of `bufs` available through a single descriptor referring
+    /// to an indirect table of them, returning its buffer ID.
+    pub fn push_indirect(&mut self, mem: &MemCtx, bufs: &[Buf]) -> u16 {
+        let mut writable = Vec::new();
+        let table = self.space.alloc_read(16 * bufs.len());
+        for (i, buf) in bufs.iter().enumerate() {
+            let (addr, len, flags) = self.space.place(mem, buf, &mut writable);
+            write_packed_desc(mem, table + 16 * i, addr, len, 0, flags);
+        }
+        let len = 16 * bufs.len() as u32;
+        self.push_descs(mem, &[(table, len, VIRTQ_DESC_F_INDIRECT)], writable)
+    }
+
+    /// Make a chain of the descriptors `descs`, given as their address, length
+    /// and flags (less NEXT), available, returning its buffer ID.
+    ///
+    /// The chain is noted as having the writable buffers `writable`.
+    pub fn push_descs(
+        &mut self,
+        mem: &MemCtx,
+        descs: &[(GuestAddr, u32, u16)],
+        writable: Vec<GuestRegion>,
+    ) -> u16 {
+        assert!(!descs.is_empty() && descs.len() <= self.size as usize);
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let first = self.desc + 16 * self.avail_idx as usize;
+        let mut first_flags = 0;
+        for (i, (addr, len, mut flags)) in descs.iter().copied().enumerate() {
+            if i + 1 < descs.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            flags |= match self.avail_wrap {
+                true => VIRTQ_DESC_F_AVAIL,
+                false => VIRTQ_DESC_F_USED,
+            };
+            // The first descriptor is made available only once the rest of
+            // the chain is in place
+            let daddr = self.desc + 16 * self.avail_idx as usize;
+            if i == 0 {
+                first_flags = flags;
+                write_packed_desc(mem, daddr, addr, len, id, 0);
+            } else {
+                write_packed_desc(mem, daddr, addr, len, id, flags);
+            }
+
+            self.avail_idx += 1;
+            if self.avail_idx == self.size {
+                self.avail_idx = 0;
+                self.avail_wrap = !self.avail_wrap;
+            }
+        }
+        poke(mem, first + 14, first_flags);
+
+        self.chains.insert(id, (descs.len() as u16, writable));
+        id
+    }
+
+    /// Take the next chain the device has marked as used, if any, returning
+    /// its buffer ID and the length written to it.
+    pub fn next_used(&mut self, mem: &MemCtx) -> Option<(u16, u32)> {
+        let daddr = self.desc + 16 * self.used_idx as usize;
+        let flags: u16 = peek(mem, daddr + 14);
+        let avail = flags & VIRTQ_DESC_F_AVAIL != 0;
+        let used = flags & VIRTQ_DESC_F_USED != 0;
+        if avail != self.used_wrap || used != self.used_wrap {
+            return None;
+        }
+        let id: u16 = peek(mem, daddr + 12);
+        let len: u32 = peek(mem, daddr + 8);
+
+        self.used_idx += self.chains[&id].0;
+        if self.used_idx >= self.size {
+            self.used_idx -= self.size;
+            self.used_wrap = !self.used_wrap;
+        }
+        Some((id, len))
+    }
+
+    /// Contents of the writable buffers of the chain with buffer ID `id`.
+    pub fn written(&self, mem: &MemCtx, id: u16) -> Vec<u8> {
+        contents(mem, &self.chains[&id].1)
+    }
+
+    /// Set the driver event suppression structure, asking to be interrupted
+    /// as `flags` dictates: for RING_EVENT_FLAGS_DESC, once the device has
+    /// used the descriptor at `idx` in the lap of the ring of `wrap`.
+    pub fn set_driver_event(
+        &self,
+        mem: &MemCtx,
+        flags: u16,
+        idx: u16,
+        wrap: bool,
+    ) {
+        poke(mem, self.driver_event, idx | (wrap as u16) << 15);
+        poke(mem, self.driver_event + 2, flags);
+    }
+
+    /// The device event suppression structure, as its flags, descriptor
+    /// index and wrap counter.
+    pub fn device_event(&self, mem: &MemCtx) -> (u16, u16, bool) {
+        let off_wrap: u16 = peek(mem, self.device_event);
+        let flags: u16 = peek(mem, self.device_event + 2);
+        (flags, off_wrap & 0x7fff, off_wrap >> 15 != 0)
+    }
+}
+
+fn write_packed_desc(
+    mem: &MemCtx,
+    daddr: GuestAddr,
+    addr: GuestAddr,
+    len: u32,
+    id: u16,
+    flags: u16,
+) {
+    poke(mem, daddr, addr.0);
+    poke(mem, daddr + 8, len);
+    poke(mem, daddr + 12, id);
+    poke(mem, daddr + 14, flags);
+}
+
+fn poke<T: Copy>(mem: &MemCtx, addr: GuestAddr, val: T) {
+    let region = GuestRegion(addr, size_of::<T>());
+    mem.direct_writable_region(&region).unwrap().write(&val).unwrap();
+}
+fn peek<T: Copy>(mem: &MemCtx, addr: GuestAddr) -> T {
+    let region = GuestRegion(addr, size_of::<T>());
+    mem.direct_readable_region(&region).unwrap().read().unwrap()
+}
@@ -17,7 +17,7 @@ use crate::vmm::VmmHdl;
 
 use super::bits::*;
 use super::pci::{PciVirtio, PciVirtioState};
-use super::queue::{VirtQueue, VirtQueues};
+use super::queue::{MapInfo, VirtQueue, VirtQueues};
 use super::{VirtioDevice, VqChange, VqIntr};
 
 use erased_serde::Serialize;
@@ -75,6 +75,7 @@ impl PciVirtioViona {
             VIRTIO_DEV_NET,
             pci::bits::CLASS_NETWORK,
             VIRTIO_NET_CFG_SIZE,
+            true,
         );
 
         Ok(Arc::new_cyclic(|me| {
@@ -137,7 +138,7 @@ impl VirtioDevice for PciVirtioViona {
             }
         });
     }
-    fn get_features(&self) -> u32 {
+    fn get_features(&self) -> u64 {
         let mut feat = VIRTIO_NET_F_MAC;
         // We drop the "VIRTIO_NET_F_MTU" flag from feat if we are unable to
         // query it. This can happen when executing within a non-global Zone.
@@ -146,13 +147,31 @@ impl VirtioDevice for PciVirtioViona {
         if self.mtu.is_some() {
             feat |= VIRTIO_NET_F_MTU;
         }
-        feat |= self.dev_features;
+        feat |= self.dev_features as u64;
 
         feat
     }
-    fn set_features(&self, feat: u32) {
+    fn transport_features(&self, feat: u64) -> u64 {
+        // The in-kernel emulation only handles split rings, and makes no
+        // promise of using them in order
+        let mut strip = VIRTIO_F_RING_PACKED | VIRTIO_F_IN_ORDER;
+        if !self.hdl.modern {
+            // Nor can it place rings anywhere other than where the legacy
+            // interface would, without support for the modern ring init.
+            strip |= VIRTIO_F_VERSION_1;
+        }
+        feat & !strip
+    }
+    fn features_ok(&self, feat: u64) -> bool {
+        // The virtio-net header always includes `num_buffers` under
+        // VIRTIO_F_VERSION_1, while the in-kernel emulation only expects it
+        // with mergeable receive buffers.
+        feat & VIRTIO_F_VERSION_1 == 0 || feat & VIRTIO_NET_F_MGR_RXBUF != 0
+    }
+    fn set_features(&self, feat: u64) {
+        // The in-kernel emulation only knows of the legacy (32-bit) features
         self.hdl
-            .set_features(feat)
+            .set_features(feat as u32)
             .unwrap_or_else(|_| todo!("viona error handling"));
     }
 
@@ -166,20 +185,19 @@ impl VirtioDevice for PciVirtioViona {
         vq: &Arc<VirtQueue>,
         change: VqChange,
         _ctx: &DispCtx,
-    ) {
-        match change {
-            VqChange::Reset => {
-                self.hdl
-                    .ring_reset(vq.id)
-                    .unwrap_or_else(|_| todo!("viona error handling"));
-            }
-            VqChange::Address => {
-                if let Some(info) = vq.map_info() {
-                    self.hdl
-                        .ring_init(vq.id, vq.size, info.desc_addr)
-                        .unwrap_or_else(|_| todo!("viona error handling"));
+    ) -> std::result::Result<(), ()> {
+        let res = match change {
+            VqChange::Reset => self.hdl.ring_reset(vq.id),
+            VqChange::Address => match vq.map_info() {
+                // Rings laid out as the legacy interface requires can be
+                // described by a single address, while those placed through
+                // the modern interface may be anywhere.
+                Some(info) if info.is_legacy(vq.size) => {
+                    self.hdl.ring_init(vq.id, vq.size, info.desc_addr)
                 }
-            }
+                Some(info) => self.hdl.ring_init_modern(vq.id, vq.size, &info),
+                None => Ok(()),
+            },
             VqChange::IntrCfg => {
                 let mut addr = 0;
                 let mut msg = 0;
@@ -198,11 +216,12 @@ impl VirtioDevice for PciVirtioViona {
                         }
                     }
                 });
-                self.hdl
-                    .ring_cfg_msi(vq.id, addr, msg)
-                    .unwrap_or_else(|_| todo!("viona error handling"));
+                self.hdl.ring_cfg_msi(vq.id, addr, msg)
             }
-        }
+        };
+        // Left to the driver to reset the device, rather than bringing down
+        // the instance
+        res.map_err(|_| ())
     }
 }
 impl Entity for PciVirtioViona {
@@ -292,6 +311,9 @@ lazy_static! {
 
 struct VionaHdl {
     fp: File,
+    /// Whether the kernel supports VNA_IOC_RING_INIT_MODERN, for rings laid
+    /// out other than as the legacy interface requires
+    modern: bool,
 }
 impl VionaHdl {
     fn new(link_id: u32, vm_fd: RawFd) -> Result<Self> {
@@ -303,7 +325,20 @@ impl VionaHdl {
         let mut vna_create =
             viona_api::vioc_create { c_linkid: link_id, c_vmfd: vm_fd };
         sys::ioctl(fp.as_raw_fd(), viona_api::VNA_IOC_CREATE, &mut vna_create)?;
-        Ok(Self { fp })
+
+        let mut this = Self { fp, modern: false };
+        this.modern = this.probe_modern();
+        Ok(this)
+    }
+    /// Check for support of VNA_IOC_RING_INIT_MODERN by issuing it for a ring
+    /// which does not exist: a kernel knowing of the ioctl refuses that with
+    /// EINVAL, while others refuse the ioctl itself with ENOTTY.
+    fn probe_modern(&self) -> bool {
+        let info = MapInfo { desc_addr: 0, avail_addr: 0, used_addr: 0 };
+        match self.ring_init_modern(viona_api::VIONA_VQ_MAX, 0, &info) {
+            Ok(()) => true,
+            Err(e) => e.raw_os_error() == Some(libc::EINVAL),
+        }
     }
     fn fd(&self) -> RawFd {
         self.fp.as_raw_fd()
@@ -332,6 +367,27 @@ impl VionaHdl {
         )?;
         Ok(())
     }
+    fn ring_init_modern(
+        &self,
+        idx: u16,
+        size: u16,
+        info: &MapInfo,
+    ) -> Result<()> {
+        let mut vna_ring_init = viona_api::vioc_ring_init_modern {
+            rim_index: idx,
+            rim_qsize: size,
+            _pad: [0; 2],
+            rim_qaddr_desc: info.desc_addr,
+            rim_qaddr_avail: info.avail_addr,
+            rim_qaddr_used: info.used_addr,
+        };
+        sys::ioctl(
+            self.fd(),
+            viona_api::VNA_IOC_RING_INIT_MODERN,
+            &mut vna_ring_init,
+        )?;
+        Ok(())
+    }
     fn ring_reset(&self, idx: u16) -> Result<()> {
         sys::ioctl_usize(
             self.fd(),
@@ -279,6 +279,11 @@ impl Instance {
         self.disp.async_ctx()
     }
 
+    /// Dispatcher used to run emulation on behalf of the instance
+    pub fn disp(&self) -> &Dispatcher {
+        &self.disp
+    }
+
     pub fn logger(&self) -> &slog::Logger {
         &self.logger
     }
@@ -19,6 +19,10 @@ pub enum MigrateStateError {
     /// The device doesn't implement [`Migrate::import`].
     #[error("device state importation unimplemented for `{0}`")]
     ImportUnimplmented(String),
+
+    /// The deserialized device state couldn't be applied to the device.
+    #[error("couldn't import device state: {0}")]
+    ImportFailed(String),
 }
 
 impl From<erased_serde::Error> for MigrateStateError {
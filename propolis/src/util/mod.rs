@@ -1,3 +1,4 @@
 pub mod aspace;
 pub mod regmap;
 pub mod sys;
+pub mod token_bucket;
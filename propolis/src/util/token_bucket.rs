@@ -0,0 +1,120 @@
+//! Token buckets, for limiting the rate at which something is consumed.
+
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, Instant};
+
+/// A bucket of tokens, refilled at a fixed rate up to its capacity.
+///
+/// Amounts larger than the capacity are admitted from a full bucket, leaving
+/// it in debt until they are repaid by refilling.
+pub struct TokenBucket {
+    /// Tokens added per second
+    rate: f64,
+    /// Maximum tokens which may accumulate: the burst allowance
+    capacity: f64,
+    /// Currently available tokens.  This may go negative when an amount larger
+    /// than the capacity is taken.
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket refilled at `rate` tokens per second, holding at
+    /// most `burst` tokens (or one second's worth of them).
+    ///
+    /// Neither the rate nor the burst may be zero.
+    pub fn new(rate: u64, burst: Option<u64>, now: Instant) -> Result<Self> {
+        if rate == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "token bucket rate must be non-zero",
+            ));
+        }
+        let capacity = burst.unwrap_or(rate) as f64;
+        if capacity == 0.0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "token bucket burst must be non-zero",
+            ));
+        }
+        Ok(Self { rate: rate as f64, capacity, tokens: capacity, last: now })
+    }
+
+    /// Add the tokens accrued since the bucket was last refilled.
+    pub fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate)
+            .min(self.capacity);
+        self.last = now;
+    }
+
+    /// Time until `amount` tokens are available.
+    ///
+    /// Amounts larger than the bucket capacity need only wait for the bucket
+    /// to fill completely.
+    pub fn delay(&self, amount: f64) -> Duration {
+        let needed = amount.min(self.capacity);
+        if self.tokens >= needed {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((needed - self.tokens) / self.rate)
+        }
+    }
+
+    /// Remove `amount` tokens, whether or not they are available.
+    pub fn take(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+
+    /// Attempt to take `amount` tokens, refilling the bucket first.
+    ///
+    /// Returns `None` if they were taken, or the time to wait before trying
+    /// again.
+    pub fn try_take(&mut self, amount: f64, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        let delay = self.delay(amount);
+        if delay > Duration::ZERO {
+            return Some(delay);
+        }
+        self.take(amount);
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn burst_then_refill() {
+        let now = Instant::now();
+        let mut b = TokenBucket::new(1000, Some(500), now).unwrap();
+        assert_eq!(b.try_take(500.0, now), None);
+        let delay = b.try_take(100.0, now).expect("bucket should be empty");
+        assert_eq!(delay, Duration::from_millis(100));
+
+        // Tokens accrue at the rate, but not beyond the capacity
+        assert_eq!(b.try_take(100.0, now + delay), None);
+        let later = now + Duration::from_secs(10);
+        assert_eq!(b.try_take(500.0, later), None);
+        assert!(b.try_take(1.0, later).is_some());
+    }
+
+    #[test]
+    fn failed_take_leaves_tokens() {
+        let now = Instant::now();
+        let mut b = TokenBucket::new(10, None, now).unwrap();
+        assert_eq!(b.try_take(6.0, now), None);
+        assert!(b.try_take(6.0, now).is_some());
+        assert_eq!(b.try_take(4.0, now), None);
+    }
+
+    #[test]
+    fn zero_rate_or_burst_rejected() {
+        let now = Instant::now();
+        for (rate, burst) in [(0, None), (0, Some(10)), (10, Some(0))] {
+            let err = TokenBucket::new(rate, burst, now).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+    }
+}
@@ -410,9 +410,9 @@ impl VmmHdl {
     /// it will not be backed by any real vmm reousrces.
     pub(crate) fn new_test() -> Result<Self> {
         use tempfile::tempfile;
-        // Create a 2M temp file to use as our VM "memory"
+        // Create a 3M temp file to use as our VM "memory"
         let fp = tempfile()?;
-        fp.set_len(2 * 1024 * 1024).unwrap();
+        fp.set_len(3 * 1024 * 1024).unwrap();
         Ok(Self {
             inner: VmmFile(fp),
             destroyed: AtomicBool::new(false),
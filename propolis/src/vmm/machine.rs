@@ -122,52 +122,40 @@ impl Machine {
         // TODO: meaningfully populate these
         let guard_space = GuardSpace::new(crate::common::PAGE_SIZE)?;
         let mut map = ASpace::new(0, MAX_PHYSMEM);
-        map.register(
-            0,
-            1024 * 1024,
-            MapEnt {
-                kind: MapKind::SysMem(0, Prot::READ),
-                name: "test-readable".to_string(),
-                guest_map: Some(Mapping::new(
-                    1024 * 1024,
-                    Prot::READ,
-                    &hdl.inner,
-                    0,
-                )?),
-                seg_map: Some(Mapping::new(
-                    1024 * 1024,
-                    Prot::READ | Prot::WRITE,
-                    &hdl.inner,
-                    0,
-                )?),
-            },
-        )
-        .map_err(|e| {
-            std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", e))
-        })?;
-        map.register(
-            1024 * 1024,
-            1024 * 1024,
-            MapEnt {
-                kind: MapKind::SysMem(0, Prot::WRITE),
-                name: "test-writable".to_string(),
-                guest_map: Some(Mapping::new(
-                    1024 * 1024,
-                    Prot::WRITE,
-                    &hdl.inner,
-                    1024 * 1024,
-                )?),
-                seg_map: Some(Mapping::new(
-                    1024 * 1024,
-                    Prot::READ | Prot::WRITE,
-                    &hdl.inner,
-                    1024 * 1024,
-                )?),
-            },
-        )
-        .map_err(|e| {
-            std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", e))
-        })?;
+        let regions = [
+            ("test-readable", Prot::READ),
+            ("test-writable", Prot::WRITE),
+            ("test-readwrite", Prot::READ | Prot::WRITE),
+        ];
+        for (i, (name, prot)) in regions.iter().enumerate() {
+            let start = i * 1024 * 1024;
+            map.register(
+                start,
+                1024 * 1024,
+                MapEnt {
+                    kind: MapKind::SysMem(0, *prot),
+                    name: name.to_string(),
+                    guest_map: Some(Mapping::new(
+                        1024 * 1024,
+                        *prot,
+                        &hdl.inner,
+                        start as i64,
+                    )?),
+                    seg_map: Some(Mapping::new(
+                        1024 * 1024,
+                        Prot::READ | Prot::WRITE,
+                        &hdl.inner,
+                        start as i64,
+                    )?),
+                },
+            )
+            .map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("{:?}", e),
+                )
+            })?;
+        }
 
         Ok(Arc::new(Machine {
             hdl: Arc::new(hdl),
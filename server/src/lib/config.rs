@@ -1,16 +1,19 @@
 //! Describes a server config which may be parsed from a TOML file.
 
 use std::collections::{btree_map, BTreeMap};
-use std::num::NonZeroUsize;
+use std::convert::TryFrom;
+use std::num::{NonZeroU64, NonZeroUsize};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use serde_derive::{Deserialize, Serialize};
 use thiserror::Error;
 
 use propolis::block;
 use propolis::dispatch::Dispatcher;
+use propolis::hw::nvme;
 use propolis::inventory;
 
 /// Errors which may be returned when parsing the server configuration.
@@ -27,6 +30,12 @@ pub enum ParseError {
 
     #[error("Could not unmarshall {0} with function {1}")]
     AsError(String, String),
+
+    #[error("Key {1} in {0} must list a value for each block device")]
+    ValueCountMismatch(String, String),
+
+    #[error("Key {1} in {0} must list at least one value")]
+    EmptyList(String, String),
 }
 
 /// Configuration for the Propolis server.
@@ -41,6 +50,11 @@ pub struct Config {
 
     #[serde(default, rename = "block_dev")]
     block_devs: BTreeMap<String, BlockDevice>,
+
+    /// Directory from which media may be inserted into read-only disks while
+    /// the instance runs.  Media changes are refused if this is not set.
+    #[serde(default)]
+    media_dir: Option<PathBuf>,
 }
 
 impl Config {
@@ -54,13 +68,17 @@ impl Config {
         devices: BTreeMap<String, Device>,
         block_devs: BTreeMap<String, BlockDevice>,
     ) -> Config {
-        Config { bootrom: bootrom.into(), devices, block_devs }
+        Config { bootrom: bootrom.into(), devices, block_devs, media_dir: None }
     }
 
     pub fn get_bootrom(&self) -> &Path {
         &self.bootrom
     }
 
+    pub fn get_media_dir(&self) -> Option<&Path> {
+        self.media_dir.as_deref()
+    }
+
     pub fn devs(&self) -> IterDevs {
         IterDevs { inner: self.devices.iter() }
     }
@@ -76,6 +94,46 @@ impl Config {
         })?;
         entry.create_block_backend(disp)
     }
+
+    pub fn block_throttle_limits(
+        &self,
+        name: &str,
+    ) -> Result<block::ThrottleLimits, ParseError> {
+        let entry = self.block_devs.get(name).ok_or_else(|| {
+            ParseError::KeyNotFound(name.to_string(), "block_dev".to_string())
+        })?;
+        entry.throttle_limits()
+    }
+
+    pub fn block_fault_policy(
+        &self,
+        name: &str,
+    ) -> Result<Option<block::FaultPolicy>, ParseError> {
+        let entry = self.block_devs.get(name).ok_or_else(|| {
+            ParseError::KeyNotFound(name.to_string(), "block_dev".to_string())
+        })?;
+        entry.fault_policy()
+    }
+
+    pub fn block_overlay_scratch(
+        &self,
+        name: &str,
+    ) -> Result<Option<block::OverlayScratch>, ParseError> {
+        let entry = self.block_devs.get(name).ok_or_else(|| {
+            ParseError::KeyNotFound(name.to_string(), "block_dev".to_string())
+        })?;
+        entry.overlay_scratch()
+    }
+
+    pub fn block_commit_path(
+        &self,
+        name: &str,
+    ) -> Result<Option<PathBuf>, ParseError> {
+        let entry = self.block_devs.get(name).ok_or_else(|| {
+            ParseError::KeyNotFound(name.to_string(), "block_dev".to_string())
+        })?;
+        Ok(entry.commit_path())
+    }
 }
 
 /// A hard-coded device, either enabled by default or accessible locally
@@ -96,6 +154,147 @@ impl Device {
     pub fn get<T: FromStr, S: AsRef<str>>(&self, key: S) -> Option<T> {
         self.get_string(key)?.parse().ok()
     }
+
+    /// Names of the block devices backing the device: those listed in
+    /// `block_devs`, or else the single `block_dev`.
+    pub fn block_devs(&self, name: &str) -> Result<Vec<&str>, ParseError> {
+        let as_str = |v: &'_ toml::Value| {
+            v.as_str().ok_or_else(|| {
+                ParseError::AsError(name.to_string(), "as_str".to_string())
+            })
+        };
+        match self.options.get("block_devs") {
+            Some(list) => {
+                let list = list.as_array().ok_or_else(|| {
+                    ParseError::AsError(
+                        name.to_string(),
+                        "as_array".to_string(),
+                    )
+                })?;
+                if list.is_empty() {
+                    return Err(ParseError::EmptyList(
+                        name.to_string(),
+                        "block_devs".to_string(),
+                    ));
+                }
+                list.iter().map(as_str).collect()
+            }
+            None => {
+                let dev = self.options.get("block_dev").ok_or_else(|| {
+                    ParseError::KeyNotFound(
+                        name.to_string(),
+                        "block_dev".to_string(),
+                    )
+                })?;
+                Ok(vec![as_str(dev)?])
+            }
+        }
+    }
+
+    /// Names of the ports a virtio-console device has in addition to its
+    /// console port, as listed in `ports`.
+    pub fn console_ports(&self, name: &str) -> Result<Vec<String>, ParseError> {
+        let as_error = |func: &str| {
+            ParseError::AsError(name.to_string(), func.to_string())
+        };
+        match self.options.get("ports") {
+            Some(list) => list
+                .as_array()
+                .ok_or_else(|| as_error("as_array"))?
+                .iter()
+                .map(|v| {
+                    v.as_str()
+                        .map(String::from)
+                        .ok_or_else(|| as_error("as_str"))
+                })
+                .collect(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Directory in which the sockets for the named ports of a virtio-console
+    /// device are created, as given in `socket_dir`, or else the working
+    /// directory of the server.
+    pub fn console_socket_dir(
+        &self,
+        name: &str,
+    ) -> Result<PathBuf, ParseError> {
+        match self.options.get("socket_dir") {
+            Some(dir) => dir.as_str().map(PathBuf::from).ok_or_else(|| {
+                ParseError::AsError(name.to_string(), "as_str".to_string())
+            }),
+            None => Ok(PathBuf::from(".")),
+        }
+    }
+
+    /// Limit, in bytes per second, on the random data provided by a
+    /// virtio-rng device, as given in `rate_limit`.
+    pub fn rng_rate_limit(
+        &self,
+        name: &str,
+    ) -> Result<Option<NonZeroU64>, ParseError> {
+        match self.options.get("rate_limit") {
+            None => Ok(None),
+            Some(val) => val
+                .as_integer()
+                .and_then(|v| u64::try_from(v).ok())
+                .and_then(NonZeroU64::new)
+                .map(Some)
+                .ok_or_else(|| {
+                    ParseError::AsError(
+                        name.to_string(),
+                        "as_integer".to_string(),
+                    )
+                }),
+        }
+    }
+
+    /// Identifying details configured for an NVMe device, and for each of
+    /// the namespaces backed by its `count` block devices.
+    ///
+    /// Namespace identifiers (`eui64`, `nguid`) are listed in the same order
+    /// as the block devices, though may be given as a single string for a
+    /// lone block device.
+    pub fn nvme_identity(
+        &self,
+        name: &str,
+        count: usize,
+    ) -> Result<(nvme::CtrlIdentity, Vec<nvme::NsIdentity>), ParseError> {
+        let as_string = |v: &'_ toml::Value| {
+            v.as_str().map(String::from).ok_or_else(|| {
+                ParseError::AsError(name.to_string(), "as_str".to_string())
+            })
+        };
+        let string =
+            |key: &str| self.options.get(key).map(as_string).transpose();
+        let strings = |key: &str| -> Result<Vec<Option<String>>, ParseError> {
+            let values = match self.options.get(key) {
+                Some(toml::Value::Array(list)) => list.iter().collect(),
+                Some(value) => vec![value],
+                None => return Ok(vec![None; count]),
+            };
+            if values.len() != count {
+                return Err(ParseError::ValueCountMismatch(
+                    name.to_string(),
+                    key.to_string(),
+                ));
+            }
+            values.into_iter().map(|v| as_string(v).map(Some)).collect()
+        };
+
+        let ctrl = nvme::CtrlIdentity {
+            serial_number: string("serial_number")?,
+            model_number: string("model_number")?,
+            firmware_rev: string("firmware_rev")?,
+            ieee_oui: string("ieee_oui")?,
+        };
+        let namespaces = strings("eui64")?
+            .into_iter()
+            .zip(strings("nguid")?)
+            .map(|(eui64, nguid)| nvme::NsIdentity { eui64, nguid })
+            .collect();
+        Ok((ctrl, namespaces))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -108,6 +307,131 @@ pub struct BlockDevice {
 }
 
 impl BlockDevice {
+    /// Parses the optional `iops`, `iops_burst`, `bps`, and `bps_burst` limits
+    /// for the block device.
+    pub fn throttle_limits(&self) -> Result<block::ThrottleLimits, ParseError> {
+        let get = |key: &str| -> Result<Option<u64>, ParseError> {
+            match self.options.get(key) {
+                None => Ok(None),
+                Some(val) => val
+                    .as_integer()
+                    .and_then(|v| u64::try_from(v).ok())
+                    .map(Some)
+                    .ok_or_else(|| {
+                        ParseError::AsError(
+                            key.to_string(),
+                            "as_integer".to_string(),
+                        )
+                    }),
+            }
+        };
+        Ok(block::ThrottleLimits {
+            iops: get("iops")?,
+            iops_burst: get("iops_burst")?,
+            bps: get("bps")?,
+            bps_burst: get("bps_burst")?,
+        })
+    }
+
+    /// Parses the optional `faults` table for the block device, describing
+    /// the faults to be injected into its requests.
+    pub fn fault_policy(
+        &self,
+    ) -> Result<Option<block::FaultPolicy>, ParseError> {
+        let faults = match self.options.get("faults") {
+            None => return Ok(None),
+            Some(val) => val.as_table().ok_or_else(|| {
+                ParseError::AsError(
+                    "faults".to_string(),
+                    "as_table".to_string(),
+                )
+            })?,
+        };
+        let as_error = |key: &str, func: &str| {
+            ParseError::AsError(key.to_string(), func.to_string())
+        };
+        let get_u64 = |key: &str| -> Result<Option<u64>, ParseError> {
+            match faults.get(key) {
+                None => Ok(None),
+                Some(val) => val
+                    .as_integer()
+                    .and_then(|v| u64::try_from(v).ok())
+                    .map(Some)
+                    .ok_or_else(|| as_error(key, "as_integer")),
+            }
+        };
+        let get_bool = |key: &str| -> Result<bool, ParseError> {
+            match faults.get(key) {
+                None => Ok(false),
+                Some(val) => {
+                    val.as_bool().ok_or_else(|| as_error(key, "as_bool"))
+                }
+            }
+        };
+
+        let mut fail_lba_ranges = Vec::new();
+        if let Some(val) = faults.get("fail_lba_ranges") {
+            let ranges = val
+                .as_array()
+                .ok_or_else(|| as_error("fail_lba_ranges", "as_array"))?;
+            for range in ranges {
+                let bounds = range
+                    .as_array()
+                    .filter(|b| b.len() == 2)
+                    .and_then(|b| {
+                        let start = u64::try_from(b[0].as_integer()?).ok()?;
+                        let end = u64::try_from(b[1].as_integer()?).ok()?;
+                        Some(start..end)
+                    })
+                    .ok_or_else(|| as_error("fail_lba_ranges", "as_array"))?;
+                fail_lba_ranges.push(bounds);
+            }
+        }
+
+        Ok(Some(block::FaultPolicy {
+            fail_every: get_u64("fail_every")?.and_then(NonZeroU64::new),
+            fail_lba_ranges,
+            latency: get_u64("latency_ms")?.map(Duration::from_millis),
+            unsupported: get_bool("unsupported")?,
+            read_only: get_bool("readonly")?,
+        }))
+    }
+
+    /// Parses the optional `overlay` for the block device, which keeps writes
+    /// apart from the backend in a copy-on-write overlay: either `"memory"`,
+    /// or the path of a (sparse) scratch file, created afresh.
+    pub fn overlay_scratch(
+        &self,
+    ) -> Result<Option<block::OverlayScratch>, ParseError> {
+        let overlay = match self.options.get("overlay") {
+            None => return Ok(None),
+            Some(val) => val.as_str().ok_or_else(|| {
+                ParseError::AsError("overlay".to_string(), "as_str".to_string())
+            })?,
+        };
+        match overlay {
+            "memory" => Ok(Some(block::OverlayScratch::Memory)),
+            path => {
+                let fp = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(path)?;
+                Ok(Some(block::OverlayScratch::File(fp)))
+            }
+        }
+    }
+
+    /// Path of the raw image underlying a `file` block device, into which
+    /// writes held in an overlay atop it may be committed.
+    pub fn commit_path(&self) -> Option<PathBuf> {
+        match &self.bdtype as &str {
+            "file" => Some(self.options.get("path")?.as_str()?.into()),
+            _ => None,
+        }
+    }
+
     pub fn create_block_backend(
         &self,
         _disp: &Dispatcher,
@@ -136,8 +460,50 @@ impl BlockDevice {
                     self.options.get("readonly")?.as_str()?.parse().ok()
                 }()
                 .unwrap_or(false);
+                let block_size = match self.options.get("block_size") {
+                    None => 512,
+                    Some(val) => val
+                        .as_integer()
+                        .and_then(|v| usize::try_from(v).ok())
+                        .ok_or_else(|| {
+                            ParseError::AsError(
+                                "block_size".to_string(),
+                                "as_integer".to_string(),
+                            )
+                        })?,
+                };
                 let nworkers = NonZeroUsize::new(8).unwrap();
                 let be = propolis::block::FileBackend::create(
+                    path, readonly, block_size, nworkers,
+                )?;
+                let child = inventory::ChildRegister::new(&be, None);
+
+                Ok((be, child))
+            }
+            "qcow2" => {
+                let path = self
+                    .options
+                    .get("path")
+                    .ok_or_else(|| {
+                        ParseError::KeyNotFound(
+                            "path".to_string(),
+                            "options".to_string(),
+                        )
+                    })?
+                    .as_str()
+                    .ok_or_else(|| {
+                        ParseError::AsError(
+                            "path".to_string(),
+                            "as_str".to_string(),
+                        )
+                    })?;
+
+                let readonly: bool = || -> Option<bool> {
+                    self.options.get("readonly")?.as_str()?.parse().ok()
+                }()
+                .unwrap_or(false);
+                let nworkers = NonZeroUsize::new(8).unwrap();
+                let be = propolis::block::Qcow2Backend::create(
                     path, readonly, nworkers,
                 )?;
                 let child = inventory::ChildRegister::new(&be, None);
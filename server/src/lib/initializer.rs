@@ -1,11 +1,12 @@
 use std::fs::File;
 use std::io::{Error, ErrorKind};
-use std::num::NonZeroUsize;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::path::Path;
 use std::sync::Arc;
 use std::time::SystemTime;
 
 use propolis::block;
-use propolis::chardev::{self, BlockingSource, Source};
+use propolis::chardev::{self, BlockingSource, Sink, Source};
 use propolis::common::PAGE_SIZE;
 use propolis::dispatch::Dispatcher;
 use propolis::hw::chipset::{i440fx::I440Fx, Chipset};
@@ -165,7 +166,7 @@ impl<'a> MachineInitializer<'a> {
     pub fn initialize_uart(
         &self,
         chipset: &RegisteredChipset,
-    ) -> Result<Serial<LpcUart>, Error> {
+    ) -> Result<Serial, Error> {
         let uarts = vec![
             (ibmpc::IRQ_COM1, ibmpc::PORT_COM1, "com1"),
             (ibmpc::IRQ_COM2, ibmpc::PORT_COM2, "com2"),
@@ -215,35 +216,102 @@ impl<'a> MachineInitializer<'a> {
         bdf: pci::Bdf,
         backend: Arc<dyn block::Backend>,
         be_register: ChildRegister,
-    ) -> Result<(), Error> {
+    ) -> Result<Arc<dyn block::Device>, Error> {
         let be_info = backend.info();
         let vioblk = virtio::PciVirtioBlock::new(0x100, be_info);
         let id = self.inv.register_instance(&vioblk, bdf.to_string())?;
         let _ = self.inv.register_child(be_register, id).unwrap();
 
         backend.attach(vioblk.clone(), self.disp)?;
-        chipset.device().pci_attach(bdf, vioblk);
+        chipset.device().pci_attach(bdf, vioblk.clone());
+
+        Ok(vioblk)
+    }
+
+    /// Creates a virtio-console device with a named port for each of
+    /// `port_names` (in addition to its console port), returning a serial
+    /// connection to the console port.
+    ///
+    /// Each named port is reachable through a socket in `socket_dir`, named
+    /// for the device (`name`) and the port.
+    pub fn initialize_virtio_console(
+        &self,
+        chipset: &RegisteredChipset,
+        bdf: pci::Bdf,
+        name: &str,
+        port_names: Vec<String>,
+        socket_dir: &Path,
+    ) -> Result<Serial, Error> {
+        let console = virtio::PciVirtioConsole::new(0x100, port_names)?;
+        self.inv.register_instance(&console, bdf.to_string())?;
+
+        for port in console.ports().iter().filter(|p| p.name().is_some()) {
+            let path =
+                socket_dir.join(format!("{}.{}", name, port.name().unwrap()));
+            let sock = chardev::UDSock::bind(&path)?;
+            sock.spawn(
+                Arc::clone(port) as Arc<dyn Sink>,
+                Arc::clone(port) as Arc<dyn Source>,
+                self.disp,
+            );
+        }
 
+        let port = Arc::clone(console.console());
+        chipset.device().pci_attach(bdf, console);
+
+        let sink_size = NonZeroUsize::new(64).unwrap();
+        let source_size = NonZeroUsize::new(1024).unwrap();
+        Ok(Serial::new(port, sink_size, source_size))
+    }
+
+    /// Creates a virtio-rng device, optionally limited to providing
+    /// `rate_limit` bytes of random data per second.
+    pub fn initialize_virtio_rng(
+        &self,
+        chipset: &RegisteredChipset,
+        bdf: pci::Bdf,
+        rate_limit: Option<NonZeroU64>,
+    ) -> Result<(), Error> {
+        let rng = virtio::PciVirtioRng::new(0x100, rate_limit)?;
+        self.inv.register_instance(&rng, bdf.to_string())?;
+        chipset.device().pci_attach(bdf, rng);
         Ok(())
     }
 
+    /// Creates an NVMe controller with a namespace for each of the given
+    /// backends, returning the namespaces in the same order.
     pub fn initialize_nvme_block(
         &self,
         chipset: &RegisteredChipset,
         bdf: pci::Bdf,
         name: String,
-        backend: Arc<dyn block::Backend>,
-        be_register: ChildRegister,
-    ) -> Result<(), Error> {
-        let be_info = backend.info();
-        let nvme = nvme::PciNvme::create(0x1de, 0x1000, name, be_info);
+        version: nvme::NvmeVersion,
+        ident: &nvme::CtrlIdentity,
+        backends: Vec<(
+            Arc<dyn block::Backend>,
+            ChildRegister,
+            nvme::NsIdentity,
+        )>,
+    ) -> Result<Vec<Arc<dyn block::Device>>, Error> {
+        let nvme = nvme::PciNvme::create(0x1de, 0x1000, name, version, ident)
+            .map_err(|e| {
+            Error::new(ErrorKind::InvalidInput, e.to_string())
+        })?;
         let id = self.inv.register_instance(&nvme, bdf.to_string())?;
-        let _ = self.inv.register_child(be_register, id).unwrap();
 
-        backend.attach(nvme.clone(), self.disp)?;
+        let mut namespaces = Vec::with_capacity(backends.len());
+        for (backend, be_register, ns_ident) in backends {
+            let ns = nvme.add_ns(backend.info(), &ns_ident).map_err(|e| {
+                Error::new(ErrorKind::InvalidInput, e.to_string())
+            })?;
+            let _ = self.inv.register_child(be_register, id).unwrap();
+
+            backend.attach(ns.clone(), self.disp)?;
+            namespaces.push(ns as Arc<dyn block::Device>);
+        }
         chipset.device().pci_attach(bdf, nvme);
 
-        Ok(())
+        Ok(namespaces)
     }
 
     pub fn initialize_vnic(
@@ -264,7 +332,7 @@ impl<'a> MachineInitializer<'a> {
         chipset: &RegisteredChipset,
         disk: &propolis_client::api::DiskRequest,
         bdf: pci::Bdf,
-    ) -> Result<(), Error> {
+    ) -> Result<(Arc<dyn block::Backend>, Arc<dyn block::Device>), Error> {
         info!(self.log, "Creating Crucible disk from {:#?}", disk);
         let be = propolis::block::CrucibleBackend::create(
             disk.gen,
@@ -275,26 +343,55 @@ impl<'a> MachineInitializer<'a> {
         info!(self.log, "Creating ChildRegister");
         let creg = ChildRegister::new(&be, None);
 
-        match disk.device.as_ref() {
+        let backend = Arc::clone(&be) as Arc<dyn block::Backend>;
+        let device = match disk.device.as_ref() {
             "virtio" => {
                 info!(self.log, "Calling initialize_virtio_block");
-                self.initialize_virtio_block(chipset, bdf, be, creg)
+                self.initialize_virtio_block(chipset, bdf, be, creg)?
             }
             "nvme" => {
+                let ident = disk.nvme_identity.clone().unwrap_or_default();
+                let ctrl_ident = nvme::CtrlIdentity {
+                    serial_number: ident.serial_number,
+                    model_number: ident.model_number,
+                    firmware_rev: ident.firmware_rev,
+                    ieee_oui: ident.ieee_oui,
+                };
+                let ns_ident =
+                    nvme::NsIdentity { eui64: ident.eui64, nguid: ident.nguid };
+
+                let version = match disk.nvme_version {
+                    Some(propolis_client::api::NvmeVersion::V1_0) => {
+                        nvme::NvmeVersion::V1_0
+                    }
+                    Some(propolis_client::api::NvmeVersion::V1_3) => {
+                        nvme::NvmeVersion::V1_3
+                    }
+                    Some(propolis_client::api::NvmeVersion::V1_4) => {
+                        nvme::NvmeVersion::V1_4
+                    }
+                    None => nvme::NvmeVersion::default(),
+                };
+
                 info!(self.log, "Calling initialize_nvme_block");
                 self.initialize_nvme_block(
                     chipset,
                     bdf,
                     disk.name.clone(),
-                    be,
-                    creg,
-                )
+                    version,
+                    &ctrl_ident,
+                    vec![(be, creg, ns_ident)],
+                )?
+                .remove(0)
             }
-            _ => Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Bad disk device!",
-            )),
-        }
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "Bad disk device!",
+                ))
+            }
+        };
+        Ok((backend, device))
     }
 
     pub fn initialize_in_memory_virtio_from_bytes(
@@ -312,7 +409,8 @@ impl<'a> MachineInitializer<'a> {
         let creg = ChildRegister::new(&be, None);
 
         info!(self.log, "Calling initialize_virtio_block");
-        self.initialize_virtio_block(chipset, bdf, be, creg)
+        self.initialize_virtio_block(chipset, bdf, be, creg)?;
+        Ok(())
     }
 
     pub fn initialize_fwcfg(&self, cpus: u8) -> Result<(), Error> {
@@ -6,14 +6,15 @@ use propolis::chardev::{pollers, Sink, Source};
 use propolis::dispatch::AsyncCtx;
 
 /// Represents a serial connection into the VM.
-pub struct Serial<Device: Sink + Source> {
-    uart: Arc<Device>,
+pub struct Serial {
+    sink: Arc<dyn Sink>,
+    source: Arc<dyn Source>,
 
     sink_poller: Arc<pollers::SinkBuffer>,
     source_poller: Arc<pollers::SourceBuffer>,
 }
 
-impl<Device: Sink + Source> Serial<Device> {
+impl Serial {
     /// Creates a new buffered serial connection on top of `uart.`
     ///
     /// Creation of this object disables "autodiscard", and destruction
@@ -21,14 +22,15 @@ impl<Device: Sink + Source> Serial<Device> {
     ///
     /// # Arguments
     ///
-    /// * `uart` - The device which data will be read from / written to.
+    /// * `uart` - The device (such as a UART or virtio-console port) which
+    ///   data will be read from / written to.
     /// * `sink_size` - A lower bound on the size of the writeback buffer.
     /// * `source_size` - A lower bound on the size of the read buffer.
-    pub fn new(
+    pub fn new<Device: Sink + Source>(
         uart: Arc<Device>,
         sink_size: NonZeroUsize,
         source_size: NonZeroUsize,
-    ) -> Serial<Device> {
+    ) -> Serial {
         let sink_poller = pollers::SinkBuffer::new(sink_size);
         let source_poller = pollers::SourceBuffer::new(pollers::Params {
             buf_size: source_size,
@@ -39,7 +41,7 @@ impl<Device: Sink + Source> Serial<Device> {
         source_poller.attach(uart.as_ref());
         uart.set_autodiscard(false);
 
-        Serial { uart, sink_poller, source_poller }
+        Serial { sink: uart.clone(), source: uart, sink_poller, source_poller }
     }
 
     pub async fn read_source(
@@ -47,7 +49,7 @@ impl<Device: Sink + Source> Serial<Device> {
         buf: &mut [u8],
         actx: &AsyncCtx,
     ) -> Option<usize> {
-        self.source_poller.read(buf, self.uart.as_ref(), actx).await
+        self.source_poller.read(buf, self.source.as_ref(), actx).await
     }
 
     pub async fn write_sink(
@@ -55,12 +57,12 @@ impl<Device: Sink + Source> Serial<Device> {
         buf: &[u8],
         actx: &AsyncCtx,
     ) -> Option<usize> {
-        self.sink_poller.write(buf, self.uart.as_ref(), actx).await
+        self.sink_poller.write(buf, self.sink.as_ref(), actx).await
     }
 }
 
-impl<Device: Sink + Source> Drop for Serial<Device> {
+impl Drop for Serial {
     fn drop(&mut self) {
-        self.uart.set_autodiscard(true);
+        self.source.set_autodiscard(true);
     }
 }
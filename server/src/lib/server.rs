@@ -11,9 +11,13 @@ use hyper::upgrade::{self, Upgraded};
 use hyper::{header, Body, Response, StatusCode};
 use slog::{error, info, o, Logger};
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::io::{Error, ErrorKind};
+use std::num::{NonZeroU64, NonZeroUsize};
 use std::ops::Range;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 use tokio::sync::{oneshot, watch, Mutex};
 use tokio::task::JoinHandle;
@@ -26,9 +30,10 @@ use tokio_tungstenite::WebSocketStream;
 use uuid::Uuid;
 
 use propolis::bhyve_api;
+use propolis::block;
 use propolis::dispatch::AsyncCtx;
+use propolis::hw::nvme::NvmeVersion;
 use propolis::hw::pci;
-use propolis::hw::uart::LpcUart;
 use propolis::instance::Instance;
 use propolis_client::api;
 
@@ -80,9 +85,17 @@ pub(crate) struct InstanceContext {
     // The instance, which may or may not be instantiated.
     pub instance: Arc<Instance>,
     pub properties: api::InstanceProperties,
-    serial: Option<Arc<Serial<LpcUart>>>,
+    serial: Option<Arc<Serial>>,
     state_watcher: watch::Receiver<StateChange>,
     serial_task: Option<SerialTask>,
+    // Block backends for attached disks, by name.
+    block_backends: BTreeMap<String, Arc<dyn block::Backend>>,
+    // Fault-injecting wrappers around block backends, by disk name.
+    fault_backends: BTreeMap<String, Arc<block::FaultBackend>>,
+    // Copy-on-write overlays atop block backends, by disk name.
+    overlays: BTreeMap<String, DiskOverlay>,
+    // Block devices for attached disks, by name.
+    block_devices: BTreeMap<String, Arc<dyn block::Device>>,
 }
 
 /// Contextual information accessible from HTTP callbacks.
@@ -137,6 +150,115 @@ fn propolis_to_api_state(
     }
 }
 
+fn api_to_throttle_limits(
+    throttle: &api::DiskThrottle,
+) -> block::ThrottleLimits {
+    block::ThrottleLimits {
+        iops: throttle.iops,
+        iops_burst: throttle.iops_burst,
+        bps: throttle.bps,
+        bps_burst: throttle.bps_burst,
+    }
+}
+
+fn propolis_to_api_op_stats(
+    stats: &block::OpStatsSnapshot,
+) -> api::DiskOpStats {
+    api::DiskOpStats {
+        ops: stats.ops,
+        bytes: stats.bytes,
+        errors: stats.errors,
+        latency_us: stats.latency_us.to_vec(),
+    }
+}
+
+/// Applies I/O limits to an attached block backend.
+fn set_throttle_limits(
+    backend: &Arc<dyn block::Backend>,
+    limits: block::ThrottleLimits,
+) -> Result<(), Error> {
+    let driver = backend.driver().ok_or_else(|| {
+        Error::new(ErrorKind::Other, "block backend not attached")
+    })?;
+    driver.throttle().set_limits(limits)
+}
+
+fn api_to_fault_policy(faults: &api::DiskFaults) -> block::FaultPolicy {
+    block::FaultPolicy {
+        fail_every: faults.fail_every.and_then(NonZeroU64::new),
+        fail_lba_ranges: faults
+            .fail_lba_ranges
+            .iter()
+            .map(|r| r.start..r.end)
+            .collect(),
+        latency: faults.latency_ms.map(Duration::from_millis),
+        unsupported: faults.unsupported,
+        read_only: faults.read_only,
+    }
+}
+
+/// Copy-on-write overlay atop the backend of a disk.
+struct DiskOverlay {
+    backend: Arc<block::OverlayBackend>,
+    /// Image underlying the disk, into which the overlay may be committed
+    commit_path: Option<PathBuf>,
+}
+
+/// Places a block backend beneath a [`block::OverlayBackend`] if its
+/// configuration calls for an overlay.
+fn wrap_overlay_backend(
+    config: &Config,
+    name: &str,
+    backend: Arc<dyn block::Backend>,
+    overlays: &mut BTreeMap<String, DiskOverlay>,
+) -> Result<Arc<dyn block::Backend>, Error> {
+    let scratch = config.block_overlay_scratch(name).map_err(|e| {
+        Error::new(ErrorKind::InvalidData, format!("ParseError: {:?}", e))
+    })?;
+    match scratch {
+        Some(scratch) => {
+            let overlay = block::OverlayBackend::create(
+                backend,
+                scratch,
+                NonZeroUsize::new(8).unwrap(),
+            )?;
+            let commit_path = config.block_commit_path(name).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("ParseError: {:?}", e),
+                )
+            })?;
+            overlays.insert(
+                name.to_string(),
+                DiskOverlay { backend: Arc::clone(&overlay), commit_path },
+            );
+            Ok(overlay)
+        }
+        None => Ok(backend),
+    }
+}
+
+/// Wraps a block backend in a [`block::FaultBackend`] if its configuration
+/// calls for faults to be injected.
+fn wrap_fault_backend(
+    config: &Config,
+    name: &str,
+    backend: Arc<dyn block::Backend>,
+    fault_backends: &mut BTreeMap<String, Arc<block::FaultBackend>>,
+) -> Result<Arc<dyn block::Backend>, Error> {
+    let policy = config.block_fault_policy(name).map_err(|e| {
+        Error::new(ErrorKind::InvalidData, format!("ParseError: {:?}", e))
+    })?;
+    match policy {
+        Some(policy) => {
+            let fault = block::FaultBackend::create(backend, policy)?;
+            fault_backends.insert(name.to_string(), Arc::clone(&fault));
+            Ok(fault)
+        }
+        None => Ok(backend),
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 enum SlotType {
     NIC,
@@ -266,7 +388,11 @@ async fn instance_ensure(
         let _ = tx.send(StateChange { gen: last.gen + 1, state: next_state });
     }));
 
-    let mut com1 = None;
+    let mut serial = None;
+    let mut block_backends = BTreeMap::new();
+    let mut fault_backends = BTreeMap::new();
+    let mut overlays = BTreeMap::new();
+    let mut block_devices = BTreeMap::new();
 
     // Initialize (some) of the instance's hardware.
     //
@@ -285,7 +411,7 @@ async fn instance_ensure(
             init.initialize_rom(server_context.config.get_bootrom())?;
             init.initialize_kernel_devs(lowmem, highmem)?;
             let chipset = init.initialize_chipset()?;
-            com1 = Some(Arc::new(init.initialize_uart(&chipset)?));
+            serial = Some(Arc::new(init.initialize_uart(&chipset)?));
             init.initialize_ps2(&chipset)?;
             init.initialize_qemu_debug_port()?;
 
@@ -312,7 +438,16 @@ async fn instance_ensure(
                         )
                     })?;
 
-                init.initialize_crucible(&chipset, disk, bdf)?;
+                let (backend, device) =
+                    init.initialize_crucible(&chipset, disk, bdf)?;
+                if let Some(throttle) = &disk.throttle {
+                    set_throttle_limits(
+                        &backend,
+                        api_to_throttle_limits(throttle),
+                    )?;
+                }
+                block_backends.insert(disk.name.clone(), backend);
+                block_devices.insert(disk.name.clone(), device);
                 info!(rqctx.log, "Disk {} created successfully", disk.name);
             }
 
@@ -375,6 +510,18 @@ async fn instance_ensure(
                                     format!("ParseError: {:?}", e),
                                 )
                             })?;
+                        let backend = wrap_overlay_backend(
+                            &server_context.config,
+                            block_dev_name,
+                            backend,
+                            &mut overlays,
+                        )?;
+                        let backend = wrap_fault_backend(
+                            &server_context.config,
+                            block_dev_name,
+                            backend,
+                            &mut fault_backends,
+                        )?;
 
                         let bdf: pci::Bdf =
                             dev.get("pci-path").ok_or_else(|| {
@@ -384,38 +531,31 @@ async fn instance_ensure(
                                 )
                             })?;
 
-                        init.initialize_virtio_block(
-                            &chipset, bdf, backend, creg,
-                        )?;
-                    }
-                    "pci-nvme" => {
-                        let block_dev_name = dev
-                            .options
-                            .get("block_dev")
-                            .ok_or_else(|| {
-                                Error::new(
-                                    ErrorKind::InvalidData,
-                                    format!(
-                                        "no block_dev key for {}!",
-                                        devname
-                                    ),
-                                )
-                            })?
-                            .as_str()
-                            .ok_or_else(|| {
+                        let limits = server_context
+                            .config
+                            .block_throttle_limits(block_dev_name)
+                            .map_err(|e| {
                                 Error::new(
                                     ErrorKind::InvalidData,
-                                    format!(
-                                        "as_str() failed for {}'s block_dev!",
-                                        devname
-                                    ),
+                                    format!("ParseError: {:?}", e),
                                 )
                             })?;
 
-                        let (backend, creg) = server_context
-                            .config
-                            .create_block_backend(block_dev_name, &disp)
-                            .map_err(|e| {
+                        let device = init.initialize_virtio_block(
+                            &chipset,
+                            bdf,
+                            Arc::clone(&backend),
+                            creg,
+                        )?;
+                        set_throttle_limits(&backend, limits)?;
+                        block_backends
+                            .insert(block_dev_name.to_string(), backend);
+                        block_devices
+                            .insert(block_dev_name.to_string(), device);
+                    }
+                    "pci-nvme" => {
+                        let block_dev_names =
+                            dev.block_devs(devname).map_err(|e| {
                                 Error::new(
                                     ErrorKind::InvalidData,
                                     format!("ParseError: {:?}", e),
@@ -430,13 +570,83 @@ async fn instance_ensure(
                                 )
                             })?;
 
-                        init.initialize_nvme_block(
+                        let version: NvmeVersion =
+                            match dev.get_string("nvme_version") {
+                                Some(v) => v.parse().map_err(|e| {
+                                    Error::new(
+                                        ErrorKind::InvalidData,
+                                        format!("ParseError: {:?}", e),
+                                    )
+                                })?,
+                                None => Default::default(),
+                            };
+
+                        let (ctrl_ident, ns_idents) = dev
+                            .nvme_identity(devname, block_dev_names.len())
+                            .map_err(|e| {
+                                Error::new(
+                                    ErrorKind::InvalidData,
+                                    format!("ParseError: {:?}", e),
+                                )
+                            })?;
+
+                        // Each block device backs a namespace of its own
+                        let mut backends = Vec::new();
+                        let mut regs = Vec::new();
+                        for (block_dev_name, ns_ident) in
+                            block_dev_names.iter().zip(ns_idents)
+                        {
+                            let (backend, creg) = server_context
+                                .config
+                                .create_block_backend(block_dev_name, &disp)
+                                .map_err(|e| {
+                                    Error::new(
+                                        ErrorKind::InvalidData,
+                                        format!("ParseError: {:?}", e),
+                                    )
+                                })?;
+                            let backend = wrap_overlay_backend(
+                                &server_context.config,
+                                block_dev_name,
+                                backend,
+                                &mut overlays,
+                            )?;
+                            let backend = wrap_fault_backend(
+                                &server_context.config,
+                                block_dev_name,
+                                backend,
+                                &mut fault_backends,
+                            )?;
+                            backends.push(Arc::clone(&backend));
+                            regs.push((backend, creg, ns_ident));
+                        }
+
+                        let devices = init.initialize_nvme_block(
                             &chipset,
                             bdf,
-                            block_dev_name.to_string(),
-                            backend,
-                            creg,
+                            block_dev_names[0].to_string(),
+                            version,
+                            &ctrl_ident,
+                            regs,
                         )?;
+                        for ((block_dev_name, backend), device) in
+                            block_dev_names.iter().zip(backends).zip(devices)
+                        {
+                            let limits = server_context
+                                .config
+                                .block_throttle_limits(block_dev_name)
+                                .map_err(|e| {
+                                    Error::new(
+                                        ErrorKind::InvalidData,
+                                        format!("ParseError: {:?}", e),
+                                    )
+                                })?;
+                            set_throttle_limits(&backend, limits)?;
+                            block_backends
+                                .insert(block_dev_name.to_string(), backend);
+                            block_devices
+                                .insert(block_dev_name.to_string(), device);
+                        }
                     }
                     "pci-virtio-viona" => {
                         let name = dev.get_string("vnic").ok_or_else(|| {
@@ -454,6 +664,65 @@ async fn instance_ensure(
                             })?;
                         init.initialize_vnic(&chipset, name, bdf)?;
                     }
+                    "pci-virtio-console" => {
+                        let bdf: pci::Bdf =
+                            dev.get("pci-path").ok_or_else(|| {
+                                Error::new(
+                                    ErrorKind::InvalidData,
+                                    "Cannot parse console PCI",
+                                )
+                            })?;
+                        let ports =
+                            dev.console_ports(devname).map_err(|e| {
+                                Error::new(
+                                    ErrorKind::InvalidData,
+                                    format!("ParseError: {:?}", e),
+                                )
+                            })?;
+                        let socket_dir =
+                            dev.console_socket_dir(devname).map_err(|e| {
+                                Error::new(
+                                    ErrorKind::InvalidData,
+                                    format!("ParseError: {:?}", e),
+                                )
+                            })?;
+                        let console =
+                            Arc::new(init.initialize_virtio_console(
+                                &chipset,
+                                bdf,
+                                devname,
+                                ports,
+                                &socket_dir,
+                            )?);
+
+                        // The console port may stand in for COM1 as the
+                        // instance's serial console.
+                        let as_serial = dev
+                            .options
+                            .get("serial")
+                            .and_then(|v| v.as_bool())
+                            .unwrap_or(false);
+                        if as_serial {
+                            serial = Some(console);
+                        }
+                    }
+                    "pci-virtio-rng" => {
+                        let bdf: pci::Bdf =
+                            dev.get("pci-path").ok_or_else(|| {
+                                Error::new(
+                                    ErrorKind::InvalidData,
+                                    "Cannot parse rng PCI",
+                                )
+                            })?;
+                        let rate_limit =
+                            dev.rng_rate_limit(devname).map_err(|e| {
+                                Error::new(
+                                    ErrorKind::InvalidData,
+                                    format!("ParseError: {:?}", e),
+                                )
+                            })?;
+                        init.initialize_virtio_rng(&chipset, bdf, rate_limit)?;
+                    }
                     _ => {
                         return Err(Error::new(
                             ErrorKind::InvalidData,
@@ -478,9 +747,13 @@ async fn instance_ensure(
     *context = Some(InstanceContext {
         instance: instance.clone(),
         properties,
-        serial: com1,
+        serial,
         state_watcher: rx,
         serial_task: None,
+        block_backends,
+        fault_backends,
+        overlays,
+        block_devices,
     });
     drop(context);
 
@@ -663,7 +936,7 @@ async fn instance_state_put(
 
 async fn instance_serial_task(
     mut detach: oneshot::Receiver<()>,
-    serial: Arc<Serial<LpcUart>>,
+    serial: Arc<Serial>,
     ws_stream: WebSocketStream<Upgraded>,
     log: Logger,
     actx: &AsyncCtx,
@@ -929,6 +1202,396 @@ async fn instance_serial_detach(
     Ok(HttpResponseUpdatedNoContent {})
 }
 
+#[endpoint {
+    method = GET,
+    path = "/instances/{instance_id}/disk-stats",
+}]
+async fn instance_disk_stats(
+    rqctx: Arc<RequestContext<Context>>,
+    path_params: Path<api::InstancePathParams>,
+) -> Result<HttpResponseOk<api::InstanceDiskStatsResponse>, HttpError> {
+    let context = rqctx.context().context.lock().await;
+
+    let context = context.as_ref().ok_or_else(|| {
+        HttpError::for_internal_error(
+            "Server not initialized (no instance)".to_string(),
+        )
+    })?;
+    if path_params.into_inner().instance_id != context.properties.id {
+        return Err(HttpError::for_internal_error(
+            "UUID mismatch (path did not match struct)".to_string(),
+        ));
+    }
+
+    let disks = context
+        .block_backends
+        .iter()
+        .filter_map(|(name, backend)| {
+            let stats = backend.driver()?.stats().snapshot();
+            Some(api::DiskStats {
+                name: name.clone(),
+                read: propolis_to_api_op_stats(&stats.read),
+                write: propolis_to_api_op_stats(&stats.write),
+                flush: propolis_to_api_op_stats(&stats.flush),
+                discard: propolis_to_api_op_stats(&stats.discard),
+                write_zeroes: propolis_to_api_op_stats(&stats.write_zeroes),
+                in_flight: stats.in_flight,
+            })
+        })
+        .collect();
+
+    Ok(HttpResponseOk(api::InstanceDiskStatsResponse { disks }))
+}
+
+#[endpoint {
+    method = PUT,
+    path = "/instances/{instance_id}/disks/{name}/throttle",
+}]
+async fn instance_disk_throttle_put(
+    rqctx: Arc<RequestContext<Context>>,
+    path_params: Path<api::InstanceDiskPathParams>,
+    request: TypedBody<api::DiskThrottleRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let context = rqctx.context().context.lock().await;
+
+    let context = context.as_ref().ok_or_else(|| {
+        HttpError::for_internal_error(
+            "Server not initialized (no instance)".to_string(),
+        )
+    })?;
+    let path_params = path_params.into_inner();
+    if path_params.instance_id != context.properties.id {
+        return Err(HttpError::for_internal_error(
+            "UUID mismatch (path did not match struct)".to_string(),
+        ));
+    }
+
+    let request = request.into_inner();
+    let name = path_params.name;
+    let backend = context.block_backends.get(&name).ok_or_else(|| {
+        HttpError::for_not_found(None, format!("no such disk: {}", name))
+    })?;
+    set_throttle_limits(backend, api_to_throttle_limits(&request.throttle))
+        .map_err(|e| match e.kind() {
+            ErrorKind::InvalidInput => {
+                HttpError::for_bad_request(None, e.to_string())
+            }
+            _ => HttpError::for_internal_error(e.to_string()),
+        })?;
+
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+#[endpoint {
+    method = PUT,
+    path = "/instances/{instance_id}/disks/{name}/faults",
+}]
+async fn instance_disk_faults_put(
+    rqctx: Arc<RequestContext<Context>>,
+    path_params: Path<api::InstanceDiskPathParams>,
+    request: TypedBody<api::DiskFaultsRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let context = rqctx.context().context.lock().await;
+
+    let context = context.as_ref().ok_or_else(|| {
+        HttpError::for_internal_error(
+            "Server not initialized (no instance)".to_string(),
+        )
+    })?;
+    let path_params = path_params.into_inner();
+    if path_params.instance_id != context.properties.id {
+        return Err(HttpError::for_internal_error(
+            "UUID mismatch (path did not match struct)".to_string(),
+        ));
+    }
+
+    let request = request.into_inner();
+    let name = path_params.name;
+    let fault = context.fault_backends.get(&name).ok_or_else(|| {
+        HttpError::for_not_found(
+            None,
+            format!("no fault injection for disk: {}", name),
+        )
+    })?;
+    fault.set_policy(api_to_fault_policy(&request.faults));
+
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Resolve the path of media to be inserted into a disk, which must name a
+/// regular file within the configured media directory.
+fn resolve_media_path(
+    media_dir: Option<&std::path::Path>,
+    path: &str,
+) -> Result<PathBuf, HttpError> {
+    let media_dir = media_dir.ok_or_else(|| {
+        HttpError::for_bad_request(
+            None,
+            "media changes require a configured media directory".to_string(),
+        )
+    })?;
+    let media_dir = media_dir.canonicalize().map_err(|e| {
+        HttpError::for_internal_error(format!(
+            "bad media directory {}: {}",
+            media_dir.display(),
+            e
+        ))
+    })?;
+    let resolved = media_dir.join(path).canonicalize().map_err(|e| {
+        HttpError::for_bad_request(
+            None,
+            format!("cannot open media {}: {}", path, e),
+        )
+    })?;
+    if !resolved.starts_with(&media_dir) {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!("media {} is outside the media directory", path),
+        ));
+    }
+    if !resolved.metadata().map_or(false, |md| md.is_file()) {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!("media {} is not a regular file", path),
+        ));
+    }
+    Ok(resolved)
+}
+
+#[endpoint {
+    method = PUT,
+    path = "/instances/{instance_id}/disks/{name}/media",
+}]
+async fn instance_disk_media_put(
+    rqctx: Arc<RequestContext<Context>>,
+    path_params: Path<api::InstanceDiskPathParams>,
+    request: TypedBody<api::DiskMediaRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let server_context = rqctx.context();
+    let mut context = server_context.context.lock().await;
+
+    let context = context.as_mut().ok_or_else(|| {
+        HttpError::for_internal_error(
+            "Server not initialized (no instance)".to_string(),
+        )
+    })?;
+    let path_params = path_params.into_inner();
+    if path_params.instance_id != context.properties.id {
+        return Err(HttpError::for_internal_error(
+            "UUID mismatch (path did not match struct)".to_string(),
+        ));
+    }
+
+    let request = request.into_inner();
+    let name = path_params.name;
+    let device = context.block_devices.get(&name).ok_or_else(|| {
+        HttpError::for_not_found(None, format!("no such disk: {}", name))
+    })?;
+    let old = context.block_backends.get(&name);
+    if old.map_or(false, |be| be.info().writable) {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!("cannot change media of writable disk: {}", name),
+        ));
+    }
+
+    // Inserted media is always read-only
+    let new = match request.path.as_ref() {
+        Some(path) => {
+            let path = resolve_media_path(
+                server_context.config.get_media_dir(),
+                path,
+            )?;
+            let block_size = request
+                .block_size
+                .or_else(|| old.map(|be| be.info().block_size))
+                .ok_or_else(|| {
+                    HttpError::for_bad_request(
+                        None,
+                        "block size required for media inserted into an empty disk"
+                            .to_string(),
+                    )
+                })?;
+            let be = block::FileBackend::create(
+                &path,
+                true,
+                block_size as usize,
+                NonZeroUsize::new(8).unwrap(),
+            )
+            .map_err(|e| {
+                HttpError::for_bad_request(
+                    None,
+                    format!("cannot open media {}: {}", path.display(), e),
+                )
+            })?;
+            Some(be as Arc<dyn block::Backend>)
+        }
+        None => None,
+    };
+
+    // Faults injected into the disk carry over to the new media.  Once ejected,
+    // its (detached) fault backend is retained, keeping any changes made to
+    // the policy in the meantime for whatever media is inserted next.
+    let fault = context.fault_backends.get(&name).map(|f| f.policy());
+    let (new, new_fault) = match (new, fault) {
+        (Some(be), Some(policy)) => {
+            let fault = block::FaultBackend::create(be, policy)
+                .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
+            (Some(Arc::clone(&fault) as Arc<dyn block::Backend>), Some(fault))
+        }
+        (new, _) => (new, None),
+    };
+
+    // Carry any I/O limits over to the new media
+    let limits = old.and_then(|be| be.driver()).map(|d| d.throttle().limits());
+
+    block::change_media(device, old, new.as_ref(), context.instance.disp())
+        .await
+        .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
+
+    if let (Some(new), Some(limits)) = (new.as_ref(), limits) {
+        set_throttle_limits(new, limits)
+            .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
+    }
+    if let Some(fault) = new_fault {
+        context.fault_backends.insert(name.clone(), fault);
+    }
+    match new {
+        Some(new) => context.block_backends.insert(name, new),
+        None => context.block_backends.remove(&name),
+    };
+
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+#[endpoint {
+    method = PUT,
+    path = "/instances/{instance_id}/disks/{name}/resize",
+}]
+async fn instance_disk_resize_put(
+    rqctx: Arc<RequestContext<Context>>,
+    path_params: Path<api::InstanceDiskPathParams>,
+    request: TypedBody<api::DiskResizeRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let context = rqctx.context().context.lock().await;
+
+    let context = context.as_ref().ok_or_else(|| {
+        HttpError::for_internal_error(
+            "Server not initialized (no instance)".to_string(),
+        )
+    })?;
+    let path_params = path_params.into_inner();
+    if path_params.instance_id != context.properties.id {
+        return Err(HttpError::for_internal_error(
+            "UUID mismatch (path did not match struct)".to_string(),
+        ));
+    }
+
+    let request = request.into_inner();
+    let name = path_params.name;
+    let not_found =
+        || HttpError::for_not_found(None, format!("no such disk: {}", name));
+    let device = context.block_devices.get(&name).ok_or_else(not_found)?;
+    let backend = context.block_backends.get(&name).ok_or_else(not_found)?;
+
+    let block_size = backend.info().block_size as u64;
+    if request.size % block_size != 0 {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!(
+                "size {} not multiple of block size {}",
+                request.size, block_size
+            ),
+        ));
+    }
+    block::resize(
+        device,
+        backend,
+        request.size / block_size,
+        context.instance.disp(),
+    )
+    .map_err(|e| match e.kind() {
+        ErrorKind::Unsupported => HttpError::for_client_error(
+            Some("Unsupported".to_string()),
+            StatusCode::BAD_REQUEST,
+            format!("cannot resize disk {}: {}", name, e),
+        ),
+        _ => HttpError::for_bad_request(None, e.to_string()),
+    })?;
+
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+#[endpoint {
+    method = PUT,
+    path = "/instances/{instance_id}/disks/{name}/overlay",
+}]
+async fn instance_disk_overlay_put(
+    rqctx: Arc<RequestContext<Context>>,
+    path_params: Path<api::InstanceDiskPathParams>,
+    request: TypedBody<api::DiskOverlayRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let context = rqctx.context().context.lock().await;
+
+    let context = context.as_ref().ok_or_else(|| {
+        HttpError::for_internal_error(
+            "Server not initialized (no instance)".to_string(),
+        )
+    })?;
+    let path_params = path_params.into_inner();
+    if path_params.instance_id != context.properties.id {
+        return Err(HttpError::for_internal_error(
+            "UUID mismatch (path did not match struct)".to_string(),
+        ));
+    }
+
+    let request = request.into_inner();
+    let name = path_params.name;
+    let overlay = context.overlays.get(&name).ok_or_else(|| {
+        HttpError::for_not_found(None, format!("no overlay for disk: {}", name))
+    })?;
+
+    // The overlay must not be changed beneath I/O in flight, so the guest must
+    // not (yet, or any longer) be running.
+    match context.instance.current_state() {
+        propolis::instance::State::Initialize
+        | propolis::instance::State::Halt
+        | propolis::instance::State::Destroy => {}
+        _ => {
+            return Err(HttpError::for_bad_request(
+                None,
+                "overlays may only be changed while the instance is not running"
+                    .to_string(),
+            ));
+        }
+    }
+
+    let backend = Arc::clone(&overlay.backend);
+    let res = match request.action {
+        api::DiskOverlayAction::Discard => {
+            tokio::task::spawn_blocking(move || backend.discard()).await
+        }
+        api::DiskOverlayAction::Commit => {
+            let path = overlay.commit_path.clone().ok_or_else(|| {
+                HttpError::for_bad_request(
+                    None,
+                    format!("overlay for disk {} cannot be committed", name),
+                )
+            })?;
+            tokio::task::spawn_blocking(move || {
+                let target =
+                    std::fs::OpenOptions::new().write(true).open(&path)?;
+                backend.commit(&target)
+            })
+            .await
+        }
+    };
+    res.map_err(|e| HttpError::for_internal_error(e.to_string()))?
+        .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
+
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
 // This endpoint is meant to only be called during a migration from the destination
 // instance to the source instance as part of the HTTP connection upgrade used to
 // establish the migration link. We don't actually want this exported via OpenAPI
@@ -976,6 +1639,12 @@ pub fn api() -> ApiDescription<Context> {
     api.register(instance_state_put).unwrap();
     api.register(instance_serial).unwrap();
     api.register(instance_serial_detach).unwrap();
+    api.register(instance_disk_stats).unwrap();
+    api.register(instance_disk_throttle_put).unwrap();
+    api.register(instance_disk_faults_put).unwrap();
+    api.register(instance_disk_media_put).unwrap();
+    api.register(instance_disk_resize_put).unwrap();
+    api.register(instance_disk_overlay_put).unwrap();
     api.register(instance_migrate_start).unwrap();
     api.register(instance_migrate_status).unwrap();
     api
@@ -1,13 +1,17 @@
 use std::collections::{btree_map, BTreeMap};
-use std::num::NonZeroUsize;
+use std::convert::TryFrom;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use serde_derive::Deserialize;
 
 use crate::hw::pci;
 use propolis::block;
 use propolis::dispatch::Dispatcher;
+use propolis::hw::nvme;
 use propolis::inventory::ChildRegister;
 
 #[derive(Deserialize, Debug)]
@@ -55,7 +59,33 @@ impl Config {
         disp: &Dispatcher,
     ) -> (Arc<dyn block::Backend>, ChildRegister) {
         let entry = self.inner.block_devs.get(name).unwrap();
-        entry.block_dev(disp)
+        let (be, creg) = entry.block_dev(disp);
+        let be = match entry.overlay_scratch() {
+            Some(scratch) => {
+                let overlay: Arc<dyn block::Backend> =
+                    block::OverlayBackend::create(
+                        be,
+                        scratch,
+                        NonZeroUsize::new(8).unwrap(),
+                    )
+                    .unwrap();
+                overlay
+            }
+            None => be,
+        };
+        match entry.fault_policy() {
+            Some(policy) => {
+                let fault: Arc<dyn block::Backend> =
+                    block::FaultBackend::create(be, policy).unwrap();
+                (fault, creg)
+            }
+            None => (be, creg),
+        }
+    }
+
+    pub fn block_throttle_limits(&self, name: &str) -> block::ThrottleLimits {
+        let entry = self.inner.block_devs.get(name).unwrap();
+        entry.throttle_limits()
     }
 }
 
@@ -69,6 +99,128 @@ pub struct Device {
     pub options: BTreeMap<String, toml::Value>,
 }
 
+impl Device {
+    /// Names of the block devices backing the device: those listed in
+    /// `block_devs`, or else the single `block_dev`.
+    pub fn block_devs(&self) -> Vec<&str> {
+        match self.options.get("block_devs") {
+            Some(list) => list
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|v| v.as_str().unwrap())
+                .collect(),
+            None => {
+                vec![self.options.get("block_dev").unwrap().as_str().unwrap()]
+            }
+        }
+    }
+
+    /// Names of the ports a virtio-console device has in addition to its
+    /// console port, as listed in `ports`.
+    pub fn console_ports(&self) -> Vec<String> {
+        match self.options.get("ports") {
+            Some(list) => list
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|v| v.as_str().unwrap().to_string())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Directory in which the sockets for the ports of a virtio-console
+    /// device are created, as given in `socket_dir`, or else the working
+    /// directory.
+    pub fn console_socket_dir(&self) -> PathBuf {
+        match self.options.get("socket_dir") {
+            Some(dir) => PathBuf::from(dir.as_str().unwrap()),
+            None => PathBuf::from("."),
+        }
+    }
+
+    /// Limit, in bytes per second, on the random data provided by a
+    /// virtio-rng device, as given in `rate_limit`.
+    pub fn rng_rate_limit(&self) -> std::io::Result<Option<NonZeroU64>> {
+        match self.options.get("rate_limit") {
+            None => Ok(None),
+            Some(v) => v
+                .as_integer()
+                .and_then(|v| u64::try_from(v).ok())
+                .and_then(NonZeroU64::new)
+                .map(Some)
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("invalid rate_limit: {}", v),
+                    )
+                }),
+        }
+    }
+
+    /// Revision of the NVMe specification an NVMe device presents, as given
+    /// in `nvme_version` (defaulting to 1.0).
+    pub fn nvme_version(&self) -> std::io::Result<nvme::NvmeVersion> {
+        match self.options.get("nvme_version") {
+            Some(v) => {
+                v.as_str().and_then(|s| s.parse().ok()).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("invalid nvme_version: {}", v),
+                    )
+                })
+            }
+            None => Ok(nvme::NvmeVersion::default()),
+        }
+    }
+
+    /// Identifying details configured for an NVMe device, and for each of
+    /// the namespaces backed by its `count` block devices.
+    ///
+    /// Namespace identifiers (`eui64`, `nguid`) are listed in the same order
+    /// as the block devices, though may be given as a single string for a
+    /// lone block device.
+    pub fn nvme_identity(
+        &self,
+        count: usize,
+    ) -> (nvme::CtrlIdentity, Vec<nvme::NsIdentity>) {
+        let string = |key: &str| {
+            self.options.get(key).map(|v| v.as_str().unwrap().to_string())
+        };
+        let strings = |key: &str| -> Vec<Option<String>> {
+            let values = match self.options.get(key) {
+                Some(toml::Value::Array(list)) => list.iter().collect(),
+                Some(value) => vec![value],
+                None => return vec![None; count],
+            };
+            assert_eq!(
+                values.len(),
+                count,
+                "{} must list a value for each block device",
+                key
+            );
+            values
+                .into_iter()
+                .map(|v| Some(v.as_str().unwrap().to_string()))
+                .collect()
+        };
+
+        let ctrl = nvme::CtrlIdentity {
+            serial_number: string("serial_number"),
+            model_number: string("model_number"),
+            firmware_rev: string("firmware_rev"),
+            ieee_oui: string("ieee_oui"),
+        };
+        let namespaces = strings("eui64")
+            .into_iter()
+            .zip(strings("nguid"))
+            .map(|(eui64, nguid)| nvme::NsIdentity { eui64, nguid })
+            .collect();
+        (ctrl, namespaces)
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct BlockDevice {
     #[serde(default, rename = "type")]
@@ -79,6 +231,78 @@ pub struct BlockDevice {
 }
 
 impl BlockDevice {
+    /// Parses the optional `iops`, `iops_burst`, `bps`, and `bps_burst` limits
+    /// for the block device.
+    pub fn throttle_limits(&self) -> block::ThrottleLimits {
+        let get = |key: &str| -> Option<u64> {
+            let val = self.options.get(key)?;
+            Some(u64::try_from(val.as_integer().unwrap()).unwrap())
+        };
+        block::ThrottleLimits {
+            iops: get("iops"),
+            iops_burst: get("iops_burst"),
+            bps: get("bps"),
+            bps_burst: get("bps_burst"),
+        }
+    }
+
+    /// Parses the optional `faults` table for the block device, describing
+    /// the faults to be injected into its requests.
+    pub fn fault_policy(&self) -> Option<block::FaultPolicy> {
+        let faults = self.options.get("faults")?.as_table().unwrap();
+        let get_u64 = |key: &str| -> Option<u64> {
+            let val = faults.get(key)?;
+            Some(u64::try_from(val.as_integer().unwrap()).unwrap())
+        };
+        let get_bool = |key: &str| -> bool {
+            faults.get(key).map(|v| v.as_bool().unwrap()).unwrap_or(false)
+        };
+        let fail_lba_ranges = faults
+            .get("fail_lba_ranges")
+            .map(|v| {
+                v.as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|r| {
+                        let r = r.as_array().unwrap();
+                        assert_eq!(r.len(), 2, "LBA ranges are [start, end]");
+                        let start = r[0].as_integer().unwrap();
+                        let end = r[1].as_integer().unwrap();
+                        u64::try_from(start).unwrap()
+                            ..u64::try_from(end).unwrap()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Some(block::FaultPolicy {
+            fail_every: get_u64("fail_every").and_then(NonZeroU64::new),
+            fail_lba_ranges,
+            latency: get_u64("latency_ms").map(Duration::from_millis),
+            unsupported: get_bool("unsupported"),
+            read_only: get_bool("readonly"),
+        })
+    }
+
+    /// Parses the optional `overlay` for the block device, which keeps writes
+    /// apart from the backend in a copy-on-write overlay: either `"memory"`,
+    /// or the path of a (sparse) scratch file, created afresh.
+    pub fn overlay_scratch(&self) -> Option<block::OverlayScratch> {
+        match self.options.get("overlay")?.as_str().unwrap() {
+            "memory" => Some(block::OverlayScratch::Memory),
+            path => {
+                let fp = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(path)
+                    .unwrap();
+                Some(block::OverlayScratch::File(fp))
+            }
+        }
+    }
+
     pub fn block_dev(
         &self,
         _disp: &Dispatcher,
@@ -91,8 +315,31 @@ impl BlockDevice {
                     self.options.get("readonly")?.as_str()?.parse().ok()
                 }()
                 .unwrap_or(false);
+                let block_size =
+                    self.options.get("block_size").map_or(512, |v| {
+                        usize::try_from(v.as_integer().unwrap()).unwrap()
+                    });
 
                 let be = block::FileBackend::create(
+                    path,
+                    readonly,
+                    block_size,
+                    NonZeroUsize::new(8).unwrap(),
+                )
+                .unwrap();
+
+                let creg = ChildRegister::new(&be, None);
+                (be, creg)
+            }
+            "qcow2" => {
+                let path = self.options.get("path").unwrap().as_str().unwrap();
+
+                let readonly: bool = || -> Option<bool> {
+                    self.options.get("readonly")?.as_str()?.parse().ok()
+                }()
+                .unwrap_or(false);
+
+                let be = block::Qcow2Backend::create(
                     path,
                     readonly,
                     NonZeroUsize::new(8).unwrap(),
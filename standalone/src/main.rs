@@ -228,6 +228,11 @@ fn main() {
                         vioblk.clone() as Arc<dyn block::Device>,
                         disp,
                     )?;
+                    if let Some(driver) = backend.driver() {
+                        driver.throttle().set_limits(
+                            config.block_throttle_limits(block_dev),
+                        )?;
+                    }
 
                     chipset.pci_attach(bdf, vioblk);
                 }
@@ -242,25 +247,78 @@ fn main() {
                     inv.register_instance(&viona, bdf.to_string())?;
                     chipset.pci_attach(bdf, viona);
                 }
-                "pci-nvme" => {
-                    let block_dev =
-                        dev.options.get("block_dev").unwrap().as_str().unwrap();
+                "pci-virtio-console" => {
+                    let bdf = bdf.unwrap();
+                    let console = hw::virtio::PciVirtioConsole::new(
+                        0x100,
+                        dev.console_ports(),
+                    )?;
 
-                    let (backend, creg) = config.block_dev(block_dev, disp);
+                    // Each port is reachable through a socket named for the
+                    // device, and the port itself if it is not the console.
+                    let socket_dir = dev.console_socket_dir();
+                    for port in console.ports() {
+                        let path = match port.name() {
+                            Some(pname) => format!("{}.{}", name, pname),
+                            None => name.to_string(),
+                        };
+                        let sock =
+                            chardev::UDSock::bind(&socket_dir.join(path))?;
+                        sock.spawn(
+                            Arc::clone(port) as Arc<dyn Sink>,
+                            Arc::clone(port) as Arc<dyn Source>,
+                            disp,
+                        );
+                    }
+
+                    inv.register_instance(&console, bdf.to_string())?;
+                    chipset.pci_attach(bdf, console);
+                }
+                "pci-virtio-rng" => {
                     let bdf = bdf.unwrap();
 
-                    let info = backend.info();
+                    let rng = hw::virtio::PciVirtioRng::new(
+                        0x100,
+                        dev.rng_rate_limit()?,
+                    )?;
+                    inv.register_instance(&rng, bdf.to_string())?;
+                    chipset.pci_attach(bdf, rng);
+                }
+                "pci-nvme" => {
+                    let block_devs = dev.block_devs();
+                    let bdf = bdf.unwrap();
+
+                    let version = dev.nvme_version()?;
+
+                    let (ctrl_ident, ns_idents) =
+                        dev.nvme_identity(block_devs.len());
+
                     let nvme = hw::nvme::PciNvme::create(
                         0x1de,
                         0x1000,
-                        block_dev.to_string(),
-                        info,
-                    );
-
+                        block_devs[0].to_string(),
+                        version,
+                        &ctrl_ident,
+                    )
+                    .unwrap();
                     let id = inv.register_instance(&nvme, bdf.to_string())?;
-                    let _be_id = inv.register_child(creg, id)?;
 
-                    backend.attach(nvme.clone(), disp)?;
+                    // Each block device backs a namespace of its own
+                    for (block_dev, ns_ident) in
+                        block_devs.iter().zip(ns_idents)
+                    {
+                        let (backend, creg) = config.block_dev(block_dev, disp);
+                        let _be_id = inv.register_child(creg, id)?;
+
+                        let ns =
+                            nvme.add_ns(backend.info(), &ns_ident).unwrap();
+                        backend.attach(ns, disp)?;
+                        if let Some(driver) = backend.driver() {
+                            driver.throttle().set_limits(
+                                config.block_throttle_limits(block_dev),
+                            )?;
+                        }
+                    }
 
                     chipset.pci_attach(bdf, nvme);
                 }
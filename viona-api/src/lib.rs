@@ -7,6 +7,7 @@ pub const VNA_IOC_RING_RESET: i32 = VNA_IOC | 0x11;
 pub const VNA_IOC_RING_KICK: i32 = VNA_IOC | 0x12;
 pub const VNA_IOC_RING_SET_MSI: i32 = VNA_IOC | 0x13;
 pub const VNA_IOC_RING_INTR_CLR: i32 = VNA_IOC | 0x14;
+pub const VNA_IOC_RING_INIT_MODERN: i32 = VNA_IOC | 0x18;
 
 pub const VNA_IOC_INTR_POLL: i32 = VNA_IOC | 0x20;
 pub const VNA_IOC_SET_FEATURES: i32 = VNA_IOC | 0x21;
@@ -35,6 +36,16 @@ mod structs {
         pub ri_qaddr: u64,
     }
 
+    #[repr(C)]
+    pub struct vioc_ring_init_modern {
+        pub rim_index: u16,
+        pub rim_qsize: u16,
+        pub _pad: [u16; 2],
+        pub rim_qaddr_desc: u64,
+        pub rim_qaddr_avail: u64,
+        pub rim_qaddr_used: u64,
+    }
+
     #[repr(C)]
     pub struct vioc_ring_msi {
         pub rm_index: u16,